@@ -52,6 +52,12 @@ use tox::toxcore::crypto_core::*;
 use tox::toxcore::io_tokio::*;
 use tox::toxcore::dht::dht_friend::*;
 use tox::toxcore::net_crypto::*;
+use tox::toxcore::onion::client::*;
+use tox::toxcore::friend_connection::*;
+use tox::toxcore::messenger::*;
+use tox::toxcore::friend_requests::*;
+use tox::toxcore::tcp::client::Connections;
+use tox::toxcore::toxid::NoSpam;
 
 fn main() {
     env_logger::init();
@@ -78,7 +84,7 @@ fn main() {
         gen_keypair()
     };
 
-    let (real_pk, _real_sk) = gen_keypair();
+    let (real_pk, real_sk) = gen_keypair();
 
     // Create a channel for server to communicate with network
     let (tx, rx) = mpsc::unbounded::<(DhtPacket, SocketAddr)>();
@@ -93,20 +99,14 @@ fn main() {
     // Lossless unlike lossy guarantees delivery and ordering of sent packets.
     let (lossless_tx, lossless_rx) = mpsc::unbounded();
     let (lossy_tx, lossy_rx) = mpsc::unbounded();
+    let (new_connection_tx, new_connection_rx) = mpsc::unbounded();
+    let (connection_status_tx, connection_status_rx) = mpsc::unbounded();
+    let (tcp_tx, tcp_rx) = mpsc::unbounded();
+    let (tcp_data_tx, tcp_data_rx) = mpsc::unbounded();
 
     let local_addr: SocketAddr = "0.0.0.0:33445".parse().unwrap(); // 0.0.0.0 for ipv4
     // let local_addr: SocketAddr = "[::]:33445".parse().unwrap(); // [::] for ipv6
 
-    // Ignore DHT PublicKey updates for now
-    let dht_pk_handler = dht_pk_rx
-        .map_err(|_| Error::new(ErrorKind::Other, "rx error"))
-        .for_each(|_| future::ok(()));
-
-    // Ignore lossless packets for now
-    let lossless_handler = lossless_rx
-        .map_err(|_| Error::new(ErrorKind::Other, "rx error"))
-        .for_each(|_| future::ok(()));
-
     // Ignore lossy packets for now
     let lossy_handler = lossy_rx
         .map_err(|_| Error::new(ErrorKind::Other, "rx error"))
@@ -114,18 +114,65 @@ fn main() {
 
     let net_crypto = NetCrypto::new(NetCryptoNewArgs {
         udp_tx: tx.clone(),
-        dht_pk_tx,
+        tcp_tx,
+        dht_pk_tx: dht_pk_tx.clone(),
         lossless_tx,
         lossy_tx,
+        new_connection_tx,
+        connection_status_tx,
         dht_pk: server_pk,
         dht_sk: server_sk.clone(),
-        real_pk
+        real_pk,
+        real_sk: real_sk.clone()
     });
 
+    // TCP relays are used when a friend can't be reached via UDP and to send
+    // onion requests when there are not enough DHT nodes
+    let (onion_response_tx, onion_response_rx) = mpsc::unbounded();
+    let mut tcp_connections = Connections::new(server_pk, server_sk.clone(), tcp_data_tx);
+    tcp_connections.set_onion_response_sink(onion_response_tx);
+    let tcp_handler = net_crypto.run_tcp(tcp_connections.clone(), tcp_rx, tcp_data_rx);
+
     let lan_discovery_sender = LanDiscoverySender::new(tx.clone(), server_pk, local_addr.is_ipv6());
 
-    let mut server_obj = Server::new(tx, server_pk, server_sk);
-    server_obj.set_net_crypto(net_crypto);
+    let mut server_obj = Server::new(tx.clone(), server_pk, server_sk);
+    server_obj.set_net_crypto(net_crypto.clone());
+
+    let mut onion_client = OnionClient::new(tx, dht_pk_tx, server_pk, real_pk, real_sk, server_obj.close_nodes.clone());
+    let (friend_request_tx, friend_request_rx) = mpsc::unbounded();
+    onion_client.set_friend_request_sink(friend_request_tx);
+    onion_client.set_tcp_connections(tcp_connections.clone());
+    server_obj.set_onion_client(onion_client.clone());
+    let onion_tcp_handler = onion_client.run_tcp(onion_response_rx);
+
+    // Friend requests module accepts requests with our NoSpam. Ignore accepted
+    // requests for now
+    let (accepted_request_tx, accepted_request_rx) = mpsc::unbounded();
+    let friend_requests = FriendRequests::new(onion_client.clone(), NoSpam::new(), accepted_request_tx);
+    let friend_requests_handler = friend_requests.run(friend_request_rx);
+
+    let accepted_request_handler = accepted_request_rx
+        .map_err(|_| Error::new(ErrorKind::Other, "rx error"))
+        .for_each(|_| future::ok(()));
+
+    // Friend connections handle DHT PublicKey updates, new crypto connections,
+    // connection status events and lossless packets. They also run net crypto
+    // and onion client main loops and connect to friends through TCP relays
+    let (friend_status_tx, friend_status_rx) = mpsc::unbounded();
+    let (friend_lossless_tx, friend_lossless_rx) = mpsc::unbounded();
+    let mut friend_connections = FriendConnections::new(server_obj.clone(), onion_client.clone(), net_crypto.clone(), friend_status_tx, friend_lossless_tx);
+    friend_connections.set_tcp_connections(tcp_connections.clone());
+    let friend_connections_handler = friend_connections.run(dht_pk_rx, new_connection_rx, connection_status_rx, lossless_rx);
+
+    // Messenger handles friends' status changes and their lossless packets.
+    // Ignore messenger events for now
+    let (messenger_event_tx, messenger_event_rx) = mpsc::unbounded();
+    let messenger = Messenger::new(friend_connections, net_crypto, messenger_event_tx);
+    let messenger_handler = messenger.run(friend_status_rx, friend_lossless_rx);
+
+    let messenger_event_handler = messenger_event_rx
+        .map_err(|_| Error::new(ErrorKind::Other, "rx error"))
+        .for_each(|_| future::ok(()));
 
     // Bootstrap from nodes
     for &(pk, saddr) in &[
@@ -158,6 +205,9 @@ fn main() {
         let saddr: SocketAddr = saddr.parse().unwrap();
         let bootstrap_pn = PackedNode::new(true, saddr, &bootstrap_pk);
         assert!(server_obj.try_add_to_close_nodes(&bootstrap_pn));
+        onion_client.add_path_node(bootstrap_pn);
+        // bootstrap nodes usually run TCP relays on the same address
+        tcp_connections.add_relay(bootstrap_pk, saddr);
     }
 
     // add example friend
@@ -229,9 +279,15 @@ fn main() {
     let server: IoFuture<()> = Box::new(network); // TODO: remove these boxes on rustc 1.26
     let server: IoFuture<()> = Box::new(server.select(run_server(&server_obj)).map(|_| ()).map_err(|(e, _)| e));
     let server: IoFuture<()> = Box::new(server.select(run_lan_discovery_sender(lan_discovery_sender)).map(|_| ()).map_err(|(e, _)| e));
-    let server: IoFuture<()> = Box::new(server.select(dht_pk_handler).map(|_| ()).map_err(|(e, _)| e));
-    let server: IoFuture<()> = Box::new(server.select(lossless_handler).map(|_| ()).map_err(|(e, _)| e));
+    let server: IoFuture<()> = Box::new(server.select(friend_connections_handler).map(|_| ()).map_err(|(e, _)| e));
+    let server: IoFuture<()> = Box::new(server.select(friend_requests_handler).map(|_| ()).map_err(|(e, _)| e));
+    let server: IoFuture<()> = Box::new(server.select(accepted_request_handler).map(|_| ()).map_err(|(e, _)| e));
+    let server: IoFuture<()> = Box::new(server.select(messenger_handler).map(|_| ()).map_err(|(e, _)| e));
+    let server: IoFuture<()> = Box::new(server.select(messenger_event_handler).map(|_| ()).map_err(|(e, _)| e));
     let server: IoFuture<()> = Box::new(server.select(lossy_handler).map(|_| ()).map_err(|(e, _)| e));
+    let server: IoFuture<()> = Box::new(server.select(tcp_handler).map(|_| ()).map_err(|(e, _)| e));
+    let server: IoFuture<()> = Box::new(server.select(tcp_connections.run()).map(|_| ()).map_err(|(e, _)| e));
+    let server: IoFuture<()> = Box::new(server.select(onion_tcp_handler).map(|_| ()).map_err(|(e, _)| e));
 
     let server = server.map_err(move |err| {
         error!("Processing ended with error: {:?}", err);
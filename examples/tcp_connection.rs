@@ -65,6 +65,7 @@ fn main() {
         to_client_rx,
         from_server_tx,
         to_server_rx,
+        connection: _connection,
         processor
     } = ClientProcessor::new();
 
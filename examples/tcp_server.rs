@@ -19,27 +19,28 @@
 */
 
 extern crate tox;
+extern crate failure;
 extern crate futures;
 extern crate tokio;
-extern crate tokio_codec;
 
 #[macro_use]
 extern crate log;
 extern crate env_logger;
 
 use tox::toxcore::crypto_core::*;
-use tox::toxcore::tcp::handshake::make_server_handshake;
-use tox::toxcore::tcp::codec;
-use tox::toxcore::tcp::server::{Server, ServerProcessor};
+use tox::toxcore::dht::codec::DhtCodec;
+use tox::toxcore::dht::server::Server as DhtServer;
+use tox::toxcore::io_tokio::IoFuture;
+use tox::toxcore::tcp::server::{ServerConfig, new_onion_bridge, run, run_onion_bridge};
 
 use futures::prelude::*;
+use futures::sync::mpsc;
 
-use tokio::util::FutureExt;
-use tokio::net::TcpListener;
-use tokio_codec::Framed;
+use tokio::net::{TcpListener, UdpFramed, UdpSocket};
+use tokio::timer::Interval;
 
-use std::time;
 use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
 
 fn main() {
     env_logger::init();
@@ -56,97 +57,55 @@ fn main() {
     let addr = "0.0.0.0:12345".parse().unwrap();
     let listener = TcpListener::bind(&addr).unwrap();
 
-    info!("Listening on addr={}, {:?}", addr, &server_pk);
-
-    // Ignore all TCP onion requests for now
-    let server_inner = Server::new();
-
-    // TODO move this processing future into a standalone library function
-    let server = listener.incoming().for_each(move |socket| {
-        let addr = socket.peer_addr()
-            .map_err(|e| {
-                error!("could not get peer addr: {}", e);
-                e
-            })?;
-        debug!("A new client connected from {}", addr);
-
-        let register_client = make_server_handshake(socket, server_sk.clone())
-            .map_err(|e|
-                Error::new(ErrorKind::Other,
-                    format!("Handshake error {:?}", e))
-            )
-            .map(|(socket, channel, client_pk)| {
-                debug!("Handshake for client {:?} complited", &client_pk);
-                (socket, channel, client_pk)
-            });
-
-        let server_inner_c = server_inner.clone();
-        let process = register_client.and_then(move |(socket, channel, client_pk)| {
-            let secure_socket = Framed::new(socket, codec::Codec::new(channel));
-            let (to_client, from_client) = secure_socket.split();
-            let ServerProcessor { from_client_tx, to_client_rx, processor } =
-                ServerProcessor::create(
-                    server_inner_c,
-                    client_pk.clone(),
-                    addr.ip(),
-                    addr.port()
-                );
-
-            // writer = for each Packet from to_client_rx send it to client
-            let writer = to_client_rx
-                .map_err(|()| Error::from(ErrorKind::UnexpectedEof))
-                .fold(to_client, move |to_client, packet| {
-                    debug!("Send {:?} => {:?}", client_pk, packet);
-                    to_client.send(packet)
-                        .deadline(time::Instant::now() + time::Duration::from_secs(30))
-                        .map_err(|_|
-                            Error::new(ErrorKind::Other,
-                                format!("Writer timed out"))
-                        )
-                })
-                // drop to_client when to_client_rx stream is exhausted
-                .map(|_to_client| ())
-                .map_err(|_|
-                    Error::new(ErrorKind::Other,
-                        format!("Writer ended with error"))
-                );
-
-            // reader = for each Packet from client send it to server processor
-            let reader = from_client
-                .forward(from_client_tx
-                    .sink_map_err(|e|
-                        Error::new(ErrorKind::Other,
-                            format!("Could not forward message from client to server {:?}", e))
-                    )
-                )
-                .map(|(_from_client, _sink_err)| ())
-                .map_err(|_|
-                    Error::new(ErrorKind::Other,
-                        format!("Reader ended with error"))
-                );
-
-            processor
-                .select(reader)
-                    .map_err(move |(err, _select_next)| {
-                        err
-                    })
-                    .map(|_| ())
-                .select(writer)
-                    .map_err(move |(err, _select_next)| {
-                        err
-                    })
-        });
-
-        tokio::spawn( process.map(|_| ()).map_err(|_| ()) );
-
-        Ok(())
-    })
-    .map_err(|err| {
+    // DHT node is used to send onion requests of TCP relay clients
+    let udp_addr = "0.0.0.0:33445".parse().unwrap();
+    let socket = UdpSocket::bind(&udp_addr).unwrap();
+
+    info!("Listening on addr={}, udp_addr={}, {:?}", addr, udp_addr, &server_pk);
+
+    let (udp_tx, udp_rx) = mpsc::unbounded();
+    let mut dht_server = DhtServer::new(udp_tx, server_pk, server_sk.clone());
+    // Route TCP onion requests through the DHT node
+    let (server, onion_request_rx, onion_response_rx) = new_onion_bridge(&mut dht_server);
+    let onion_bridge = run_onion_bridge(server.clone(), dht_server.clone(), onion_request_rx, onion_response_rx);
+
+    let (sink, stream) = UdpFramed::new(socket, DhtCodec).split();
+
+    let dht_server_c = dht_server.clone();
+    let network_reader = stream
+        .then(|res| Ok::<_, Error>(res.ok()))
+        .filter_map(|packet| packet) // ignore packets that can't be decoded
+        .for_each(move |(packet, addr)|
+            dht_server_c.handle_packet(packet, addr).or_else(|err| {
+                debug!("Failed to handle DHT packet: {:?}", err);
+                Ok(())
+            })
+        );
+
+    let network_writer = udp_rx
+        .map_err(|()| Error::new(ErrorKind::Other, "rx error"))
+        .fold(sink, |sink, (packet, addr)|
+            sink.send((packet, addr)).map_err(|e| Error::new(ErrorKind::Other, e.compat()))
+        )
+        .map(|_sink| ());
+
+    let dht_main_loop = Interval::new(Instant::now(), Duration::from_secs(1))
+        .map_err(|e| Error::new(ErrorKind::Other, format!("DHT timer error: {:?}", e)))
+        .for_each(move |_instant| dht_server.dht_main_loop());
+
+    let future: IoFuture<()> = Box::new(run(listener, server_sk, server, ServerConfig::default())
+        .select(onion_bridge).map(|_| ()).map_err(|(e, _)| e)
+        .select(network_reader).map(|_| ()).map_err(|(e, _)| e)
+        .select(network_writer).map(|_| ()).map_err(|(e, _)| e)
+        .select(dht_main_loop).map(|_| ()).map_err(|(e, _)| e));
+
+    let future = future
+        .map_err(|err| {
             // All tasks must have an `Error` type of `()`. This forces error
             // handling and helps avoid silencing failures.
             //
             // In our example, we are only going to log the error to STDOUT.
-            println!("listener.incoming() error = {:?}", err);
-    });
-    tokio::run(server);
+            println!("Server ended with error: {:?}", err);
+        });
+    tokio::run(future);
 }
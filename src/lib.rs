@@ -102,6 +102,9 @@ pub mod toxcore {
     pub mod dht;
     pub mod onion;
     pub mod net_crypto;
+    pub mod friend_connection;
+    pub mod friend_requests;
+    pub mod messenger;
 }
 
 /// Tox Encrypt Save (a.k.a. **TES**) module. Can be used to ecrypt / decrypt
@@ -141,6 +141,21 @@ pub struct Bucket {
 /// Default number of nodes that bucket can hold.
 pub const BUCKET_DEFAULT_SIZE: usize = 8;
 
+/// Result of adding [`PackedNode`](../packed_node/struct.PackedNode.html) to
+/// `Bucket` or `Kbucket`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddNodeResult {
+    /// Node is too distant to be added
+    NotAdded,
+    /// Node was already in the bucket and it was updated
+    Updated,
+    /// Node was added to the bucket
+    Added,
+    /// Node was added to the full bucket and the most distant node was
+    /// evicted from it
+    Evicted(PackedNode),
+}
+
 impl Bucket {
     /** Create a new `Bucket` to store nodes close to the `PublicKey`.
 
@@ -201,6 +216,21 @@ impl Bucket {
     */
     pub fn try_add(&mut self, base_pk: &PublicKey, new_node: &PackedNode)
         -> bool
+    {
+        self.try_add_node(base_pk, new_node) != AddNodeResult::NotAdded
+    }
+
+    /**
+    Try to add [`PackedNode`] to the bucket the same way as [`try_add`] does
+    it. Returns [`AddNodeResult`] that tells whether the node was added or
+    updated and which node was evicted to make room for it.
+
+    [`PackedNode`]: ../packed_node/struct.PackedNode.html
+    [`try_add`]: #method.try_add
+    [`AddNodeResult`]: ./enum.AddNodeResult.html
+    */
+    pub fn try_add_node(&mut self, base_pk: &PublicKey, new_node: &PackedNode)
+        -> AddNodeResult
     {
         debug!(target: "Bucket", "Trying to add PackedNode.");
         trace!(target: "Bucket", "With bucket: {:?}; PK: {:?} and new node: {:?}",
@@ -213,33 +243,38 @@ impl Bucket {
                 debug!(target: "Bucket",
                     "Updated: the node was already in the bucket.");
                 self.nodes[index] = new_node;
-                true
+                AddNodeResult::Updated
             },
             Err(index) if index == self.nodes.len() => {
                 // index is pointing past the end
                 if self.is_full() {
                     debug!(target: "Bucket",
                         "Node is too distant to add to the bucket.");
-                    false
+                    AddNodeResult::NotAdded
                 } else {
                     // distance to the PK was bigger than the other keys, but
                     // there's still free space in the bucket for a node
                     debug!(target: "Bucket",
                         "Node inserted at the end of the bucket.");
                     self.nodes.push(new_node);
-                    true
+                    AddNodeResult::Added
                 }
             },
             Err(index) => {
                 // index is pointing inside the list
-                if self.is_full() {
+                let evicted = if self.is_full() {
                     debug!(target: "Bucket",
                         "No free space left in the bucket, the last node removed.");
-                    self.nodes.pop();
-                }
+                    self.nodes.pop()
+                } else {
+                    None
+                };
                 debug!(target: "Bucket", "Node inserted inside the bucket.");
                 self.nodes.insert(index, new_node);
-                true
+                match evicted {
+                    Some(node) => AddNodeResult::Evicted(node.into()),
+                    None => AddNodeResult::Added,
+                }
             },
         }
     }
@@ -434,14 +469,25 @@ impl Kbucket {
     Returns `true` if node was added successfully, `false` otherwise.
     */
     pub fn try_add(&mut self, node: &PackedNode) -> bool {
+        self.try_add_node(node) != AddNodeResult::NotAdded
+    }
+
+    /** Add [`PackedNode`](./struct.PackedNode.html) to `Kbucket` the same
+    way as [`try_add`](#method.try_add) does it.
+
+    Returns [`AddNodeResult`](./enum.AddNodeResult.html) that tells whether
+    the node was added or updated and which node was evicted to make room
+    for it.
+    */
+    pub fn try_add_node(&mut self, node: &PackedNode) -> AddNodeResult {
         debug!(target: "Kbucket", "Trying to add PackedNode.");
         trace!(target: "Kbucket", "With PN: {:?}; and self: {:?}", node, self);
 
         match self.bucket_index(&node.pk) {
-            Some(index) => self.buckets[index].try_add(&self.pk, node),
+            Some(index) => self.buckets[index].try_add_node(&self.pk, node),
             None => {
                 trace!("Failed to add node: {:?}", node);
-                false
+                AddNodeResult::NotAdded
             }
         }
     }
@@ -702,6 +748,26 @@ mod tests {
         quickcheck(with_nodes as fn(PackedNode, PackedNode) -> TestResult);
     }
 
+    #[test]
+    fn dht_bucket_try_add_node_test() {
+        fn with_nodes(n1: PackedNode, n2: PackedNode) -> TestResult {
+            let pk = PublicKey([0; PUBLICKEYBYTES]);
+            if pk.distance(&n2.pk, &n1.pk) != Ordering::Less {
+                // n2 should be closer to evict n1
+                return TestResult::discard()
+            }
+
+            let mut bucket = Bucket::new(Some(1));
+
+            assert_eq!(AddNodeResult::Added, bucket.try_add_node(&pk, &n1));
+            assert_eq!(AddNodeResult::Updated, bucket.try_add_node(&pk, &n1));
+            assert_eq!(AddNodeResult::Evicted(n1), bucket.try_add_node(&pk, &n2));
+            assert_eq!(AddNodeResult::NotAdded, bucket.try_add_node(&pk, &n1));
+            TestResult::passed()
+        }
+        quickcheck(with_nodes as fn(PackedNode, PackedNode) -> TestResult);
+    }
+
     // Bucket::remove()
 
     #[test]
@@ -21,7 +21,7 @@
 //! Module for LAN discovery.
 
 use std::iter;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use futures::stream;
 use futures::sync::mpsc;
@@ -139,6 +139,40 @@ impl LanDiscoverySender {
     }
 }
 
+/// Check if IPv4 address belongs to a local network.
+fn ipv4_is_lan(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    ip.is_loopback() || ip.is_private() || ip.is_link_local() ||
+        // RFC 6598 shared address space 100.64.0.0/10
+        (octets[0] == 100 && octets[1] & 0xC0 == 0x40)
+}
+
+/// Check if IPv6 address belongs to a local network.
+fn ipv6_is_lan(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    if segments[..5].iter().all(|&s| s == 0) && segments[5] == 0xFFFF {
+        // IPv4-mapped address
+        return ipv4_is_lan(Ipv4Addr::new(
+            (segments[6] >> 8) as u8, segments[6] as u8,
+            (segments[7] >> 8) as u8, segments[7] as u8,
+        ))
+    }
+    ip.is_loopback() ||
+        // link-local address fe80::/10
+        segments[0] & 0xFFC0 == 0xFE80 ||
+        // all-nodes multicast address used for LAN discovery
+        ip == Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 1)
+}
+
+/// Check if IP address belongs to a local network the same way as c-toxcore
+/// does it. IPv4-mapped IPv6 addresses are checked as IPv4 addresses.
+pub fn ip_is_lan(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ipv4_is_lan(ip),
+        IpAddr::V6(ip) => ipv6_is_lan(ip),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -226,4 +260,29 @@ mod tests {
             rx = rx1;
         }
     }
+
+    #[test]
+    fn ip_is_lan_ipv4() {
+        assert!(ip_is_lan("127.0.0.1".parse().unwrap()));
+        assert!(ip_is_lan("10.1.2.3".parse().unwrap()));
+        assert!(ip_is_lan("172.16.0.1".parse().unwrap()));
+        assert!(ip_is_lan("192.168.1.1".parse().unwrap()));
+        assert!(ip_is_lan("169.254.1.1".parse().unwrap()));
+        assert!(ip_is_lan("100.64.0.1".parse().unwrap()));
+        assert!(ip_is_lan("100.127.255.254".parse().unwrap()));
+        assert!(!ip_is_lan("100.128.0.1".parse().unwrap()));
+        assert!(!ip_is_lan("8.8.8.8".parse().unwrap()));
+        assert!(!ip_is_lan("172.32.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ip_is_lan_ipv6() {
+        assert!(ip_is_lan("::1".parse().unwrap()));
+        assert!(ip_is_lan("fe80::1".parse().unwrap()));
+        assert!(ip_is_lan("ff02::1".parse().unwrap()));
+        assert!(ip_is_lan("::ffff:192.168.1.1".parse().unwrap()));
+        assert!(!ip_is_lan("::ffff:8.8.8.8".parse().unwrap()));
+        assert!(!ip_is_lan("2001:db8::1".parse().unwrap()));
+        assert!(!ip_is_lan("ff02::2".parse().unwrap()));
+    }
 }
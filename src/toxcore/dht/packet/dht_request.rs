@@ -31,6 +31,7 @@ use std::io::{Error, ErrorKind};
 use toxcore::binary_io::*;
 use toxcore::crypto_core::*;
 use toxcore::dht::codec::*;
+use toxcore::dht::packed_node::*;
 
 /** DHT Request packet struct.
 DHT Request packet consists of NatPingRequest and NatPingResponse.
@@ -287,6 +288,99 @@ impl ToBytes for DhtPkAnnounce {
     }
 }
 
+impl DhtPkAnnounce {
+    /// Create new `DhtPkAnnounce` object.
+    pub fn new(shared_secret: &PrecomputedKey, real_pk: &PublicKey, payload: DhtPkAnnouncePayload) -> DhtPkAnnounce {
+        let nonce = gen_nonce();
+
+        let mut buf = [0; MAX_DHT_PACKET_SIZE];
+        let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+        let payload = seal_precomputed(&buf[..size], &nonce, shared_secret);
+
+        DhtPkAnnounce {
+            pk: *real_pk,
+            nonce,
+            payload,
+        }
+    }
+    /** Decrypt payload and try to parse it as `DhtPkAnnouncePayload`.
+
+    Returns `Error` in case of failure:
+
+    - fails to decrypt
+    - fails to parse as `DhtPkAnnouncePayload`
+    */
+    pub fn get_payload(&self, shared_secret: &PrecomputedKey) -> Result<DhtPkAnnouncePayload, Error> {
+        let decrypted = open_precomputed(&self.payload, &self.nonce, shared_secret)
+            .map_err(|()| {
+                debug!("Decrypting DhtPkAnnounce failed!");
+                Error::new(ErrorKind::Other, "DhtPkAnnounce decrypt error.")
+            })?;
+        match DhtPkAnnouncePayload::from_bytes(&decrypted) {
+            IResult::Incomplete(e) => {
+                debug!(target: "DhtRequest", "DhtPkAnnouncePayload deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("DhtPkAnnouncePayload deserialize error: {:?}", e)))
+            },
+            IResult::Error(e) => {
+                debug!(target: "DhtRequest", "DhtPkAnnouncePayload deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("DhtPkAnnouncePayload deserialize error: {:?}", e)))
+            },
+            IResult::Done(_, payload) => {
+                Ok(payload)
+            }
+        }
+    }
+}
+
+/** Unencrypted payload of `DhtPkAnnounce` packet. The same payload is sent
+inside onion data packets when friend's DHT `PublicKey` is not known yet.
+
+`no_reply` is a monotonically increasing number, the packet should be accepted
+only if this number is bigger than the last one received from the same friend.
+
+Length    | Content
+--------- | -------------------------
+`1`       | `0x9C`
+`8`       | `no_reply`
+`32`      | DHT `PublicKey` of sender
+`[0, 204]`| Nodes in packed format
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DhtPkAnnouncePayload {
+    /// Number used to prevent replay attacks
+    pub no_reply: u64,
+    /// DHT `PublicKey` of sender
+    pub dht_pk: PublicKey,
+    /// Up to 4 DHT nodes close to sender that can be used to find him
+    pub nodes: Vec<PackedNode>,
+}
+
+impl FromBytes for DhtPkAnnouncePayload {
+    named!(from_bytes<DhtPkAnnouncePayload>, do_parse!(
+        tag!(&[0x9c][..]) >>
+        no_reply: be_u64 >>
+        dht_pk: call!(PublicKey::from_bytes) >>
+        nodes: many0!(PackedNode::from_bytes) >>
+        cond_reduce!(nodes.len() <= 4, eof!()) >>
+        (DhtPkAnnouncePayload { no_reply, dht_pk, nodes })
+    ));
+}
+
+impl ToBytes for DhtPkAnnouncePayload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x9c) >>
+            gen_be_u64!(self.no_reply) >>
+            gen_slice!(self.dht_pk.as_ref()) >>
+            gen_cond!(self.nodes.len() > 4, |buf| gen_error(buf, 0)) >>
+            gen_many_ref!(&self.nodes, |buf, node| PackedNode::to_bytes(node, buf))
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use toxcore::dht::packet::dht_request::*;
@@ -310,6 +404,53 @@ mod tests {
         })
     );
 
+    encode_decode_test!(
+        dht_pk_announce_inner_payload_encode_decode,
+        DhtPkAnnouncePayload {
+            no_reply: 42,
+            dht_pk: gen_keypair().0,
+            nodes: vec![
+                PackedNode::new(false, "127.0.0.1:12345".parse().unwrap(), &gen_keypair().0)
+            ]
+        }
+    );
+
+    #[test]
+    fn dht_pk_announce_payload_encrypt_decrypt() {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, bob_sk) = gen_keypair();
+        let payload = DhtPkAnnouncePayload {
+            no_reply: 42,
+            dht_pk: gen_keypair().0,
+            nodes: vec![
+                PackedNode::new(false, "127.0.0.1:12345".parse().unwrap(), &gen_keypair().0)
+            ]
+        };
+        // encode payload with shared secret
+        let dht_pk_announce = DhtPkAnnounce::new(&precompute(&bob_pk, &alice_sk), &alice_pk, payload.clone());
+        // decode payload with bob's secret key
+        let decoded_payload = dht_pk_announce.get_payload(&precompute(&dht_pk_announce.pk, &bob_sk)).unwrap();
+        // payloads should be equal
+        assert_eq!(decoded_payload, payload);
+    }
+
+    #[test]
+    fn dht_pk_announce_payload_encrypt_decrypt_invalid_key() {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, _bob_sk) = gen_keypair();
+        let (_eve_pk, eve_sk) = gen_keypair();
+        let payload = DhtPkAnnouncePayload {
+            no_reply: 42,
+            dht_pk: gen_keypair().0,
+            nodes: Vec::new()
+        };
+        // encode payload with shared secret
+        let dht_pk_announce = DhtPkAnnounce::new(&precompute(&bob_pk, &alice_sk), &alice_pk, payload);
+        // try to decode payload with eve's secret key
+        let decoded_payload = dht_pk_announce.get_payload(&precompute(&dht_pk_announce.pk, &eve_sk));
+        assert!(decoded_payload.is_err());
+    }
+
     #[test]
     fn dht_request_payload_encrypt_decrypt() {
         let (alice_pk, alice_sk) = gen_keypair();
@@ -0,0 +1,118 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Errors that can happen when DHT `Server` handles received packets.
+*/
+
+use futures::Future;
+
+use std::io::Error;
+
+use toxcore::crypto_core::*;
+use toxcore::dht::packet::DhtPacket;
+
+/// Shorthand for the future returned by DHT packet handlers.
+pub type HandlePacketFuture = Box<Future<Item = (), Error = HandlePacketError> + Send>;
+
+/// Error that can happen when DHT `Server` handles received packet.
+#[derive(Debug, Fail)]
+pub enum HandlePacketError {
+    /// Error indicates that payload of the packet can't be decrypted or parsed
+    #[fail(display = "Failed to get payload of the packet: {}", error)]
+    GetPayload {
+        /// Decryption or parsing error
+        #[cause]
+        error: Error
+    },
+    /// Error indicates that received ping_id is 0
+    #[fail(display = "Received ping_id is 0")]
+    ZeroPingId,
+    /// Error indicates that we didn't send requests to the node
+    #[fail(display = "No ping data for the node {:?}", pk)]
+    NoPingData {
+        /// `PublicKey` of the node
+        pk: PublicKey
+    },
+    /// Error indicates that received ping_id is unknown or timed out
+    #[fail(display = "Received ping_id {} is unknown or timed out", ping_id)]
+    InvalidPingId {
+        /// Received ping_id
+        ping_id: u64
+    },
+    /// Error indicates that `NatPingResponse` was received from unknown friend
+    #[fail(display = "No friend with PublicKey {:?}", pk)]
+    NoFriend {
+        /// `PublicKey` of the friend
+        pk: PublicKey
+    },
+    /// Error indicates that net_crypto module is not initialised
+    #[fail(display = "Net crypto is not initialised")]
+    NetCryptoNotInitialized,
+    /// Error indicates that net_crypto module failed to handle the packet
+    #[fail(display = "Net crypto failed to handle the packet: {}", error)]
+    NetCrypto {
+        /// Net crypto error
+        #[cause]
+        error: Error
+    },
+    /// Error indicates that onion client module is not initialised
+    #[fail(display = "Onion client is not initialised")]
+    OnionClientNotInitialized,
+    /// Error indicates that onion client module failed to handle the packet
+    #[fail(display = "Onion client failed to handle the packet: {}", error)]
+    OnionClient {
+        /// Onion client error
+        #[cause]
+        error: Error
+    },
+    /// Error indicates that onion announce request or onion data request
+    /// can't be handled
+    #[fail(display = "Failed to handle onion announce packet: {}", error)]
+    OnionAnnounce {
+        /// Onion announce error
+        #[cause]
+        error: Error
+    },
+    /// Error indicates that onion response doesn't contain onion return for
+    /// the next node
+    #[fail(display = "Onion response doesn't have the next onion return")]
+    NoNextOnionReturn,
+    /// Error indicates that `OnionResponse1` contains onion return for the
+    /// next node while it should be the last one
+    #[fail(display = "OnionResponse1 has the next onion return")]
+    UnexpectedNextOnionReturn,
+    /// Error indicates that `OnionResponse1` should be redirected to TCP relay
+    /// but we don't have it
+    #[fail(display = "OnionResponse1 can't be redirected to TCP relay")]
+    NoTcpOnionSink,
+    /// Error indicates that response packet can't be sent
+    #[fail(display = "Failed to send the packet: {}", error)]
+    SendTo {
+        /// Sending error
+        #[cause]
+        error: Error
+    },
+    /// Error indicates that DHT `Server` doesn't handle packets of this kind
+    #[fail(display = "DhtPacket is not handled: {:?}", packet)]
+    UnhandledPacket {
+        /// Received packet
+        packet: DhtPacket
+    },
+}
@@ -0,0 +1,58 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Events that happen in DHT `Server` and can be observed by embedders.
+*/
+
+use std::net::SocketAddr;
+
+use toxcore::crypto_core::*;
+use toxcore::dht::packed_node::*;
+
+/// Event that happens in DHT `Server`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DhtEvent {
+    /// Node was added to the Close List
+    NodeAdded(PackedNode),
+    /// Node was evicted from the Close List to make room for a closer node
+    NodeEvicted(PackedNode),
+    /// Friend got its first close node
+    FriendFound {
+        /// DHT `PublicKey` of the friend
+        friend_pk: PublicKey,
+        /// The first close node of the friend
+        node: PackedNode,
+    },
+    /// Friend responded to our `PingRequest` sent during hole punching
+    HolePunched {
+        /// DHT `PublicKey` of the friend
+        friend_pk: PublicKey,
+        /// Address of the friend behind NAT
+        addr: SocketAddr,
+    },
+    /// Friend was added to its own close nodes so its address is returned by
+    /// `DhtFriend::get_addrs_of_clients`
+    FriendAddrKnown {
+        /// DHT `PublicKey` of the friend
+        friend_pk: PublicKey,
+        /// Address of the friend
+        addr: SocketAddr,
+    },
+}
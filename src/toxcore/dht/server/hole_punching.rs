@@ -70,6 +70,9 @@ pub struct HolePunching {
     /// multi NatPingRequest has this same ping_id
     /// because every NatPingRequest receives NatPingResponse.
     pub ping_id: u64,
+    /// IP address of the friend we are punching hole to,
+    /// `None` if hole punching is not in progress
+    pub punching_ip: Option<IpAddr>,
 }
 
 impl HolePunching {
@@ -84,6 +87,7 @@ impl HolePunching {
             first_punching_index: 0,
             last_punching_index: 0,
             ping_id: HolePunching::new_ping_id(),
+            punching_ip: None,
         }
     }
 
@@ -219,6 +223,8 @@ impl HolePunching {
             return Box::new(future::ok(()))
         }
 
+        self.punching_ip = Some(ip);
+
         // algorithm from irungentoo
         let first_port = ports[0];
         let num_ports = ports.len();
@@ -377,6 +383,7 @@ mod tests {
         thread::sleep(Duration::from_millis(150));
 
         hole_punch.try_nat_punch(&alice, friend_pk, addrs, Duration::from_millis(150)).wait().unwrap();
+        assert_eq!(hole_punch.punching_ip, Some("127.0.0.1".parse().unwrap()));
 
         let (received, _rx) = rx.into_future().wait().unwrap();
         let (packet, _addr_to_send) = received.unwrap();
@@ -0,0 +1,225 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*!
+Iterative lookup of nodes closest to an arbitrary `PublicKey`.
+
+The lookup keeps a list of candidates sorted by distance to the target. Each
+round up to [`LOOKUP_PARALLELISM`] closest candidates that were not queried
+yet are asked for nodes close to the target by `NodesRequest`. Nodes from
+received `NodesResponse`s become new candidates and candidates that didn't
+answer are dropped. The lookup converges when [`LOOKUP_NODES`] closest
+candidates are queried so no closer nodes can appear.
+
+[`LOOKUP_PARALLELISM`]: ./constant.LOOKUP_PARALLELISM.html
+[`LOOKUP_NODES`]: ./constant.LOOKUP_NODES.html
+*/
+
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+use toxcore::crypto_core::*;
+use toxcore::dht::kbucket::Distance;
+use toxcore::dht::packed_node::*;
+
+/// Number of `NodesRequest`s sent simultaneously during one round of lookup.
+pub const LOOKUP_PARALLELISM: usize = 3;
+/// Number of closest nodes the lookup is looking for.
+pub const LOOKUP_NODES: usize = 8;
+/// Timeout in seconds for `NodesResponse`s during one round of lookup.
+pub const LOOKUP_TIMEOUT: u64 = 3;
+
+/// State of iterative lookup of nodes closest to `target`.
+#[derive(Clone, Debug)]
+pub struct Lookup {
+    /// `PublicKey` we are looking for
+    target: PublicKey,
+    /// Our own DHT `PublicKey` that is never queried
+    own_pk: PublicKey,
+    /// Nodes that can be queried sorted by distance to target
+    candidates: Vec<PackedNode>,
+    /// `PublicKey`s of nodes that were queried
+    queried: HashSet<PublicKey>,
+    /// Nodes that answered sorted by distance to target
+    alive: Vec<PackedNode>,
+}
+
+impl Lookup {
+    /// Create new `Lookup` starting with the given nodes.
+    pub fn new(target: PublicKey, own_pk: PublicKey, nodes: &[PackedNode]) -> Lookup {
+        let mut lookup = Lookup {
+            target,
+            own_pk,
+            candidates: Vec::new(),
+            queried: HashSet::new(),
+            alive: Vec::new(),
+        };
+        lookup.add_candidates(nodes);
+        lookup
+    }
+
+    /// Compare nodes by distance to the target.
+    fn cmp(&self, a: &PackedNode, b: &PackedNode) -> Ordering {
+        self.target.distance(&a.pk, &b.pk)
+    }
+
+    /// Add nodes that we learned about to candidates.
+    fn add_candidates(&mut self, nodes: &[PackedNode]) {
+        for node in nodes {
+            if node.pk == self.own_pk || self.candidates.iter().any(|n| n.pk == node.pk) {
+                continue;
+            }
+            if self.queried.contains(&node.pk) {
+                // the node was queried already and either answered or not
+                continue;
+            }
+            let index = match self.candidates.binary_search_by(|n| self.cmp(n, node)) {
+                Ok(index) | Err(index) => index,
+            };
+            self.candidates.insert(index, *node);
+        }
+    }
+
+    /// Choose nodes that should be queried in the next round and mark them as
+    /// queried. Returns empty vector when the lookup is finished.
+    pub fn next_round(&mut self) -> Vec<PackedNode> {
+        let nodes = self.candidates.iter()
+            .take(LOOKUP_NODES)
+            .filter(|node| !self.queried.contains(&node.pk))
+            .take(LOOKUP_PARALLELISM)
+            .cloned()
+            .collect::<Vec<_>>();
+        for node in &nodes {
+            self.queried.insert(node.pk);
+        }
+        nodes
+    }
+
+    /// Handle `NodesResponse` from the queried node.
+    pub fn handle_response(&mut self, node_pk: &PublicKey, nodes: &[PackedNode]) {
+        let node = match self.candidates.iter().find(|n| &n.pk == node_pk) {
+            Some(node) if self.queried.contains(node_pk) => *node,
+            _ => return,
+        };
+        if !self.alive.iter().any(|n| n.pk == node.pk) {
+            let index = match self.alive.binary_search_by(|n| self.cmp(n, &node)) {
+                Ok(index) | Err(index) => index,
+            };
+            self.alive.insert(index, node);
+        }
+        self.add_candidates(nodes);
+    }
+
+    /// Finish the round dropping queried candidates that didn't answer.
+    pub fn finish_round(&mut self) {
+        let queried = &self.queried;
+        let alive = &self.alive;
+        self.candidates.retain(|node|
+            !queried.contains(&node.pk) || alive.iter().any(|n| n.pk == node.pk)
+        );
+    }
+
+    /// Check if all closest candidates are queried so the lookup can't find
+    /// closer nodes.
+    pub fn is_finished(&self) -> bool {
+        self.candidates.iter()
+            .take(LOOKUP_NODES)
+            .all(|node| self.queried.contains(&node.pk))
+    }
+
+    /// Closest nodes that answered sorted by distance to the target.
+    pub fn result(&self) -> Vec<PackedNode> {
+        self.alive.iter().take(LOOKUP_NODES).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    /// Create node which `PublicKey` has the given first byte and zeros after
+    /// it so that its distance to zero key is determined by this byte.
+    fn node(byte: u8) -> PackedNode {
+        let mut pk = [0; PUBLICKEYBYTES];
+        pk[0] = byte;
+        PackedNode::new(false, SocketAddr::from(([127, 0, 0, 1], 33445 + u16::from(byte))), &PublicKey(pk))
+    }
+
+    fn zero_pk() -> PublicKey {
+        PublicKey([0; PUBLICKEYBYTES])
+    }
+
+    #[test]
+    fn next_round_chooses_closest() {
+        let mut lookup = Lookup::new(zero_pk(), gen_keypair().0, &[node(5), node(1), node(4), node(2)]);
+        assert_eq!(lookup.next_round(), vec![node(1), node(2), node(4)]);
+        assert_eq!(lookup.next_round(), vec![node(5)]);
+        assert!(lookup.next_round().is_empty());
+    }
+
+    #[test]
+    fn own_pk_is_not_queried() {
+        let own = node(1);
+        let mut lookup = Lookup::new(zero_pk(), own.pk, &[own, node(2)]);
+        assert_eq!(lookup.next_round(), vec![node(2)]);
+    }
+
+    #[test]
+    fn handle_response_adds_candidates() {
+        let mut lookup = Lookup::new(zero_pk(), gen_keypair().0, &[node(10)]);
+        assert_eq!(lookup.next_round(), vec![node(10)]);
+        lookup.handle_response(&node(10).pk, &[node(3), node(10), node(7)]);
+        lookup.finish_round();
+        assert!(!lookup.is_finished());
+        assert_eq!(lookup.next_round(), vec![node(3), node(7)]);
+        lookup.handle_response(&node(3).pk, &[]);
+        lookup.finish_round();
+        // node 7 didn't answer
+        assert!(lookup.is_finished());
+        assert_eq!(lookup.result(), vec![node(3), node(10)]);
+    }
+
+    #[test]
+    fn handle_response_from_unknown_node() {
+        let mut lookup = Lookup::new(zero_pk(), gen_keypair().0, &[node(10)]);
+        lookup.handle_response(&node(10).pk, &[node(3)]);
+        lookup.handle_response(&node(4).pk, &[node(3)]);
+        assert!(lookup.result().is_empty());
+    }
+
+    #[test]
+    fn is_finished_when_closest_are_queried() {
+        let nodes = (1 .. 12).map(node).collect::<Vec<_>>();
+        let mut lookup = Lookup::new(zero_pk(), gen_keypair().0, &nodes);
+        let mut rounds = 0;
+        while !lookup.is_finished() {
+            for node in lookup.next_round() {
+                lookup.handle_response(&node.pk, &[]);
+            }
+            lookup.finish_round();
+            rounds += 1;
+        }
+        // far nodes are not queried
+        assert_eq!(rounds, 3);
+        assert_eq!(lookup.result(), nodes[.. LOOKUP_NODES].to_vec());
+    }
+}
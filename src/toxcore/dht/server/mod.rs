@@ -29,10 +29,14 @@ This module works on top of other modules.
 pub mod client;
 pub mod ping_sender;
 pub mod hole_punching;
+pub mod lookup;
+pub mod errors;
+pub mod events;
 
 use futures::{Future, Sink, Stream, future, stream};
-use futures::sync::mpsc;
+use futures::sync::{mpsc, oneshot};
 use parking_lot::RwLock;
+use tokio::timer::Delay;
 
 use std::io::{ErrorKind, Error};
 use std::net::SocketAddr;
@@ -56,7 +60,12 @@ use toxcore::dht::dht_friend::*;
 use toxcore::dht::server::hole_punching::*;
 use toxcore::tcp::packet::OnionRequest;
 use toxcore::dht::server::ping_sender::*;
+use toxcore::dht::lan_discovery::ip_is_lan;
+use toxcore::dht::server::lookup::*;
+use toxcore::dht::server::errors::*;
+use toxcore::dht::server::events::*;
 use toxcore::net_crypto::*;
+use toxcore::onion::client::*;
 
 /// Shorthand for the transmit half of the message channel.
 type Tx = mpsc::UnboundedSender<(DhtPacket, SocketAddr)>;
@@ -64,6 +73,13 @@ type Tx = mpsc::UnboundedSender<(DhtPacket, SocketAddr)>;
 /// Shorthand for the transmit half of the TCP onion channel.
 type TcpOnionTx = mpsc::UnboundedSender<(InnerOnionResponse, SocketAddr)>;
 
+/// Shorthand for the transmit half of the DHT events channel.
+type EventTx = mpsc::UnboundedSender<DhtEvent>;
+
+/// Shorthand for the map of requests that wait for a response by
+/// `PublicKey` of the node and ping_id of the sent request.
+type PendingRequests<T> = Arc<RwLock<HashMap<(PublicKey, u64), oneshot::Sender<T>>>>;
+
 /// Ping timeout in seconds
 pub const PING_TIMEOUT: u64 = 5;
 /// Number of Nodes Req sending times to find close nodes
@@ -143,6 +159,17 @@ pub struct Server {
     // pure bootstrap server when we don't have friends and therefore don't
     // have to handle related packets
     net_crypto: Option<NetCrypto>,
+    // Onion client that handles `OnionAnnounceResponse`, `OnionDataResponse`
+    // and `DhtPkAnnounce` packets. It can be `None` in case of pure bootstrap
+    // server
+    onion_client: Option<OnionClient>,
+    // `PingRequest`s sent by `request_ping` that wait for `PingResponse`
+    ping_requests: PendingRequests<PingResponsePayload>,
+    // `NodesRequest`s sent by `request_nodes` that wait for `NodesResponse`
+    nodes_requests: PendingRequests<NodesResponsePayload>,
+    // Sink for events that happen in the server
+    // None if nobody listens to them
+    event_sink: Option<EventTx>,
 }
 
 /// Struct for grouping parameters to Server's main loop
@@ -189,7 +216,11 @@ impl Server {
             motd: Vec::new(),
             config: ConfigArgs::default(),
             tcp_onion_sink: None,
-            net_crypto: None
+            net_crypto: None,
+            onion_client: None,
+            ping_requests: Arc::new(RwLock::new(HashMap::new())),
+            nodes_requests: Arc::new(RwLock::new(HashMap::new())),
+            event_sink: None,
         }
     }
 
@@ -205,6 +236,13 @@ impl Server {
         friends.push(friend);
     }
 
+    /// remove friend by its DHT `PublicKey`
+    pub fn remove_friend(&self, pk: &PublicKey) {
+        let mut friends = self.friends.write();
+
+        friends.retain(|friend| friend.pk != *pk);
+    }
+
     /// set various config values
     pub fn set_config_values(&mut self, config: ConfigArgs) {
         self.config = config;
@@ -378,11 +416,22 @@ impl Server {
 
     /// Send PingRequest to node
     pub fn send_ping_req(&self, node: &PackedNode) -> IoFuture<()> {
-        let mut ping_map = self.ping_map.write();
-        let client = ping_map.entry(node.pk).or_insert_with(PingData::new);
+        let ping_id = self.insert_new_ping_id(&node.pk);
+        self.send_ping_req_with_id(node, ping_id)
+    }
+
+    // add new ping_id to PingData of the node and return it
+    fn insert_new_ping_id(&self, pk: &PublicKey) -> u64 {
+        self.ping_map.write()
+            .entry(*pk)
+            .or_insert_with(PingData::new)
+            .insert_new_ping_id()
+    }
 
+    // send PingRequest with the given ping_id
+    fn send_ping_req_with_id(&self, node: &PackedNode, ping_id: u64) -> IoFuture<()> {
         let payload = PingRequestPayload {
-            id: client.insert_new_ping_id(),
+            id: ping_id,
         };
         let ping_req = DhtPacket::PingRequest(PingRequest::new(
             &precompute(&node.pk, &self.sk),
@@ -392,6 +441,74 @@ impl Server {
         self.send_to(node.saddr, ping_req)
     }
 
+    /**
+    Send `PingRequest` to the node and wait for its `PingResponse`. Resolves
+    with the payload of the response and the measured round-trip time or
+    fails with `ErrorKind::TimedOut` error if the node doesn't respond within
+    [`PING_TIMEOUT`].
+
+    [`PING_TIMEOUT`]: ./constant.PING_TIMEOUT.html
+    */
+    pub fn request_ping(&self, node: &PackedNode) -> IoFuture<(PingResponsePayload, Duration)> {
+        let ping_id = self.insert_new_ping_id(&node.pk);
+        let request = self.send_ping_req_with_id(node, ping_id);
+        Server::wait_response(&self.ping_requests, (node.pk, ping_id), request, Duration::from_secs(PING_TIMEOUT))
+    }
+
+    /**
+    Send `NodesRequest` searching for `search_pk` to the node and wait for its
+    `NodesResponse`. Resolves with the payload of the response and the
+    measured round-trip time or fails with `ErrorKind::TimedOut` error if the
+    node doesn't respond within [`PING_TIMEOUT`].
+
+    [`PING_TIMEOUT`]: ./constant.PING_TIMEOUT.html
+    */
+    pub fn request_nodes(&self, node: &PackedNode, search_pk: PublicKey) -> IoFuture<(NodesResponsePayload, Duration)> {
+        self.request_nodes_with_timeout(node, search_pk, Duration::from_secs(PING_TIMEOUT))
+    }
+
+    // send NodesRequest to the node and wait for its NodesResponse until
+    // timeout expires
+    fn request_nodes_with_timeout(&self, node: &PackedNode, search_pk: PublicKey, timeout: Duration)
+        -> IoFuture<(NodesResponsePayload, Duration)> {
+        // Check if packet is going to be sent to ourself.
+        if self.pk == node.pk {
+            return Box::new(
+                future::err(
+                    Error::new(ErrorKind::Other, "Can't send NodesRequest to ourselves")
+                )
+            )
+        }
+
+        let ping_id = self.insert_new_ping_id(&node.pk);
+        let request = self.send_nodes_req_with_id(node, search_pk, ping_id);
+        Server::wait_response(&self.nodes_requests, (node.pk, ping_id), request, timeout)
+    }
+
+    // register request in the pending requests map, send it and wait for
+    // the response until timeout expires
+    fn wait_response<T: Send + 'static>(requests: &PendingRequests<T>, key: (PublicKey, u64), request: IoFuture<()>,
+        timeout: Duration) -> IoFuture<(T, Duration)> {
+        let (tx, rx) = oneshot::channel();
+        requests.write().insert(key, tx);
+
+        let sent_time = clock_now();
+        let response = rx
+            .map_err(|_| Error::new(ErrorKind::Other, "Request was cancelled"))
+            .map(move |payload| (payload, clock_elapsed(sent_time)));
+        let timeout = Delay::new(sent_time + timeout)
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Request timer error: {:?}", e)))
+            .and_then(|()| Err(Error::new(ErrorKind::TimedOut, "Request timed out")));
+
+        let requests = requests.clone();
+        Box::new(request
+            .and_then(|()| response.select(timeout).map(|(res, _)| res).map_err(|(e, _)| e))
+            .then(move |res| {
+                requests.write().remove(&key);
+                res
+            }))
+    }
+
     /// Send NodesRequest to peer
     pub fn send_nodes_req(&self, target_peer: PackedNode, search_pk: PublicKey, client: &mut PingData) -> IoFuture<()> {
         // Check if packet is going to be sent to ourself.
@@ -403,9 +520,15 @@ impl Server {
             )
         }
 
+        let ping_id = client.insert_new_ping_id();
+        self.send_nodes_req_with_id(&target_peer, search_pk, ping_id)
+    }
+
+    // send NodesRequest with the given ping_id
+    fn send_nodes_req_with_id(&self, target_peer: &PackedNode, search_pk: PublicKey, ping_id: u64) -> IoFuture<()> {
         let payload = NodesRequestPayload {
             pk: search_pk,
-            id: client.insert_new_ping_id(),
+            id: ping_id,
         };
         let nodes_req = DhtPacket::NodesRequest(NodesRequest::new(
             &precompute(&target_peer.pk, &self.sk),
@@ -416,6 +539,61 @@ impl Server {
         self.send_to(target_peer.saddr, nodes_req)
     }
 
+    /**
+    Find nodes closest to `target_pk` by iterative lookup. Lookup starts from
+    nodes of the Close List and close lists of friends. Resolves with up to
+    [`LOOKUP_NODES`] closest nodes that answered our `NodesRequest`s sorted by
+    distance to `target_pk`.
+
+    [`LOOKUP_NODES`]: ./lookup/constant.LOOKUP_NODES.html
+    */
+    pub fn lookup(&self, target_pk: PublicKey) -> IoFuture<Vec<PackedNode>> {
+        let mut nodes = self.close_nodes.read().iter().collect::<Vec<_>>();
+        for friend in self.friends.read().iter() {
+            nodes.extend(friend.close_nodes.to_packed_node());
+        }
+
+        let server = self.clone();
+        let future = future::loop_fn(Lookup::new(target_pk, self.pk, &nodes), move |mut lookup| {
+            let nodes = lookup.next_round();
+            let finished = nodes.is_empty();
+            let round = if finished {
+                Box::new(future::ok(Vec::new()))
+            } else {
+                server.lookup_round(target_pk, &nodes)
+            };
+            round.map(move |responses| {
+                if finished {
+                    return future::Loop::Break(lookup.result())
+                }
+                for (pk, nodes) in responses {
+                    lookup.handle_response(&pk, &nodes);
+                }
+                lookup.finish_round();
+                future::Loop::Continue(lookup)
+            })
+        });
+        Box::new(future)
+    }
+
+    // send NodesRequests to the nodes during lookup and collect their
+    // responses until all of them answer or LOOKUP_TIMEOUT expires
+    fn lookup_round(&self, target_pk: PublicKey, nodes: &[PackedNode]) -> IoFuture<Vec<(PublicKey, Vec<PackedNode>)>> {
+        let requests = nodes.iter()
+            .map(|node| {
+                let pk = node.pk;
+                self.request_nodes_with_timeout(node, target_pk, Duration::from_secs(LOOKUP_TIMEOUT))
+                    .map(move |(payload, _rtt)| (pk, payload.nodes))
+            })
+            .collect::<Vec<_>>();
+
+        // ignore errors of particular requests
+        Box::new(stream::futures_unordered(requests)
+            .then(|res| Ok::<_, Error>(res.ok()))
+            .filter_map(|response| response)
+            .collect())
+    }
+
     // send NatPingRequests to all of my friends and do hole punching.
     fn send_nat_ping_req(&self, nat_ping_req_interval: Duration) -> IoFuture<()> {
         let mut friends = self.friends.write();
@@ -470,7 +648,7 @@ impl Server {
     Function to handle incoming packets. If there is a response packet,
     send back it to the peer.
     */
-    pub fn handle_packet(&self, packet: DhtPacket, addr: SocketAddr) -> IoFuture<()> {
+    pub fn handle_packet(&self, packet: DhtPacket, addr: SocketAddr) -> HandlePacketFuture {
         match packet {
             DhtPacket::PingRequest(packet) => {
                 debug!("Received ping request");
@@ -478,7 +656,7 @@ impl Server {
             },
             DhtPacket::PingResponse(packet) => {
                 debug!("Received ping response");
-                self.handle_ping_resp(packet)
+                self.handle_ping_resp(packet, addr)
             },
             DhtPacket::NodesRequest(packet) => {
                 debug!("Received NodesRequest");
@@ -540,15 +718,20 @@ impl Server {
                 debug!("Received OnionResponse1");
                 self.handle_onion_response_1(packet)
             },
+            DhtPacket::OnionAnnounceResponse(packet) => {
+                debug!("Received OnionAnnounceResponse");
+                self.handle_onion_announce_response(packet)
+            },
+            DhtPacket::OnionDataResponse(packet) => {
+                debug!("Received OnionDataResponse");
+                self.handle_onion_data_response(packet)
+            },
             DhtPacket::BootstrapInfo(packet) => {
                 debug!("Received BootstrapInfo");
                 self.handle_bootstrap_info(packet, addr)
             },
-            ref p => {
-                Box::new( future::err(
-                    Error::new(ErrorKind::Other,
-                        format!("DhtPacket is not handled {:?}", p)
-                )))
+            packet => {
+                Box::new( future::err(HandlePacketError::UnhandledPacket { packet }) )
             }
         }
     }
@@ -562,10 +745,10 @@ impl Server {
     handle received PingRequest packet, then create PingResponse packet
     and send back it to the peer.
     */
-    fn handle_ping_req(&self, packet: PingRequest, addr: SocketAddr) -> IoFuture<()> {
+    fn handle_ping_req(&self, packet: PingRequest, addr: SocketAddr) -> HandlePacketFuture {
         let payload = packet.get_payload(&self.sk);
         let payload = match payload {
-            Err(e) => return Box::new(future::err(e)),
+            Err(error) => return Box::new(future::err(HandlePacketError::GetPayload { error })),
             Ok(payload) => payload,
         };
 
@@ -588,60 +771,103 @@ impl Server {
         // the result of try_add is ignored, if it is not added, then PingRequest is not sent to the node.
         self.ping_sender.write().try_add(&self, &node_to_ping);
 
-        self.send_to(addr, ping_resp)
+        Box::new(self.send_to(addr, ping_resp).map_err(|error| HandlePacketError::SendTo { error }))
     }
     /**
     handle received PingResponse packet. If ping_id is correct, try_add peer to close_nodes.
     */
-    fn handle_ping_resp(&self, packet: PingResponse) -> IoFuture<()> {
-        let mut ping_map = self.ping_map.write();
+    fn handle_ping_resp(&self, packet: PingResponse, addr: SocketAddr) -> HandlePacketFuture {
         let payload = packet.get_payload(&self.sk);
         let payload = match payload {
-            Err(e) => return Box::new(future::err(e)),
+            Err(error) => return Box::new(future::err(HandlePacketError::GetPayload { error })),
             Ok(payload) => payload,
         };
 
         if payload.id == 0u64 {
-            return Box::new( future::err(
-                Error::new(ErrorKind::Other,
-                    "PingResponse.ping_id == 0"
-            )))
+            return Box::new( future::err(HandlePacketError::ZeroPingId) )
         }
 
-        let client = ping_map.get_mut(&packet.pk);
-        let client = match client {
-            None => {
-                return Box::new( future::err(
-                    Error::new(ErrorKind::Other,
-                        "get_client() failed in handle_ping_resp()"
-                )))
-            },
-            Some(client) => client,
-        };
+        // ping_map lock should be released before taking friends lock since
+        // hole punching sends PingRequests while holding friends lock
+        {
+            let mut ping_map = self.ping_map.write();
+            let client = ping_map.get_mut(&packet.pk);
+            let client = match client {
+                None => {
+                    return Box::new( future::err(HandlePacketError::NoPingData { pk: packet.pk }) )
+                },
+                Some(client) => client,
+            };
 
-        let timeout_dur = Duration::from_secs(PING_TIMEOUT);
-        if client.check_ping_id(payload.id, timeout_dur) {
+            let timeout_dur = Duration::from_secs(PING_TIMEOUT);
+            if !client.check_ping_id(payload.id, timeout_dur) {
+                return Box::new( future::err(HandlePacketError::InvalidPingId { ping_id: payload.id }) )
+            }
             client.last_resp_time = Instant::now();
-            Box::new( future::ok(()) )
-        } else {
-            Box::new( future::err(
-                Error::new(ErrorKind::Other, "PingResponse.ping_id does not match")
-            ))
         }
+
+        if let Some(tx) = self.ping_requests.write().remove(&(packet.pk, payload.id)) {
+            // pass response to the request that is waiting for it
+            tx.send(payload).ok();
+        }
+        if let Some(friend) = self.friends.write().iter_mut().find(|friend| friend.pk == packet.pk) {
+            // friend responded to PingRequest sent during hole punching
+            if friend.hole_punch.punching_ip == Some(addr.ip()) {
+                friend.hole_punch.punching_ip = None;
+                self.send_event(DhtEvent::HolePunched {
+                    friend_pk: friend.pk,
+                    addr,
+                });
+            }
+        }
+        Box::new( future::ok(()) )
     }
     /**
-    handle received NodesRequest packet, responds with NodesResponse
+    Get up to 4 nodes closest to `pk` from the Close List and close lists of
+    friends that can be sent to the node with address `addr`. LAN nodes are
+    returned only to LAN nodes and IPv6 nodes are returned only to IPv6 nodes
+    the same way as c-toxcore does it. The node with `pk` itself is never
+    returned.
     */
-    fn handle_nodes_req(&self, packet: NodesRequest, addr: SocketAddr) -> IoFuture<()> {
-        let close_nodes = self.close_nodes.read();
+    fn get_closest_nodes(&self, pk: &PublicKey, addr: SocketAddr) -> Vec<PackedNode> {
+        fn is_ipv4(addr: &SocketAddr) -> bool {
+            match *addr {
+                SocketAddr::V4(_) => true,
+                SocketAddr::V6(ref addr) => addr.ip().segments()[..6] == [0, 0, 0, 0, 0, 0xFFFF],
+            }
+        }
 
+        let requester_is_lan = ip_is_lan(addr.ip());
+        let requester_is_ipv4 = is_ipv4(&addr);
+        let can_send = |node: &PackedNode| node.pk != *pk &&
+            (requester_is_lan || !ip_is_lan(node.saddr.ip())) && (!requester_is_ipv4 || is_ipv4(&node.saddr));
+
+        let mut bucket = Bucket::new(Some(4));
+        for node in self.close_nodes.read().iter() {
+            if can_send(&node) {
+                bucket.try_add(pk, &node);
+            }
+        }
+        for friend in self.friends.read().iter() {
+            for node in friend.close_nodes.to_packed_node() {
+                if can_send(&node) {
+                    bucket.try_add(pk, &node);
+                }
+            }
+        }
+        bucket.to_packed_node()
+    }
+    /**
+    handle received NodesRequest packet, responds with NodesResponse
+    */
+    fn handle_nodes_req(&self, packet: NodesRequest, addr: SocketAddr) -> HandlePacketFuture {
         let payload = packet.get_payload(&self.sk);
         let payload = match payload {
-            Err(e) => return Box::new(future::err(e)),
+            Err(error) => return Box::new(future::err(HandlePacketError::GetPayload { error })),
             Ok(payload) => payload,
         };
 
-        let close_nodes = close_nodes.get_closest(&self.pk);
+        let close_nodes = self.get_closest_nodes(&payload.pk, addr);
         let resp_payload = NodesResponsePayload {
             nodes: close_nodes,
             id: payload.id,
@@ -662,27 +888,24 @@ impl Server {
         // the result of try_add is ignored, if it is not added, then PingRequest is not sent to the node.
         self.ping_sender.write().try_add(&self, &node_to_ping);
 
-        self.send_to(addr, nodes_resp)
+        Box::new(self.send_to(addr, nodes_resp).map_err(|error| HandlePacketError::SendTo { error }))
     }
     /**
     handle received NodesResponse from peer.
     */
-    fn handle_nodes_resp(&self, packet: NodesResponse) -> IoFuture<()> {
+    fn handle_nodes_resp(&self, packet: NodesResponse) -> HandlePacketFuture {
         let mut ping_map = self.ping_map.write();
 
         let payload = packet.get_payload(&self.sk);
         let payload = match payload {
-            Err(e) => return Box::new(future::err(e)),
+            Err(error) => return Box::new(future::err(HandlePacketError::GetPayload { error })),
             Ok(payload) => payload,
         };
 
         let client = ping_map.get_mut(&packet.pk);
         let client = match client {
             None => {
-                return Box::new( future::err(
-                    Error::new(ErrorKind::Other,
-                        "get_client() failed in handle_nodes_resp()"
-                )))
+                return Box::new( future::err(HandlePacketError::NoPingData { pk: packet.pk }) )
             },
             Some(client) => client,
         };
@@ -696,53 +919,52 @@ impl Server {
                 // not worried about removing evicted nodes from ping_map
                 // they will be removed by timeout eventually since we won't
                 // ping them anymore
-                close_nodes.try_add(node);
+                self.add_to_close_nodes(&mut close_nodes, node);
                 bootstrap_nodes.try_add(&self.pk, node);
                 friends.iter_mut().for_each(|friend| {
-                    friend.add_to_close(node);
+                    self.add_to_friend_close_nodes(friend, node);
                 });
             }
+            if let Some(tx) = self.nodes_requests.write().remove(&(packet.pk, payload.id)) {
+                // pass response to the request that is waiting for it
+                tx.send(payload).ok();
+            }
             Box::new( future::ok(()) )
         } else {
-            Box::new( future::err(
-                Error::new(ErrorKind::Other, "NodesResponse.ping_id does not match")
-            ))
+            Box::new( future::err(HandlePacketError::InvalidPingId { ping_id: payload.id }) )
         }
     }
 
     /** handle received CookieRequest and pass it to net_crypto module
     */
-    fn handle_cookie_request(&self, packet: CookieRequest, addr: SocketAddr) -> IoFuture<()> {
+    fn handle_cookie_request(&self, packet: CookieRequest, addr: SocketAddr) -> HandlePacketFuture {
         if let Some(ref net_crypto) = self.net_crypto {
-            net_crypto.handle_udp_cookie_request(packet, addr)
+            Box::new(net_crypto.handle_udp_cookie_request(packet, addr)
+                .map_err(|error| HandlePacketError::NetCrypto { error }))
         } else {
-            Box::new( future::err(
-                Error::new(ErrorKind::Other, "Net crypto is not initialised")
-            ))
+            Box::new( future::err(HandlePacketError::NetCryptoNotInitialized) )
         }
     }
 
     /** handle received CookieResponse and pass it to net_crypto module
     */
-    fn handle_cookie_response(&self, packet: CookieResponse, addr: SocketAddr) -> IoFuture<()> {
+    fn handle_cookie_response(&self, packet: CookieResponse, addr: SocketAddr) -> HandlePacketFuture {
         if let Some(ref net_crypto) = self.net_crypto {
-            net_crypto.handle_udp_cookie_response(packet, addr)
+            Box::new(net_crypto.handle_udp_cookie_response(packet, addr)
+                .map_err(|error| HandlePacketError::NetCrypto { error }))
         } else {
-            Box::new( future::err(
-                Error::new(ErrorKind::Other, "Net crypto is not initialised")
-            ))
+            Box::new( future::err(HandlePacketError::NetCryptoNotInitialized) )
         }
     }
 
     /** handle received CryptoHandshake and pass it to net_crypto module
     */
-    fn handle_crypto_handshake(&self, packet: CryptoHandshake, addr: SocketAddr) -> IoFuture<()> {
+    fn handle_crypto_handshake(&self, packet: CryptoHandshake, addr: SocketAddr) -> HandlePacketFuture {
         if let Some(ref net_crypto) = self.net_crypto {
-            net_crypto.handle_udp_crypto_handshake(packet, addr)
+            Box::new(net_crypto.handle_udp_crypto_handshake(packet, addr)
+                .map_err(|error| HandlePacketError::NetCrypto { error }))
         } else {
-            Box::new( future::err(
-                Error::new(ErrorKind::Other, "Net crypto is not initialised")
-            ))
+            Box::new( future::err(HandlePacketError::NetCryptoNotInitialized) )
         }
     }
 
@@ -750,11 +972,11 @@ impl Server {
     handle received DhtRequest, resend if it's sent for someone else, parse and
     handle payload if it's sent for us
     */
-    fn handle_dht_req(&self, packet: DhtRequest, addr: SocketAddr) -> IoFuture<()> {
+    fn handle_dht_req(&self, packet: DhtRequest, addr: SocketAddr) -> HandlePacketFuture {
         if packet.rpk == self.pk { // the target peer is me
             let payload = packet.get_payload(&self.sk);
             let payload = match payload {
-                Err(e) => return Box::new(future::err(e)),
+                Err(error) => return Box::new(future::err(HandlePacketError::GetPayload { error })),
                 Ok(payload) => payload,
             };
 
@@ -768,27 +990,37 @@ impl Server {
                     let timeout_dur = Duration::from_secs(NAT_PING_PUNCHING_INTERVAL);
                     self.handle_nat_ping_resp(nat_payload, &packet.spk, timeout_dur)
                 },
-                DhtRequestPayload::DhtPkAnnounce(_dht_pk_payload) => {
+                DhtRequestPayload::DhtPkAnnounce(dht_pk_payload) => {
                     debug!("Received DHT PublicKey Announce");
-                    // TODO: handle this packet in onion client
-                    Box::new( future::ok(()) )
+                    self.handle_dht_pk_announce(dht_pk_payload)
                 },
             }
         } else {
             let close_nodes = self.close_nodes.read();
             if let Some(addr) = close_nodes.get_node(&packet.rpk) { // search close_nodes to find target peer
                 let packet = DhtPacket::DhtRequest(packet);
-                self.send_to(addr, packet)
+                Box::new(self.send_to(addr, packet).map_err(|error| HandlePacketError::SendTo { error }))
             } else { // do nothing
                 Box::new( future::ok(()) )
             }
         }
     }
 
+    /** handle received DhtPkAnnounce and pass it to onion client module
+    */
+    fn handle_dht_pk_announce(&self, packet: DhtPkAnnounce) -> HandlePacketFuture {
+        if let Some(ref onion_client) = self.onion_client {
+            Box::new(onion_client.handle_dht_pk_announce(packet)
+                .map_err(|error| HandlePacketError::OnionClient { error }))
+        } else {
+            Box::new( future::err(HandlePacketError::OnionClientNotInitialized) )
+        }
+    }
+
     /**
     handle received NatPingRequest packet, respond with NatPingResponse
     */
-    fn handle_nat_ping_req(&self, payload: NatPingRequest, spk: &PublicKey, addr: SocketAddr) -> IoFuture<()> {
+    fn handle_nat_ping_req(&self, payload: NatPingRequest, spk: &PublicKey, addr: SocketAddr) -> HandlePacketFuture {
         let resp_payload = DhtRequestPayload::NatPingResponse(NatPingResponse {
             id: payload.id,
         });
@@ -798,30 +1030,24 @@ impl Server {
             &self.pk,
             resp_payload
         ));
-        self.send_to(addr, nat_ping_resp)
+        Box::new(self.send_to(addr, nat_ping_resp).map_err(|error| HandlePacketError::SendTo { error }))
     }
 
     /**
     handle received NatPingResponse packet, enable hole-punching
     */
-    fn handle_nat_ping_resp(&self, payload: NatPingResponse, spk: &PublicKey, send_nat_ping_interval: Duration) -> IoFuture<()> {
+    fn handle_nat_ping_resp(&self, payload: NatPingResponse, spk: &PublicKey, send_nat_ping_interval: Duration) -> HandlePacketFuture {
         let mut friends = self.friends.write();
         let friend = friends.iter_mut()
             .find(|friend| friend.pk == *spk);
         let friend = match friend {
-            None => return Box::new( future::err(
-                Error::new(ErrorKind::Other,
-                           "Can't find friend"
-                ))),
+            None => return Box::new( future::err(HandlePacketError::NoFriend { pk: *spk }) ),
             Some(friend) => friend,
 
         };
 
         if payload.id == 0 {
-            return Box::new( future::err(
-                Error::new(ErrorKind::Other,
-                    "NodesResponse.ping_id == 0"
-            )))
+            return Box::new( future::err(HandlePacketError::ZeroPingId) )
         }
 
         if friend.hole_punch.last_recv_ping_time.elapsed() < send_nat_ping_interval &&
@@ -830,16 +1056,14 @@ impl Server {
             friend.hole_punch.is_punching_done = false;
             Box::new( future::ok(()) )
         } else {
-            Box::new( future::err(
-                Error::new(ErrorKind::Other, "NatPingResponse.ping_id does not match or timed out")
-            ))
+            Box::new( future::err(HandlePacketError::InvalidPingId { ping_id: payload.id }) )
         }
     }
     /**
     handle received LanDiscovery packet, then create NodesRequest packet
     and send back it to the peer.
     */
-    fn handle_lan_discovery(&self, packet: LanDiscovery, addr: SocketAddr) -> IoFuture<()> {
+    fn handle_lan_discovery(&self, packet: LanDiscovery, addr: SocketAddr) -> HandlePacketFuture {
         // if Lan Discovery packet has my PK, then it is sent by myself.
         if packet.pk == self.pk {
             return Box::new(future::ok(()));
@@ -854,18 +1078,18 @@ impl Server {
         let ping_map = ping_map.deref_mut();
         let client = ping_map.entry(packet.pk).or_insert_with(PingData::new);
 
-        self.send_nodes_req(target_node, self.pk, client)
+        Box::new(self.send_nodes_req(target_node, self.pk, client).map_err(|error| HandlePacketError::SendTo { error }))
     }
     /**
     handle received OnionRequest0 packet, then create OnionRequest1 packet
     and send it to the next peer.
     */
-    fn handle_onion_request_0(&self, packet: OnionRequest0, addr: SocketAddr) -> IoFuture<()> {
+    fn handle_onion_request_0(&self, packet: OnionRequest0, addr: SocketAddr) -> HandlePacketFuture {
         let onion_symmetric_key = self.onion_symmetric_key.read();
         let shared_secret = precompute(&packet.temporary_pk, &self.sk);
         let payload = packet.get_payload(&shared_secret);
         let payload = match payload {
-            Err(e) => return Box::new(future::err(e)),
+            Err(error) => return Box::new(future::err(HandlePacketError::GetPayload { error })),
             Ok(payload) => payload,
         };
 
@@ -880,18 +1104,18 @@ impl Server {
             payload: payload.inner,
             onion_return
         });
-        self.send_to(payload.ip_port.to_saddr(), next_packet)
+        Box::new(self.send_to(payload.ip_port.to_saddr(), next_packet).map_err(|error| HandlePacketError::SendTo { error }))
     }
     /**
     handle received OnionRequest1 packet, then create OnionRequest2 packet
     and send it to the next peer.
     */
-    fn handle_onion_request_1(&self, packet: OnionRequest1, addr: SocketAddr) -> IoFuture<()> {
+    fn handle_onion_request_1(&self, packet: OnionRequest1, addr: SocketAddr) -> HandlePacketFuture {
         let onion_symmetric_key = self.onion_symmetric_key.read();
         let shared_secret = precompute(&packet.temporary_pk, &self.sk);
         let payload = packet.get_payload(&shared_secret);
         let payload = match payload {
-            Err(e) => return Box::new(future::err(e)),
+            Err(error) => return Box::new(future::err(HandlePacketError::GetPayload { error })),
             Ok(payload) => payload,
         };
 
@@ -906,18 +1130,18 @@ impl Server {
             payload: payload.inner,
             onion_return
         });
-        self.send_to(payload.ip_port.to_saddr(), next_packet)
+        Box::new(self.send_to(payload.ip_port.to_saddr(), next_packet).map_err(|error| HandlePacketError::SendTo { error }))
     }
     /**
     handle received OnionRequest2 packet, then create OnionAnnounceRequest
     or OnionDataRequest packet and send it to the next peer.
     */
-    fn handle_onion_request_2(&self, packet: OnionRequest2, addr: SocketAddr) -> IoFuture<()> {
+    fn handle_onion_request_2(&self, packet: OnionRequest2, addr: SocketAddr) -> HandlePacketFuture {
         let onion_symmetric_key = self.onion_symmetric_key.read();
         let shared_secret = precompute(&packet.temporary_pk, &self.sk);
         let payload = packet.get_payload(&shared_secret);
         let payload = match payload {
-            Err(e) => return Box::new(future::err(e)),
+            Err(error) => return Box::new(future::err(HandlePacketError::GetPayload { error })),
             Ok(payload) => payload,
         };
 
@@ -936,45 +1160,46 @@ impl Server {
                 onion_return
             }),
         };
-        self.send_to(payload.ip_port.to_saddr(), next_packet)
+        Box::new(self.send_to(payload.ip_port.to_saddr(), next_packet).map_err(|error| HandlePacketError::SendTo { error }))
     }
     /**
     handle received OnionAnnounceRequest packet and send OnionAnnounceResponse
     packet back if request succeed.
     */
-    fn handle_onion_announce_request(&self, packet: OnionAnnounceRequest, addr: SocketAddr) -> IoFuture<()> {
+    fn handle_onion_announce_request(&self, packet: OnionAnnounceRequest, addr: SocketAddr) -> HandlePacketFuture {
         let mut onion_announce = self.onion_announce.write();
         let close_nodes = self.close_nodes.read();
         let onion_return = packet.onion_return.clone();
         let response = onion_announce.handle_onion_announce_request(packet, &self.sk, &close_nodes, addr);
         match response {
-            Ok(response) => self.send_to(addr, DhtPacket::OnionResponse3(OnionResponse3 {
+            Ok(response) => Box::new(self.send_to(addr, DhtPacket::OnionResponse3(OnionResponse3 {
                 onion_return,
                 payload: InnerOnionResponse::OnionAnnounceResponse(response)
-            })),
-            Err(e) => Box::new(future::err(e))
+            })).map_err(|error| HandlePacketError::SendTo { error })),
+            Err(error) => Box::new(future::err(HandlePacketError::OnionAnnounce { error }))
         }
     }
     /**
     handle received OnionDataRequest packet and send OnionResponse3 with inner
     OnionDataResponse to destination node through its onion path.
     */
-    fn handle_onion_data_request(&self, packet: OnionDataRequest) -> IoFuture<()> {
+    fn handle_onion_data_request(&self, packet: OnionDataRequest) -> HandlePacketFuture {
         let onion_announce = self.onion_announce.read();
         match onion_announce.handle_data_request(packet) {
-            Ok((response, addr)) => self.send_to(addr, DhtPacket::OnionResponse3(response)),
-            Err(e) => Box::new(future::err(e))
+            Ok((response, addr)) => Box::new(self.send_to(addr, DhtPacket::OnionResponse3(response))
+                .map_err(|error| HandlePacketError::SendTo { error })),
+            Err(error) => Box::new(future::err(HandlePacketError::OnionAnnounce { error }))
         }
     }
     /**
     handle received OnionResponse3 packet, then create OnionResponse2 packet
     and send it to the next peer which address is stored in encrypted onion return.
     */
-    fn handle_onion_response_3(&self, packet: OnionResponse3) -> IoFuture<()> {
+    fn handle_onion_response_3(&self, packet: OnionResponse3) -> HandlePacketFuture {
         let onion_symmetric_key = self.onion_symmetric_key.read();
         let payload = packet.onion_return.get_payload(&onion_symmetric_key);
         let payload = match payload {
-            Err(e) => return Box::new(future::err(e)),
+            Err(error) => return Box::new(future::err(HandlePacketError::GetPayload { error })),
             Ok(payload) => payload,
         };
 
@@ -983,23 +1208,20 @@ impl Server {
                 onion_return: next_onion_return,
                 payload: packet.payload
             });
-            self.send_to(ip_port.to_saddr(), next_packet)
+            Box::new(self.send_to(ip_port.to_saddr(), next_packet).map_err(|error| HandlePacketError::SendTo { error }))
         } else {
-            Box::new( future::err(
-                Error::new(ErrorKind::Other,
-                    format!("OnionResponse3 next_onion_return is none")
-            )))
+            Box::new( future::err(HandlePacketError::NoNextOnionReturn) )
         }
     }
     /**
     handle received OnionResponse2 packet, then create OnionResponse1 packet
     and send it to the next peer which address is stored in encrypted onion return.
     */
-    fn handle_onion_response_2(&self, packet: OnionResponse2) -> IoFuture<()> {
+    fn handle_onion_response_2(&self, packet: OnionResponse2) -> HandlePacketFuture {
         let onion_symmetric_key = self.onion_symmetric_key.read();
         let payload = packet.onion_return.get_payload(&onion_symmetric_key);
         let payload = match payload {
-            Err(e) => return Box::new(future::err(e)),
+            Err(error) => return Box::new(future::err(HandlePacketError::GetPayload { error })),
             Ok(payload) => payload,
         };
 
@@ -1008,12 +1230,9 @@ impl Server {
                 onion_return: next_onion_return,
                 payload: packet.payload
             });
-            self.send_to(ip_port.to_saddr(), next_packet)
+            Box::new(self.send_to(ip_port.to_saddr(), next_packet).map_err(|error| HandlePacketError::SendTo { error }))
         } else {
-            Box::new( future::err(
-                Error::new(ErrorKind::Other,
-                    format!("OnionResponse2 next_onion_return is none")
-            )))
+            Box::new( future::err(HandlePacketError::NoNextOnionReturn) )
         }
     }
     /**
@@ -1021,11 +1240,11 @@ impl Server {
     or OnionDataResponse packet and send it to the next peer which address
     is stored in encrypted onion return.
     */
-    fn handle_onion_response_1(&self, packet: OnionResponse1) -> IoFuture<()> {
+    fn handle_onion_response_1(&self, packet: OnionResponse1) -> HandlePacketFuture {
         let onion_symmetric_key = self.onion_symmetric_key.read();
         let payload = packet.onion_return.get_payload(&onion_symmetric_key);
         let payload = match payload {
-            Err(e) => return Box::new(future::err(e)),
+            Err(error) => return Box::new(future::err(HandlePacketError::GetPayload { error })),
             Ok(payload) => payload,
         };
 
@@ -1036,7 +1255,7 @@ impl Server {
                         InnerOnionResponse::OnionAnnounceResponse(inner) => DhtPacket::OnionAnnounceResponse(inner),
                         InnerOnionResponse::OnionDataResponse(inner) => DhtPacket::OnionDataResponse(inner),
                     };
-                    self.send_to(ip_port.to_saddr(), next_packet)
+                    Box::new(self.send_to(ip_port.to_saddr(), next_packet).map_err(|error| HandlePacketError::SendTo { error }))
                 },
                 ProtocolType::TCP => {
                     if let Some(ref tcp_onion_sink) = self.tcp_onion_sink {
@@ -1046,22 +1265,36 @@ impl Server {
                             .map_err(|_| {
                                 // This may only happen if sink is gone
                                 // So cast SendError<T> to a corresponding std::io::Error
-                                Error::from(ErrorKind::UnexpectedEof)
+                                HandlePacketError::SendTo { error: Error::from(ErrorKind::UnexpectedEof) }
                             })
                         )
                     } else {
-                        Box::new( future::err(
-                            Error::new(ErrorKind::Other,
-                                format!("OnionResponse1 can't be redirected to TCP relay")
-                        )))
+                        Box::new( future::err(HandlePacketError::NoTcpOnionSink) )
                     }
                 },
             }
         } else {
-            Box::new( future::err(
-                Error::new(ErrorKind::Other,
-                    format!("OnionResponse1 next_onion_return is some")
-            )))
+            Box::new( future::err(HandlePacketError::UnexpectedNextOnionReturn) )
+        }
+    }
+    /** handle received OnionAnnounceResponse and pass it to onion client module
+    */
+    fn handle_onion_announce_response(&self, packet: OnionAnnounceResponse) -> HandlePacketFuture {
+        if let Some(ref onion_client) = self.onion_client {
+            Box::new(onion_client.handle_announce_response(packet)
+                .map_err(|error| HandlePacketError::OnionClient { error }))
+        } else {
+            Box::new( future::err(HandlePacketError::OnionClientNotInitialized) )
+        }
+    }
+    /** handle received OnionDataResponse and pass it to onion client module
+    */
+    fn handle_onion_data_response(&self, packet: OnionDataResponse) -> HandlePacketFuture {
+        if let Some(ref onion_client) = self.onion_client {
+            Box::new(onion_client.handle_data_response(packet)
+                .map_err(|error| HandlePacketError::OnionClient { error }))
+        } else {
+            Box::new( future::err(HandlePacketError::OnionClientNotInitialized) )
         }
     }
     /// refresh onion symmetric key to enforce onion paths expiration
@@ -1074,11 +1307,54 @@ impl Server {
     /// add PackedNode object to close_nodes as a thread-safe manner
     pub fn try_add_to_close_nodes(&self, pn: &PackedNode) -> bool {
         let mut close_nodes = self.close_nodes.write();
-        close_nodes.try_add(pn)
+        self.add_to_close_nodes(&mut close_nodes, pn)
+    }
+    // add node to the Close List and send events about it
+    fn add_to_close_nodes(&self, close_nodes: &mut Kbucket, node: &PackedNode) -> bool {
+        match close_nodes.try_add_node(node) {
+            AddNodeResult::NotAdded => false,
+            AddNodeResult::Updated => true,
+            AddNodeResult::Added => {
+                self.send_event(DhtEvent::NodeAdded(*node));
+                true
+            },
+            AddNodeResult::Evicted(evicted) => {
+                self.send_event(DhtEvent::NodeEvicted(evicted));
+                self.send_event(DhtEvent::NodeAdded(*node));
+                true
+            },
+        }
+    }
+    // add node to close nodes of the friend and send events about it
+    fn add_to_friend_close_nodes(&self, friend: &mut DhtFriend, node: &PackedNode) {
+        let had_close_nodes = !friend.close_nodes.is_empty();
+        let knew_addr = friend.close_nodes.contains(&friend.pk);
+
+        friend.add_to_close(node);
+
+        if !had_close_nodes && !friend.close_nodes.is_empty() {
+            self.send_event(DhtEvent::FriendFound {
+                friend_pk: friend.pk,
+                node: *node,
+            });
+        }
+        if !knew_addr && friend.close_nodes.contains(&friend.pk) {
+            self.send_event(DhtEvent::FriendAddrKnown {
+                friend_pk: friend.pk,
+                addr: node.saddr,
+            });
+        }
+    }
+    // send event to the event sink if it's set
+    fn send_event(&self, event: DhtEvent) {
+        if let Some(ref event_sink) = self.event_sink {
+            // nobody listens to events if receiver is gone
+            event_sink.unbounded_send(event).ok();
+        }
     }
     /// handle OnionRequest from TCP relay and send OnionRequest1 packet
     /// to the next node in the onion path
-    pub fn handle_tcp_onion_request(&self, packet: OnionRequest, addr: SocketAddr) -> IoFuture<()> {
+    pub fn handle_tcp_onion_request(&self, packet: OnionRequest, addr: SocketAddr) -> HandlePacketFuture {
         let onion_symmetric_key = self.onion_symmetric_key.read();
 
         let onion_return = OnionReturn::new(
@@ -1092,15 +1368,15 @@ impl Server {
             payload: packet.payload,
             onion_return
         });
-        self.send_to(packet.ip_port.to_saddr(), next_packet)
+        Box::new(self.send_to(packet.ip_port.to_saddr(), next_packet).map_err(|error| HandlePacketError::SendTo { error }))
     }
     // handle BootstrapInfo, respond with BootstrapInfo
-    fn handle_bootstrap_info(&self, _packet: BootstrapInfo, addr: SocketAddr) -> IoFuture<()> {
+    fn handle_bootstrap_info(&self, _packet: BootstrapInfo, addr: SocketAddr) -> HandlePacketFuture {
         let packet = DhtPacket::BootstrapInfo(BootstrapInfo {
             version: self.tox_core_version,
             motd: self.motd.clone(),
         });
-        self.send_to(addr, packet)
+        Box::new(self.send_to(addr, packet).map_err(|error| HandlePacketError::SendTo { error }))
     }
     /// set toxcore verson and motd
     pub fn set_bootstrap_info(&mut self, version: u32, motd: Vec<u8>) {
@@ -1111,10 +1387,18 @@ impl Server {
     pub fn set_tcp_onion_sink(&mut self, tcp_onion_sink: TcpOnionTx) {
         self.tcp_onion_sink = Some(tcp_onion_sink)
     }
+    /// set sink for `DhtEvent`s that happen in the server
+    pub fn set_event_sink(&mut self, event_sink: EventTx) {
+        self.event_sink = Some(event_sink)
+    }
     /// set net crypto module
     pub fn set_net_crypto(&mut self, net_crypto: NetCrypto) {
         self.net_crypto = Some(net_crypto);
     }
+    /// set onion client module
+    pub fn set_onion_client(&mut self, onion_client: OnionClient) {
+        self.onion_client = Some(onion_client);
+    }
 }
 
 #[cfg(test)]
@@ -1123,6 +1407,7 @@ mod tests {
 
     use futures::Future;
     use std::net::SocketAddr;
+    use tokio;
     use tokio_executor;
     use tokio_timer::clock::*;
 
@@ -1133,6 +1418,15 @@ mod tests {
     const ONION_RETURN_2_PAYLOAD_SIZE: usize = ONION_RETURN_2_SIZE - secretbox::NONCEBYTES;
     const ONION_RETURN_3_PAYLOAD_SIZE: usize = ONION_RETURN_3_SIZE - secretbox::NONCEBYTES;
 
+    macro_rules! assert_error {
+        ($result:expr, $error:pat) => (
+            match $result {
+                Err($error) => {},
+                other => panic!("Expected {} but got {:?}", stringify!($error), other),
+            }
+        )
+    }
+
     fn create_node() -> (Server, PrecomputedKey, PublicKey, SecretKey,
             mpsc::UnboundedReceiver<(DhtPacket, SocketAddr)>, SocketAddr) {
         crypto_init();
@@ -1152,6 +1446,35 @@ mod tests {
         ping_map.insert(pk, client);
     }
 
+    type NetworkNode = (Server, mpsc::UnboundedReceiver<(DhtPacket, SocketAddr)>, SocketAddr);
+
+    fn create_network_nodes(count: u16) -> Vec<NetworkNode> {
+        (0 .. count).map(|i| {
+            let (pk, sk) = gen_keypair();
+            let (tx, rx) = mpsc::unbounded();
+            let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), 33445 + i);
+            (Server::new(tx, pk, sk), rx, addr)
+        }).collect()
+    }
+
+    // deliver packets between servers by their addresses
+    fn run_network(nodes: Vec<NetworkNode>) -> IoFuture<()> {
+        let servers = Arc::new(nodes.iter()
+            .map(|&(ref server, _, addr)| (addr, server.clone()))
+            .collect::<HashMap<_, _>>());
+        let futures = nodes.into_iter().map(move |(_, rx, from)| {
+            let servers = servers.clone();
+            rx.map_err(|()| Error::from(ErrorKind::UnexpectedEof))
+                .for_each(move |(packet, to)| -> IoFuture<()> {
+                    match servers.get(&to) {
+                        Some(server) => Box::new(server.handle_packet(packet, from).then(|_| Ok(()))),
+                        None => Box::new(future::ok(())),
+                    }
+                })
+        });
+        Box::new(stream::futures_unordered(futures).for_each(|()| Ok(())))
+    }
+
     #[test]
     fn server_is_clonable() {
         let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
@@ -1175,6 +1498,125 @@ mod tests {
         let _ = alice.get_ping_map();
     }
 
+    #[test]
+    fn lookup_without_nodes() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        assert!(alice.lookup(gen_keypair().0).wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn lookup_test() {
+        let target_pk = gen_keypair().0;
+        let network_nodes = create_network_nodes(6);
+        let nodes = network_nodes.iter()
+            .map(|&(ref server, _, addr)| PackedNode::new(false, addr, &server.pk))
+            .collect::<Vec<_>>();
+
+        // alice knows only the first node and the first node knows all others
+        let alice = network_nodes[0].0.clone();
+        alice.close_nodes.write().try_add(&nodes[1]);
+        for node in &nodes[2 ..] {
+            network_nodes[1].0.close_nodes.write().try_add(node);
+        }
+
+        let mut expected = nodes[1 ..].to_vec();
+        expected.sort_by(|a, b| target_pk.distance(&a.pk, &b.pk));
+
+        let lookup = alice.lookup(target_pk).map(move |found| assert_eq!(found, expected));
+        let network = run_network(network_nodes);
+        let test = lookup.select(network).map(|_| ()).map_err(|(e, _)| e);
+        tokio::runtime::Runtime::new().unwrap().block_on(test).unwrap();
+    }
+
+    #[test]
+    fn request_ping_test() {
+        let network_nodes = create_network_nodes(2);
+        let alice = network_nodes[0].0.clone();
+        let bob = PackedNode::new(false, network_nodes[1].2, &network_nodes[1].0.pk);
+
+        let request = alice.request_ping(&bob).map(|(_payload, rtt)|
+            assert!(rtt < Duration::from_secs(PING_TIMEOUT))
+        );
+        let network = run_network(network_nodes);
+        tokio::run(request.select(network).map(|_| ()).map_err(|(e, _)| panic!("Request error: {:?}", e)));
+
+        assert!(alice.ping_requests.read().is_empty());
+    }
+
+    #[test]
+    fn request_nodes_test() {
+        let network_nodes = create_network_nodes(3);
+        let alice = network_nodes[0].0.clone();
+        let bob = PackedNode::new(false, network_nodes[1].2, &network_nodes[1].0.pk);
+        let charlie = PackedNode::new(false, network_nodes[2].2, &network_nodes[2].0.pk);
+        network_nodes[1].0.close_nodes.write().try_add(&charlie);
+
+        let request = alice.request_nodes(&bob, charlie.pk).map(move |(payload, rtt)| {
+            assert_eq!(payload.nodes, vec![charlie]);
+            assert!(rtt < Duration::from_secs(PING_TIMEOUT));
+        });
+        let network = run_network(network_nodes);
+        tokio::run(request.select(network).map(|_| ()).map_err(|(e, _)| panic!("Request error: {:?}", e)));
+
+        assert!(alice.nodes_requests.read().is_empty());
+    }
+
+    #[test]
+    fn request_nodes_to_ourselves() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+        let node = PackedNode::new(false, addr, &alice.pk);
+
+        assert!(alice.request_nodes(&node, alice.pk).wait().is_err());
+    }
+
+    #[test]
+    fn request_timeout_test() {
+        let (alice, _precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+        let bob = PackedNode::new(false, addr, &bob_pk);
+
+        let ping_id = alice.insert_new_ping_id(&bob.pk);
+        let request = alice.send_ping_req_with_id(&bob, ping_id);
+        let request = Server::wait_response(&alice.ping_requests, (bob.pk, ping_id), request, Duration::from_millis(10));
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let error = runtime.block_on(request).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(alice.ping_requests.read().is_empty());
+    }
+
+    #[test]
+    fn try_add_to_close_nodes_events_test() {
+        let (tx, _rx) = mpsc::unbounded();
+        let (_pk, sk) = gen_keypair();
+        let mut alice = Server::new(tx, PublicKey([0; PUBLICKEYBYTES]), sk);
+        let (event_tx, event_rx) = mpsc::unbounded();
+        alice.set_event_sink(event_tx);
+
+        // all nodes have the same kbucket index and get to the same bucket
+        let node_with_byte = |byte: u8| {
+            let mut pk = [0; PUBLICKEYBYTES];
+            pk[0] = 1;
+            pk[1] = byte;
+            PackedNode::new(false, SocketAddr::new("127.0.0.1".parse().unwrap(), 33445 + byte as u16), &PublicKey(pk))
+        };
+        let far_nodes = (10 .. 10 + BUCKET_DEFAULT_SIZE as u8).map(node_with_byte).collect::<Vec<_>>();
+        for node in &far_nodes {
+            assert!(alice.try_add_to_close_nodes(node));
+        }
+        // updating doesn't produce events
+        assert!(alice.try_add_to_close_nodes(&far_nodes[0]));
+
+        let close_node = node_with_byte(1);
+        assert!(alice.try_add_to_close_nodes(&close_node));
+
+        drop(alice);
+        let mut expected = far_nodes.iter().map(|&node| DhtEvent::NodeAdded(node)).collect::<Vec<_>>();
+        expected.push(DhtEvent::NodeEvicted(far_nodes[BUCKET_DEFAULT_SIZE - 1]));
+        expected.push(DhtEvent::NodeAdded(close_node));
+        assert_eq!(event_rx.collect().wait().unwrap(), expected);
+    }
+
     #[test]
     fn add_friend_test() {
         let (alice, _precomp, bob_pk, _bob_sk, _rx, _addr) = create_node();
@@ -1183,6 +1625,19 @@ mod tests {
         alice.add_friend(friend);
     }
 
+    #[test]
+    fn remove_friend_test() {
+        let (alice, _precomp, bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        alice.add_friend(DhtFriend::new(bob_pk, 0));
+        alice.add_friend(DhtFriend::new(gen_keypair().0, 0));
+        alice.remove_friend(&bob_pk);
+
+        let friends = alice.friends.read();
+        assert_eq!(friends.len(), 1);
+        assert!(friends[0].pk != bob_pk);
+    }
+
     // test handle_packet() with BootstrapInfo packet type
     #[test]
     fn server_handle_packet_with_bootstrap_info_packet_test() {
@@ -1224,7 +1679,7 @@ mod tests {
         let req_payload = PingRequestPayload { id: 42 };
         let ping_req = DhtPacket::PingRequest(PingRequest::new(&precomp, &alice.pk, req_payload));
 
-        assert!(alice.handle_packet(ping_req, addr).wait().is_err());
+        assert_error!(alice.handle_packet(ping_req, addr).wait(), HandlePacketError::GetPayload { .. });
     }
 
     // handle_ping_resp()
@@ -1244,6 +1699,56 @@ mod tests {
         assert!(alice.handle_packet(ping_resp, addr).wait().is_ok());
     }
 
+    #[test]
+    fn server_handle_ping_resp_hole_punched_test() {
+        let (mut alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+        let (event_tx, event_rx) = mpsc::unbounded();
+        alice.set_event_sink(event_tx);
+
+        // bob is a friend that we tried to punch hole to
+        let mut friend = DhtFriend::new(bob_pk, 0);
+        friend.hole_punch.num_punch_tries = 1;
+        friend.hole_punch.punching_ip = Some(addr.ip());
+        alice.add_friend(friend);
+
+        let mut client = PingData::new();
+        let ping_id = client.insert_new_ping_id();
+        let resp_payload = PingResponsePayload { id: ping_id };
+        let ping_resp = DhtPacket::PingResponse(PingResponse::new(&precomp, &bob_pk, resp_payload));
+
+        add_to_ping_map(&alice, bob_pk, client);
+
+        assert!(alice.handle_packet(ping_resp, addr).wait().is_ok());
+        assert_eq!(alice.friends.read()[0].hole_punch.num_punch_tries, 1);
+        assert_eq!(alice.friends.read()[0].hole_punch.punching_ip, None);
+
+        drop(alice);
+        let events = event_rx.collect().wait().unwrap();
+        assert_eq!(events, vec![DhtEvent::HolePunched { friend_pk: bob_pk, addr }]);
+    }
+
+    #[test]
+    fn server_handle_ping_resp_not_punching_test() {
+        let (mut alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+        let (event_tx, event_rx) = mpsc::unbounded();
+        alice.set_event_sink(event_tx);
+
+        // bob is a friend but we don't punch hole to him
+        alice.add_friend(DhtFriend::new(bob_pk, 0));
+
+        let mut client = PingData::new();
+        let ping_id = client.insert_new_ping_id();
+        let resp_payload = PingResponsePayload { id: ping_id };
+        let ping_resp = DhtPacket::PingResponse(PingResponse::new(&precomp, &bob_pk, resp_payload));
+
+        add_to_ping_map(&alice, bob_pk, client);
+
+        assert!(alice.handle_packet(ping_resp, addr).wait().is_ok());
+
+        drop(alice);
+        assert!(event_rx.collect().wait().unwrap().is_empty());
+    }
+
     #[test]
     fn server_handle_ping_resp_invalid_payload_test() {
         let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
@@ -1256,7 +1761,7 @@ mod tests {
 
         add_to_ping_map(&alice, bob_pk, client);
 
-        assert!(alice.handle_packet(ping_resp, addr).wait().is_err());
+        assert_error!(alice.handle_packet(ping_resp, addr).wait(), HandlePacketError::GetPayload { .. });
     }
 
     #[test]
@@ -1270,7 +1775,7 @@ mod tests {
         let client = PingData::new();
         add_to_ping_map(&alice, bob_pk, client);
 
-        assert!(alice.handle_packet(ping_resp, addr).wait().is_err());
+        assert_error!(alice.handle_packet(ping_resp, addr).wait(), HandlePacketError::ZeroPingId);
     }
 
     #[test]
@@ -1285,7 +1790,7 @@ mod tests {
 
         add_to_ping_map(&alice, bob_pk, client);
 
-        assert!(alice.handle_packet(ping_resp, addr).wait().is_err());
+        assert_error!(alice.handle_packet(ping_resp, addr).wait(), HandlePacketError::InvalidPingId { .. });
     }
 
     // handle_nodes_req()
@@ -1315,6 +1820,109 @@ mod tests {
         assert_eq!(nodes_resp_payload.id, req_payload.id);
     }
 
+    fn handle_nodes_req(alice: &Server, precomp: &PrecomputedKey, bob_pk: &PublicKey, bob_sk: &SecretKey,
+        rx: mpsc::UnboundedReceiver<(DhtPacket, SocketAddr)>, addr: SocketAddr, target_pk: PublicKey)
+        -> (Vec<PackedNode>, mpsc::UnboundedReceiver<(DhtPacket, SocketAddr)>) {
+        let req_payload = NodesRequestPayload { pk: target_pk, id: 42 };
+        let nodes_req = DhtPacket::NodesRequest(NodesRequest::new(precomp, bob_pk, req_payload));
+
+        assert!(alice.handle_packet(nodes_req, addr).wait().is_ok());
+
+        let (received, rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let nodes_resp = unpack!(packet, DhtPacket::NodesResponse);
+
+        (nodes_resp.get_payload(bob_sk).unwrap().nodes, rx)
+    }
+
+    #[test]
+    fn server_handle_nodes_req_closest_to_requested_pk_test() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        let mut friend = DhtFriend::new(gen_keypair().0, 0);
+        let mut nodes = Vec::new();
+        for i in 0 .. 8 {
+            let node = PackedNode::new(false, SocketAddr::new("127.0.0.1".parse().unwrap(), 12350 + i), &gen_keypair().0);
+            assert!(alice.try_add_to_close_nodes(&node));
+            nodes.push(node);
+        }
+        for i in 0 .. 8 {
+            let node = PackedNode::new(false, SocketAddr::new("127.0.0.1".parse().unwrap(), 12360 + i), &gen_keypair().0);
+            assert!(friend.close_nodes.try_add(&friend.pk, &node));
+            nodes.push(node);
+        }
+        alice.add_friend(friend);
+
+        let target_pk = gen_keypair().0;
+        let mut expected = Bucket::new(Some(4));
+        for node in &nodes {
+            expected.try_add(&target_pk, node);
+        }
+
+        let (received_nodes, _rx) = handle_nodes_req(&alice, &precomp, &bob_pk, &bob_sk, rx, addr, target_pk);
+
+        assert_eq!(received_nodes, expected.to_packed_node());
+    }
+
+    #[test]
+    fn server_handle_nodes_req_without_requested_pk_test() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        let target_node = PackedNode::new(false, "127.0.0.1:12350".parse().unwrap(), &gen_keypair().0);
+        let node = PackedNode::new(false, "127.0.0.1:12351".parse().unwrap(), &gen_keypair().0);
+        assert!(alice.try_add_to_close_nodes(&target_node));
+        assert!(alice.try_add_to_close_nodes(&node));
+
+        let (received_nodes, _rx) = handle_nodes_req(&alice, &precomp, &bob_pk, &bob_sk, rx, addr, target_node.pk);
+
+        assert_eq!(received_nodes, vec![node]);
+    }
+
+    #[test]
+    fn server_handle_nodes_req_lan_nodes_test() {
+        let (alice, precomp, bob_pk, bob_sk, rx, _addr) = create_node();
+
+        let lan_node = PackedNode::new(false, "192.168.1.1:12345".parse().unwrap(), &gen_keypair().0);
+        let global_node = PackedNode::new(false, "8.8.8.8:12345".parse().unwrap(), &gen_keypair().0);
+        assert!(alice.try_add_to_close_nodes(&lan_node));
+        assert!(alice.try_add_to_close_nodes(&global_node));
+
+        let addr = "1.2.3.4:12346".parse().unwrap();
+        let (received_nodes, _rx) = handle_nodes_req(&alice, &precomp, &bob_pk, &bob_sk, rx, addr, bob_pk);
+
+        assert_eq!(received_nodes, vec![global_node]);
+    }
+
+    #[test]
+    fn server_handle_nodes_req_ipv6_nodes_test() {
+        let (alice, precomp, bob_pk, bob_sk, rx, _addr) = create_node();
+
+        let ipv4_node = PackedNode::new(false, "8.8.8.8:12345".parse().unwrap(), &gen_keypair().0);
+        let ipv6_node = PackedNode::new(false, "[2001:db8::1]:12345".parse().unwrap(), &gen_keypair().0);
+        assert!(alice.try_add_to_close_nodes(&ipv4_node));
+        assert!(alice.try_add_to_close_nodes(&ipv6_node));
+
+        // IPv4 node doesn't receive IPv6 nodes
+        let addr = "1.2.3.4:12346".parse().unwrap();
+        let (received_nodes, rx) = handle_nodes_req(&alice, &precomp, &bob_pk, &bob_sk, rx, addr, bob_pk);
+        assert_eq!(received_nodes, vec![ipv4_node]);
+
+        // neither does IPv4-mapped address
+        let addr = "[::ffff:1.2.3.4]:12346".parse().unwrap();
+        let (received_nodes, rx) = handle_nodes_req(&alice, &precomp, &bob_pk, &bob_sk, rx, addr, bob_pk);
+        assert_eq!(received_nodes, vec![ipv4_node]);
+
+        // IPv6 node receives both
+        let addr = "[2001:db8::2]:12346".parse().unwrap();
+        let (received_nodes, _rx) = handle_nodes_req(&alice, &precomp, &bob_pk, &bob_sk, rx, addr, bob_pk);
+        assert_eq!(received_nodes.len(), 2);
+        assert!(received_nodes.contains(&ipv4_node));
+        assert!(received_nodes.contains(&ipv6_node));
+    }
+
     #[test]
     fn server_handle_nodes_req_invalid_payload_test() {
         let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
@@ -1323,7 +1931,7 @@ mod tests {
         let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
         let nodes_req = DhtPacket::NodesRequest(NodesRequest::new(&precomp, &alice.pk, req_payload));
 
-        assert!(alice.handle_packet(nodes_req, addr).wait().is_err());
+        assert_error!(alice.handle_packet(nodes_req, addr).wait(), HandlePacketError::GetPayload { .. });
     }
 
     // handle_nodes_resp()
@@ -1353,6 +1961,38 @@ mod tests {
         assert_eq!(server_close_nodes.get_node(&bob_pk), close_nodes.get_node(&bob_pk));
     }
 
+    #[test]
+    fn server_handle_nodes_resp_friend_events_test() {
+        let (mut alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+        let (event_tx, event_rx) = mpsc::unbounded();
+        alice.set_event_sink(event_tx);
+
+        let friend_pk = gen_keypair().0;
+        alice.add_friend(DhtFriend::new(friend_pk, 0));
+
+        // bob knows the friend and the friend's address
+        let node = PackedNode::new(false, addr, &bob_pk);
+        let friend_node = PackedNode::new(false, "127.0.0.1:12347".parse().unwrap(), &friend_pk);
+
+        let mut client = PingData::new();
+        let ping_id = client.insert_new_ping_id();
+        let resp_payload = NodesResponsePayload { nodes: vec![node, friend_node], id: ping_id };
+        let nodes_resp = DhtPacket::NodesResponse(NodesResponse::new(&precomp, &bob_pk, resp_payload));
+
+        add_to_ping_map(&alice, bob_pk, client);
+
+        assert!(alice.handle_packet(nodes_resp, addr).wait().is_ok());
+
+        drop(alice);
+        let events = event_rx.collect().wait().unwrap();
+        assert_eq!(events, vec![
+            DhtEvent::NodeAdded(node),
+            DhtEvent::FriendFound { friend_pk, node },
+            DhtEvent::NodeAdded(friend_node),
+            DhtEvent::FriendAddrKnown { friend_pk, addr: friend_node.saddr },
+        ]);
+    }
+
     #[test]
     fn server_handle_nodes_resp_invalid_payload_test() {
         let (alice, precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
@@ -1363,7 +2003,7 @@ mod tests {
         ], id: 38 };
         let nodes_resp = DhtPacket::NodesResponse(NodesResponse::new(&precomp, &alice.pk, resp_payload));
 
-        assert!(alice.handle_packet(nodes_resp, addr).wait().is_err());
+        assert_error!(alice.handle_packet(nodes_resp, addr).wait(), HandlePacketError::GetPayload { .. });
     }
 
     #[test]
@@ -1379,7 +2019,7 @@ mod tests {
         let client = PingData::new();
         add_to_ping_map(&alice, bob_pk, client);
 
-        assert!(alice.handle_packet(nodes_resp, addr).wait().is_err());
+        assert_error!(alice.handle_packet(nodes_resp, addr).wait(), HandlePacketError::InvalidPingId { .. });
     }
 
     #[test]
@@ -1396,7 +2036,7 @@ mod tests {
 
         add_to_ping_map(&alice, bob_pk, client);
 
-        assert!(alice.handle_packet(nodes_resp, addr).wait().is_err());
+        assert_error!(alice.handle_packet(nodes_resp, addr).wait(), HandlePacketError::InvalidPingId { .. });
     }
 
     // handle_cookie_request
@@ -1409,18 +2049,25 @@ mod tests {
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (real_pk, real_sk) = gen_keypair();
         let (bob_pk, bob_sk) = gen_keypair();
         let (bob_real_pk, _bob_real_sk) = gen_keypair();
         let precomp = precompute(&alice.pk, &bob_sk);
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk,
-            real_pk
+            real_pk,
+            real_sk
         });
 
         alice.set_net_crypto(net_crypto);
@@ -1459,7 +2106,7 @@ mod tests {
         };
         let cookie_request = DhtPacket::CookieRequest(CookieRequest::new(&precomp, &bob_pk, cookie_request_payload));
 
-        assert!(alice.handle_packet(cookie_request, addr).wait().is_err());
+        assert_error!(alice.handle_packet(cookie_request, addr).wait(), HandlePacketError::NetCryptoNotInitialized);
     }
 
     // handle_cookie_response
@@ -1477,7 +2124,7 @@ mod tests {
         };
         let cookie_response = DhtPacket::CookieResponse(CookieResponse::new(&precomp, cookie_response_payload));
 
-        assert!(alice.handle_packet(cookie_response, addr).wait().is_err());
+        assert_error!(alice.handle_packet(cookie_response, addr).wait(), HandlePacketError::NetCryptoNotInitialized);
     }
 
     // handle_crypto_handshake
@@ -1497,7 +2144,7 @@ mod tests {
         };
         let crypto_handshake = DhtPacket::CryptoHandshake(CryptoHandshake::new(&precomp, crypto_handshake_payload, cookie));
 
-        assert!(alice.handle_packet(crypto_handshake, addr).wait().is_err());
+        assert_error!(alice.handle_packet(crypto_handshake, addr).wait(), HandlePacketError::NetCryptoNotInitialized);
     }
 
     // handle_dht_req
@@ -1534,6 +2181,48 @@ mod tests {
         assert!(alice.handle_packet(dht_req, addr).wait().is_ok());
     }
 
+    #[test]
+    fn server_handle_dht_req_dht_pk_announce_test() {
+        let (mut alice, _precomp, bob_pk, bob_sk, _rx, addr) = create_node();
+
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, dht_pk_rx) = mpsc::unbounded();
+        let (real_pk, real_sk) = gen_keypair();
+        let onion_client = OnionClient::new(udp_tx, dht_pk_tx, alice.pk, real_pk, real_sk, alice.close_nodes.clone());
+        let (bob_real_pk, bob_real_sk) = gen_keypair();
+        onion_client.add_friend(bob_real_pk);
+        alice.set_onion_client(onion_client);
+
+        let dht_pk_announce = DhtPkAnnounce::new(&precompute(&real_pk, &bob_real_sk), &bob_real_pk, DhtPkAnnouncePayload {
+            no_reply: 42,
+            dht_pk: bob_pk,
+            nodes: Vec::new()
+        });
+        let dht_payload = DhtRequestPayload::DhtPkAnnounce(dht_pk_announce);
+        let dht_req = DhtPacket::DhtRequest(DhtRequest::new(&precompute(&alice.pk, &bob_sk), &alice.pk, &bob_pk, dht_payload));
+
+        assert!(alice.handle_packet(dht_req, addr).wait().is_ok());
+
+        let (received, _dht_pk_rx) = dht_pk_rx.into_future().wait().unwrap();
+        assert_eq!(received.unwrap(), (bob_real_pk, bob_pk));
+    }
+
+    #[test]
+    fn server_handle_dht_req_dht_pk_announce_uninitialized_test() {
+        let (alice, _precomp, bob_pk, bob_sk, _rx, addr) = create_node();
+
+        let (bob_real_pk, bob_real_sk) = gen_keypair();
+        let dht_pk_announce = DhtPkAnnounce::new(&precompute(&gen_keypair().0, &bob_real_sk), &bob_real_pk, DhtPkAnnouncePayload {
+            no_reply: 42,
+            dht_pk: bob_pk,
+            nodes: Vec::new()
+        });
+        let dht_payload = DhtRequestPayload::DhtPkAnnounce(dht_pk_announce);
+        let dht_req = DhtPacket::DhtRequest(DhtRequest::new(&precompute(&alice.pk, &bob_sk), &alice.pk, &bob_pk, dht_payload));
+
+        assert_error!(alice.handle_packet(dht_req, addr).wait(), HandlePacketError::OnionClientNotInitialized);
+    }
+
     #[test]
     fn server_handle_dht_req_invalid_payload() {
         let (alice, _precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
@@ -1545,7 +2234,7 @@ mod tests {
             payload: vec![42; 123]
         });
 
-        assert!(alice.handle_packet(dht_req, addr).wait().is_err());
+        assert_error!(alice.handle_packet(dht_req, addr).wait(), HandlePacketError::GetPayload { .. });
     }
 
     // handle nat ping request
@@ -1607,7 +2296,7 @@ mod tests {
         let client = PingData::new();
         add_to_ping_map(&alice, bob_pk, client);
 
-        assert!(alice.handle_packet(dht_req, addr).wait().is_err());
+        assert_error!(alice.handle_packet(dht_req, addr).wait(), HandlePacketError::NoFriend { .. });
     }
 
     #[test]
@@ -1623,7 +2312,7 @@ mod tests {
 
         add_to_ping_map(&alice, bob_pk, client);
 
-        assert!(alice.handle_packet(dht_req, addr).wait().is_err());
+        assert_error!(alice.handle_packet(dht_req, addr).wait(), HandlePacketError::NoFriend { .. });
     }
 
     // handle_onion_request_0
@@ -1673,7 +2362,7 @@ mod tests {
             payload: vec![42; 123] // not encrypted with dht pk
         });
 
-        assert!(alice.handle_packet(packet, addr).wait().is_err());
+        assert_error!(alice.handle_packet(packet, addr).wait(), HandlePacketError::GetPayload { .. });
     }
 
     // handle_onion_request_1
@@ -1731,7 +2420,7 @@ mod tests {
             }
         });
 
-        assert!(alice.handle_packet(packet, addr).wait().is_err());
+        assert_error!(alice.handle_packet(packet, addr).wait(), HandlePacketError::GetPayload { .. });
     }
 
     // handle_onion_request_2
@@ -1832,7 +2521,7 @@ mod tests {
             }
         });
 
-        assert!(alice.handle_packet(packet, addr).wait().is_err());
+        assert_error!(alice.handle_packet(packet, addr).wait(), HandlePacketError::GetPayload { .. });
     }
 
     // handle_onion_announce_request
@@ -2131,6 +2820,34 @@ mod tests {
         assert!(alice.handle_packet(packet, addr).wait().is_err());
     }
 
+    // handle_onion_announce_response
+    #[test]
+    fn handle_onion_announce_response_uninitialized_test() {
+        let (alice, precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let packet = DhtPacket::OnionAnnounceResponse(OnionAnnounceResponse::new(&precomp, 12345, OnionAnnounceResponsePayload {
+            announce_status: AnnounceStatus::Failed,
+            ping_id_or_pk: initial_ping_id(),
+            nodes: Vec::new()
+        }));
+
+        assert_error!(alice.handle_packet(packet, addr).wait(), HandlePacketError::OnionClientNotInitialized);
+    }
+
+    // handle_onion_data_response
+    #[test]
+    fn handle_onion_data_response_uninitialized_test() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let packet = DhtPacket::OnionDataResponse(OnionDataResponse {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123]
+        });
+
+        assert_error!(alice.handle_packet(packet, addr).wait(), HandlePacketError::OnionClientNotInitialized);
+    }
+
     // handle_onion_response_1
     #[test]
     fn server_handle_onion_response_1_with_onion_announce_response_test() {
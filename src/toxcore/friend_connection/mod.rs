@@ -0,0 +1,929 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! The implementation of friend connection module.
+
+Friend connection module ties DHT, onion client and net crypto modules
+together. It owns per-friend state and:
+
+- searches for friends with onion client and DHT;
+- starts net crypto connection to a friend once his DHT `PublicKey` is known
+  and sets friend's UDP address when DHT finds it;
+- sends `Alive` packets to friends and kills connections that don't receive
+  any packets for a long time, after which they are reestablished;
+- shares our TCP relays with friends and connects to friends through relays
+  shared by them;
+- runs net crypto and onion client main loops.
+
+*/
+
+pub mod packet;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream, future, stream};
+use futures::sync::mpsc;
+use parking_lot::RwLock;
+use tokio::timer::Interval;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::dht_friend::DhtFriend;
+use toxcore::dht::packed_node::PackedNode;
+use toxcore::dht::packet::MAX_CRYPTO_DATA_SIZE;
+use toxcore::dht::server::Server;
+use toxcore::friend_connection::packet::*;
+use toxcore::io_tokio::*;
+use toxcore::net_crypto::*;
+use toxcore::onion::client::OnionClient;
+use toxcore::tcp::client::Connections;
+use toxcore::time::*;
+
+/// How often we should send `Alive` packet to a friend in seconds.
+pub const FRIEND_PING_INTERVAL: u64 = 8;
+
+/// Connection to a friend is considered dead if we didn't receive any packets
+/// from him during this number of seconds.
+pub const FRIEND_CONNECTION_TIMEOUT: u64 = FRIEND_PING_INTERVAL * 4;
+
+/// How often we should share our TCP relays with a friend in seconds.
+pub const SHARE_RELAYS_INTERVAL: u64 = 300;
+
+/// Maximum number of TCP relays shared by a friend that we store.
+pub const FRIEND_MAX_STORED_TCP_RELAYS: usize = 24;
+
+/// How often `main_loop` is called by `run` in milliseconds.
+pub const MAIN_LOOP_INTERVAL: u64 = 50;
+
+/// Shorthand for the transmit half of the message channel for sending friend's
+/// connection status. The key is a long term `PublicKey` of the friend, the
+/// value is `true` when the friend goes online and `false` when he goes
+/// offline.
+type ConnectionStatusTx = mpsc::UnboundedSender<(PublicKey, bool)>;
+
+/// Shorthand for the transmit half of the message channel for sending lossless
+/// packets that are not handled by friend connection module. The key is a long
+/// term `PublicKey` of the friend that sent this packet.
+type LosslessTx = mpsc::UnboundedSender<(PublicKey, Vec<u8>)>;
+
+/// Shorthand for the receive half of the message channel for receiving DHT
+/// `PublicKey`s of friends. The first key is a long term key, the second key is
+/// a DHT key.
+type DhtPkRx = mpsc::UnboundedReceiver<(PublicKey, PublicKey)>;
+
+/// Shorthand for the receive half of the message channel for receiving long
+/// term and DHT `PublicKey`s of friends that initiated crypto connection with
+/// us.
+type NewConnectionRx = mpsc::UnboundedReceiver<(PublicKey, PublicKey)>;
+
+/// Shorthand for the receive half of the message channel for receiving net
+/// crypto connection status events.
+type NetCryptoStatusRx = mpsc::UnboundedReceiver<(PublicKey, ConnectionEvent)>;
+
+/// Shorthand for the receive half of the message channel for receiving
+/// lossless packets from net crypto.
+type LosslessRx = mpsc::UnboundedReceiver<(PublicKey, Vec<u8>)>;
+
+/// Friend related data stored in friend connection module.
+#[derive(Clone, Debug)]
+struct Friend {
+    /// Long term `PublicKey` of the friend
+    real_pk: PublicKey,
+    /// DHT `PublicKey` of the friend if it's known
+    dht_pk: Option<PublicKey>,
+    /// Whether net crypto connection to the friend is established
+    connected: bool,
+    /// Time when we received the last packet from the friend
+    ping_received_time: Option<Instant>,
+    /// Time when we sent the last `Alive` packet to the friend
+    ping_sent_time: Option<Instant>,
+    /// Time when we shared our TCP relays with the friend last time
+    share_relays_time: Option<Instant>,
+    /// TCP relays shared by the friend, the most recent first
+    tcp_relays: Vec<PackedNode>,
+}
+
+impl Friend {
+    /// Create new `Friend`.
+    fn new(real_pk: PublicKey) -> Friend {
+        Friend {
+            real_pk,
+            dht_pk: None,
+            connected: false,
+            ping_received_time: None,
+            ping_sent_time: None,
+            share_relays_time: None,
+            tcp_relays: Vec::new(),
+        }
+    }
+}
+
+/** Connections to friends over DHT, onion and net crypto.
+
+Friend connections should be created after `Server`, `OnionClient` and
+`NetCrypto` are created. `NetCrypto` is configured to accept connections only
+from added friends.
+*/
+#[derive(Clone)]
+pub struct FriendConnections {
+    /// Sink to send friend's connection status when it changes
+    connection_status_tx: ConnectionStatusTx,
+    /// Sink to send lossless packets that are not handled by friend connection
+    /// module
+    lossless_tx: LosslessTx,
+    /// Friends by their long term `PublicKey`
+    friends: Arc<RwLock<HashMap<PublicKey, Friend>>>,
+    /// Long term `PublicKey`s of friends shared with `NetCrypto` accept policy
+    friends_pks: Arc<RwLock<HashSet<PublicKey>>>,
+    /// TCP relays we are connected to that should be shared with friends
+    tcp_relays: Arc<RwLock<Vec<PackedNode>>>,
+    /// DHT server used to find friends' addresses
+    dht: Server,
+    /// Onion client used to find friends' DHT `PublicKey`s
+    onion_client: OnionClient,
+    /// Net crypto module used to connect to friends
+    net_crypto: NetCrypto,
+    /// TCP connections used to connect to friends through relays
+    tcp_connections: Option<Connections>,
+}
+
+impl FriendConnections {
+    /// Create new `FriendConnections` object.
+    pub fn new(
+        dht: Server,
+        onion_client: OnionClient,
+        net_crypto: NetCrypto,
+        connection_status_tx: ConnectionStatusTx,
+        lossless_tx: LosslessTx
+    ) -> FriendConnections {
+        let friends_pks = Arc::new(RwLock::new(HashSet::new()));
+        net_crypto.set_accept_policy(AcceptPolicy::FriendsOnly(friends_pks.clone()));
+        FriendConnections {
+            connection_status_tx,
+            lossless_tx,
+            friends: Arc::new(RwLock::new(HashMap::new())),
+            friends_pks,
+            tcp_relays: Arc::new(RwLock::new(Vec::new())),
+            dht,
+            onion_client,
+            net_crypto,
+            tcp_connections: None,
+        }
+    }
+
+    /// Set TCP connections used to connect to friends through relays. They
+    /// should use the same DHT key pair as `NetCrypto`.
+    pub fn set_tcp_connections(&mut self, tcp_connections: Connections) {
+        self.tcp_connections = Some(tcp_connections);
+    }
+
+    /// Add friend to search for him and connect to him when he is found.
+    pub fn add_friend(&self, real_pk: PublicKey) {
+        let mut friends = self.friends.write();
+        if friends.contains_key(&real_pk) {
+            return;
+        }
+        friends.insert(real_pk, Friend::new(real_pk));
+        self.friends_pks.write().insert(real_pk);
+        self.onion_client.add_friend(real_pk);
+    }
+
+    /// Remove friend killing connection to him if it exists.
+    pub fn remove_friend(&self, real_pk: PublicKey) -> IoFuture<()> {
+        let friend = match self.friends.write().remove(&real_pk) {
+            Some(friend) => friend,
+            None => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "No such friend")
+            )),
+        };
+        self.friends_pks.write().remove(&real_pk);
+        self.onion_client.remove_friend(&real_pk);
+        let tcp_future = match friend.dht_pk {
+            Some(dht_pk) => {
+                self.dht.remove_friend(&dht_pk);
+                self.remove_tcp_friend(&dht_pk)
+            },
+            None => Box::new(future::ok(())),
+        };
+        // connection doesn't exist if the friend wasn't found yet
+        let kill_future = self.net_crypto.kill_connection(real_pk).or_else(|_| Ok(()));
+        Box::new(tcp_future.join(kill_future).map(|_| ()))
+    }
+
+    /// Check if net crypto connection to the friend is established.
+    pub fn is_connected(&self, real_pk: &PublicKey) -> bool {
+        self.friends.read().get(real_pk).map(|friend| friend.connected).unwrap_or(false)
+    }
+
+    /// Get TCP relays shared by the friend.
+    pub fn friend_tcp_relays(&self, real_pk: &PublicKey) -> Vec<PackedNode> {
+        self.friends.read().get(real_pk).map(|friend| friend.tcp_relays.clone()).unwrap_or_default()
+    }
+
+    /// Add TCP relay we are connected to so that it will be shared with
+    /// friends.
+    pub fn add_tcp_relay(&self, relay: PackedNode) {
+        let mut tcp_relays = self.tcp_relays.write();
+        if !tcp_relays.iter().any(|known| known.pk == relay.pk) {
+            tcp_relays.push(relay);
+        }
+    }
+
+    /// Remove TCP relay we are not connected to anymore.
+    pub fn remove_tcp_relay(&self, relay_pk: &PublicKey) {
+        self.tcp_relays.write().retain(|relay| relay.pk != *relay_pk);
+    }
+
+    /// Remove the friend with the given DHT `PublicKey` from TCP connections.
+    fn remove_tcp_friend(&self, dht_pk: &PublicKey) -> IoFuture<()> {
+        match self.tcp_connections {
+            // routes to the friend might be not requested yet
+            Some(ref tcp_connections) => Box::new(tcp_connections.remove_friend(dht_pk).or_else(|_| Ok(()))),
+            None => Box::new(future::ok(())),
+        }
+    }
+
+    /// Set DHT `PublicKey` of the friend replacing the friend in DHT and TCP
+    /// connections so that they will search for his new key.
+    fn set_dht_pk(&self, friend: &mut Friend, dht_pk: PublicKey) -> IoFuture<()> {
+        let remove_future = match friend.dht_pk {
+            Some(old_dht_pk) => {
+                self.dht.remove_friend(&old_dht_pk);
+                self.remove_tcp_friend(&old_dht_pk)
+            },
+            None => Box::new(future::ok(())),
+        };
+        friend.dht_pk = Some(dht_pk);
+        self.dht.add_friend(DhtFriend::new(dht_pk, 0));
+        if let Some(ref tcp_connections) = self.tcp_connections {
+            tcp_connections.add_friend(dht_pk);
+            for relay in &friend.tcp_relays {
+                tcp_connections.add_friend_relay(dht_pk, relay.pk, relay.saddr);
+            }
+        }
+        remove_future
+    }
+
+    /// Mark the friend as disconnected sending connection status if he was
+    /// connected.
+    fn set_disconnected(&self, friend: &mut Friend) -> IoFuture<()> {
+        if friend.connected {
+            friend.connected = false;
+            send_to(&self.connection_status_tx, (friend.real_pk, false))
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Handle DHT `PublicKey` of the friend received from onion client or net
+    /// crypto. If the key has changed then current connection is killed since
+    /// it's bound to the old key.
+    pub fn handle_dht_pk(&self, real_pk: PublicKey, dht_pk: PublicKey) -> IoFuture<()> {
+        let mut friends = self.friends.write();
+        let friend = match friends.get_mut(&real_pk) {
+            Some(friend) => friend,
+            None => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "DHT PublicKey is received for unknown friend")
+            )),
+        };
+
+        if friend.dht_pk == Some(dht_pk) {
+            return Box::new(future::ok(()));
+        }
+
+        let had_dht_pk = friend.dht_pk.is_some();
+        let dht_pk_future = self.set_dht_pk(friend, dht_pk);
+
+        if had_dht_pk {
+            let status_future = self.set_disconnected(friend);
+            // the connection will be recreated with the new key in main loop
+            let kill_future = self.net_crypto.kill_connection(real_pk).or_else(|_| Ok(()));
+            Box::new(dht_pk_future.join3(status_future, kill_future).map(|_| ()))
+        } else {
+            dht_pk_future
+        }
+    }
+
+    /// Handle crypto connection initiated by the friend and accepted by net
+    /// crypto. The connection already exists so we only need to remember
+    /// friend's DHT `PublicKey`.
+    pub fn handle_new_connection(&self, real_pk: PublicKey, dht_pk: PublicKey) -> IoFuture<()> {
+        let mut friends = self.friends.write();
+        let friend = match friends.get_mut(&real_pk) {
+            Some(friend) => friend,
+            None => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "New connection is received from unknown friend")
+            )),
+        };
+
+        if friend.dht_pk != Some(dht_pk) {
+            self.set_dht_pk(friend, dht_pk)
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Handle net crypto connection status event updating friend's connection
+    /// status.
+    pub fn handle_connection_event(&self, real_pk: PublicKey, event: ConnectionEvent) -> IoFuture<()> {
+        let mut friends = self.friends.write();
+        let friend = match friends.get_mut(&real_pk) {
+            Some(friend) => friend,
+            None => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "Connection event is received for unknown friend")
+            )),
+        };
+
+        match event {
+            ConnectionEvent::Established => {
+                friend.connected = true;
+                friend.ping_received_time = Some(clock_now());
+                friend.ping_sent_time = None;
+                friend.share_relays_time = None;
+                send_to(&self.connection_status_tx, (real_pk, true))
+            },
+            ConnectionEvent::Udp | ConnectionEvent::Tcp => Box::new(future::ok(())),
+            ConnectionEvent::TimedOut | ConnectionEvent::Killed => self.set_disconnected(friend),
+        }
+    }
+
+    /// Handle lossless packet received from the friend. `Alive` and
+    /// `ShareRelays` packets are handled by this module, other packets are
+    /// sent to the lossless sink.
+    pub fn handle_lossless_packet(&self, real_pk: PublicKey, data: Vec<u8>) -> IoFuture<()> {
+        let mut friends = self.friends.write();
+        let friend = match friends.get_mut(&real_pk) {
+            Some(friend) => friend,
+            None => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "Lossless packet is received from unknown friend")
+            )),
+        };
+
+        friend.ping_received_time = Some(clock_now());
+
+        match Packet::from_bytes(&data) {
+            IResult::Done(_, Packet::Alive(_)) => Box::new(future::ok(())),
+            IResult::Done(_, Packet::ShareRelays(packet)) => {
+                for relay in packet.relays {
+                    if !friend.tcp_relays.iter().any(|known| known.pk == relay.pk) {
+                        // the friend is connected to this relay so we can
+                        // reach him through it
+                        if let (Some(dht_pk), Some(tcp_connections)) = (friend.dht_pk, self.tcp_connections.as_ref()) {
+                            tcp_connections.add_friend_relay(dht_pk, relay.pk, relay.saddr);
+                        }
+                        friend.tcp_relays.insert(0, relay);
+                    }
+                }
+                friend.tcp_relays.truncate(FRIEND_MAX_STORED_TCP_RELAYS);
+                Box::new(future::ok(()))
+            },
+            _ => send_to(&self.lossless_tx, (real_pk, data)),
+        }
+    }
+
+    /// Get address of the friend found by DHT.
+    fn friend_saddr(&self, dht_pk: &PublicKey) -> Option<SocketAddr> {
+        self.dht.friends.read().iter()
+            .find(|friend| friend.pk == *dht_pk)
+            .and_then(|friend| friend.close_nodes.nodes.iter().find(|node| node.pk == *dht_pk))
+            .map(|node| node.saddr)
+    }
+
+    /// Send friend connection packet to the friend as lossless packet.
+    fn send_packet(&self, real_pk: PublicKey, packet: Packet) -> IoFuture<()> {
+        let mut buf = [0; MAX_CRYPTO_DATA_SIZE];
+        match packet.to_bytes((&mut buf, 0)) {
+            Ok((_, size)) => Box::new(self.net_crypto.send_lossless(real_pk, buf[..size].to_vec()).map(|_| ())),
+            Err(e) => Box::new(future::err(
+                Error::new(ErrorKind::Other, format!("Failed to serialize packet: {:?}", e))
+            )),
+        }
+    }
+
+    /// Main loop of friend connections, it should be called every
+    /// `MAIN_LOOP_INTERVAL` milliseconds.
+    pub fn main_loop(&self) -> IoFuture<()> {
+        let ping_interval = Duration::from_secs(FRIEND_PING_INTERVAL);
+        let connection_timeout = Duration::from_secs(FRIEND_CONNECTION_TIMEOUT);
+        let share_relays_interval = Duration::from_secs(SHARE_RELAYS_INTERVAL);
+
+        let mut friends = self.friends.write();
+        let mut futures = Vec::new();
+        for friend in friends.values_mut() {
+            if let Some(dht_pk) = friend.dht_pk {
+                // does nothing if the connection already exists
+                self.net_crypto.add_connection(friend.real_pk, dht_pk);
+                if let Some(saddr) = self.friend_saddr(&dht_pk) {
+                    futures.push(self.net_crypto.set_friend_udp_addr(friend.real_pk, saddr));
+                }
+            }
+
+            if !friend.connected {
+                continue;
+            }
+
+            let timed_out = friend.ping_received_time
+                .map(|time| clock_elapsed(time) >= connection_timeout)
+                .unwrap_or(true);
+            if timed_out {
+                futures.push(self.set_disconnected(friend));
+                // the connection will be recreated in the next iteration
+                futures.push(Box::new(self.net_crypto.kill_connection(friend.real_pk).or_else(|_| Ok(()))));
+                continue;
+            }
+
+            if friend.ping_sent_time.map(|time| clock_elapsed(time) >= ping_interval).unwrap_or(true) {
+                friend.ping_sent_time = Some(clock_now());
+                futures.push(self.send_packet(friend.real_pk, Packet::Alive(Alive)));
+            }
+
+            if friend.share_relays_time.map(|time| clock_elapsed(time) >= share_relays_interval).unwrap_or(true) {
+                let relays = self.tcp_relays.read().iter().take(MAX_SHARED_RELAYS).cloned().collect::<Vec<_>>();
+                if !relays.is_empty() {
+                    friend.share_relays_time = Some(clock_now());
+                    futures.push(self.send_packet(friend.real_pk, Packet::ShareRelays(ShareRelays { relays })));
+                }
+            }
+        }
+
+        // failure to send a packet to one friend shouldn't affect others
+        let futures_stream = stream::futures_unordered(futures).then(|_| Ok(()));
+        Box::new(futures_stream.for_each(|()| Ok(())))
+    }
+
+    /// Run friend connections handling messages from the given channels of
+    /// onion client and net crypto modules and calling `main_loop`
+    /// periodically. Main loops of onion client and net crypto are run as
+    /// well. `dht_pk_rx` should receive DHT `PublicKey`s from both onion
+    /// client and net crypto.
+    pub fn run(&self,
+        dht_pk_rx: DhtPkRx,
+        new_connection_rx: NewConnectionRx,
+        connection_status_rx: NetCryptoStatusRx,
+        lossless_rx: LosslessRx
+    ) -> IoFuture<()> {
+        let friend_connections = self.clone();
+        let dht_pk_future = dht_pk_rx
+            .map_err(|()| Error::new(ErrorKind::Other, "rx error"))
+            .for_each(move |(real_pk, dht_pk)|
+                friend_connections.handle_dht_pk(real_pk, dht_pk).or_else(|e| {
+                    debug!("Failed to handle DHT PublicKey: {}", e);
+                    Ok(())
+                })
+            );
+
+        let friend_connections = self.clone();
+        let new_connection_future = new_connection_rx
+            .map_err(|()| Error::new(ErrorKind::Other, "rx error"))
+            .for_each(move |(real_pk, dht_pk)|
+                friend_connections.handle_new_connection(real_pk, dht_pk).or_else(|e| {
+                    debug!("Failed to handle new connection: {}", e);
+                    Ok(())
+                })
+            );
+
+        let friend_connections = self.clone();
+        let connection_status_future = connection_status_rx
+            .map_err(|()| Error::new(ErrorKind::Other, "rx error"))
+            .for_each(move |(real_pk, event)|
+                friend_connections.handle_connection_event(real_pk, event).or_else(|e| {
+                    debug!("Failed to handle connection event: {}", e);
+                    Ok(())
+                })
+            );
+
+        let friend_connections = self.clone();
+        let lossless_future = lossless_rx
+            .map_err(|()| Error::new(ErrorKind::Other, "rx error"))
+            .for_each(move |(real_pk, data)|
+                friend_connections.handle_lossless_packet(real_pk, data).or_else(|e| {
+                    debug!("Failed to handle lossless packet: {}", e);
+                    Ok(())
+                })
+            );
+
+        let friend_connections = self.clone();
+        let wakeups = Interval::new(clock_now(), Duration::from_millis(MAIN_LOOP_INTERVAL));
+        let main_loop_future = wakeups
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Friend connections timer error: {:?}", e)))
+            .for_each(move |_instant| friend_connections.main_loop());
+
+        Box::new(dht_pk_future
+            .join5(new_connection_future, connection_status_future, lossless_future, main_loop_future)
+            .join3(self.net_crypto.run(), self.onion_client.run())
+            .map(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio;
+    use tokio::net::TcpListener;
+    use tokio::util::FutureExt;
+
+    use toxcore::dht::kbucket::*;
+    use toxcore::dht::packet::DhtPacket;
+    use toxcore::tcp::server::{self, ServerConfig};
+
+    type UdpRx = mpsc::UnboundedReceiver<(DhtPacket, SocketAddr)>;
+    type TcpRx = mpsc::UnboundedReceiver<(DhtPacket, PublicKey)>;
+    type ConnectionStatusRx = mpsc::UnboundedReceiver<(PublicKey, bool)>;
+
+    fn create_friend_connections() -> (FriendConnections, NetCrypto, UdpRx, TcpRx, ConnectionStatusRx, LosslessRx) {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx: udp_tx.clone(),
+            tcp_tx,
+            dht_pk_tx: dht_pk_tx.clone(),
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+        let dht = Server::new(udp_tx.clone(), dht_pk, dht_sk);
+        let close_nodes = Arc::new(RwLock::new(Kbucket::new(&dht_pk)));
+        let onion_client = OnionClient::new(udp_tx, dht_pk_tx, dht_pk, real_pk, real_sk, close_nodes);
+
+        let (friend_status_tx, friend_status_rx) = mpsc::unbounded();
+        let (friend_lossless_tx, friend_lossless_rx) = mpsc::unbounded();
+        let friend_connections = FriendConnections::new(
+            dht,
+            onion_client,
+            net_crypto.clone(),
+            friend_status_tx,
+            friend_lossless_tx
+        );
+        (friend_connections, net_crypto, udp_rx, tcp_rx, friend_status_rx, friend_lossless_rx)
+    }
+
+    /// Node that can reach its friends only through TCP relays.
+    struct TcpNode {
+        friend_connections: FriendConnections,
+        tcp_connections: Connections,
+        real_pk: PublicKey,
+        dht_pk: PublicKey,
+        status_rx: ConnectionStatusRx,
+        future: IoFuture<()>,
+    }
+
+    fn create_tcp_node() -> TcpNode {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, tcp_rx) = mpsc::unbounded();
+        let (tcp_data_tx, tcp_data_rx) = mpsc::unbounded();
+        let (dht_pk_tx, dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx: udp_tx.clone(),
+            tcp_tx,
+            dht_pk_tx: dht_pk_tx.clone(),
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+        let dht = Server::new(udp_tx.clone(), dht_pk, dht_sk.clone());
+        let close_nodes = Arc::new(RwLock::new(Kbucket::new(&dht_pk)));
+        let onion_client = OnionClient::new(udp_tx, dht_pk_tx, dht_pk, real_pk, real_sk, close_nodes);
+        let tcp_connections = Connections::new(dht_pk, dht_sk, tcp_data_tx);
+
+        let (friend_status_tx, friend_status_rx) = mpsc::unbounded();
+        let (friend_lossless_tx, _friend_lossless_rx) = mpsc::unbounded();
+        let mut friend_connections = FriendConnections::new(
+            dht,
+            onion_client,
+            net_crypto.clone(),
+            friend_status_tx,
+            friend_lossless_tx
+        );
+        friend_connections.set_tcp_connections(tcp_connections.clone());
+
+        // UDP packets are dropped
+        let udp_future = udp_rx
+            .map_err(|()| Error::new(ErrorKind::Other, "rx error"))
+            .for_each(|_| Ok(()));
+        let future = friend_connections.run(dht_pk_rx, new_connection_rx, connection_status_rx, lossless_rx)
+            .join4(
+                net_crypto.run_tcp(tcp_connections.clone(), tcp_rx, tcp_data_rx),
+                tcp_connections.run(),
+                udp_future
+            )
+            .map(|_| ());
+
+        TcpNode {
+            friend_connections,
+            tcp_connections,
+            real_pk,
+            dht_pk,
+            status_rx: friend_status_rx,
+            future: Box::new(future),
+        }
+    }
+
+    #[test]
+    fn add_remove_friend() {
+        let (friend_connections, _net_crypto, _udp_rx, _tcp_rx, _status_rx, _lossless_rx) = create_friend_connections();
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let (friend_dht_pk, _friend_dht_sk) = gen_keypair();
+
+        friend_connections.add_friend(friend_pk);
+        assert!(friend_connections.friends.read().contains_key(&friend_pk));
+        assert!(friend_connections.friends_pks.read().contains(&friend_pk));
+
+        friend_connections.handle_dht_pk(friend_pk, friend_dht_pk).wait().unwrap();
+        assert_eq!(friend_connections.dht.friends.read().len(), 1);
+
+        friend_connections.remove_friend(friend_pk).wait().unwrap();
+        assert!(friend_connections.friends.read().is_empty());
+        assert!(friend_connections.friends_pks.read().is_empty());
+        assert!(friend_connections.dht.friends.read().is_empty());
+    }
+
+    #[test]
+    fn remove_unknown_friend() {
+        let (friend_connections, _net_crypto, _udp_rx, _tcp_rx, _status_rx, _lossless_rx) = create_friend_connections();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        assert!(friend_connections.remove_friend(friend_pk).wait().is_err());
+    }
+
+    #[test]
+    fn handle_dht_pk_connects_to_friend() {
+        let (friend_connections, net_crypto, _udp_rx, tcp_rx, _status_rx, _lossless_rx) = create_friend_connections();
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let (friend_dht_pk, _friend_dht_sk) = gen_keypair();
+        let friend_saddr = "127.0.0.1:33445".parse().unwrap();
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_dht_pk(friend_pk, friend_dht_pk).wait().unwrap();
+
+        // DHT found the friend
+        let node = PackedNode::new(true, friend_saddr, &friend_dht_pk);
+        assert!(friend_connections.dht.friends.write()[0].close_nodes.try_add(&friend_dht_pk, &node));
+        assert_eq!(friend_connections.friend_saddr(&friend_dht_pk), Some(friend_saddr));
+
+        friend_connections.main_loop().wait().unwrap();
+        net_crypto.main_loop().wait().unwrap();
+
+        // CookieRequest is too big to be sent via UDP while UDP is not
+        // confirmed to be alive so it's sent via TCP relays only
+        let (received, _tcp_rx) = tcp_rx.into_future().wait().unwrap();
+        let (packet, pk) = received.unwrap();
+        assert_eq!(pk, friend_dht_pk);
+        unpack!(packet, DhtPacket::CookieRequest);
+    }
+
+    #[test]
+    fn handle_dht_pk_changed() {
+        let (friend_connections, _net_crypto, _udp_rx, _tcp_rx, status_rx, _lossless_rx) = create_friend_connections();
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let (friend_dht_pk, _friend_dht_sk) = gen_keypair();
+        let (new_friend_dht_pk, _new_friend_dht_sk) = gen_keypair();
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_dht_pk(friend_pk, friend_dht_pk).wait().unwrap();
+        friend_connections.handle_connection_event(friend_pk, ConnectionEvent::Established).wait().unwrap();
+        friend_connections.handle_dht_pk(friend_pk, new_friend_dht_pk).wait().unwrap();
+
+        assert!(!friend_connections.is_connected(&friend_pk));
+        let dht_friends = friend_connections.dht.friends.read();
+        assert_eq!(dht_friends.len(), 1);
+        assert_eq!(dht_friends[0].pk, new_friend_dht_pk);
+
+        let statuses = status_rx.take(2).collect().wait().unwrap();
+        assert_eq!(statuses, vec![(friend_pk, true), (friend_pk, false)]);
+    }
+
+    #[test]
+    fn handle_dht_pk_unknown_friend() {
+        let (friend_connections, _net_crypto, _udp_rx, _tcp_rx, _status_rx, _lossless_rx) = create_friend_connections();
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let (friend_dht_pk, _friend_dht_sk) = gen_keypair();
+
+        assert!(friend_connections.handle_dht_pk(friend_pk, friend_dht_pk).wait().is_err());
+    }
+
+    #[test]
+    fn handle_new_connection() {
+        let (friend_connections, _net_crypto, _udp_rx, _tcp_rx, _status_rx, _lossless_rx) = create_friend_connections();
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let (friend_dht_pk, _friend_dht_sk) = gen_keypair();
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_new_connection(friend_pk, friend_dht_pk).wait().unwrap();
+
+        assert_eq!(friend_connections.friends.read()[&friend_pk].dht_pk, Some(friend_dht_pk));
+        assert_eq!(friend_connections.dht.friends.read()[0].pk, friend_dht_pk);
+    }
+
+    #[test]
+    fn handle_connection_event() {
+        let (friend_connections, _net_crypto, _udp_rx, _tcp_rx, status_rx, _lossless_rx) = create_friend_connections();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        friend_connections.add_friend(friend_pk);
+
+        friend_connections.handle_connection_event(friend_pk, ConnectionEvent::Established).wait().unwrap();
+        assert!(friend_connections.is_connected(&friend_pk));
+
+        friend_connections.handle_connection_event(friend_pk, ConnectionEvent::Udp).wait().unwrap();
+        assert!(friend_connections.is_connected(&friend_pk));
+
+        friend_connections.handle_connection_event(friend_pk, ConnectionEvent::Killed).wait().unwrap();
+        assert!(!friend_connections.is_connected(&friend_pk));
+
+        // already disconnected friend doesn't produce status change
+        friend_connections.handle_connection_event(friend_pk, ConnectionEvent::TimedOut).wait().unwrap();
+
+        drop(friend_connections);
+        let statuses = status_rx.collect().wait().unwrap();
+        assert_eq!(statuses, vec![(friend_pk, true), (friend_pk, false)]);
+    }
+
+    #[test]
+    fn handle_lossless_alive() {
+        let (friend_connections, _net_crypto, _udp_rx, _tcp_rx, _status_rx, lossless_rx) = create_friend_connections();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_lossless_packet(friend_pk, vec![0x10]).wait().unwrap();
+
+        assert!(friend_connections.friends.read()[&friend_pk].ping_received_time.is_some());
+
+        drop(friend_connections);
+        assert!(lossless_rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn handle_lossless_share_relays() {
+        let (friend_connections, _net_crypto, _udp_rx, _tcp_rx, _status_rx, _lossless_rx) = create_friend_connections();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        friend_connections.add_friend(friend_pk);
+
+        let relays = vec![
+            PackedNode::new(false, "1.2.3.4:12345".parse().unwrap(), &gen_keypair().0),
+            PackedNode::new(false, "1.2.3.5:12345".parse().unwrap(), &gen_keypair().0),
+        ];
+        let packet = Packet::ShareRelays(ShareRelays { relays: relays.clone() });
+        let mut buf = [0; MAX_CRYPTO_DATA_SIZE];
+        let (_, size) = packet.to_bytes((&mut buf, 0)).unwrap();
+        friend_connections.handle_lossless_packet(friend_pk, buf[..size].to_vec()).wait().unwrap();
+        // the same relays shouldn't be duplicated
+        friend_connections.handle_lossless_packet(friend_pk, buf[..size].to_vec()).wait().unwrap();
+
+        let stored_relays = friend_connections.friend_tcp_relays(&friend_pk);
+        assert_eq!(stored_relays.len(), 2);
+        assert!(relays.iter().all(|relay| stored_relays.contains(relay)));
+    }
+
+    #[test]
+    fn friends_connect_through_shared_relay() {
+        let (relay_pk, relay_sk) = gen_keypair();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let relay = server::run(listener, relay_sk, server::Server::new(), ServerConfig::default());
+
+        let node_1 = create_tcp_node();
+        let node_2 = create_tcp_node();
+        node_1.friend_connections.add_friend(node_2.real_pk);
+        node_2.friend_connections.add_friend(node_1.real_pk);
+
+        // DHT `PublicKey`s are usually found by onion client
+        node_1.friend_connections.handle_dht_pk(node_2.real_pk, node_2.dht_pk).wait().unwrap();
+        node_2.friend_connections.handle_dht_pk(node_1.real_pk, node_1.dht_pk).wait().unwrap();
+
+        // only the second node knows the relay and it shared the relay with
+        // the first node before
+        node_2.tcp_connections.add_relay(relay_pk, addr);
+        let packet = Packet::ShareRelays(ShareRelays {
+            relays: vec![PackedNode::new(false, addr, &relay_pk)],
+        });
+        let mut buf = [0; MAX_CRYPTO_DATA_SIZE];
+        let (_, size) = packet.to_bytes((&mut buf, 0)).unwrap();
+        node_1.friend_connections.handle_lossless_packet(node_2.real_pk, buf[..size].to_vec()).wait().unwrap();
+
+        let real_pk_1 = node_1.real_pk;
+        let real_pk_2 = node_2.real_pk;
+        let status_1 = node_1.status_rx
+            .into_future()
+            .map(move |(received, _status_rx)| assert_eq!(received.unwrap(), (real_pk_2, true)))
+            .map_err(|_| Error::new(ErrorKind::Other, "rx error"));
+        let status_2 = node_2.status_rx
+            .into_future()
+            .map(move |(received, _status_rx)| assert_eq!(received.unwrap(), (real_pk_1, true)))
+            .map_err(|_| Error::new(ErrorKind::Other, "rx error"));
+
+        let network = relay
+            .join3(node_1.future, node_2.future)
+            .map(|_| ());
+
+        let test = status_1.join(status_2)
+            .map(|_| ())
+            .select(network)
+            .map(|_| ())
+            .map_err(|(e, _select_next)| e)
+            .timeout(Duration::from_secs(20));
+
+        tokio::runtime::Runtime::new().unwrap().block_on(test).unwrap();
+    }
+
+    #[test]
+    fn handle_lossless_other() {
+        let (friend_connections, _net_crypto, _udp_rx, _tcp_rx, _status_rx, lossless_rx) = create_friend_connections();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_lossless_packet(friend_pk, vec![64, 42, 42]).wait().unwrap();
+
+        let (received, _lossless_rx) = lossless_rx.into_future().wait().unwrap();
+        assert_eq!(received.unwrap(), (friend_pk, vec![64, 42, 42]));
+    }
+
+    #[test]
+    fn handle_lossless_unknown_friend() {
+        let (friend_connections, _net_crypto, _udp_rx, _tcp_rx, _status_rx, _lossless_rx) = create_friend_connections();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        assert!(friend_connections.handle_lossless_packet(friend_pk, vec![0x10]).wait().is_err());
+    }
+
+    #[test]
+    fn main_loop_pings_friend() {
+        let (friend_connections, _net_crypto, _udp_rx, _tcp_rx, _status_rx, _lossless_rx) = create_friend_connections();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.add_tcp_relay(PackedNode::new(false, "1.2.3.4:12345".parse().unwrap(), &gen_keypair().0));
+        friend_connections.handle_connection_event(friend_pk, ConnectionEvent::Established).wait().unwrap();
+
+        friend_connections.main_loop().wait().unwrap();
+
+        let friends = friend_connections.friends.read();
+        let friend = &friends[&friend_pk];
+        assert!(friend.ping_sent_time.is_some());
+        assert!(friend.share_relays_time.is_some());
+    }
+
+    #[test]
+    fn main_loop_disconnects_timed_out_friend() {
+        let (friend_connections, _net_crypto, _udp_rx, _tcp_rx, status_rx, _lossless_rx) = create_friend_connections();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_connection_event(friend_pk, ConnectionEvent::Established).wait().unwrap();
+        friend_connections.friends.write().get_mut(&friend_pk).unwrap().ping_received_time =
+            Some(clock_now() - Duration::from_secs(FRIEND_CONNECTION_TIMEOUT + 1));
+
+        friend_connections.main_loop().wait().unwrap();
+
+        assert!(!friend_connections.is_connected(&friend_pk));
+
+        drop(friend_connections);
+        let statuses = status_rx.collect().wait().unwrap();
+        assert_eq!(statuses, vec![(friend_pk, true), (friend_pk, false)]);
+    }
+}
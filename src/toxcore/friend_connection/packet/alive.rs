@@ -0,0 +1,63 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Alive struct
+*/
+
+use toxcore::binary_io::*;
+
+/** Alive packet is sent to a friend periodically to let him know that the
+connection is still alive. If a friend doesn't receive any packets during
+`FRIEND_CONNECTION_TIMEOUT` the connection is considered dead and is killed.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`1`    | `0x10`
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Alive;
+
+impl FromBytes for Alive {
+    named!(from_bytes<Alive>, do_parse!(
+        tag!("\x10") >>
+        (Alive)
+    ));
+}
+
+impl ToBytes for Alive {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x10)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        alive_encode_decode,
+        Alive
+    );
+}
@@ -0,0 +1,78 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Friend connection packets that are sent over net crypto connection as
+lossless packets.
+*/
+
+mod alive;
+mod share_relays;
+
+pub use self::alive::*;
+pub use self::share_relays::*;
+
+use toxcore::binary_io::*;
+
+/** Friend connection packet enum that encapsulates all types of packets
+handled by friend connection module.
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Packet {
+    /// [`Alive`](./struct.Alive.html) structure.
+    Alive(Alive),
+    /// [`ShareRelays`](./struct.ShareRelays.html) structure.
+    ShareRelays(ShareRelays),
+}
+
+impl FromBytes for Packet {
+    named!(from_bytes<Packet>, alt!(
+        map!(Alive::from_bytes, Packet::Alive) |
+        map!(ShareRelays::from_bytes, Packet::ShareRelays)
+    ));
+}
+
+impl ToBytes for Packet {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        match *self {
+            Packet::Alive(ref p) => p.to_bytes(buf),
+            Packet::ShareRelays(ref p) => p.to_bytes(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use toxcore::crypto_core::*;
+    use toxcore::dht::packed_node::*;
+
+    encode_decode_test!(
+        packet_alive_encode_decode,
+        Packet::Alive(Alive)
+    );
+
+    encode_decode_test!(
+        packet_share_relays_encode_decode,
+        Packet::ShareRelays(ShareRelays {
+            relays: vec![PackedNode::new(false, "1.2.3.4:12345".parse().unwrap(), &gen_keypair().0)]
+        })
+    );
+}
@@ -0,0 +1,93 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! ShareRelays struct
+*/
+
+use toxcore::binary_io::*;
+use toxcore::dht::packed_node::*;
+
+/// Maximum number of TCP relays that can be sent in one `ShareRelays` packet.
+pub const MAX_SHARED_RELAYS: usize = 3;
+
+/** ShareRelays packet is used to share TCP relays we are connected to with a
+friend. Friend can connect to these relays so that we can reach each other
+through them when direct UDP connection is not possible.
+
+Serialized form:
+
+Length      | Content
+----------- | ------
+`1`         | `0x11`
+`[0, 153]`  | Nodes in packed format
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ShareRelays {
+    /// TCP relays we are connected to
+    pub relays: Vec<PackedNode>,
+}
+
+impl FromBytes for ShareRelays {
+    named!(from_bytes<ShareRelays>, do_parse!(
+        tag!("\x11") >>
+        relays: many0!(PackedNode::from_bytes) >>
+        cond_reduce!(relays.len() <= MAX_SHARED_RELAYS, eof!()) >>
+        (ShareRelays { relays })
+    ));
+}
+
+impl ToBytes for ShareRelays {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x11) >>
+            gen_cond!(self.relays.len() > MAX_SHARED_RELAYS, |buf| gen_error(buf, 0)) >>
+            gen_many_ref!(&self.relays, |buf, relay| PackedNode::to_bytes(relay, buf))
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use toxcore::crypto_core::*;
+
+    encode_decode_test!(
+        share_relays_encode_decode,
+        ShareRelays {
+            relays: vec![
+                PackedNode::new(false, "1.2.3.4:12345".parse().unwrap(), &gen_keypair().0),
+                PackedNode::new(false, "[1234:5678:abcd:ef::1]:12346".parse().unwrap(), &gen_keypair().0),
+            ]
+        }
+    );
+
+    #[test]
+    fn share_relays_too_many_relays() {
+        let relays = ShareRelays {
+            relays: (0 .. MAX_SHARED_RELAYS as u16 + 1)
+                .map(|i| PackedNode::new(false, format!("1.2.3.4:{}", 12345 + i).parse().unwrap(), &gen_keypair().0))
+                .collect()
+        };
+        let mut buf = [0; 1024];
+        assert!(relays.to_bytes((&mut buf, 0)).is_err());
+    }
+}
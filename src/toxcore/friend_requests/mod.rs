@@ -0,0 +1,447 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! The implementation of friend requests module.
+
+Friend request is sent to a person we want to add as a friend. It contains
+`NoSpam` from his `ToxId` and a message. Requests are sent through onion
+nodes the receiver is announced on, so the receiver is added to onion client
+in order to find these nodes. Requests are resent periodically until
+they are cancelled, e.g. when the friend goes online.
+
+Received friend requests are accepted only if their `NoSpam` matches our own
+`NoSpam` from `NospamKeys`. Accepted requests are sent to the sink.
+
+*/
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream, future, stream};
+use futures::sync::mpsc;
+use parking_lot::RwLock;
+use tokio::timer::Interval;
+
+use toxcore::crypto_core::*;
+use toxcore::io_tokio::*;
+use toxcore::onion::client::OnionClient;
+use toxcore::onion::packet::{FriendRequest, MAX_FRIEND_REQUEST_MESSAGE_LENGTH};
+use toxcore::time::*;
+use toxcore::toxid::{NoSpam, ToxId};
+
+/// How often we should resend friend request in seconds.
+pub const FRIEND_REQUEST_RESEND_INTERVAL: u64 = 5;
+
+/// Number of the last accepted friend requests senders that are stored to
+/// ignore duplicated requests.
+pub const MAX_RECEIVED_STORED: usize = 32;
+
+/// How often `main_loop` is called by `run` in seconds.
+pub const MAIN_LOOP_INTERVAL: u64 = 1;
+
+/// Shorthand for the transmit half of the message channel for sending
+/// accepted friend requests. The key is a long term `PublicKey` of the sender,
+/// the value is a message of the request.
+type AcceptedRequestTx = mpsc::UnboundedSender<(PublicKey, String)>;
+
+/// Shorthand for the receive half of the message channel for receiving
+/// friend requests from onion client.
+type FriendRequestRx = mpsc::UnboundedReceiver<(PublicKey, FriendRequest)>;
+
+/// Friend request that is being sent.
+#[derive(Clone, Debug)]
+struct SentRequest {
+    /// Friend request packet
+    request: FriendRequest,
+    /// Time when the request was sent last time
+    sent_time: Option<Instant>,
+}
+
+/// Friend requests module that sends our friend requests and accepts
+/// received ones.
+#[derive(Clone)]
+pub struct FriendRequests {
+    /// Sink to send accepted friend requests
+    accepted_request_tx: AcceptedRequestTx,
+    /// Onion client used to send friend requests
+    onion_client: OnionClient,
+    /// Our `NoSpam` that received requests should contain
+    nospam: Arc<RwLock<NoSpam>>,
+    /// Long term `PublicKey`s of the last accepted requests senders, the most
+    /// recent first
+    received: Arc<RwLock<Vec<PublicKey>>>,
+    /// Friend requests we are sending by long term `PublicKey` of receiver
+    sent: Arc<RwLock<HashMap<PublicKey, SentRequest>>>,
+}
+
+impl FriendRequests {
+    /// Create new `FriendRequests` object.
+    pub fn new(onion_client: OnionClient, nospam: NoSpam, accepted_request_tx: AcceptedRequestTx) -> FriendRequests {
+        FriendRequests {
+            accepted_request_tx,
+            onion_client,
+            nospam: Arc::new(RwLock::new(nospam)),
+            received: Arc::new(RwLock::new(Vec::new())),
+            sent: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Set our `NoSpam`. Requests with the old `NoSpam` will be rejected.
+    pub fn set_nospam(&self, nospam: NoSpam) {
+        *self.nospam.write() = nospam;
+    }
+
+    /// Start sending friend request to the owner of `ToxId`. The owner is added
+    /// to onion client to find him. The request is resent until it's cancelled.
+    pub fn send_friend_request(&self, tox_id: &ToxId, message: String) -> Result<(), Error> {
+        if message.is_empty() {
+            return Err(Error::new(ErrorKind::Other, "Friend request message is empty"));
+        }
+        if message.len() > MAX_FRIEND_REQUEST_MESSAGE_LENGTH {
+            return Err(Error::new(ErrorKind::Other, "Friend request message is too long"));
+        }
+        let request = FriendRequest {
+            nospam: tox_id.nospam(),
+            message,
+        };
+        self.sent.write().insert(tox_id.pk, SentRequest {
+            request,
+            sent_time: None,
+        });
+        self.onion_client.add_friend(tox_id.pk);
+        Ok(())
+    }
+
+    /// Stop sending friend request to the friend.
+    pub fn cancel_friend_request(&self, real_pk: &PublicKey) {
+        self.sent.write().remove(real_pk);
+    }
+
+    /// Handle friend request received from onion client. If `NoSpam` of the
+    /// request matches our `NoSpam` the request is sent to the sink.
+    /// Duplicated requests from the same sender are ignored.
+    pub fn handle_friend_request(&self, real_pk: PublicKey, request: FriendRequest) -> IoFuture<()> {
+        if request.nospam != *self.nospam.read() {
+            return Box::new(future::err(
+                Error::new(ErrorKind::Other, "Friend request NoSpam doesn't match")
+            ));
+        }
+
+        let mut received = self.received.write();
+        if received.contains(&real_pk) {
+            return Box::new(future::ok(()));
+        }
+        received.insert(0, real_pk);
+        received.truncate(MAX_RECEIVED_STORED);
+
+        send_to(&self.accepted_request_tx, (real_pk, request.message))
+    }
+
+    /// Main loop of friend requests, it should be called every
+    /// `MAIN_LOOP_INTERVAL` seconds.
+    pub fn main_loop(&self) -> IoFuture<()> {
+        let resend_interval = Duration::from_secs(FRIEND_REQUEST_RESEND_INTERVAL);
+
+        let futures = self.sent.write().iter_mut()
+            .filter(|&(_, ref sent)| sent.sent_time.map(|time| clock_elapsed(time) >= resend_interval).unwrap_or(true))
+            .map(|(&real_pk, sent)| {
+                sent.sent_time = Some(clock_now());
+                self.onion_client.send_friend_request(real_pk, sent.request.clone())
+            })
+            .collect::<Vec<_>>();
+
+        // friend may be not found yet so we ignore errors
+        let futures_stream = stream::futures_unordered(futures).then(|_| Ok(()));
+        Box::new(futures_stream.for_each(|()| Ok(())))
+    }
+
+    /// Run friend requests handling requests received by onion client and
+    /// calling `main_loop` periodically.
+    pub fn run(&self, friend_request_rx: FriendRequestRx) -> IoFuture<()> {
+        let friend_requests = self.clone();
+        let friend_request_future = friend_request_rx
+            .map_err(|()| Error::new(ErrorKind::Other, "rx error"))
+            .for_each(move |(real_pk, request)|
+                friend_requests.handle_friend_request(real_pk, request).or_else(|e| {
+                    debug!("Failed to handle friend request: {}", e);
+                    Ok(())
+                })
+            );
+
+        let friend_requests = self.clone();
+        let wakeups = Interval::new(clock_now(), Duration::from_secs(MAIN_LOOP_INTERVAL));
+        let main_loop_future = wakeups
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Friend requests timer error: {:?}", e)))
+            .for_each(move |_instant| friend_requests.main_loop());
+
+        Box::new(friend_request_future
+            .join(main_loop_future)
+            .map(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    use toxcore::dht::kbucket::*;
+    use toxcore::dht::packed_node::PackedNode;
+    use toxcore::dht::packet::DhtPacket;
+    use toxcore::onion::packet::*;
+
+    type AcceptedRequestRx = mpsc::UnboundedReceiver<(PublicKey, String)>;
+    type UdpRx = mpsc::UnboundedReceiver<(DhtPacket, SocketAddr)>;
+
+    fn create_friend_requests() -> (FriendRequests, AcceptedRequestRx) {
+        let (friend_requests, accepted_request_rx, _udp_rx, _nodes) = create_friend_requests_with_nodes(0);
+        (friend_requests, accepted_request_rx)
+    }
+
+    /// Create `FriendRequests` with onion client that has DHT close nodes.
+    /// Secret keys of the nodes are returned to decrypt onion requests.
+    fn create_friend_requests_with_nodes(nodes_count: u16) -> (FriendRequests, AcceptedRequestRx, UdpRx, Vec<(PackedNode, SecretKey)>) {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (dht_pk, _dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let mut kbucket = Kbucket::new(&dht_pk);
+        let nodes = (0 .. nodes_count).map(|i| {
+            let (pk, sk) = gen_keypair();
+            let node = PackedNode::new(true, SocketAddr::new("127.0.0.1".parse().unwrap(), 33445 + i), &pk);
+            assert!(kbucket.try_add(&node));
+            (node, sk)
+        }).collect();
+        let close_nodes = Arc::new(RwLock::new(kbucket));
+        let onion_client = OnionClient::new(udp_tx, dht_pk_tx, dht_pk, real_pk, real_sk, close_nodes);
+
+        let (accepted_request_tx, accepted_request_rx) = mpsc::unbounded();
+        let friend_requests = FriendRequests::new(onion_client, NoSpam([42; 4]), accepted_request_tx);
+        (friend_requests, accepted_request_rx, udp_rx, nodes)
+    }
+
+    /// Decrypt `OnionRequest0` sent to `saddr` the same way as it would be done
+    /// by nodes of onion path.
+    fn unpack_onion_request(nodes: &[(PackedNode, SecretKey)], request_0: OnionRequest0, saddr: SocketAddr) -> OnionRequest2Payload {
+        let find_sk = |saddr: SocketAddr| nodes.iter().find(|&&(ref node, _)| node.saddr == saddr).unwrap().1.clone();
+        let payload_0 = request_0.get_payload(&precompute(&request_0.temporary_pk, &find_sk(saddr))).unwrap();
+        let request_1 = OnionRequest1 {
+            nonce: request_0.nonce,
+            temporary_pk: payload_0.temporary_pk,
+            payload: payload_0.inner,
+            onion_return: OnionReturn {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; ONION_RETURN_1_SIZE - secretbox::NONCEBYTES]
+            }
+        };
+        let payload_1 = request_1.get_payload(&precompute(&request_1.temporary_pk, &find_sk(payload_0.ip_port.to_saddr()))).unwrap();
+        let request_2 = OnionRequest2 {
+            nonce: request_1.nonce,
+            temporary_pk: payload_1.temporary_pk,
+            payload: payload_1.inner,
+            onion_return: OnionReturn {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; ONION_RETURN_2_SIZE - secretbox::NONCEBYTES]
+            }
+        };
+        request_2.get_payload(&precompute(&request_2.temporary_pk, &find_sk(payload_1.ip_port.to_saddr()))).unwrap()
+    }
+
+    #[test]
+    fn send_friend_request() {
+        let (friend_requests, _accepted_request_rx) = create_friend_requests();
+        let tox_id = ToxId::new(gen_keypair().0);
+
+        friend_requests.send_friend_request(&tox_id, "Hello".to_string()).unwrap();
+
+        {
+            let sent = friend_requests.sent.read();
+            let request = &sent[&tox_id.pk].request;
+            assert_eq!(request.nospam, tox_id.nospam());
+            assert_eq!(request.message, "Hello");
+        }
+
+        friend_requests.cancel_friend_request(&tox_id.pk);
+        assert!(friend_requests.sent.read().is_empty());
+    }
+
+    #[test]
+    fn send_friend_request_to_found_friend() {
+        let (friend_requests, _accepted_request_rx, udp_rx, nodes) = create_friend_requests_with_nodes(3);
+        let (friend_pk, friend_sk) = gen_keypair();
+        let (friend_data_pk, friend_data_sk) = gen_keypair();
+        let tox_id = ToxId::new(friend_pk);
+
+        friend_requests.send_friend_request(&tox_id, "Hello".to_string()).unwrap();
+
+        // onion client searches for the friend
+        friend_requests.onion_client.main_loop().wait().unwrap();
+
+        let mut udp_rx = udp_rx;
+        let mut search_request = None;
+        while search_request.is_none() {
+            let (received, rx) = udp_rx.into_future().wait().unwrap();
+            udp_rx = rx;
+            let (packet, addr) = received.unwrap();
+            let request_0 = unpack!(packet, DhtPacket::OnionRequest0);
+            let payload = unpack_onion_request(&nodes, request_0, addr);
+            let node_saddr = payload.ip_port.to_saddr();
+            let request = unpack!(payload.inner, InnerOnionRequest::InnerOnionAnnounceRequest);
+            let node_sk = nodes.iter().find(|&&(ref node, _)| node.saddr == node_saddr).unwrap().1.clone();
+            let shared_secret = precompute(&request.pk, &node_sk);
+            let request_payload = request.get_payload(&shared_secret).unwrap();
+            if request_payload.search_pk == friend_pk {
+                search_request = Some((shared_secret, request_payload.sendback_data));
+            }
+        }
+
+        // the node responds that the friend is announced on it
+        let (shared_secret, sendback_data) = search_request.unwrap();
+        let response = OnionAnnounceResponse::new(&shared_secret, sendback_data, OnionAnnounceResponsePayload {
+            announce_status: AnnounceStatus::Found,
+            ping_id_or_pk: pk_as_digest(friend_data_pk),
+            nodes: Vec::new()
+        });
+        friend_requests.onion_client.handle_announce_response(response).wait().unwrap();
+
+        friend_requests.main_loop().wait().unwrap();
+        drop(friend_requests);
+
+        let friend_request = udp_rx.filter_map(|(packet, addr)| {
+            let request_0 = unpack!(packet, DhtPacket::OnionRequest0);
+            let payload = unpack_onion_request(&nodes, request_0, addr);
+            match payload.inner {
+                InnerOnionRequest::InnerOnionDataRequest(request) => Some(request),
+                _ => None,
+            }
+        }).collect().wait().unwrap();
+        assert_eq!(friend_request.len(), 1);
+        let request = &friend_request[0];
+        assert_eq!(request.destination_pk, friend_pk);
+
+        // friend decrypts data request as OnionDataResponse
+        let response = OnionDataResponse {
+            nonce: request.nonce,
+            temporary_pk: request.temporary_pk,
+            payload: request.payload.clone(),
+        };
+        let payload = response.get_payload(&precompute(&response.temporary_pk, &friend_data_sk)).unwrap();
+        let inner_payload = payload.get_payload(&response.nonce, &precompute(&payload.real_pk, &friend_sk)).unwrap();
+        let friend_request = unpack!(inner_payload, OnionDataResponseInnerPayload::FriendRequest);
+        assert_eq!(friend_request.nospam, tox_id.nospam());
+        assert_eq!(friend_request.message, "Hello");
+    }
+
+    #[test]
+    fn send_friend_request_invalid_message() {
+        let (friend_requests, _accepted_request_rx) = create_friend_requests();
+        let tox_id = ToxId::new(gen_keypair().0);
+
+        assert!(friend_requests.send_friend_request(&tox_id, String::new()).is_err());
+        assert!(friend_requests.send_friend_request(&tox_id, "x".repeat(MAX_FRIEND_REQUEST_MESSAGE_LENGTH + 1)).is_err());
+        assert!(friend_requests.sent.read().is_empty());
+    }
+
+    #[test]
+    fn handle_friend_request() {
+        let (friend_requests, accepted_request_rx) = create_friend_requests();
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let request = FriendRequest {
+            nospam: NoSpam([42; 4]),
+            message: "Hello".to_string()
+        };
+
+        friend_requests.handle_friend_request(friend_pk, request.clone()).wait().unwrap();
+        // duplicated request is ignored
+        friend_requests.handle_friend_request(friend_pk, request).wait().unwrap();
+
+        drop(friend_requests);
+        let accepted = accepted_request_rx.collect().wait().unwrap();
+        assert_eq!(accepted, vec![(friend_pk, "Hello".to_string())]);
+    }
+
+    #[test]
+    fn handle_friend_request_invalid_nospam() {
+        let (friend_requests, accepted_request_rx) = create_friend_requests();
+        let request = FriendRequest {
+            nospam: NoSpam([43; 4]),
+            message: "Hello".to_string()
+        };
+
+        assert!(friend_requests.handle_friend_request(gen_keypair().0, request.clone()).wait().is_err());
+
+        // the old NoSpam is rejected after it's changed
+        friend_requests.set_nospam(NoSpam([44; 4]));
+        let request = FriendRequest {
+            nospam: NoSpam([42; 4]),
+            ..request
+        };
+        assert!(friend_requests.handle_friend_request(gen_keypair().0, request).wait().is_err());
+
+        drop(friend_requests);
+        assert!(accepted_request_rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn handle_friend_request_stores_limited_senders() {
+        let (friend_requests, _accepted_request_rx) = create_friend_requests();
+        let request = FriendRequest {
+            nospam: NoSpam([42; 4]),
+            message: "Hello".to_string()
+        };
+
+        let first_pk = gen_keypair().0;
+        friend_requests.handle_friend_request(first_pk, request.clone()).wait().unwrap();
+        for _ in 0 .. MAX_RECEIVED_STORED {
+            friend_requests.handle_friend_request(gen_keypair().0, request.clone()).wait().unwrap();
+        }
+
+        let received = friend_requests.received.read();
+        assert_eq!(received.len(), MAX_RECEIVED_STORED);
+        assert!(!received.contains(&first_pk));
+    }
+
+    #[test]
+    fn main_loop_resends_requests() {
+        let (friend_requests, _accepted_request_rx) = create_friend_requests();
+        let tox_id = ToxId::new(gen_keypair().0);
+
+        friend_requests.send_friend_request(&tox_id, "Hello".to_string()).unwrap();
+
+        // friend is not found yet so sending fails but it shouldn't affect
+        // main loop
+        friend_requests.main_loop().wait().unwrap();
+
+        let sent_time = friend_requests.sent.read()[&tox_id.pk].sent_time.unwrap();
+
+        // the request shouldn't be sent again until resend interval passes
+        friend_requests.main_loop().wait().unwrap();
+        assert_eq!(friend_requests.sent.read()[&tox_id.pk].sent_time, Some(sent_time));
+
+        friend_requests.sent.write().get_mut(&tox_id.pk).unwrap().sent_time =
+            Some(clock_now() - Duration::from_secs(FRIEND_REQUEST_RESEND_INTERVAL + 1));
+        friend_requests.main_loop().wait().unwrap();
+        let sent_time = friend_requests.sent.read()[&tox_id.pk].sent_time.unwrap();
+        assert!(clock_elapsed(sent_time) < Duration::from_secs(FRIEND_REQUEST_RESEND_INTERVAL));
+    }
+}
@@ -0,0 +1,986 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! File transfers between friends.
+
+File transfer starts with `FileSendRequest` packet. When receiver accepts the
+file with `FileControl` packet sender starts to send `FileData` packets. Both
+sides can pause, resume or cancel the transfer with `FileControl` packets.
+Receiver can resume previously broken transfer of the file with the same
+`FileId` by sending seek `FileControl` packet before accepting the file.
+
+Data is sent only when there is enough free space in net crypto sent packets
+buffer so that file transfers don't overflow it and leave some space for
+other lossless packets like messages.
+*/
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+use futures::{Async, Future, Poll, Stream, future};
+use futures::sync::mpsc;
+use tokio::io::AsyncRead;
+
+use toxcore::crypto_core::*;
+use toxcore::io_tokio::*;
+use toxcore::messenger::{Friend, Messenger, MessengerEvent};
+use toxcore::messenger::packet::*;
+use toxcore::net_crypto::CRYPTO_MIN_QUEUE_LENGTH;
+
+/// Number of free slots in net crypto sent packets buffer that file transfers
+/// leave for other lossless packets.
+pub const MIN_SLOTS_FREE: u32 = CRYPTO_MIN_QUEUE_LENGTH / 4;
+
+/// File size that means that the size of the file is unknown.
+pub const UNKNOWN_FILE_SIZE: u64 = u64::max_value();
+
+/// Stream of received file chunks. It ends when the whole file is received and
+/// fails when the transfer is cancelled.
+pub type FileDataStream = Box<Stream<Item=Vec<u8>, Error=Error> + Send>;
+
+/// Shorthand for the transmit half of the message channel for sending
+/// received file chunks.
+type FileDataTx = mpsc::UnboundedSender<Result<Vec<u8>, Error>>;
+
+/// Status of a file transfer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum FileStatus {
+    /// File is not accepted by receiver yet
+    NotAccepted,
+    /// File is accepted and its data is being transferred
+    Transferring,
+    /// All data is sent and we are waiting until it's delivered
+    Finished,
+}
+
+/// File we are sending to a friend.
+pub(super) struct SendingFile {
+    /// Size of the file in bytes
+    file_size: u64,
+    /// Status of the transfer
+    status: FileStatus,
+    /// Whether the transfer is paused by us
+    paused_by_us: bool,
+    /// Whether the transfer is paused by the friend
+    paused_by_friend: bool,
+    /// Number of bytes sent to the friend including skipped ones
+    transferred: u64,
+    /// Number of bytes that should be skipped in the reader because of seek
+    skip: u64,
+    /// Source of the file data
+    reader: Box<AsyncRead + Send + Sync>,
+    /// Data read from the reader that is not sent yet. It's kept until it's
+    /// sent successfully
+    buffer: Vec<u8>,
+    /// Whether the reader reached its end
+    eof: bool,
+    /// Number of the last sent lossless packet when all data is sent
+    last_packet_number: Option<u32>,
+}
+
+impl SendingFile {
+    /// Create new `SendingFile`.
+    fn new(file_size: u64, reader: Box<AsyncRead + Send + Sync>) -> SendingFile {
+        SendingFile {
+            file_size,
+            status: FileStatus::NotAccepted,
+            paused_by_us: false,
+            paused_by_friend: false,
+            transferred: 0,
+            skip: 0,
+            reader,
+            buffer: Vec::new(),
+            eof: false,
+            last_packet_number: None,
+        }
+    }
+
+    /// Check if the next chunk can be sent to the friend.
+    fn is_active(&self) -> bool {
+        self.status == FileStatus::Transferring && !self.paused_by_us && !self.paused_by_friend
+    }
+
+    /// Read the next chunk of data from the reader. All chunks except the
+    /// last one have `MAX_FILE_DATA_SIZE` length. The last chunk is marked as
+    /// `true`. Returns `NotReady` if the reader doesn't have enough data yet.
+    /// The same chunk is returned until `consume_chunk` is called.
+    fn poll_chunk(&mut self) -> Poll<(Vec<u8>, bool), Error> {
+        let mut buf = [0; MAX_FILE_DATA_SIZE];
+
+        while self.skip > 0 {
+            let len = cmp::min(self.skip, MAX_FILE_DATA_SIZE as u64) as usize;
+            match self.reader.poll_read(&mut buf[..len])? {
+                Async::Ready(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "File is shorter than seek position")),
+                Async::Ready(read) => self.skip -= read as u64,
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+
+        let remaining = self.file_size - self.transferred;
+        let chunk_size = cmp::min(remaining, MAX_FILE_DATA_SIZE as u64) as usize;
+        while !self.eof && self.buffer.len() < chunk_size {
+            let len = chunk_size - self.buffer.len();
+            match self.reader.poll_read(&mut buf[..len])? {
+                Async::Ready(0) => self.eof = true,
+                Async::Ready(read) => self.buffer.extend_from_slice(&buf[..read]),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+
+        let chunk = self.buffer.clone();
+        // receiver detects the end of file by short chunk
+        let last = self.eof || self.transferred + chunk.len() as u64 == self.file_size || chunk.len() < MAX_FILE_DATA_SIZE;
+        Ok(Async::Ready((chunk, last)))
+    }
+
+    /// Mark the chunk returned by `poll_chunk` as sent.
+    fn consume_chunk(&mut self) {
+        self.transferred += self.buffer.len() as u64;
+        self.buffer.clear();
+    }
+}
+
+/// File we are receiving from a friend.
+pub(super) struct ReceivingFile {
+    /// Size of the file in bytes
+    file_size: u64,
+    /// Status of the transfer
+    status: FileStatus,
+    /// Whether the transfer is paused by us
+    paused_by_us: bool,
+    /// Whether the transfer is paused by the friend
+    paused_by_friend: bool,
+    /// Number of bytes received from the friend including skipped ones
+    transferred: u64,
+    /// Sink to send received data when the file is accepted
+    data_tx: Option<FileDataTx>,
+}
+
+impl ReceivingFile {
+    /// Create new `ReceivingFile`.
+    fn new(file_size: u64) -> ReceivingFile {
+        ReceivingFile {
+            file_size,
+            status: FileStatus::NotAccepted,
+            paused_by_us: false,
+            paused_by_friend: false,
+            transferred: 0,
+            data_tx: None,
+        }
+    }
+
+    /// Fail the data stream if it exists.
+    fn kill(self) {
+        if let Some(data_tx) = self.data_tx {
+            // receiver of the stream can be dropped already
+            data_tx.unbounded_send(Err(Error::new(ErrorKind::Other, "File transfer is killed"))).ok();
+        }
+    }
+}
+
+/// Files that are being transferred between us and a friend by their
+/// numbers.
+#[derive(Default)]
+pub(super) struct FileTransfers {
+    /// Files we are sending to the friend
+    sending: HashMap<u8, SendingFile>,
+    /// Files we are receiving from the friend
+    receiving: HashMap<u8, ReceivingFile>,
+}
+
+impl FileTransfers {
+    /// Cancel all file transfers. It's used when the friend goes offline.
+    pub(super) fn kill_all(&mut self) {
+        self.sending.clear();
+        for (_, file) in self.receiving.drain() {
+            file.kill();
+        }
+    }
+}
+
+/// Get friend if he is online.
+fn online_friend<'a>(friends: &'a mut HashMap<PublicKey, Friend>, real_pk: &PublicKey) -> Result<&'a mut Friend, Error> {
+    match friends.get_mut(real_pk) {
+        Some(ref friend) if !friend.online => Err(Error::new(ErrorKind::Other, "Friend is not online")),
+        Some(friend) => Ok(friend),
+        None => Err(Error::new(ErrorKind::Other, "No such friend")),
+    }
+}
+
+impl Messenger {
+    /// Offer a file to the friend. Data of the file is read from `reader` when
+    /// the friend accepts the file. `file_size` can be `UNKNOWN_FILE_SIZE` for
+    /// streams. Returns number of the file that is used to control the
+    /// transfer.
+    pub fn send_file<R>(&self,
+        real_pk: PublicKey,
+        file_type: u32,
+        file_size: u64,
+        file_id: FileId,
+        file_name: String,
+        reader: R
+    ) -> IoFuture<u8> where R: AsyncRead + Send + Sync + 'static {
+        if file_name.len() > MAX_FILENAME_LENGTH {
+            return Box::new(future::err(
+                Error::new(ErrorKind::Other, "File name is too long")
+            ));
+        }
+
+        let file_number = {
+            let mut friends = self.friends.write();
+            let friend = match online_friend(&mut friends, &real_pk) {
+                Ok(friend) => friend,
+                Err(e) => return Box::new(future::err(e)),
+            };
+            let file_number = match (0 .. 256u16).map(|n| n as u8).find(|n| !friend.files.sending.contains_key(n)) {
+                Some(file_number) => file_number,
+                None => return Box::new(future::err(
+                    Error::new(ErrorKind::Other, "Too many files are being sent")
+                )),
+            };
+            friend.files.sending.insert(file_number, SendingFile::new(file_size, Box::new(reader)));
+            file_number
+        };
+
+        let packet = Packet::FileSendRequest(FileSendRequest {
+            file_number,
+            file_type,
+            file_size,
+            file_id,
+            file_name,
+        });
+        let friends = self.friends.clone();
+        Box::new(self.send_packet(real_pk, packet).then(move |result| match result {
+            Ok(_) => Ok(file_number),
+            Err(e) => {
+                if let Some(friend) = friends.write().get_mut(&real_pk) {
+                    friend.files.sending.remove(&file_number);
+                }
+                Err(e)
+            },
+        }))
+    }
+
+    /// Accept the file offered by the friend. Returns stream of the file data.
+    pub fn accept_file(&self, real_pk: PublicKey, file_number: u8) -> IoFuture<FileDataStream> {
+        let mut friends = self.friends.write();
+        let friend = match online_friend(&mut friends, &real_pk) {
+            Ok(friend) => friend,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let file = match friend.files.receiving.get_mut(&file_number) {
+            Some(ref file) if file.status != FileStatus::NotAccepted => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "File is already accepted")
+            )),
+            Some(file) => file,
+            None => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "No such file")
+            )),
+        };
+
+        let (data_tx, data_rx) = mpsc::unbounded();
+        file.status = FileStatus::Transferring;
+        file.data_tx = Some(data_tx);
+
+        let stream: FileDataStream = Box::new(data_rx
+            .map_err(|()| Error::new(ErrorKind::Other, "rx error"))
+            .and_then(future::result));
+        let packet = Packet::FileControl(FileControl {
+            transfer_direction: TransferDirection::Receive,
+            file_number,
+            control_type: ControlType::Accept,
+        });
+        Box::new(self.send_packet(real_pk, packet).map(move |_| stream))
+    }
+
+    /** Control the file transfer. `transfer_direction` is relative to us.
+
+    - `Accept` resumes the transfer paused by us. To accept offered file
+      `accept_file` should be used.
+    - `Pause` pauses the transfer.
+    - `Kill` cancels the transfer.
+    - `Seek` sets position from which the friend should start sending the file.
+      It can be used only for the received file before it's accepted.
+    */
+    pub fn control_file(&self,
+        real_pk: PublicKey,
+        file_number: u8,
+        transfer_direction: TransferDirection,
+        control_type: ControlType
+    ) -> IoFuture<()> {
+        let mut friends = self.friends.write();
+        let friend = match online_friend(&mut friends, &real_pk) {
+            Ok(friend) => friend,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        let result = match transfer_direction {
+            TransferDirection::Send => Self::control_sending_file(&mut friend.files, file_number, control_type),
+            TransferDirection::Receive => Self::control_receiving_file(&mut friend.files, file_number, control_type),
+        };
+        if let Err(e) = result {
+            return Box::new(future::err(e));
+        }
+
+        let packet = Packet::FileControl(FileControl {
+            transfer_direction,
+            file_number,
+            control_type,
+        });
+        Box::new(self.send_packet(real_pk, packet).map(|_| ()))
+    }
+
+    /// Apply control command sent by us to the file we are sending.
+    fn control_sending_file(files: &mut FileTransfers, file_number: u8, control_type: ControlType) -> Result<(), Error> {
+        let error = match files.sending.get_mut(&file_number) {
+            None => "No such file",
+            Some(file) => match control_type {
+                ControlType::Accept if file.paused_by_us => {
+                    file.paused_by_us = false;
+                    return Ok(());
+                },
+                ControlType::Accept => "File is not paused",
+                ControlType::Pause if file.status == FileStatus::Transferring && !file.paused_by_us => {
+                    file.paused_by_us = true;
+                    return Ok(());
+                },
+                ControlType::Pause => "File can't be paused",
+                ControlType::Kill => {
+                    files.sending.remove(&file_number);
+                    return Ok(());
+                },
+                ControlType::Seek(_) => "Only receiver can seek",
+            },
+        };
+        Err(Error::new(ErrorKind::Other, error))
+    }
+
+    /// Apply control command sent by us to the file we are receiving.
+    fn control_receiving_file(files: &mut FileTransfers, file_number: u8, control_type: ControlType) -> Result<(), Error> {
+        let error = match files.receiving.get_mut(&file_number) {
+            None => "No such file",
+            Some(file) => match control_type {
+                ControlType::Accept if file.paused_by_us => {
+                    file.paused_by_us = false;
+                    return Ok(());
+                },
+                ControlType::Accept => "File is not paused",
+                ControlType::Pause if file.status == FileStatus::Transferring && !file.paused_by_us => {
+                    file.paused_by_us = true;
+                    return Ok(());
+                },
+                ControlType::Pause => "File can't be paused",
+                ControlType::Kill => {
+                    if let Some(file) = files.receiving.remove(&file_number) {
+                        file.kill();
+                    }
+                    return Ok(());
+                },
+                ControlType::Seek(position) if file.status == FileStatus::NotAccepted && position < file.file_size => {
+                    file.transferred = position;
+                    return Ok(());
+                },
+                ControlType::Seek(_) => "File can't be seeked",
+            },
+        };
+        Err(Error::new(ErrorKind::Other, error))
+    }
+
+    /// Handle `FileSendRequest` packet sending the request as event.
+    pub(super) fn handle_file_send_request(&self, real_pk: PublicKey, friend: &mut Friend, packet: FileSendRequest) -> IoFuture<()> {
+        if friend.files.receiving.contains_key(&packet.file_number) {
+            return Box::new(future::err(
+                Error::new(ErrorKind::Other, "File with the same number is already being received")
+            ));
+        }
+        friend.files.receiving.insert(packet.file_number, ReceivingFile::new(packet.file_size));
+        send_to(&self.event_tx, (real_pk, MessengerEvent::FileSendRequest(packet)))
+    }
+
+    /// Handle `FileControl` packet sent by the friend. The event is sent if
+    /// the command is applied.
+    pub(super) fn handle_file_control(&self, real_pk: PublicKey, friend: &mut Friend, packet: FileControl) -> IoFuture<()> {
+        // direction of the transfer relative to us
+        let transfer_direction = packet.transfer_direction.toggle();
+        let result = match transfer_direction {
+            TransferDirection::Send => Self::handle_sending_file_control(&mut friend.files, packet.file_number, packet.control_type),
+            TransferDirection::Receive => Self::handle_receiving_file_control(&mut friend.files, packet.file_number, packet.control_type),
+        };
+        if let Err(e) = result {
+            return Box::new(future::err(e));
+        }
+
+        send_to(&self.event_tx, (real_pk, MessengerEvent::FileControl {
+            file_number: packet.file_number,
+            transfer_direction,
+            control_type: packet.control_type,
+        }))
+    }
+
+    /// Apply control command sent by the friend to the file we are sending.
+    fn handle_sending_file_control(files: &mut FileTransfers, file_number: u8, control_type: ControlType) -> Result<(), Error> {
+        let error = match files.sending.get_mut(&file_number) {
+            None => "No such file",
+            Some(file) => match control_type {
+                ControlType::Accept if file.status == FileStatus::NotAccepted => {
+                    file.status = FileStatus::Transferring;
+                    return Ok(());
+                },
+                ControlType::Accept if file.paused_by_friend => {
+                    file.paused_by_friend = false;
+                    return Ok(());
+                },
+                ControlType::Accept => "File is already accepted",
+                ControlType::Pause if file.status == FileStatus::Transferring && !file.paused_by_friend => {
+                    file.paused_by_friend = true;
+                    return Ok(());
+                },
+                ControlType::Pause => "File can't be paused",
+                ControlType::Kill => {
+                    files.sending.remove(&file_number);
+                    return Ok(());
+                },
+                ControlType::Seek(position) if file.status == FileStatus::NotAccepted && position < file.file_size => {
+                    file.transferred = position;
+                    file.skip = position;
+                    return Ok(());
+                },
+                ControlType::Seek(_) => "File can't be seeked",
+            },
+        };
+        Err(Error::new(ErrorKind::Other, error))
+    }
+
+    /// Apply control command sent by the friend to the file we are receiving.
+    fn handle_receiving_file_control(files: &mut FileTransfers, file_number: u8, control_type: ControlType) -> Result<(), Error> {
+        let error = match files.receiving.get_mut(&file_number) {
+            None => "No such file",
+            Some(file) => match control_type {
+                ControlType::Accept if file.paused_by_friend => {
+                    file.paused_by_friend = false;
+                    return Ok(());
+                },
+                ControlType::Accept => "File is not paused",
+                ControlType::Pause if file.status == FileStatus::Transferring && !file.paused_by_friend => {
+                    file.paused_by_friend = true;
+                    return Ok(());
+                },
+                ControlType::Pause => "File can't be paused",
+                ControlType::Kill => {
+                    if let Some(file) = files.receiving.remove(&file_number) {
+                        file.kill();
+                    }
+                    return Ok(());
+                },
+                ControlType::Seek(_) => "Only receiver can seek",
+            },
+        };
+        Err(Error::new(ErrorKind::Other, error))
+    }
+
+    /// Handle `FileData` packet sending the data to the stream of the file.
+    /// When the last chunk is received the stream is closed.
+    pub(super) fn handle_file_data(&self, real_pk: PublicKey, friend: &mut Friend, packet: FileData) -> IoFuture<()> {
+        let finished = match friend.files.receiving.get_mut(&packet.file_number) {
+            Some(file) if file.status == FileStatus::Transferring => {
+                let is_short = packet.data.len() < MAX_FILE_DATA_SIZE;
+                let remaining = file.file_size - file.transferred;
+                let mut data = packet.data;
+                data.truncate(cmp::min(remaining, MAX_FILE_DATA_SIZE as u64) as usize);
+                file.transferred += data.len() as u64;
+                if !data.is_empty() {
+                    if let Some(ref data_tx) = file.data_tx {
+                        // receiver of the stream can be dropped already
+                        data_tx.unbounded_send(Ok(data)).ok();
+                    }
+                }
+                is_short || file.transferred == file.file_size
+            },
+            Some(_) => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "File is not accepted")
+            )),
+            None => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "No such file")
+            )),
+        };
+
+        if finished {
+            // dropping the sink closes the stream
+            friend.files.receiving.remove(&packet.file_number);
+            send_to(&self.event_tx, (real_pk, MessengerEvent::FileFinished {
+                file_number: packet.file_number,
+                transfer_direction: TransferDirection::Receive,
+            }))
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Send data of accepted files to the friend while there is free space in
+    /// net crypto sent packets buffer and report files which data is
+    /// delivered. Files are served in turn so that they share the bandwidth.
+    /// This function should be called from within a task since it polls file
+    /// readers.
+    pub(super) fn files_loop(&self, real_pk: PublicKey, friend: &mut Friend) -> Vec<IoFuture<()>> {
+        let mut futures = Vec::new();
+
+        let finished = friend.files.sending.iter()
+            .filter(|&(_, file)| file.last_packet_number.map(|number|
+                self.net_crypto.is_packet_delivered(real_pk, number)
+            ).unwrap_or(false))
+            .map(|(&file_number, _)| file_number)
+            .collect::<Vec<_>>();
+        for file_number in finished {
+            friend.files.sending.remove(&file_number);
+            futures.push(send_to(&self.event_tx, (real_pk, MessengerEvent::FileFinished {
+                file_number,
+                transfer_direction: TransferDirection::Send,
+            })));
+        }
+
+        let mut capacity = self.net_crypto.lossless_send_capacity(real_pk).saturating_sub(MIN_SLOTS_FREE);
+        let mut active = friend.files.sending.iter()
+            .filter(|&(_, file)| file.is_active())
+            .map(|(&file_number, _)| file_number)
+            .collect::<Vec<_>>();
+        active.sort();
+
+        while capacity > 0 && !active.is_empty() {
+            let mut still_active = Vec::new();
+            for file_number in active {
+                if capacity == 0 {
+                    break;
+                }
+                let file = friend.files.sending.get_mut(&file_number)
+                    .expect("Active file is in sending files");
+                let (data, last) = match file.poll_chunk() {
+                    Ok(Async::Ready(chunk)) => chunk,
+                    Ok(Async::NotReady) => continue,
+                    Err(e) => {
+                        debug!("Failed to read file {}: {}", file_number, e);
+                        friend.files.sending.remove(&file_number);
+                        futures.push(self.send_packet_ignore_number(real_pk, Packet::FileControl(FileControl {
+                            transfer_direction: TransferDirection::Send,
+                            file_number,
+                            control_type: ControlType::Kill,
+                        })));
+                        continue;
+                    },
+                };
+                capacity -= 1;
+                let mut future = self.send_packet(real_pk, Packet::FileData(FileData { file_number, data }));
+                // net crypto fails right away if the packet can't be queued,
+                // once it's queued it will be delivered
+                let packet_number = match future.poll() {
+                    Ok(Async::Ready(packet_number)) => Some(packet_number),
+                    Ok(Async::NotReady) => None,
+                    Err(e) => {
+                        // the chunk will be sent again in the next iteration
+                        debug!("Failed to send data of file {}: {}", file_number, e);
+                        continue;
+                    },
+                };
+                file.consume_chunk();
+                if last {
+                    file.status = FileStatus::Finished;
+                    if packet_number.is_some() {
+                        file.last_packet_number = packet_number;
+                    } else {
+                        let friends = self.friends.clone();
+                        futures.push(Box::new(future.map(move |packet_number| {
+                            let mut friends = friends.write();
+                            let file = friends.get_mut(&real_pk)
+                                .and_then(|friend| friend.files.sending.get_mut(&file_number));
+                            if let Some(file) = file {
+                                file.last_packet_number = Some(packet_number);
+                            }
+                        })));
+                    }
+                } else {
+                    still_active.push(file_number);
+                    if packet_number.is_none() {
+                        futures.push(Box::new(future.map(|_| ())));
+                    }
+                }
+            }
+            active = still_active;
+        }
+
+        futures
+    }
+
+    /// Send messenger packet to the friend ignoring its number.
+    fn send_packet_ignore_number(&self, real_pk: PublicKey, packet: Packet) -> IoFuture<()> {
+        Box::new(self.send_packet(real_pk, packet).map(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use toxcore::messenger::tests::*;
+
+    fn create_online_friend() -> Friend {
+        Friend {
+            online: true,
+            ..Friend::default()
+        }
+    }
+
+    #[test]
+    fn sending_file_poll_chunk() {
+        let data = vec![42; MAX_FILE_DATA_SIZE + 10];
+        let mut file = SendingFile::new(data.len() as u64, Box::new(Cursor::new(data)));
+
+        let (chunk, last) = unpack!(file.poll_chunk().unwrap(), Async::Ready);
+        assert_eq!(chunk, vec![42; MAX_FILE_DATA_SIZE]);
+        assert!(!last);
+        file.consume_chunk();
+
+        let (chunk, last) = unpack!(file.poll_chunk().unwrap(), Async::Ready);
+        assert_eq!(chunk, vec![42; 10]);
+        assert!(last);
+        file.consume_chunk();
+        assert_eq!(file.transferred, MAX_FILE_DATA_SIZE as u64 + 10);
+    }
+
+    #[test]
+    fn sending_file_poll_chunk_until_consumed() {
+        let data = (0 .. 100).collect::<Vec<u8>>();
+        let mut file = SendingFile::new(data.len() as u64, Box::new(Cursor::new(data.clone())));
+
+        // chunk that failed to be sent is returned again
+        let (chunk, last) = unpack!(file.poll_chunk().unwrap(), Async::Ready);
+        assert_eq!(chunk, data);
+        assert!(last);
+        assert_eq!(file.transferred, 0);
+
+        let (chunk, last) = unpack!(file.poll_chunk().unwrap(), Async::Ready);
+        assert_eq!(chunk, data);
+        assert!(last);
+
+        file.consume_chunk();
+        assert_eq!(file.transferred, 100);
+    }
+
+    #[test]
+    fn sending_file_poll_chunk_seek() {
+        let data = (0 .. 100).collect::<Vec<u8>>();
+        let mut file = SendingFile::new(data.len() as u64, Box::new(Cursor::new(data)));
+        file.skip = 90;
+        file.transferred = 90;
+
+        let (chunk, last) = unpack!(file.poll_chunk().unwrap(), Async::Ready);
+        assert_eq!(chunk, (90 .. 100).collect::<Vec<u8>>());
+        assert!(last);
+    }
+
+    #[test]
+    fn sending_file_poll_chunk_seek_too_far() {
+        let mut file = SendingFile::new(100, Box::new(Cursor::new(vec![42; 10])));
+        file.skip = 90;
+        file.transferred = 90;
+
+        assert!(file.poll_chunk().is_err());
+    }
+
+    #[test]
+    fn sending_file_poll_chunk_unknown_size() {
+        let data = vec![42; MAX_FILE_DATA_SIZE * 2];
+        let mut file = SendingFile::new(UNKNOWN_FILE_SIZE, Box::new(Cursor::new(data)));
+
+        let (chunk, last) = unpack!(file.poll_chunk().unwrap(), Async::Ready);
+        assert_eq!(chunk.len(), MAX_FILE_DATA_SIZE);
+        assert!(!last);
+        file.consume_chunk();
+
+        let (chunk, last) = unpack!(file.poll_chunk().unwrap(), Async::Ready);
+        assert_eq!(chunk.len(), MAX_FILE_DATA_SIZE);
+        assert!(!last);
+        file.consume_chunk();
+
+        // empty chunk tells the receiver that the file is ended
+        let (chunk, last) = unpack!(file.poll_chunk().unwrap(), Async::Ready);
+        assert!(chunk.is_empty());
+        assert!(last);
+    }
+
+    #[test]
+    fn send_file_friend_offline() {
+        let (messenger, _event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+        messenger.friends.write().insert(friend_pk, Friend::default());
+
+        let future = messenger.send_file(friend_pk, 0, 10, FileId::new(), "file".to_owned(), Cursor::new(vec![42; 10]));
+        assert!(future.wait().is_err());
+        assert!(messenger.friends.read()[&friend_pk].files.sending.is_empty());
+    }
+
+    #[test]
+    fn send_file_name_too_long() {
+        let (messenger, _event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+        messenger.friends.write().insert(friend_pk, create_online_friend());
+
+        let file_name = "a".repeat(MAX_FILENAME_LENGTH + 1);
+        let future = messenger.send_file(friend_pk, 0, 10, FileId::new(), file_name, Cursor::new(vec![42; 10]));
+        assert!(future.wait().is_err());
+    }
+
+    #[test]
+    fn handle_file_send_request() {
+        let (messenger, event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+        let mut friend = create_online_friend();
+
+        let packet = FileSendRequest {
+            file_number: 1,
+            file_type: 0,
+            file_size: 100,
+            file_id: FileId::new(),
+            file_name: "file".to_owned(),
+        };
+        messenger.handle_file_send_request(friend_pk, &mut friend, packet.clone()).wait().unwrap();
+
+        let file = &friend.files.receiving[&1];
+        assert_eq!(file.status, FileStatus::NotAccepted);
+        assert_eq!(file.file_size, 100);
+
+        let (event, _event_rx) = event_rx.into_future().wait().unwrap();
+        assert_eq!(event.unwrap(), (friend_pk, MessengerEvent::FileSendRequest(packet.clone())));
+
+        // the same file number can't be used twice
+        assert!(messenger.handle_file_send_request(friend_pk, &mut friend, packet).wait().is_err());
+    }
+
+    #[test]
+    fn handle_file_control_sending() {
+        let (messenger, event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+        let mut friend = create_online_friend();
+        friend.files.sending.insert(1, SendingFile::new(100, Box::new(Cursor::new(vec![42; 100]))));
+
+        let control = |control_type| FileControl {
+            transfer_direction: TransferDirection::Receive,
+            file_number: 1,
+            control_type,
+        };
+
+        // friend can't pause not accepted file
+        assert!(messenger.handle_file_control(friend_pk, &mut friend, control(ControlType::Pause)).wait().is_err());
+
+        messenger.handle_file_control(friend_pk, &mut friend, control(ControlType::Seek(50))).wait().unwrap();
+        assert_eq!(friend.files.sending[&1].transferred, 50);
+        assert_eq!(friend.files.sending[&1].skip, 50);
+
+        messenger.handle_file_control(friend_pk, &mut friend, control(ControlType::Accept)).wait().unwrap();
+        assert!(friend.files.sending[&1].is_active());
+
+        // seek is not allowed after the file is accepted
+        assert!(messenger.handle_file_control(friend_pk, &mut friend, control(ControlType::Seek(10))).wait().is_err());
+
+        messenger.handle_file_control(friend_pk, &mut friend, control(ControlType::Pause)).wait().unwrap();
+        assert!(friend.files.sending[&1].paused_by_friend);
+        assert!(!friend.files.sending[&1].is_active());
+
+        messenger.handle_file_control(friend_pk, &mut friend, control(ControlType::Accept)).wait().unwrap();
+        assert!(friend.files.sending[&1].is_active());
+
+        messenger.handle_file_control(friend_pk, &mut friend, control(ControlType::Kill)).wait().unwrap();
+        assert!(friend.files.sending.is_empty());
+
+        let events = event_rx.take(5).map(|(_pk, event)| event).collect().wait().unwrap();
+        assert_eq!(events, vec![
+            MessengerEvent::FileControl { file_number: 1, transfer_direction: TransferDirection::Send, control_type: ControlType::Seek(50) },
+            MessengerEvent::FileControl { file_number: 1, transfer_direction: TransferDirection::Send, control_type: ControlType::Accept },
+            MessengerEvent::FileControl { file_number: 1, transfer_direction: TransferDirection::Send, control_type: ControlType::Pause },
+            MessengerEvent::FileControl { file_number: 1, transfer_direction: TransferDirection::Send, control_type: ControlType::Accept },
+            MessengerEvent::FileControl { file_number: 1, transfer_direction: TransferDirection::Send, control_type: ControlType::Kill },
+        ]);
+    }
+
+    #[test]
+    fn handle_file_control_receiving_kill() {
+        let (messenger, _event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+        let mut friend = create_online_friend();
+        let (data_tx, data_rx) = mpsc::unbounded();
+        let mut file = ReceivingFile::new(100);
+        file.status = FileStatus::Transferring;
+        file.data_tx = Some(data_tx);
+        friend.files.receiving.insert(1, file);
+
+        // only receiver can seek
+        let seek = FileControl {
+            transfer_direction: TransferDirection::Send,
+            file_number: 1,
+            control_type: ControlType::Seek(10),
+        };
+        assert!(messenger.handle_file_control(friend_pk, &mut friend, seek).wait().is_err());
+
+        let kill = FileControl {
+            transfer_direction: TransferDirection::Send,
+            file_number: 1,
+            control_type: ControlType::Kill,
+        };
+        messenger.handle_file_control(friend_pk, &mut friend, kill).wait().unwrap();
+        assert!(friend.files.receiving.is_empty());
+
+        let (received, _data_rx) = data_rx.into_future().wait().unwrap();
+        assert!(received.unwrap().is_err());
+    }
+
+    #[test]
+    fn handle_file_control_no_such_file() {
+        let (messenger, _event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+        let mut friend = create_online_friend();
+
+        let packet = FileControl {
+            transfer_direction: TransferDirection::Receive,
+            file_number: 1,
+            control_type: ControlType::Accept,
+        };
+        assert!(messenger.handle_file_control(friend_pk, &mut friend, packet).wait().is_err());
+    }
+
+    #[test]
+    fn handle_file_data() {
+        let (messenger, event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+        let mut friend = create_online_friend();
+        let (data_tx, data_rx) = mpsc::unbounded();
+        let file_size = MAX_FILE_DATA_SIZE as u64 + 10;
+        let mut file = ReceivingFile::new(file_size);
+        file.status = FileStatus::Transferring;
+        file.data_tx = Some(data_tx);
+        friend.files.receiving.insert(1, file);
+
+        let packet = FileData {
+            file_number: 1,
+            data: vec![42; MAX_FILE_DATA_SIZE],
+        };
+        messenger.handle_file_data(friend_pk, &mut friend, packet).wait().unwrap();
+        assert_eq!(friend.files.receiving[&1].transferred, MAX_FILE_DATA_SIZE as u64);
+
+        // data beyond the file size is ignored
+        let packet = FileData {
+            file_number: 1,
+            data: vec![43; 20],
+        };
+        messenger.handle_file_data(friend_pk, &mut friend, packet).wait().unwrap();
+        assert!(friend.files.receiving.is_empty());
+
+        let received = data_rx.collect().wait().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(*received[0].as_ref().unwrap(), vec![42; MAX_FILE_DATA_SIZE]);
+        assert_eq!(*received[1].as_ref().unwrap(), vec![43; 10]);
+
+        let (event, _event_rx) = event_rx.into_future().wait().unwrap();
+        assert_eq!(event.unwrap(), (friend_pk, MessengerEvent::FileFinished {
+            file_number: 1,
+            transfer_direction: TransferDirection::Receive,
+        }));
+    }
+
+    #[test]
+    fn handle_file_data_not_accepted() {
+        let (messenger, _event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+        let mut friend = create_online_friend();
+        friend.files.receiving.insert(1, ReceivingFile::new(100));
+
+        let packet = FileData {
+            file_number: 1,
+            data: vec![42; 100],
+        };
+        assert!(messenger.handle_file_data(friend_pk, &mut friend, packet).wait().is_err());
+    }
+
+    #[test]
+    fn control_receiving_file_seek() {
+        let mut files = FileTransfers::default();
+        files.receiving.insert(1, ReceivingFile::new(100));
+
+        assert!(Messenger::control_receiving_file(&mut files, 1, ControlType::Seek(100)).is_err());
+        Messenger::control_receiving_file(&mut files, 1, ControlType::Seek(50)).unwrap();
+        assert_eq!(files.receiving[&1].transferred, 50);
+
+        // not accepted file can't be paused
+        assert!(Messenger::control_receiving_file(&mut files, 1, ControlType::Pause).is_err());
+        assert!(Messenger::control_receiving_file(&mut files, 2, ControlType::Kill).is_err());
+    }
+
+    #[test]
+    fn control_sending_file() {
+        let mut files = FileTransfers::default();
+        let mut file = SendingFile::new(100, Box::new(Cursor::new(vec![42; 100])));
+        file.status = FileStatus::Transferring;
+        files.sending.insert(1, file);
+
+        assert!(Messenger::control_sending_file(&mut files, 1, ControlType::Seek(50)).is_err());
+        assert!(Messenger::control_sending_file(&mut files, 1, ControlType::Accept).is_err());
+
+        Messenger::control_sending_file(&mut files, 1, ControlType::Pause).unwrap();
+        assert!(!files.sending[&1].is_active());
+        Messenger::control_sending_file(&mut files, 1, ControlType::Accept).unwrap();
+        assert!(files.sending[&1].is_active());
+
+        Messenger::control_sending_file(&mut files, 1, ControlType::Kill).unwrap();
+        assert!(files.sending.is_empty());
+    }
+
+    #[test]
+    fn kill_all() {
+        let mut files = FileTransfers::default();
+        files.sending.insert(1, SendingFile::new(100, Box::new(Cursor::new(vec![42; 100]))));
+        let (data_tx, data_rx) = mpsc::unbounded();
+        let mut file = ReceivingFile::new(100);
+        file.status = FileStatus::Transferring;
+        file.data_tx = Some(data_tx);
+        files.receiving.insert(1, file);
+
+        files.kill_all();
+        assert!(files.sending.is_empty());
+        assert!(files.receiving.is_empty());
+
+        let received = data_rx.collect().wait().unwrap();
+        assert_eq!(received.len(), 1);
+        assert!(received[0].is_err());
+    }
+
+    #[test]
+    fn files_loop_without_connection() {
+        let (messenger, _event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+        let mut friend = create_online_friend();
+        let mut file = SendingFile::new(100, Box::new(Cursor::new(vec![42; 100])));
+        file.status = FileStatus::Transferring;
+        friend.files.sending.insert(1, file);
+
+        // nothing is sent when there is no free space in the send buffer
+        assert!(messenger.files_loop(friend_pk, &mut friend).is_empty());
+        assert_eq!(friend.files.sending[&1].transferred, 0);
+    }
+}
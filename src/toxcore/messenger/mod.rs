@@ -0,0 +1,820 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! The implementation of messenger module.
+
+Messenger module works on top of friend connection module and implements
+the high level part of Tox protocol that is used by chat clients:
+
+- friend is considered online after he sends `Online` packet to us;
+- nickname, status message, user status and typing state are sent to a friend
+  every time he goes online and when they are changed;
+- text messages and actions are split into several packets if they are too
+  long;
+- read receipts are reported when the friend confirms that he received all
+  packets of a message;
+- files are transferred with `FileSendRequest`, `FileControl` and `FileData`
+  packets.
+
+All received data is reported as `MessengerEvent`s with long term `PublicKey`
+of the friend.
+
+*/
+
+pub mod packet;
+mod file_transfer;
+
+pub use self::file_transfer::*;
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Future, Stream, future, stream};
+use futures::sync::mpsc;
+use parking_lot::RwLock;
+use tokio::timer::Interval;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::packet::MAX_CRYPTO_DATA_SIZE;
+use toxcore::friend_connection::FriendConnections;
+use toxcore::io_tokio::*;
+use toxcore::messenger::file_transfer::FileTransfers;
+use toxcore::messenger::packet::*;
+use toxcore::net_crypto::NetCrypto;
+use toxcore::time::*;
+
+/// How often `main_loop` is called by `run` in milliseconds.
+pub const MAIN_LOOP_INTERVAL: u64 = 50;
+
+/// Event that is sent by messenger module when something happens with a
+/// friend.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MessengerEvent {
+    /// Friend went online
+    Online,
+    /// Friend went offline
+    Offline,
+    /// Friend changed his nickname
+    Nickname(String),
+    /// Friend changed his status message
+    StatusMessage(String),
+    /// Friend changed his status
+    UserStatus(UserStatusKind),
+    /// Friend started or stopped typing
+    Typing(bool),
+    /// Friend sent us a text message
+    Message(String),
+    /// Friend sent us an action
+    Action(String),
+    /// Friend received our message or action with the given id
+    ReadReceipt(u32),
+    /// Friend offers us a file that can be accepted with `accept_file`
+    FileSendRequest(FileSendRequest),
+    /// Friend accepted, paused, resumed, cancelled or seeked the file
+    FileControl {
+        /// Number of the file
+        file_number: u8,
+        /// Direction of the transfer relative to us
+        transfer_direction: TransferDirection,
+        /// Control command
+        control_type: ControlType,
+    },
+    /// The file is completely transferred
+    FileFinished {
+        /// Number of the file
+        file_number: u8,
+        /// Direction of the transfer relative to us
+        transfer_direction: TransferDirection,
+    },
+}
+
+/// Shorthand for the transmit half of the message channel for sending
+/// messenger events. The key is a long term `PublicKey` of the friend.
+type EventTx = mpsc::UnboundedSender<(PublicKey, MessengerEvent)>;
+
+/// Shorthand for the receive half of the message channel for receiving
+/// friend's connection status from friend connection module.
+type ConnectionStatusRx = mpsc::UnboundedReceiver<(PublicKey, bool)>;
+
+/// Shorthand for the receive half of the message channel for receiving
+/// lossless packets from friend connection module.
+type LosslessRx = mpsc::UnboundedReceiver<(PublicKey, Vec<u8>)>;
+
+/// Friend related data stored in messenger module.
+#[derive(Default)]
+struct Friend {
+    /// Whether the friend sent us `Online` packet
+    online: bool,
+    /// Whether our current nickname was sent to the friend
+    nickname_sent: bool,
+    /// Whether our current status message was sent to the friend
+    status_message_sent: bool,
+    /// Whether our current status was sent to the friend
+    user_status_sent: bool,
+    /// Whether we are typing a message to the friend
+    is_typing: bool,
+    /// Whether our current typing state was sent to the friend
+    typing_sent: bool,
+    /// Id that will be assigned to the next message sent to the friend
+    next_message_id: u32,
+    /// Numbers of the last lossless packets of sent messages that are not
+    /// confirmed yet with ids of these messages
+    receipts: Vec<(u32, u32)>,
+    /// Files that are being transferred between us and the friend
+    files: FileTransfers,
+}
+
+impl Friend {
+    /// Mark our data as not sent so that it will be sent again in the main
+    /// loop.
+    fn reset_sent(&mut self) {
+        self.nickname_sent = false;
+        self.status_message_sent = false;
+        self.user_status_sent = false;
+        self.typing_sent = false;
+    }
+}
+
+/// Split message into parts that fit into one packet. Parts are split by
+/// whitespace when it's possible, otherwise they are split on characters
+/// boundary.
+fn split_message(mut message: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    while message.len() > MAX_MESSAGE_LENGTH {
+        let mut end = MAX_MESSAGE_LENGTH;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        if let Some(pos) = message[..end].rfind(|c| c == ' ' || c == '\n') {
+            // whitespace is left at the end of the part
+            end = pos + 1;
+        }
+        parts.push(&message[..end]);
+        message = &message[end..];
+    }
+    if !message.is_empty() {
+        parts.push(message);
+    }
+    parts
+}
+
+/** Messenger that sends and receives text messages and other user data.
+
+Messenger should be created after `FriendConnections`. Friends should be
+added via messenger rather than directly via `FriendConnections`.
+*/
+#[derive(Clone)]
+pub struct Messenger {
+    /// Sink to send messenger events
+    event_tx: EventTx,
+    /// Friend connections used to connect to friends
+    friend_connections: FriendConnections,
+    /// Net crypto module used to send packets and check their delivery
+    net_crypto: NetCrypto,
+    /// Our nickname
+    nickname: Arc<RwLock<String>>,
+    /// Our status message
+    status_message: Arc<RwLock<String>>,
+    /// Our status
+    user_status: Arc<RwLock<UserStatusKind>>,
+    /// Friends by their long term `PublicKey`
+    friends: Arc<RwLock<HashMap<PublicKey, Friend>>>,
+}
+
+impl Messenger {
+    /// Create new `Messenger` object.
+    pub fn new(friend_connections: FriendConnections, net_crypto: NetCrypto, event_tx: EventTx) -> Messenger {
+        Messenger {
+            event_tx,
+            friend_connections,
+            net_crypto,
+            nickname: Arc::new(RwLock::new(String::new())),
+            status_message: Arc::new(RwLock::new(String::new())),
+            user_status: Arc::new(RwLock::new(UserStatusKind::Online)),
+            friends: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Add friend to connect to him.
+    pub fn add_friend(&self, real_pk: PublicKey) {
+        self.friends.write().entry(real_pk).or_insert_with(Friend::default);
+        self.friend_connections.add_friend(real_pk);
+    }
+
+    /// Remove friend sending `Offline` packet to him if he is online.
+    pub fn remove_friend(&self, real_pk: PublicKey) -> IoFuture<()> {
+        let mut friend = match self.friends.write().remove(&real_pk) {
+            Some(friend) => friend,
+            None => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "No such friend")
+            )),
+        };
+        friend.files.kill_all();
+        let offline_future: IoFuture<()> = if friend.online {
+            // the friend will go offline anyway when the connection is killed
+            Box::new(self.send_packet(real_pk, Packet::Offline(Offline)).then(|_| Ok(())))
+        } else {
+            Box::new(future::ok(()))
+        };
+        let friend_connections = self.friend_connections.clone();
+        Box::new(offline_future.and_then(move |()| friend_connections.remove_friend(real_pk)))
+    }
+
+    /// Check if the friend is online.
+    pub fn is_online(&self, real_pk: &PublicKey) -> bool {
+        self.friends.read().get(real_pk).map(|friend| friend.online).unwrap_or(false)
+    }
+
+    /// Set our nickname. It will be sent to all friends.
+    pub fn set_nickname(&self, nickname: String) -> Result<(), Error> {
+        if nickname.len() > MAX_NAME_LENGTH {
+            return Err(Error::new(ErrorKind::Other, "Nickname is too long"));
+        }
+        *self.nickname.write() = nickname;
+        for friend in self.friends.write().values_mut() {
+            friend.nickname_sent = false;
+        }
+        Ok(())
+    }
+
+    /// Set our status message. It will be sent to all friends.
+    pub fn set_status_message(&self, status_message: String) -> Result<(), Error> {
+        if status_message.len() > MAX_STATUS_MESSAGE_LENGTH {
+            return Err(Error::new(ErrorKind::Other, "Status message is too long"));
+        }
+        *self.status_message.write() = status_message;
+        for friend in self.friends.write().values_mut() {
+            friend.status_message_sent = false;
+        }
+        Ok(())
+    }
+
+    /// Set our status. It will be sent to all friends.
+    pub fn set_user_status(&self, user_status: UserStatusKind) {
+        *self.user_status.write() = user_status;
+        for friend in self.friends.write().values_mut() {
+            friend.user_status_sent = false;
+        }
+    }
+
+    /// Set whether we are typing a message to the friend. It will be sent to
+    /// the friend.
+    pub fn set_typing(&self, real_pk: PublicKey, is_typing: bool) -> Result<(), Error> {
+        let mut friends = self.friends.write();
+        let friend = match friends.get_mut(&real_pk) {
+            Some(friend) => friend,
+            None => return Err(Error::new(ErrorKind::Other, "No such friend")),
+        };
+        if friend.is_typing != is_typing {
+            friend.is_typing = is_typing;
+            friend.typing_sent = false;
+        }
+        Ok(())
+    }
+
+    /// Send text message to the friend. Returns id of the message that will be
+    /// reported in `ReadReceipt` event when the friend receives it.
+    pub fn send_message(&self, real_pk: PublicKey, message: &str) -> IoFuture<u32> {
+        self.send_text(real_pk, message, |message| Packet::Message(Message { message }))
+    }
+
+    /// Send action to the friend. Returns id of the action that will be
+    /// reported in `ReadReceipt` event when the friend receives it.
+    pub fn send_action(&self, real_pk: PublicKey, action: &str) -> IoFuture<u32> {
+        self.send_text(real_pk, action, |action| Packet::Action(Action { action }))
+    }
+
+    /// Split text into several packets if it's too long and send them to the
+    /// friend. Receipt is stored for the last packet since packets are
+    /// delivered in order.
+    fn send_text<F>(&self, real_pk: PublicKey, text: &str, to_packet: F) -> IoFuture<u32>
+        where F: Fn(String) -> Packet
+    {
+        if text.is_empty() {
+            return Box::new(future::err(
+                Error::new(ErrorKind::Other, "Message is empty")
+            ));
+        }
+
+        let message_id = {
+            let mut friends = self.friends.write();
+            let friend = match friends.get_mut(&real_pk) {
+                Some(friend) => friend,
+                None => return Box::new(future::err(
+                    Error::new(ErrorKind::Other, "No such friend")
+                )),
+            };
+            if !friend.online {
+                return Box::new(future::err(
+                    Error::new(ErrorKind::Other, "Friend is not online")
+                ));
+            }
+            let message_id = friend.next_message_id;
+            friend.next_message_id = friend.next_message_id.wrapping_add(1);
+            message_id
+        };
+
+        let futures = split_message(text).into_iter()
+            .map(|part| self.send_packet(real_pk, to_packet(part.to_string())))
+            .collect::<Vec<_>>();
+        let friends = self.friends.clone();
+        Box::new(future::join_all(futures).map(move |packet_numbers| {
+            if let Some(&packet_number) = packet_numbers.last() {
+                if let Some(friend) = friends.write().get_mut(&real_pk) {
+                    friend.receipts.push((packet_number, message_id));
+                }
+            }
+            message_id
+        }))
+    }
+
+    /// Send messenger packet to the friend as lossless packet. Returns number
+    /// of the sent packet.
+    fn send_packet(&self, real_pk: PublicKey, packet: Packet) -> IoFuture<u32> {
+        let mut buf = [0; MAX_CRYPTO_DATA_SIZE];
+        match packet.to_bytes((&mut buf, 0)) {
+            Ok((_, size)) => self.net_crypto.send_lossless(real_pk, buf[..size].to_vec()),
+            Err(e) => Box::new(future::err(
+                Error::new(ErrorKind::Other, format!("Failed to serialize packet: {:?}", e))
+            )),
+        }
+    }
+
+    /// Send packet with our state to the friend. It's marked as sent by
+    /// `mark_sent` only when sending succeeds so that `main_loop` resends it
+    /// otherwise.
+    fn send_state_packet<F>(&self, real_pk: PublicKey, packet: Packet, mark_sent: F) -> IoFuture<()>
+        where F: FnOnce(&mut Friend) + Send + 'static
+    {
+        let friends = self.friends.clone();
+        Box::new(self.send_packet(real_pk, packet).map(move |_| {
+            if let Some(friend) = friends.write().get_mut(&real_pk) {
+                mark_sent(friend);
+            }
+        }))
+    }
+
+    /// Mark the friend as offline sending `Offline` event if he was online.
+    fn set_offline(&self, real_pk: PublicKey, friend: &mut Friend) -> IoFuture<()> {
+        // messages that were not confirmed will never be confirmed
+        friend.receipts.clear();
+        friend.files.kill_all();
+        if friend.online {
+            friend.online = false;
+            send_to(&self.event_tx, (real_pk, MessengerEvent::Offline))
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Handle friend's connection status received from friend connection
+    /// module. When the connection is established we send `Online` packet to
+    /// the friend.
+    pub fn handle_connection_status(&self, real_pk: PublicKey, connected: bool) -> IoFuture<()> {
+        let mut friends = self.friends.write();
+        let friend = match friends.get_mut(&real_pk) {
+            Some(friend) => friend,
+            None => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "Connection status is received for unknown friend")
+            )),
+        };
+
+        if connected {
+            friend.reset_sent();
+            Box::new(self.send_packet(real_pk, Packet::Online(Online)).map(|_| ()))
+        } else {
+            self.set_offline(real_pk, friend)
+        }
+    }
+
+    /// Handle lossless packet received from the friend and send corresponding
+    /// event. All packets except `Online` are ignored until the friend is
+    /// online.
+    pub fn handle_lossless_packet(&self, real_pk: PublicKey, data: Vec<u8>) -> IoFuture<()> {
+        let mut friends = self.friends.write();
+        let friend = match friends.get_mut(&real_pk) {
+            Some(friend) => friend,
+            None => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "Lossless packet is received from unknown friend")
+            )),
+        };
+
+        let packet = match Packet::from_bytes(&data) {
+            IResult::Done(_, packet) => packet,
+            _ => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "Failed to parse messenger packet")
+            )),
+        };
+
+        let event = match packet {
+            Packet::Online(_) if friend.online => return Box::new(future::ok(())),
+            Packet::Online(_) => {
+                friend.online = true;
+                MessengerEvent::Online
+            },
+            _ if !friend.online => return Box::new(future::ok(())),
+            Packet::Offline(_) => return self.set_offline(real_pk, friend),
+            Packet::Nickname(packet) => MessengerEvent::Nickname(packet.nickname),
+            Packet::StatusMessage(packet) => MessengerEvent::StatusMessage(packet.message),
+            Packet::UserStatus(packet) => MessengerEvent::UserStatus(packet.status),
+            Packet::Typing(packet) => MessengerEvent::Typing(packet.is_typing),
+            Packet::Message(packet) => MessengerEvent::Message(packet.message),
+            Packet::Action(packet) => MessengerEvent::Action(packet.action),
+            Packet::FileSendRequest(packet) => return self.handle_file_send_request(real_pk, friend, packet),
+            Packet::FileControl(packet) => return self.handle_file_control(real_pk, friend, packet),
+            Packet::FileData(packet) => return self.handle_file_data(real_pk, friend, packet),
+        };
+
+        send_to(&self.event_tx, (real_pk, event))
+    }
+
+    /// Main loop of messenger, it should be called every `MAIN_LOOP_INTERVAL`
+    /// milliseconds.
+    pub fn main_loop(&self) -> IoFuture<()> {
+        let mut friends = self.friends.write();
+        let mut futures: Vec<IoFuture<()>> = Vec::new();
+        for (&real_pk, friend) in friends.iter_mut() {
+            if !friend.online {
+                continue;
+            }
+
+            if !friend.nickname_sent {
+                let nickname = self.nickname.read().clone();
+                futures.push(self.send_state_packet(real_pk, Packet::Nickname(Nickname { nickname }), |friend| friend.nickname_sent = true));
+            }
+
+            if !friend.status_message_sent {
+                let message = self.status_message.read().clone();
+                futures.push(self.send_state_packet(real_pk, Packet::StatusMessage(StatusMessage { message }), |friend| friend.status_message_sent = true));
+            }
+
+            if !friend.user_status_sent {
+                let status = *self.user_status.read();
+                futures.push(self.send_state_packet(real_pk, Packet::UserStatus(UserStatus { status }), |friend| friend.user_status_sent = true));
+            }
+
+            if !friend.typing_sent {
+                let is_typing = friend.is_typing;
+                futures.push(self.send_state_packet(real_pk, Packet::Typing(Typing { is_typing }), |friend| friend.typing_sent = true));
+            }
+
+            let net_crypto = &self.net_crypto;
+            let event_tx = &self.event_tx;
+            friend.receipts.retain(|&(packet_number, message_id)| {
+                if net_crypto.is_packet_delivered(real_pk, packet_number) {
+                    futures.push(send_to(event_tx, (real_pk, MessengerEvent::ReadReceipt(message_id))));
+                    false
+                } else {
+                    true
+                }
+            });
+
+            futures.extend(self.files_loop(real_pk, friend));
+        }
+
+        // failure to send a packet to one friend shouldn't affect others
+        let futures_stream = stream::futures_unordered(futures).then(|_| Ok(()));
+        Box::new(futures_stream.for_each(|()| Ok(())))
+    }
+
+    /// Run messenger handling messages from the given channels of friend
+    /// connection module and calling `main_loop` periodically.
+    pub fn run(&self, connection_status_rx: ConnectionStatusRx, lossless_rx: LosslessRx) -> IoFuture<()> {
+        let messenger = self.clone();
+        let connection_status_future = connection_status_rx
+            .map_err(|()| Error::new(ErrorKind::Other, "rx error"))
+            .for_each(move |(real_pk, connected)|
+                messenger.handle_connection_status(real_pk, connected).or_else(|e| {
+                    debug!("Failed to handle connection status: {}", e);
+                    Ok(())
+                })
+            );
+
+        let messenger = self.clone();
+        let lossless_future = lossless_rx
+            .map_err(|()| Error::new(ErrorKind::Other, "rx error"))
+            .for_each(move |(real_pk, data)|
+                messenger.handle_lossless_packet(real_pk, data).or_else(|e| {
+                    debug!("Failed to handle lossless packet: {}", e);
+                    Ok(())
+                })
+            );
+
+        let messenger = self.clone();
+        let wakeups = Interval::new(clock_now(), Duration::from_millis(MAIN_LOOP_INTERVAL));
+        let main_loop_future = wakeups
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Messenger timer error: {:?}", e)))
+            .for_each(move |_instant| messenger.main_loop());
+
+        Box::new(connection_status_future
+            .join3(lossless_future, main_loop_future)
+            .map(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use toxcore::dht::kbucket::*;
+    use toxcore::dht::server::Server;
+    use toxcore::net_crypto::NetCryptoNewArgs;
+    use toxcore::onion::client::OnionClient;
+
+    pub type EventRx = mpsc::UnboundedReceiver<(PublicKey, MessengerEvent)>;
+
+    pub fn create_messenger() -> (Messenger, EventRx) {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx: udp_tx.clone(),
+            tcp_tx,
+            dht_pk_tx: dht_pk_tx.clone(),
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+        let dht = Server::new(udp_tx.clone(), dht_pk, dht_sk);
+        let close_nodes = Arc::new(RwLock::new(Kbucket::new(&dht_pk)));
+        let onion_client = OnionClient::new(udp_tx, dht_pk_tx, dht_pk, real_pk, real_sk, close_nodes);
+
+        let (friend_status_tx, _friend_status_rx) = mpsc::unbounded();
+        let (friend_lossless_tx, _friend_lossless_rx) = mpsc::unbounded();
+        let friend_connections = FriendConnections::new(
+            dht,
+            onion_client,
+            net_crypto.clone(),
+            friend_status_tx,
+            friend_lossless_tx
+        );
+
+        let (event_tx, event_rx) = mpsc::unbounded();
+        let messenger = Messenger::new(friend_connections, net_crypto, event_tx);
+        (messenger, event_rx)
+    }
+
+    fn serialize(packet: Packet) -> Vec<u8> {
+        let mut buf = [0; MAX_CRYPTO_DATA_SIZE];
+        let (_, size) = packet.to_bytes((&mut buf, 0)).unwrap();
+        buf[..size].to_vec()
+    }
+
+    #[test]
+    fn split_message_short() {
+        assert_eq!(split_message("hello"), vec!["hello"]);
+        assert!(split_message("").is_empty());
+    }
+
+    #[test]
+    fn split_message_by_whitespace() {
+        let first = "a".repeat(MAX_MESSAGE_LENGTH - 10);
+        let second = "b".repeat(20);
+        let message = format!("{} {}", first, second);
+        let parts = split_message(&message);
+        assert_eq!(parts, vec![format!("{} ", first).as_str(), second.as_str()]);
+    }
+
+    #[test]
+    fn split_message_by_chars() {
+        // 'ф' takes 2 bytes and MAX_MESSAGE_LENGTH is even
+        let message = "ф".repeat(MAX_MESSAGE_LENGTH + 1);
+        let parts = split_message(&message);
+        assert_eq!(parts.len(), 3);
+        assert!(parts.iter().all(|part| part.len() <= MAX_MESSAGE_LENGTH));
+        assert_eq!(parts.concat(), message);
+    }
+
+    #[test]
+    fn add_remove_friend() {
+        let (messenger, _event_rx) = create_messenger();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        messenger.add_friend(friend_pk);
+        assert!(messenger.friends.read().contains_key(&friend_pk));
+        assert!(!messenger.is_online(&friend_pk));
+
+        messenger.remove_friend(friend_pk).wait().unwrap();
+        assert!(messenger.friends.read().is_empty());
+    }
+
+    #[test]
+    fn remove_unknown_friend() {
+        let (messenger, _event_rx) = create_messenger();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        assert!(messenger.remove_friend(friend_pk).wait().is_err());
+    }
+
+    #[test]
+    fn set_nickname() {
+        let (messenger, _event_rx) = create_messenger();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        messenger.add_friend(friend_pk);
+        messenger.friends.write().get_mut(&friend_pk).unwrap().nickname_sent = true;
+
+        messenger.set_nickname("Alice".to_string()).unwrap();
+
+        assert_eq!(*messenger.nickname.read(), "Alice");
+        assert!(!messenger.friends.read()[&friend_pk].nickname_sent);
+    }
+
+    #[test]
+    fn set_nickname_too_long() {
+        let (messenger, _event_rx) = create_messenger();
+
+        assert!(messenger.set_nickname("x".repeat(MAX_NAME_LENGTH + 1)).is_err());
+        assert!(messenger.set_status_message("x".repeat(MAX_STATUS_MESSAGE_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn set_typing() {
+        let (messenger, _event_rx) = create_messenger();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        messenger.add_friend(friend_pk);
+        messenger.friends.write().get_mut(&friend_pk).unwrap().typing_sent = true;
+
+        messenger.set_typing(friend_pk, true).unwrap();
+
+        assert!(messenger.set_typing(gen_keypair().0, true).is_err());
+
+        let friends = messenger.friends.read();
+        assert!(friends[&friend_pk].is_typing);
+        assert!(!friends[&friend_pk].typing_sent);
+    }
+
+    #[test]
+    fn send_message_offline_friend() {
+        let (messenger, _event_rx) = create_messenger();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        messenger.add_friend(friend_pk);
+
+        assert!(messenger.send_message(friend_pk, "hello").wait().is_err());
+        assert!(messenger.send_action(gen_keypair().0, "waves").wait().is_err());
+    }
+
+    #[test]
+    fn send_message_empty() {
+        let (messenger, _event_rx) = create_messenger();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        messenger.add_friend(friend_pk);
+        messenger.friends.write().get_mut(&friend_pk).unwrap().online = true;
+
+        assert!(messenger.send_message(friend_pk, "").wait().is_err());
+    }
+
+    #[test]
+    fn handle_connection_status_resets_sent() {
+        let (messenger, _event_rx) = create_messenger();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        messenger.add_friend(friend_pk);
+        {
+            let mut friends = messenger.friends.write();
+            let friend = friends.get_mut(&friend_pk).unwrap();
+            friend.nickname_sent = true;
+            friend.status_message_sent = true;
+            friend.user_status_sent = true;
+            friend.typing_sent = true;
+        }
+
+        // there is no net crypto connection so Online packet can't be sent
+        assert!(messenger.handle_connection_status(friend_pk, true).wait().is_err());
+
+        let friends = messenger.friends.read();
+        let friend = &friends[&friend_pk];
+        assert!(!friend.nickname_sent);
+        assert!(!friend.status_message_sent);
+        assert!(!friend.user_status_sent);
+        assert!(!friend.typing_sent);
+    }
+
+    #[test]
+    fn handle_connection_status_offline() {
+        let (messenger, event_rx) = create_messenger();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        messenger.add_friend(friend_pk);
+        messenger.handle_lossless_packet(friend_pk, serialize(Packet::Online(Online))).wait().unwrap();
+        messenger.friends.write().get_mut(&friend_pk).unwrap().receipts.push((0, 0));
+
+        messenger.handle_connection_status(friend_pk, false).wait().unwrap();
+
+        assert!(!messenger.is_online(&friend_pk));
+        assert!(messenger.friends.read()[&friend_pk].receipts.is_empty());
+
+        drop(messenger);
+        let events = event_rx.collect().wait().unwrap();
+        assert_eq!(events, vec![(friend_pk, MessengerEvent::Online), (friend_pk, MessengerEvent::Offline)]);
+    }
+
+    #[test]
+    fn handle_lossless_packets() {
+        let (messenger, event_rx) = create_messenger();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        messenger.add_friend(friend_pk);
+
+        // packets are ignored until the friend is online
+        let message = Packet::Message(Message { message: "hello".to_string() });
+        messenger.handle_lossless_packet(friend_pk, serialize(message.clone())).wait().unwrap();
+
+        let packets = vec![
+            Packet::Online(Online),
+            Packet::Nickname(Nickname { nickname: "Bob".to_string() }),
+            Packet::StatusMessage(StatusMessage { message: "Hi".to_string() }),
+            Packet::UserStatus(UserStatus { status: UserStatusKind::Busy }),
+            Packet::Typing(Typing { is_typing: true }),
+            message,
+            Packet::Action(Action { action: "waves".to_string() }),
+            Packet::Offline(Offline),
+        ];
+        for packet in packets {
+            messenger.handle_lossless_packet(friend_pk, serialize(packet)).wait().unwrap();
+        }
+
+        assert!(!messenger.is_online(&friend_pk));
+
+        drop(messenger);
+        let events = event_rx.collect().wait().unwrap();
+        assert_eq!(events, vec![
+            (friend_pk, MessengerEvent::Online),
+            (friend_pk, MessengerEvent::Nickname("Bob".to_string())),
+            (friend_pk, MessengerEvent::StatusMessage("Hi".to_string())),
+            (friend_pk, MessengerEvent::UserStatus(UserStatusKind::Busy)),
+            (friend_pk, MessengerEvent::Typing(true)),
+            (friend_pk, MessengerEvent::Message("hello".to_string())),
+            (friend_pk, MessengerEvent::Action("waves".to_string())),
+            (friend_pk, MessengerEvent::Offline),
+        ]);
+    }
+
+    #[test]
+    fn handle_lossless_invalid_packet() {
+        let (messenger, _event_rx) = create_messenger();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        messenger.add_friend(friend_pk);
+
+        assert!(messenger.handle_lossless_packet(friend_pk, vec![0x42, 1, 2, 3]).wait().is_err());
+        assert!(messenger.handle_lossless_packet(gen_keypair().0, vec![0x18]).wait().is_err());
+    }
+
+    #[test]
+    fn main_loop_sends_data() {
+        let (messenger, _event_rx) = create_messenger();
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        messenger.add_friend(friend_pk);
+        messenger.handle_lossless_packet(friend_pk, serialize(Packet::Online(Online))).wait().unwrap();
+        messenger.friends.write().get_mut(&friend_pk).unwrap().receipts.push((0, 0));
+
+        messenger.main_loop().wait().unwrap();
+
+        let friends = messenger.friends.read();
+        let friend = &friends[&friend_pk];
+        // there is no net crypto connection so packets can't be sent and
+        // they will be resent by the next main loop
+        assert!(!friend.nickname_sent);
+        assert!(!friend.status_message_sent);
+        assert!(!friend.user_status_sent);
+        assert!(!friend.typing_sent);
+        // the packet can't be delivered either
+        assert_eq!(friend.receipts, vec![(0, 0)]);
+    }
+}
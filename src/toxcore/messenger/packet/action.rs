@@ -0,0 +1,90 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Action struct
+*/
+
+use nom::rest;
+
+use std::str;
+
+use toxcore::binary_io::*;
+use toxcore::messenger::packet::MAX_MESSAGE_LENGTH;
+
+/** Action packet is used to send an action message to a friend. It's the same
+as `Message` but should be displayed like "/me" messages in IRC.
+
+Serialized form:
+
+Length      | Content
+----------- | ------
+`1`         | `0x41`
+`[1, 1372]` | Text of the action in UTF-8
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Action {
+    /// Text of the action
+    pub action: String,
+}
+
+impl FromBytes for Action {
+    named!(from_bytes<Action>, do_parse!(
+        tag!("\x41") >>
+        action: map_res!(verify!(rest, |action: &[u8]| !action.is_empty() && action.len() <= MAX_MESSAGE_LENGTH), str::from_utf8) >>
+        (Action { action: action.to_string() })
+    ));
+}
+
+impl ToBytes for Action {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_cond!(self.action.is_empty() || self.action.len() > MAX_MESSAGE_LENGTH, |buf| gen_error(buf, 0)) >>
+            gen_be_u8!(0x41) >>
+            gen_slice!(self.action.as_bytes())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        action_encode_decode,
+        Action {
+            action: "waves".to_string()
+        }
+    );
+
+    #[test]
+    fn action_too_long() {
+        let packet = Action {
+            action: "x".repeat(MAX_MESSAGE_LENGTH + 1)
+        };
+        let mut buf = [0; MAX_MESSAGE_LENGTH + 2];
+        assert!(packet.to_bytes((&mut buf, 0)).is_err());
+    }
+
+    #[test]
+    fn action_invalid_utf8() {
+        assert!(Action::from_bytes(&[0x41, 0xff, 0xfe]).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! FileControl struct
+*/
+
+use nom::{be_u8, be_u64};
+
+use toxcore::binary_io::*;
+
+/// Direction of a file transfer relative to the sender of `FileControl`
+/// packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransferDirection {
+    /// The file is sent by the sender of the packet
+    Send = 0,
+    /// The file is received by the sender of the packet
+    Receive = 1,
+}
+
+impl TransferDirection {
+    /// Get the same direction relative to the other side of the transfer.
+    pub fn toggle(self) -> TransferDirection {
+        match self {
+            TransferDirection::Send => TransferDirection::Receive,
+            TransferDirection::Receive => TransferDirection::Send,
+        }
+    }
+}
+
+impl FromBytes for TransferDirection {
+    named!(from_bytes<TransferDirection>, switch!(be_u8,
+        0 => value!(TransferDirection::Send) |
+        1 => value!(TransferDirection::Receive)
+    ));
+}
+
+/// Control command of a file transfer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ControlType {
+    /// Accept the file or resume paused transfer
+    Accept,
+    /// Pause the transfer
+    Pause,
+    /// Cancel the transfer
+    Kill,
+    /// Continue the transfer from the given position. It can be sent only by
+    /// receiver before the file is accepted.
+    Seek(u64),
+}
+
+impl FromBytes for ControlType {
+    named!(from_bytes<ControlType>, switch!(be_u8,
+        0 => value!(ControlType::Accept) |
+        1 => value!(ControlType::Pause) |
+        2 => value!(ControlType::Kill) |
+        3 => map!(be_u64, ControlType::Seek)
+    ));
+}
+
+impl ToBytes for ControlType {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        match *self {
+            ControlType::Accept => do_gen!(buf, gen_be_u8!(0)),
+            ControlType::Pause => do_gen!(buf, gen_be_u8!(1)),
+            ControlType::Kill => do_gen!(buf, gen_be_u8!(2)),
+            ControlType::Seek(position) => do_gen!(buf,
+                gen_be_u8!(3) >>
+                gen_be_u64!(position)
+            ),
+        }
+    }
+}
+
+/** FileControl packet is used to control a file transfer, e.g. to accept,
+pause or cancel it.
+
+Serialized form:
+
+Length      | Content
+----------- | ------
+`1`         | `0x51`
+`1`         | Transfer direction (0 = send, 1 = receive)
+`1`         | File number
+`1`         | Control type (0 = accept, 1 = pause, 2 = kill, 3 = seek)
+`0` or `8`  | Seek position if control type is seek
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileControl {
+    /// Direction of the transfer relative to the sender of this packet
+    pub transfer_direction: TransferDirection,
+    /// Number of the file
+    pub file_number: u8,
+    /// Control command
+    pub control_type: ControlType,
+}
+
+impl FromBytes for FileControl {
+    named!(from_bytes<FileControl>, do_parse!(
+        tag!("\x51") >>
+        transfer_direction: call!(TransferDirection::from_bytes) >>
+        file_number: be_u8 >>
+        control_type: call!(ControlType::from_bytes) >>
+        eof!() >>
+        (FileControl {
+            transfer_direction,
+            file_number,
+            control_type,
+        })
+    ));
+}
+
+impl ToBytes for FileControl {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x51) >>
+            gen_be_u8!(self.transfer_direction as u8) >>
+            gen_be_u8!(self.file_number) >>
+            gen_call!(|buf, control_type| ControlType::to_bytes(control_type, buf), &self.control_type)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        file_control_accept_encode_decode,
+        FileControl {
+            transfer_direction: TransferDirection::Receive,
+            file_number: 1,
+            control_type: ControlType::Accept
+        }
+    );
+
+    encode_decode_test!(
+        file_control_seek_encode_decode,
+        FileControl {
+            transfer_direction: TransferDirection::Receive,
+            file_number: 1,
+            control_type: ControlType::Seek(12345)
+        }
+    );
+
+    #[test]
+    fn file_control_invalid_control_type() {
+        assert!(FileControl::from_bytes(&[0x51, 0, 1, 4]).is_err());
+    }
+
+    #[test]
+    fn transfer_direction_toggle() {
+        assert_eq!(TransferDirection::Send.toggle(), TransferDirection::Receive);
+        assert_eq!(TransferDirection::Receive.toggle(), TransferDirection::Send);
+    }
+}
@@ -0,0 +1,105 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! FileData struct
+*/
+
+use nom::{be_u8, rest};
+
+use toxcore::binary_io::*;
+use toxcore::dht::packet::MAX_CRYPTO_DATA_SIZE;
+
+/// Maximum size of file data in one `FileData` packet.
+pub const MAX_FILE_DATA_SIZE: usize = MAX_CRYPTO_DATA_SIZE - 2;
+
+/** FileData packet contains a chunk of a file. All chunks except the last
+one should have `MAX_FILE_DATA_SIZE` length so that receiver can detect the
+end of file with unknown size.
+
+Serialized form:
+
+Length      | Content
+----------- | ------
+`1`         | `0x52`
+`1`         | File number
+`[0, 1371]` | Data
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileData {
+    /// Number of the file
+    pub file_number: u8,
+    /// Chunk of the file
+    pub data: Vec<u8>,
+}
+
+impl FromBytes for FileData {
+    named!(from_bytes<FileData>, do_parse!(
+        tag!("\x52") >>
+        file_number: be_u8 >>
+        data: verify!(rest, |data: &[u8]| data.len() <= MAX_FILE_DATA_SIZE) >>
+        (FileData {
+            file_number,
+            data: data.to_vec(),
+        })
+    ));
+}
+
+impl ToBytes for FileData {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_cond!(self.data.len() > MAX_FILE_DATA_SIZE, |buf| gen_error(buf, 0)) >>
+            gen_be_u8!(0x52) >>
+            gen_be_u8!(self.file_number) >>
+            gen_slice!(self.data)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        file_data_encode_decode,
+        FileData {
+            file_number: 1,
+            data: vec![42; 123]
+        }
+    );
+
+    encode_decode_test!(
+        file_data_empty_encode_decode,
+        FileData {
+            file_number: 1,
+            data: Vec::new()
+        }
+    );
+
+    #[test]
+    fn file_data_too_long() {
+        let packet = FileData {
+            file_number: 1,
+            data: vec![42; MAX_FILE_DATA_SIZE + 1]
+        };
+        let mut buf = [0; MAX_FILE_DATA_SIZE + 3];
+        assert!(packet.to_bytes((&mut buf, 0)).is_err());
+    }
+}
@@ -0,0 +1,163 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! FileSendRequest struct
+*/
+
+use nom::{be_u8, be_u32, be_u64, rest};
+
+use std::str;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::randombytes_into;
+
+/// Length of file id in bytes.
+pub const FILE_ID_LENGTH: usize = 32;
+
+/// Maximum length of a file name in bytes.
+pub const MAX_FILENAME_LENGTH: usize = 255;
+
+/** Unique id of a file that is used to resume broken file transfers. The same
+file should be sent with the same id so that receiver can match it with
+partially received file.
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FileId(pub [u8; FILE_ID_LENGTH]);
+
+impl FileId {
+    /// Create new random `FileId`.
+    pub fn new() -> FileId {
+        let mut bytes = [0; FILE_ID_LENGTH];
+        randombytes_into(&mut bytes);
+        FileId(bytes)
+    }
+}
+
+impl Default for FileId {
+    fn default() -> FileId {
+        FileId::new()
+    }
+}
+
+impl FromBytes for FileId {
+    named!(from_bytes<FileId>, map!(take!(FILE_ID_LENGTH), |bytes| {
+        let mut file_id = [0; FILE_ID_LENGTH];
+        file_id.copy_from_slice(bytes);
+        FileId(file_id)
+    }));
+}
+
+/** FileSendRequest packet is used to offer a file to a friend. Transfer starts
+when the friend accepts it with `FileControl` packet.
+
+File size equal to `u64::MAX` means that the size is unknown, e.g. when file
+is streamed.
+
+Serialized form:
+
+Length      | Content
+----------- | ------
+`1`         | `0x50`
+`1`         | File number
+`4`         | File type
+`8`         | File size
+`32`        | File id
+`[0, 255]`  | File name in UTF-8
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileSendRequest {
+    /// Number of the file that is used in other file transfer packets
+    pub file_number: u8,
+    /// Type of the file, 0 for data files and 1 for avatars
+    pub file_type: u32,
+    /// Size of the file in bytes
+    pub file_size: u64,
+    /// Unique id of the file
+    pub file_id: FileId,
+    /// Name of the file
+    pub file_name: String,
+}
+
+impl FromBytes for FileSendRequest {
+    named!(from_bytes<FileSendRequest>, do_parse!(
+        tag!("\x50") >>
+        file_number: be_u8 >>
+        file_type: be_u32 >>
+        file_size: be_u64 >>
+        file_id: call!(FileId::from_bytes) >>
+        file_name: map_res!(verify!(rest, |file_name: &[u8]| file_name.len() <= MAX_FILENAME_LENGTH), str::from_utf8) >>
+        (FileSendRequest {
+            file_number,
+            file_type,
+            file_size,
+            file_id,
+            file_name: file_name.to_string(),
+        })
+    ));
+}
+
+impl ToBytes for FileSendRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_cond!(self.file_name.len() > MAX_FILENAME_LENGTH, |buf| gen_error(buf, 0)) >>
+            gen_be_u8!(0x50) >>
+            gen_be_u8!(self.file_number) >>
+            gen_be_u32!(self.file_type) >>
+            gen_be_u64!(self.file_size) >>
+            gen_slice!(self.file_id.0) >>
+            gen_slice!(self.file_name.as_bytes())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        file_send_request_encode_decode,
+        FileSendRequest {
+            file_number: 1,
+            file_type: 0,
+            file_size: 12345,
+            file_id: FileId::new(),
+            file_name: "file.txt".to_string()
+        }
+    );
+
+    #[test]
+    fn file_send_request_too_long_name() {
+        let packet = FileSendRequest {
+            file_number: 1,
+            file_type: 0,
+            file_size: 12345,
+            file_id: FileId::new(),
+            file_name: "x".repeat(MAX_FILENAME_LENGTH + 1)
+        };
+        let mut buf = [0; MAX_FILENAME_LENGTH + 47];
+        assert!(packet.to_bytes((&mut buf, 0)).is_err());
+    }
+
+    #[test]
+    fn file_id_new() {
+        assert_ne!(FileId::new(), FileId::new());
+    }
+}
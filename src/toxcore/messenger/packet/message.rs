@@ -0,0 +1,90 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Message struct
+*/
+
+use nom::rest;
+
+use std::str;
+
+use toxcore::binary_io::*;
+use toxcore::messenger::packet::MAX_MESSAGE_LENGTH;
+
+/** Message packet is used to send a text message to a friend. Long messages
+are split into several packets.
+
+Serialized form:
+
+Length      | Content
+----------- | ------
+`1`         | `0x40`
+`[1, 1372]` | Text of the message in UTF-8
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Message {
+    /// Text of the message
+    pub message: String,
+}
+
+impl FromBytes for Message {
+    named!(from_bytes<Message>, do_parse!(
+        tag!("\x40") >>
+        message: map_res!(verify!(rest, |message: &[u8]| !message.is_empty() && message.len() <= MAX_MESSAGE_LENGTH), str::from_utf8) >>
+        (Message { message: message.to_string() })
+    ));
+}
+
+impl ToBytes for Message {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_cond!(self.message.is_empty() || self.message.len() > MAX_MESSAGE_LENGTH, |buf| gen_error(buf, 0)) >>
+            gen_be_u8!(0x40) >>
+            gen_slice!(self.message.as_bytes())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        message_encode_decode,
+        Message {
+            message: "Hi there".to_string()
+        }
+    );
+
+    #[test]
+    fn message_too_long() {
+        let packet = Message {
+            message: "x".repeat(MAX_MESSAGE_LENGTH + 1)
+        };
+        let mut buf = [0; MAX_MESSAGE_LENGTH + 2];
+        assert!(packet.to_bytes((&mut buf, 0)).is_err());
+    }
+
+    #[test]
+    fn message_invalid_utf8() {
+        assert!(Message::from_bytes(&[0x40, 0xff, 0xfe]).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Messenger packets that are sent over net crypto connection as lossless
+packets. It includes file transfer packets.
+*/
+
+mod online;
+mod offline;
+mod nickname;
+mod status_message;
+mod user_status;
+mod typing;
+mod message;
+mod action;
+mod file_send_request;
+mod file_control;
+mod file_data;
+
+pub use self::online::*;
+pub use self::offline::*;
+pub use self::nickname::*;
+pub use self::status_message::*;
+pub use self::user_status::*;
+pub use self::typing::*;
+pub use self::message::*;
+pub use self::action::*;
+pub use self::file_send_request::*;
+pub use self::file_control::*;
+pub use self::file_data::*;
+
+use toxcore::binary_io::*;
+use toxcore::dht::packet::MAX_CRYPTO_DATA_SIZE;
+
+/// Maximum length of a single message or action in bytes. Longer messages
+/// should be split into several packets.
+pub const MAX_MESSAGE_LENGTH: usize = MAX_CRYPTO_DATA_SIZE - 1;
+
+/** Messenger packet enum that encapsulates all types of packets handled by
+messenger module.
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Packet {
+    /// [`Online`](./struct.Online.html) structure.
+    Online(Online),
+    /// [`Offline`](./struct.Offline.html) structure.
+    Offline(Offline),
+    /// [`Nickname`](./struct.Nickname.html) structure.
+    Nickname(Nickname),
+    /// [`StatusMessage`](./struct.StatusMessage.html) structure.
+    StatusMessage(StatusMessage),
+    /// [`UserStatus`](./struct.UserStatus.html) structure.
+    UserStatus(UserStatus),
+    /// [`Typing`](./struct.Typing.html) structure.
+    Typing(Typing),
+    /// [`Message`](./struct.Message.html) structure.
+    Message(Message),
+    /// [`Action`](./struct.Action.html) structure.
+    Action(Action),
+    /// [`FileSendRequest`](./struct.FileSendRequest.html) structure.
+    FileSendRequest(FileSendRequest),
+    /// [`FileControl`](./struct.FileControl.html) structure.
+    FileControl(FileControl),
+    /// [`FileData`](./struct.FileData.html) structure.
+    FileData(FileData),
+}
+
+impl FromBytes for Packet {
+    named!(from_bytes<Packet>, alt!(
+        map!(Online::from_bytes, Packet::Online) |
+        map!(Offline::from_bytes, Packet::Offline) |
+        map!(Nickname::from_bytes, Packet::Nickname) |
+        map!(StatusMessage::from_bytes, Packet::StatusMessage) |
+        map!(UserStatus::from_bytes, Packet::UserStatus) |
+        map!(Typing::from_bytes, Packet::Typing) |
+        map!(Message::from_bytes, Packet::Message) |
+        map!(Action::from_bytes, Packet::Action) |
+        map!(FileSendRequest::from_bytes, Packet::FileSendRequest) |
+        map!(FileControl::from_bytes, Packet::FileControl) |
+        map!(FileData::from_bytes, Packet::FileData)
+    ));
+}
+
+impl ToBytes for Packet {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        match *self {
+            Packet::Online(ref p) => p.to_bytes(buf),
+            Packet::Offline(ref p) => p.to_bytes(buf),
+            Packet::Nickname(ref p) => p.to_bytes(buf),
+            Packet::StatusMessage(ref p) => p.to_bytes(buf),
+            Packet::UserStatus(ref p) => p.to_bytes(buf),
+            Packet::Typing(ref p) => p.to_bytes(buf),
+            Packet::Message(ref p) => p.to_bytes(buf),
+            Packet::Action(ref p) => p.to_bytes(buf),
+            Packet::FileSendRequest(ref p) => p.to_bytes(buf),
+            Packet::FileControl(ref p) => p.to_bytes(buf),
+            Packet::FileData(ref p) => p.to_bytes(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        packet_online_encode_decode,
+        Packet::Online(Online)
+    );
+
+    encode_decode_test!(
+        packet_offline_encode_decode,
+        Packet::Offline(Offline)
+    );
+
+    encode_decode_test!(
+        packet_nickname_encode_decode,
+        Packet::Nickname(Nickname {
+            nickname: "Alice".to_string()
+        })
+    );
+
+    encode_decode_test!(
+        packet_status_message_encode_decode,
+        Packet::StatusMessage(StatusMessage {
+            message: "Hello".to_string()
+        })
+    );
+
+    encode_decode_test!(
+        packet_user_status_encode_decode,
+        Packet::UserStatus(UserStatus {
+            status: UserStatusKind::Busy
+        })
+    );
+
+    encode_decode_test!(
+        packet_typing_encode_decode,
+        Packet::Typing(Typing {
+            is_typing: false
+        })
+    );
+
+    encode_decode_test!(
+        packet_message_encode_decode,
+        Packet::Message(Message {
+            message: "Hi there".to_string()
+        })
+    );
+
+    encode_decode_test!(
+        packet_action_encode_decode,
+        Packet::Action(Action {
+            action: "waves".to_string()
+        })
+    );
+
+    encode_decode_test!(
+        packet_file_send_request_encode_decode,
+        Packet::FileSendRequest(FileSendRequest {
+            file_number: 1,
+            file_type: 0,
+            file_size: 12345,
+            file_id: FileId::new(),
+            file_name: "file.txt".to_string()
+        })
+    );
+
+    encode_decode_test!(
+        packet_file_control_encode_decode,
+        Packet::FileControl(FileControl {
+            transfer_direction: TransferDirection::Send,
+            file_number: 1,
+            control_type: ControlType::Pause
+        })
+    );
+
+    encode_decode_test!(
+        packet_file_data_encode_decode,
+        Packet::FileData(FileData {
+            file_number: 1,
+            data: vec![42; 123]
+        })
+    );
+}
@@ -0,0 +1,92 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Nickname struct
+*/
+
+use nom::rest;
+
+use std::str;
+
+use toxcore::binary_io::*;
+
+/// Maximum length of a nickname in bytes.
+pub const MAX_NAME_LENGTH: usize = 128;
+
+/** Nickname packet is used to send our nickname to a friend. It's sent every
+time the friend goes online and when we change our nickname.
+
+Serialized form:
+
+Length      | Content
+----------- | ------
+`1`         | `0x30`
+`[0, 128]`  | Our nickname in UTF-8
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Nickname {
+    /// Our nickname
+    pub nickname: String,
+}
+
+impl FromBytes for Nickname {
+    named!(from_bytes<Nickname>, do_parse!(
+        tag!("\x30") >>
+        nickname: map_res!(verify!(rest, |nickname: &[u8]| nickname.len() <= MAX_NAME_LENGTH), str::from_utf8) >>
+        (Nickname { nickname: nickname.to_string() })
+    ));
+}
+
+impl ToBytes for Nickname {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_cond!(self.nickname.len() > MAX_NAME_LENGTH, |buf| gen_error(buf, 0)) >>
+            gen_be_u8!(0x30) >>
+            gen_slice!(self.nickname.as_bytes())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        nickname_encode_decode,
+        Nickname {
+            nickname: "Alice".to_string()
+        }
+    );
+
+    #[test]
+    fn nickname_too_long() {
+        let packet = Nickname {
+            nickname: "x".repeat(MAX_NAME_LENGTH + 1)
+        };
+        let mut buf = [0; MAX_NAME_LENGTH + 2];
+        assert!(packet.to_bytes((&mut buf, 0)).is_err());
+    }
+
+    #[test]
+    fn nickname_invalid_utf8() {
+        assert!(Nickname::from_bytes(&[0x30, 0xff, 0xfe]).is_err());
+    }
+}
@@ -0,0 +1,62 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Offline struct
+*/
+
+use toxcore::binary_io::*;
+
+/** Offline packet is sent to a friend when we go offline for him, e.g. when we
+remove him from our friend list.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`1`    | `0x19`
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Offline;
+
+impl FromBytes for Offline {
+    named!(from_bytes<Offline>, do_parse!(
+        tag!("\x19") >>
+        (Offline)
+    ));
+}
+
+impl ToBytes for Offline {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x19)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        offline_encode_decode,
+        Offline
+    );
+}
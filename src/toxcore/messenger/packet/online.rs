@@ -0,0 +1,63 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Online struct
+*/
+
+use toxcore::binary_io::*;
+
+/** Online packet is sent to a friend right after the crypto connection to him
+becomes established. Friend is considered online only after we receive this
+packet from him.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`1`    | `0x18`
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Online;
+
+impl FromBytes for Online {
+    named!(from_bytes<Online>, do_parse!(
+        tag!("\x18") >>
+        (Online)
+    ));
+}
+
+impl ToBytes for Online {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x18)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        online_encode_decode,
+        Online
+    );
+}
@@ -0,0 +1,92 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! StatusMessage struct
+*/
+
+use nom::rest;
+
+use std::str;
+
+use toxcore::binary_io::*;
+
+/// Maximum length of a status message in bytes.
+pub const MAX_STATUS_MESSAGE_LENGTH: usize = 1007;
+
+/** StatusMessage packet is used to send our status message to a friend. It's
+sent every time the friend goes online and when we change our status message.
+
+Serialized form:
+
+Length      | Content
+----------- | ------
+`1`         | `0x31`
+`[0, 1007]` | Our status message in UTF-8
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StatusMessage {
+    /// Our status message
+    pub message: String,
+}
+
+impl FromBytes for StatusMessage {
+    named!(from_bytes<StatusMessage>, do_parse!(
+        tag!("\x31") >>
+        message: map_res!(verify!(rest, |message: &[u8]| message.len() <= MAX_STATUS_MESSAGE_LENGTH), str::from_utf8) >>
+        (StatusMessage { message: message.to_string() })
+    ));
+}
+
+impl ToBytes for StatusMessage {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_cond!(self.message.len() > MAX_STATUS_MESSAGE_LENGTH, |buf| gen_error(buf, 0)) >>
+            gen_be_u8!(0x31) >>
+            gen_slice!(self.message.as_bytes())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        status_message_encode_decode,
+        StatusMessage {
+            message: "Hello".to_string()
+        }
+    );
+
+    #[test]
+    fn status_message_too_long() {
+        let packet = StatusMessage {
+            message: "x".repeat(MAX_STATUS_MESSAGE_LENGTH + 1)
+        };
+        let mut buf = [0; MAX_STATUS_MESSAGE_LENGTH + 2];
+        assert!(packet.to_bytes((&mut buf, 0)).is_err());
+    }
+
+    #[test]
+    fn status_message_invalid_utf8() {
+        assert!(StatusMessage::from_bytes(&[0x31, 0xff, 0xfe]).is_err());
+    }
+}
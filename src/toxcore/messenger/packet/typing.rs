@@ -0,0 +1,78 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Typing struct
+*/
+
+use nom::be_u8;
+
+use toxcore::binary_io::*;
+
+/** Typing packet is used to notify a friend that we started or stopped typing
+a message to him.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`1`    | `0x33`
+`1`    | Whether we are typing (0 or 1)
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Typing {
+    /// Whether we are typing
+    pub is_typing: bool,
+}
+
+impl FromBytes for Typing {
+    named!(from_bytes<Typing>, do_parse!(
+        tag!("\x33") >>
+        is_typing: verify!(be_u8, |is_typing| is_typing <= 1) >>
+        eof!() >>
+        (Typing { is_typing: is_typing == 1 })
+    ));
+}
+
+impl ToBytes for Typing {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x33) >>
+            gen_be_u8!(self.is_typing as u8)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        typing_encode_decode,
+        Typing {
+            is_typing: true
+        }
+    );
+
+    #[test]
+    fn typing_invalid_value() {
+        assert!(Typing::from_bytes(&[0x33, 2]).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! UserStatus struct
+*/
+
+use nom::be_u8;
+
+use toxcore::binary_io::*;
+
+/// Status of a user that is shown to his friends.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UserStatusKind {
+    /// User is online and available
+    Online = 0,
+    /// User is away
+    Away = 1,
+    /// User is busy and doesn't want to be disturbed
+    Busy = 2,
+}
+
+impl FromBytes for UserStatusKind {
+    named!(from_bytes<UserStatusKind>, switch!(be_u8,
+        0 => value!(UserStatusKind::Online) |
+        1 => value!(UserStatusKind::Away) |
+        2 => value!(UserStatusKind::Busy)
+    ));
+}
+
+/** UserStatus packet is used to send our status to a friend. It's sent every
+time the friend goes online and when we change our status.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`1`    | `0x32`
+`1`    | Status (0 = online, 1 = away, 2 = busy)
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UserStatus {
+    /// Our status
+    pub status: UserStatusKind,
+}
+
+impl FromBytes for UserStatus {
+    named!(from_bytes<UserStatus>, do_parse!(
+        tag!("\x32") >>
+        status: call!(UserStatusKind::from_bytes) >>
+        eof!() >>
+        (UserStatus { status })
+    ));
+}
+
+impl ToBytes for UserStatus {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x32) >>
+            gen_be_u8!(self.status as u8)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        user_status_encode_decode,
+        UserStatus {
+            status: UserStatusKind::Away
+        }
+    );
+
+    #[test]
+    fn user_status_invalid_status() {
+        assert!(UserStatus::from_bytes(&[0x32, 3]).is_err());
+    }
+}
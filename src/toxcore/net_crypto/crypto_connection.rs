@@ -47,6 +47,35 @@ pub const DEFAULT_RTT: u64 = 1000;
 /// rtt in milliseconds for TCP connections
 pub const TCP_RTT: u64 = 500;
 
+/// Minimal rate of sending lossless packets in packets per second
+pub const CRYPTO_PACKET_MIN_RATE: f64 = 4.0;
+
+/// Minimal number of lossless packets that can be sent without waiting for
+/// the send rate to allow it
+pub const CRYPTO_MIN_QUEUE_LENGTH: u32 = 64;
+
+/// Interval in milliseconds between recalculations of packets rates
+pub const PACKET_COUNTER_AVERAGE_INTERVAL: u64 = 50;
+
+/// Number of packets rates recalculations that are used to calculate new send
+/// rate
+pub const CONGESTION_QUEUE_ARRAY_SIZE: usize = 12;
+
+/// Number of packets rates recalculations for which numbers of sent and resent
+/// packets are stored
+pub const CONGESTION_LAST_SENT_ARRAY_SIZE: usize = CONGESTION_QUEUE_ARRAY_SIZE * 2;
+
+/// Send rate is decreasing for this amount of time in milliseconds after
+/// congestion event happened
+pub const CONGESTION_EVENT_TIMEOUT: u64 = 1000;
+
+/// If the send queue is `SEND_QUEUE_RATIO` times longer than the number of
+/// packets that can be sent in a second then the send rate is reduced
+pub const SEND_QUEUE_RATIO: f64 = 2.0;
+
+/// Constant that is used to calculate the interval between request packets
+pub const REQUEST_PACKETS_COMPARE_CONSTANT: f64 = 0.125 * 100.0;
+
 /// Packet that should be sent every second. Depending on `ConnectionStatus` it
 /// can be `CookieRequest` or `CryptoHandshake`
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -228,10 +257,13 @@ It can use both UDP and TCP (over relays) transport protocols to send data and
 can switch between them without the peers needing to disconnect and reconnect.
 
 */
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct CryptoConnection {
     /// Precomputed key of our DHT `SecretKey` and peer's DHT `PublicKey`
     pub dht_precomputed_key: PrecomputedKey,
+    /// Precomputed key of our long term `SecretKey` and peer's long term
+    /// `PublicKey` that is used to encrypt `CryptoHandshake` packets
+    pub real_precomputed_key: PrecomputedKey,
     /// Long term `PublicKey` of the peer we are connected to
     pub peer_real_pk: PublicKey,
     /// DHT `PublicKey` of the peer we are connected to
@@ -248,6 +280,9 @@ pub struct CryptoConnection {
     pub udp_received_time: Option<Instant>,
     /// Time when we made an attempt to send UDP packet
     pub udp_send_attempt_time: Option<Instant>,
+    /// Whether UDP was alive last time when we notified about connection
+    /// status change. Used to detect switching between UDP and TCP
+    pub udp_connected: bool,
     /// Buffer of sent packets
     pub send_array: PacketsArray<SentPacket>,
     /// Buffer of received packets
@@ -255,13 +290,65 @@ pub struct CryptoConnection {
     /// Round trip time - the lowest (for all packets) difference between time
     /// when a packet was sent and time when we received the confirmation
     pub rtt: Duration,
+    /// Number of data packets received since the last recalculation of
+    /// packets rates
+    pub packet_counter: u32,
+    /// Time when packets rates were recalculated last time
+    pub packet_counter_set_time: Instant,
+    /// Rate of receiving data packets in packets per second
+    pub packet_recv_rate: f64,
+    /// Number of new lossless packets sent since the last recalculation of
+    /// packets rates
+    pub packets_sent: u32,
+    /// Number of lossless packets resent since the last recalculation of
+    /// packets rates
+    pub packets_resent: u32,
+    /// Sizes of the send queue at the last recalculations of packets rates
+    pub last_sendqueue_size: [u32; CONGESTION_QUEUE_ARRAY_SIZE],
+    /// Numbers of new lossless packets sent between the last recalculations of
+    /// packets rates
+    pub last_num_packets_sent: [u32; CONGESTION_LAST_SENT_ARRAY_SIZE],
+    /// Numbers of lossless packets resent between the last recalculations of
+    /// packets rates
+    pub last_num_packets_resent: [u32; CONGESTION_LAST_SENT_ARRAY_SIZE],
+    /// Counter of packets rates recalculations that is used as index for the
+    /// arrays above
+    pub last_sendqueue_counter: usize,
+    /// Rate of sending new lossless packets in packets per second
+    pub packet_send_rate: f64,
+    /// Rate of resending requested lossless packets in packets per second
+    pub packet_send_rate_requested: f64,
+    /// Number of new lossless packets that can be sent right now
+    pub packets_left: u32,
+    /// Number of requested lossless packets that can be resent right now
+    pub packets_left_requested: u32,
+    /// Fractional part of `packets_left` that is left after the last update
+    pub packets_left_rem: f64,
+    /// Fractional part of `packets_left_requested` that is left after the
+    /// last update
+    pub packets_left_requested_rem: f64,
+    /// Time when `packets_left` and `packets_left_requested` were updated last
+    /// time
+    pub last_packets_left_set: Instant,
+    /// Time when we had to resend more packets than the send rate allows
+    pub last_congestion_event: Option<Instant>,
+    /// Time when we sent the last request packet
+    pub last_request_packet_sent: Option<Instant>,
 }
 
 impl CryptoConnection {
     /// Create new `CryptoConnection` with `CookieRequesting` status. This
     /// function is used when we initiate crypto connection with a friend.
-    pub fn new(dht_sk: SecretKey, dht_pk: PublicKey, real_pk: PublicKey, peer_real_pk: PublicKey, peer_dht_pk: PublicKey) -> CryptoConnection {
+    pub fn new(
+        dht_sk: SecretKey,
+        dht_pk: PublicKey,
+        real_sk: SecretKey,
+        real_pk: PublicKey,
+        peer_real_pk: PublicKey,
+        peer_dht_pk: PublicKey
+    ) -> CryptoConnection {
         let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let real_precomputed_key = precompute(&peer_real_pk, &real_sk);
         let (session_pk, session_sk) = gen_keypair();
 
         let cookie_request_id = random_u64();
@@ -277,6 +364,7 @@ impl CryptoConnection {
 
         CryptoConnection {
             dht_precomputed_key,
+            real_precomputed_key,
             peer_real_pk,
             peer_dht_pk,
             session_sk,
@@ -285,9 +373,28 @@ impl CryptoConnection {
             udp_addr: None,
             udp_received_time: None,
             udp_send_attempt_time: None,
+            udp_connected: false,
             send_array: PacketsArray::new(),
             recv_array: PacketsArray::new(),
             rtt: Duration::from_millis(DEFAULT_RTT),
+            packet_counter: 0,
+            packet_counter_set_time: clock_now(),
+            packet_recv_rate: 0.0,
+            packets_sent: 0,
+            packets_resent: 0,
+            last_sendqueue_size: [0; CONGESTION_QUEUE_ARRAY_SIZE],
+            last_num_packets_sent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
+            last_num_packets_resent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
+            last_sendqueue_counter: 0,
+            packet_send_rate: CRYPTO_PACKET_MIN_RATE,
+            packet_send_rate_requested: CRYPTO_PACKET_MIN_RATE,
+            packets_left: CRYPTO_MIN_QUEUE_LENGTH,
+            packets_left_requested: CRYPTO_MIN_QUEUE_LENGTH,
+            packets_left_rem: 0.0,
+            packets_left_requested_rem: 0.0,
+            last_packets_left_set: clock_now(),
+            last_congestion_event: None,
+            last_request_packet_sent: None,
         }
     }
 
@@ -296,6 +403,7 @@ impl CryptoConnection {
     /// create `CryptoConnection` yet.
     pub fn new_not_confirmed(
         dht_sk: SecretKey,
+        real_sk: SecretKey,
         peer_real_pk: PublicKey,
         peer_dht_pk: PublicKey,
         received_nonce: Nonce,
@@ -304,6 +412,7 @@ impl CryptoConnection {
         symmetric_key: &secretbox::Key
     ) -> CryptoConnection {
         let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let real_precomputed_key = precompute(&peer_real_pk, &real_sk);
         let (session_pk, session_sk) = gen_keypair();
         let sent_nonce = gen_nonce();
 
@@ -315,7 +424,7 @@ impl CryptoConnection {
             cookie_hash: cookie.hash(),
             cookie: our_encrypted_cookie,
         };
-        let handshake = CryptoHandshake::new(&dht_precomputed_key, handshake_payload, cookie);
+        let handshake = CryptoHandshake::new(&real_precomputed_key, handshake_payload, cookie);
         let status = ConnectionStatus::NotConfirmed {
             sent_nonce,
             received_nonce,
@@ -326,6 +435,7 @@ impl CryptoConnection {
 
         CryptoConnection {
             dht_precomputed_key,
+            real_precomputed_key,
             peer_real_pk,
             peer_dht_pk,
             session_sk,
@@ -334,9 +444,28 @@ impl CryptoConnection {
             udp_addr: None,
             udp_received_time: None,
             udp_send_attempt_time: None,
+            udp_connected: false,
             send_array: PacketsArray::new(),
             recv_array: PacketsArray::new(),
             rtt: Duration::from_millis(DEFAULT_RTT),
+            packet_counter: 0,
+            packet_counter_set_time: clock_now(),
+            packet_recv_rate: 0.0,
+            packets_sent: 0,
+            packets_resent: 0,
+            last_sendqueue_size: [0; CONGESTION_QUEUE_ARRAY_SIZE],
+            last_num_packets_sent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
+            last_num_packets_resent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
+            last_sendqueue_counter: 0,
+            packet_send_rate: CRYPTO_PACKET_MIN_RATE,
+            packet_send_rate_requested: CRYPTO_PACKET_MIN_RATE,
+            packets_left: CRYPTO_MIN_QUEUE_LENGTH,
+            packets_left_requested: CRYPTO_MIN_QUEUE_LENGTH,
+            packets_left_rem: 0.0,
+            packets_left_requested_rem: 0.0,
+            last_packets_left_set: clock_now(),
+            last_congestion_event: None,
+            last_request_packet_sent: None,
         }
     }
 
@@ -370,6 +499,147 @@ impl CryptoConnection {
         }
     }
 
+    /** Recalculate rate of receiving data packets and rates of sending
+    lossless packets if `PACKET_COUNTER_AVERAGE_INTERVAL` is elapsed since the
+    last recalculation.
+
+    The send rate is calculated from the number of packets that were sent
+    during the last `CONGESTION_QUEUE_ARRAY_SIZE` intervals shifted by rtt and
+    reduced by the growth of the send queue. So it's approximately the rate
+    with which the other side receives our packets. The rate is increased if
+    there were no congestion events recently and decreased otherwise.
+
+    */
+    pub fn update_packets_rates(&mut self) {
+        let elapsed = clock_elapsed(self.packet_counter_set_time);
+        if elapsed <= Duration::from_millis(PACKET_COUNTER_AVERAGE_INTERVAL) {
+            return
+        }
+
+        self.packet_recv_rate = f64::from(self.packet_counter) / duration_secs(elapsed);
+        self.packet_counter = 0;
+        self.packet_counter_set_time = clock_now();
+
+        let send_queue_size = self.send_array.len();
+
+        // growth of the send queue during the last CONGESTION_QUEUE_ARRAY_SIZE intervals
+        let pos = self.last_sendqueue_counter % CONGESTION_QUEUE_ARRAY_SIZE;
+        self.last_sendqueue_size[pos] = send_queue_size;
+        let sum = i64::from(self.last_sendqueue_size[pos]) -
+            i64::from(self.last_sendqueue_size[(pos + 1) % CONGESTION_QUEUE_ARRAY_SIZE]);
+
+        let pos = self.last_sendqueue_counter % CONGESTION_LAST_SENT_ARRAY_SIZE;
+        self.last_num_packets_sent[pos] = self.packets_sent;
+        self.last_num_packets_resent[pos] = self.packets_resent;
+        self.packets_sent = 0;
+        self.packets_resent = 0;
+
+        self.last_sendqueue_counter = (self.last_sendqueue_counter + 1) %
+            (CONGESTION_QUEUE_ARRAY_SIZE * CONGESTION_LAST_SENT_ARRAY_SIZE);
+
+        // packets sent rtt ago are the packets that the other side receives now
+        let packets_set_rem_array = CONGESTION_LAST_SENT_ARRAY_SIZE - CONGESTION_QUEUE_ARRAY_SIZE;
+        let delay = (duration_secs(self.rtt) * 1000.0 / PACKET_COUNTER_AVERAGE_INTERVAL as f64 + 0.5) as usize;
+        let delay = delay.min(packets_set_rem_array);
+
+        let mut total_sent = 0;
+        let mut total_resent = 0;
+        for i in 0 .. CONGESTION_QUEUE_ARRAY_SIZE {
+            let index = (i + packets_set_rem_array - delay + self.last_sendqueue_counter) % CONGESTION_LAST_SENT_ARRAY_SIZE;
+            total_sent += i64::from(self.last_num_packets_sent[index]);
+            total_resent += i64::from(self.last_num_packets_resent[index]);
+        }
+
+        if sum > 0 {
+            total_sent -= sum;
+        } else if total_resent > -sum {
+            total_resent = -sum;
+        }
+
+        let interval = CONGESTION_QUEUE_ARRAY_SIZE as f64 * PACKET_COUNTER_AVERAGE_INTERVAL as f64 / 1000.0;
+        let min_speed = (total_sent as f64 / interval).max(CRYPTO_PACKET_MIN_RATE);
+        let min_speed_request = (total_sent + total_resent) as f64 / interval;
+
+        let send_array_ratio = f64::from(send_queue_size) / min_speed;
+
+        let congestion_event_timed_out = self.last_congestion_event
+            .map(|time| clock_elapsed(time) > Duration::from_millis(CONGESTION_EVENT_TIMEOUT))
+            .unwrap_or(true);
+
+        self.packet_send_rate = if send_array_ratio > SEND_QUEUE_RATIO && send_queue_size > CRYPTO_MIN_QUEUE_LENGTH {
+            // the queue is too big
+            min_speed * SEND_QUEUE_RATIO / send_array_ratio
+        } else if congestion_event_timed_out {
+            min_speed * 1.2
+        } else {
+            min_speed * 0.9
+        }.max(CRYPTO_PACKET_MIN_RATE);
+
+        self.packet_send_rate_requested = (min_speed_request * 1.2).max(self.packet_send_rate);
+    }
+
+    /// Increase the numbers of lossless packets that can be sent and resent
+    /// according to the send rates and the time elapsed since the last update.
+    pub fn update_packets_left(&mut self) {
+        let elapsed = duration_secs(clock_elapsed(self.last_packets_left_set));
+        if elapsed < 1.0 / self.packet_send_rate {
+            return
+        }
+
+        fn packets_left(packets_left: &mut u32, rem: &mut f64, send_rate: f64, elapsed: f64) {
+            let n_packets = send_rate * elapsed + *rem;
+            let num_packets = n_packets as u32;
+            *rem = n_packets - f64::from(num_packets);
+            let max_packets_left = num_packets.saturating_mul(4).saturating_add(CRYPTO_MIN_QUEUE_LENGTH);
+            *packets_left = packets_left.saturating_add(num_packets).min(max_packets_left);
+        }
+
+        packets_left(&mut self.packets_left, &mut self.packets_left_rem, self.packet_send_rate, elapsed);
+        packets_left(&mut self.packets_left_requested, &mut self.packets_left_requested_rem, self.packet_send_rate_requested, elapsed);
+        self.last_packets_left_set = clock_now();
+    }
+
+    /// Check if request packet should be sent. The interval between request
+    /// packets depends on the rate of receiving data packets and the number of
+    /// packets in the receive buffer.
+    pub fn request_packet_should_be_sent(&self) -> bool {
+        let last_request_packet_sent = match self.last_request_packet_sent {
+            Some(time) => time,
+            None => return true,
+        };
+
+        let max_interval = (CRYPTO_SEND_PACKET_INTERVAL * 1000) as f64;
+        let interval = if self.packet_recv_rate > CRYPTO_PACKET_MIN_RATE {
+            let interval = REQUEST_PACKETS_COMPARE_CONSTANT /
+                ((f64::from(self.recv_array.len()) + 1.0) / (self.packet_recv_rate + 1.0));
+            let interval_2 = CRYPTO_PACKET_MIN_RATE / self.packet_recv_rate * max_interval +
+                PACKET_COUNTER_AVERAGE_INTERVAL as f64;
+            interval.min(interval_2)
+                .max(PACKET_COUNTER_AVERAGE_INTERVAL as f64)
+                .min(max_interval)
+        } else {
+            max_interval
+        };
+
+        duration_secs(clock_elapsed(last_request_packet_sent)) * 1000.0 > interval
+    }
+
+    /// Check if this connection is in `Established` status
+    pub fn is_established(&self) -> bool {
+        match self.status {
+            ConnectionStatus::Established { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Check if this connection is in `NotConfirmed` status
+    pub fn is_not_confirmed(&self) -> bool {
+        match self.status {
+            ConnectionStatus::NotConfirmed { .. } => true,
+            _ => false,
+        }
+    }
+
     /// Set time when last UDP packet was received to now
     pub fn update_udp_received_time(&mut self) {
         self.udp_received_time = Some(clock_now())
@@ -397,6 +667,11 @@ impl CryptoConnection {
     }
 }
 
+/// Get `Duration` in seconds as a floating point number
+fn duration_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -476,10 +751,10 @@ mod tests {
     #[test]
     fn crypto_connection_clone() {
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
         let connection_c = connection.clone();
         assert_eq!(connection_c, connection);
@@ -528,4 +803,166 @@ mod tests {
         let connection_c = connection.clone();
         assert_eq!(connection_c, connection);
     }
+
+    #[test]
+    fn update_packets_rates() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        connection.packet_counter = 10;
+        connection.packets_sent = 100;
+        connection.rtt = Duration::from_millis(0);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            connection.packet_counter_set_time + Duration::from_millis(100)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            connection.update_packets_rates();
+        });
+
+        assert_eq!(connection.packet_recv_rate, 100.0);
+        assert_eq!(connection.packet_counter, 0);
+        assert_eq!(connection.packets_sent, 0);
+        assert_eq!(connection.last_num_packets_sent[0], 100);
+        assert_eq!(connection.last_sendqueue_counter, 1);
+        // 100 packets were sent during 12 intervals of 50 ms which is 166.6
+        // packets per second and the rate is increased by 20%
+        assert_eq!(connection.packet_send_rate, 100.0 / 0.6 * 1.2);
+        assert_eq!(connection.packet_send_rate_requested, 100.0 / 0.6 * 1.2);
+    }
+
+    #[test]
+    fn update_packets_rates_congestion() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        connection.packets_sent = 100;
+        connection.rtt = Duration::from_millis(0);
+
+        let now = connection.packet_counter_set_time + Duration::from_millis(100);
+        connection.last_congestion_event = Some(now);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+
+        with_default(&clock, &mut enter, |_| {
+            connection.update_packets_rates();
+        });
+
+        // the rate is decreased by 10% after congestion event
+        assert_eq!(connection.packet_send_rate, 100.0 / 0.6 * 0.9);
+    }
+
+    #[test]
+    fn update_packets_rates_not_elapsed() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        connection.packet_counter = 10;
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(connection.packet_counter_set_time));
+
+        with_default(&clock, &mut enter, |_| {
+            connection.update_packets_rates();
+        });
+
+        assert_eq!(connection.packet_counter, 10);
+        assert_eq!(connection.packet_send_rate, CRYPTO_PACKET_MIN_RATE);
+    }
+
+    #[test]
+    fn update_packets_left() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        connection.packets_left = 0;
+        connection.packets_left_requested = 0;
+        connection.packet_send_rate = 10.0;
+        connection.packet_send_rate_requested = 20.0;
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            connection.last_packets_left_set + Duration::from_millis(1250)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            connection.update_packets_left();
+        });
+
+        assert_eq!(connection.packets_left, 12);
+        assert_eq!(connection.packets_left_rem, 0.5);
+        assert_eq!(connection.packets_left_requested, 25);
+        assert_eq!(connection.packets_left_requested_rem, 0.0);
+    }
+
+    #[test]
+    fn update_packets_left_max() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        connection.packets_left = 1000;
+        connection.packet_send_rate = 10.0;
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            connection.last_packets_left_set + Duration::from_secs(1)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            connection.update_packets_left();
+        });
+
+        assert_eq!(connection.packets_left, 10 * 4 + CRYPTO_MIN_QUEUE_LENGTH);
+    }
+
+    #[test]
+    fn request_packet_should_be_sent() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        assert!(connection.request_packet_should_be_sent());
+
+        let now = Instant::now();
+        connection.last_request_packet_sent = Some(now);
+
+        let mut enter = tokio_executor::enter().unwrap();
+
+        // with low receive rate request packet is sent every second
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_millis(500)));
+        with_default(&clock, &mut enter, |_| {
+            assert!(!connection.request_packet_should_be_sent());
+        });
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_millis(1001)));
+        with_default(&clock, &mut enter, |_| {
+            assert!(connection.request_packet_should_be_sent());
+        });
+
+        // with high receive rate request packets are sent more often
+        connection.packet_recv_rate = 1000.0;
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_millis(500)));
+        with_default(&clock, &mut enter, |_| {
+            assert!(connection.request_packet_should_be_sent());
+        });
+    }
 }
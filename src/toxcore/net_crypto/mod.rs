@@ -36,22 +36,25 @@ mod packets_array;
 pub use self::crypto_connection::*;
 use self::packets_array::*;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{ErrorKind, Error};
 use std::net::{SocketAddr, IpAddr};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use std::u16;
 
-use futures::Future;
-use futures::future;
+use futures::{Future, Stream};
+use futures::{future, stream};
 use futures::sync::mpsc;
 use parking_lot::RwLock;
+use tokio::timer::Interval;
 
 use toxcore::binary_io::*;
 use toxcore::crypto_core::*;
+use toxcore::dht::codec::MAX_DHT_PACKET_SIZE;
 use toxcore::dht::packet::*;
 use toxcore::io_tokio::*;
+use toxcore::tcp::client::Connections;
 use toxcore::time::*;
 
 /// Maximum size of `DhtPacket` when we try to send it to UDP address even if
@@ -82,10 +85,28 @@ const PACKET_ID_LOSSY_RANGE_START: u8 = 192;
 /// `PACKET_ID_LOSSY_RANGE_END` are considered lossy packets.
 const PACKET_ID_LOSSY_RANGE_END: u8 = 254;
 
+/// How often `main_loop` is called by `run` in milliseconds.
+pub const MAIN_LOOP_INTERVAL: u64 = 50;
+
 /// Shorthand for the transmit half of the message channel for sending DHT
 /// packets.
 type UdpTx = mpsc::UnboundedSender<(DhtPacket, SocketAddr)>;
 
+/// Shorthand for the transmit half of the message channel for sending packets
+/// to TCP relays. The key is a DHT `PublicKey` of the peer that should receive
+/// this packet.
+type TcpTx = mpsc::UnboundedSender<(DhtPacket, PublicKey)>;
+
+/// Shorthand for the receive half of the message channel for receiving packets
+/// that should be sent to TCP relays. The key is a DHT `PublicKey` of the peer
+/// that should receive this packet.
+type TcpRx = mpsc::UnboundedReceiver<(DhtPacket, PublicKey)>;
+
+/// Shorthand for the receive half of the message channel for receiving data
+/// from TCP relays. The key is a DHT `PublicKey` of the peer that sent this
+/// data.
+type TcpDataRx = mpsc::UnboundedReceiver<(PublicKey, Vec<u8>)>;
+
 /// Shorthand for the transmit half of the message channel for sending DHT
 /// `PublicKey` when it gets known. The first key is a long term key, the second
 /// key is a DHT key.
@@ -101,11 +122,73 @@ type LosslessTx = mpsc::UnboundedSender<(PublicKey, Vec<u8>)>;
 /// packet.
 type LossyTx = mpsc::UnboundedSender<(PublicKey, Vec<u8>)>;
 
+/// Shorthand for the transmit half of the message channel for sending long
+/// term and DHT `PublicKey`s of a peer when a new crypto connection initiated
+/// by this peer is accepted.
+type NewConnectionTx = mpsc::UnboundedSender<(PublicKey, PublicKey)>;
+
+/// Shorthand for the transmit half of the message channel for sending
+/// connection status events. The key is a long term `PublicKey` of the peer
+/// the connection belongs to.
+type ConnectionStatusTx = mpsc::UnboundedSender<(PublicKey, ConnectionEvent)>;
+
+/// Event that is sent when status of a crypto connection changes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionEvent {
+    /// Connection became established. It's considered that the connection uses
+    /// TCP relays until `Udp` event is sent
+    Established,
+    /// Established connection switched to direct UDP transport
+    Udp,
+    /// Established connection switched to TCP relays because UDP is not
+    /// alive anymore
+    Tcp,
+    /// Connection was removed because it timed out
+    TimedOut,
+    /// Connection was killed by the peer
+    Killed,
+}
+
+/// Policy that decides whether a crypto connection initiated by a peer we
+/// don't have a connection with should be accepted.
+#[derive(Clone)]
+pub enum AcceptPolicy {
+    /// Accept connections from any peer
+    AcceptAll,
+    /// Accept connections only from peers whose long term `PublicKey` is in
+    /// the set
+    FriendsOnly(Arc<RwLock<HashSet<PublicKey>>>),
+    /// Accept connections only if the callback returns `true` for long term
+    /// `PublicKey` of the peer
+    Callback(Arc<Fn(&PublicKey) -> bool + Send + Sync>),
+}
+
+impl Default for AcceptPolicy {
+    fn default() -> Self {
+        AcceptPolicy::FriendsOnly(Arc::new(RwLock::new(HashSet::new())))
+    }
+}
+
+impl AcceptPolicy {
+    /// Check if connection from the peer with the given long term `PublicKey`
+    /// should be accepted.
+    pub fn accepts(&self, peer_real_pk: &PublicKey) -> bool {
+        match *self {
+            AcceptPolicy::AcceptAll => true,
+            AcceptPolicy::FriendsOnly(ref friends) => friends.read().contains(peer_real_pk),
+            AcceptPolicy::Callback(ref callback) => callback(peer_real_pk),
+        }
+    }
+}
+
 /// Arguments for creating new `NetCrypto`.
 #[derive(Clone)]
 pub struct NetCryptoNewArgs {
     /// Sink to send packet to UDP socket
     pub udp_tx: UdpTx,
+    /// Sink to send packet to TCP relays. The key is a DHT `PublicKey` of the
+    /// peer that should receive this packet.
+    pub tcp_tx: TcpTx,
     /// Sink to send DHT `PublicKey` when it gets known. The first key is a long
     /// term key, the second key is a DHT key. `NetCrypto` module can learn DHT
     /// `PublicKey` of peer from `Cookie` obtained from `CryptoHandshake`
@@ -119,12 +202,20 @@ pub struct NetCryptoNewArgs {
     /// Sink to send lossy packets. The key is a long term public key of the
     /// peer that sent this packet.
     pub lossy_tx: LossyTx,
+    /// Sink to send long term and DHT `PublicKey`s of a peer when we accept
+    /// a new crypto connection initiated by this peer.
+    pub new_connection_tx: NewConnectionTx,
+    /// Sink to send connection status events. The key is a long term
+    /// `PublicKey` of the peer the connection belongs to.
+    pub connection_status_tx: ConnectionStatusTx,
     /// Our DHT `PublicKey`
     pub dht_pk: PublicKey,
     /// Our DHT `SecretKey`
     pub dht_sk: SecretKey,
     /// Our real `PublicKey`
     pub real_pk: PublicKey,
+    /// Our real `SecretKey`
+    pub real_sk: SecretKey,
 }
 
 /// Struct that manages crypto connections to friends and handles net crypto
@@ -133,6 +224,9 @@ pub struct NetCryptoNewArgs {
 pub struct NetCrypto {
     /// Sink to send packet to UDP socket
     udp_tx: UdpTx,
+    /// Sink to send packet to TCP relays. The key is a DHT `PublicKey` of the
+    /// peer that should receive this packet.
+    tcp_tx: TcpTx,
     /// Sink to send DHT `PublicKey` when it gets known. The first key is a long
     /// term key, the second key is a DHT key. `NetCrypto` module can learn DHT
     /// `PublicKey` of peer from `Cookie` obtained from `CryptoHandshake`
@@ -146,12 +240,23 @@ pub struct NetCrypto {
     /// Sink to send lossy packets. The key is a long term public key of the
     /// peer that sent this packet.
     lossy_tx: LossyTx,
+    /// Sink to send long term and DHT `PublicKey`s of a peer when we accept
+    /// a new crypto connection initiated by this peer.
+    new_connection_tx: NewConnectionTx,
+    /// Sink to send connection status events. The key is a long term
+    /// `PublicKey` of the peer the connection belongs to.
+    connection_status_tx: ConnectionStatusTx,
+    /// Policy that decides whether crypto connections initiated by unknown
+    /// peers should be accepted
+    accept_policy: Arc<RwLock<AcceptPolicy>>,
     /// Our DHT `PublicKey`
     dht_pk: PublicKey,
     /// Our DHT `SecretKey`
     dht_sk: SecretKey,
     /// Our real `PublicKey`
     real_pk: PublicKey,
+    /// Our real `SecretKey`
+    real_sk: SecretKey,
     /// Symmetric key used for cookies encryption
     symmetric_key: secretbox::Key,
     /// Connection by long term public key of DHT node map
@@ -166,12 +271,17 @@ impl NetCrypto {
     pub fn new(args: NetCryptoNewArgs) -> NetCrypto {
         NetCrypto {
             udp_tx: args.udp_tx,
+            tcp_tx: args.tcp_tx,
             dht_pk_tx: args.dht_pk_tx,
             lossless_tx: args.lossless_tx,
             lossy_tx: args.lossy_tx,
+            new_connection_tx: args.new_connection_tx,
+            connection_status_tx: args.connection_status_tx,
+            accept_policy: Arc::new(RwLock::new(AcceptPolicy::default())),
             dht_pk: args.dht_pk,
             dht_sk: args.dht_sk,
             real_pk: args.real_pk,
+            real_sk: args.real_sk,
             symmetric_key: secretbox::gen_key(),
             connections: Arc::new(RwLock::new(HashMap::new())),
             keys_by_addr: Arc::new(RwLock::new(HashMap::new()))
@@ -183,6 +293,11 @@ impl NetCrypto {
         send_to(&self.udp_tx, (packet, addr))
     }
 
+    /// Send `DhtPacket` packet to TCP relays
+    fn send_to_tcp(&self, peer_dht_pk: PublicKey, packet: DhtPacket) -> IoFuture<()> {
+        send_to(&self.tcp_tx, (packet, peer_dht_pk))
+    }
+
     /// Get long term `PublicKey` of the peer by its UDP address
     fn key_by_addr(&self, addr: SocketAddr) -> Option<PublicKey> {
         self.keys_by_addr.read().get(&(addr.ip(), addr.port())).cloned()
@@ -193,6 +308,14 @@ impl NetCrypto {
         self.connections.read().get(&pk).cloned()
     }
 
+    /// Get crypto connection by DHT `PublicKey` of the peer
+    fn connection_by_dht_key(&self, dht_pk: PublicKey) -> Option<Arc<RwLock<CryptoConnection>>> {
+        self.connections.read()
+            .values()
+            .find(|connection| connection.read().peer_dht_pk == dht_pk)
+            .cloned()
+    }
+
     /// Create `CookieResponse` packet with `Cookie` requested by `CookieRequest` packet
     fn handle_cookie_request(&self, packet: CookieRequest) -> Result<CookieResponse, Error> {
         let payload = packet.get_payload(&self.dht_sk)?;
@@ -218,6 +341,14 @@ impl NetCrypto {
         }
     }
 
+    /// Handle `CookieRequest` packet received from TCP relay
+    pub fn handle_tcp_cookie_request(&self, packet: CookieRequest, sender_pk: PublicKey) -> IoFuture<()> {
+        match self.handle_cookie_request(packet) {
+            Ok(response) => self.send_to_tcp(sender_pk, DhtPacket::CookieResponse(response)),
+            Err(e) => Box::new(future::err(e))
+        }
+    }
+
     /// Handle `CookieResponse` and if it's correct change connection status to `HandshakeSending`.
     pub fn handle_cookie_response(&self, connection: &mut CryptoConnection, packet: CookieResponse) -> IoFuture<()> {
         let cookie_request_id = if let ConnectionStatus::CookieRequesting { cookie_request_id, .. } = connection.status {
@@ -250,7 +381,7 @@ impl NetCrypto {
             cookie_hash: payload.cookie.hash(),
             cookie: our_encrypted_cookie,
         };
-        let handshake = CryptoHandshake::new(&connection.dht_precomputed_key, handshake_payload, payload.cookie);
+        let handshake = CryptoHandshake::new(&connection.real_precomputed_key, handshake_payload, payload.cookie);
 
         connection.status = ConnectionStatus::HandshakeSending {
             sent_nonce,
@@ -277,8 +408,30 @@ impl NetCrypto {
         }
     }
 
+    /// Handle `CookieResponse` packet received from TCP relay
+    pub fn handle_tcp_cookie_response(&self, packet: CookieResponse, sender_pk: PublicKey) -> IoFuture<()> {
+        if let Some(connection) = self.connection_by_dht_key(sender_pk) {
+            let mut connection = connection.write();
+            self.handle_cookie_response(&mut connection, packet)
+        } else {
+            Box::new(future::err(
+                Error::new(
+                    ErrorKind::Other,
+                    "No crypto connection for TCP sender"
+                )
+            ))
+        }
+    }
+
     /// Handle `CryptoHandshake` and if it's correct change connection status to `NotConfirmed`.
     pub fn handle_crypto_handshake(&self, connection: &mut CryptoConnection, packet: CryptoHandshake) -> IoFuture<()> {
+        self.handle_crypto_handshake_from(connection, packet, None)
+    }
+
+    /// Handle `CryptoHandshake` received from the given UDP address. The
+    /// address of the connection is updated only if the packet is valid so
+    /// that a forged handshake can't redirect the connection.
+    fn handle_crypto_handshake_from(&self, connection: &mut CryptoConnection, packet: CryptoHandshake, addr: Option<SocketAddr>) -> IoFuture<()> {
         if let ConnectionStatus::Established { .. } = connection.status {
             return Box::new(future::err(Error::new(
                 ErrorKind::Other,
@@ -286,7 +439,7 @@ impl NetCrypto {
             )))
         }
 
-        let payload = match packet.get_payload(&connection.dht_precomputed_key) {
+        let payload = match packet.get_payload(&connection.real_precomputed_key) {
             Ok(payload) => payload,
             Err(e) => return Box::new(future::err(e)),
         };
@@ -325,6 +478,11 @@ impl NetCrypto {
             )
         }
 
+        if let Some(addr) = addr {
+            self.set_udp_addr(connection, addr);
+            connection.update_udp_received_time();
+        }
+
         connection.status = match connection.status {
             ConnectionStatus::CookieRequesting { .. } => {
                 let sent_nonce = gen_nonce();
@@ -336,7 +494,7 @@ impl NetCrypto {
                     cookie_hash: payload.cookie.hash(),
                     cookie: our_encrypted_cookie,
                 };
-                let handshake = CryptoHandshake::new(&connection.dht_precomputed_key, handshake_payload, payload.cookie);
+                let handshake = CryptoHandshake::new(&connection.real_precomputed_key, handshake_payload, payload.cookie);
                 ConnectionStatus::NotConfirmed {
                     sent_nonce,
                     received_nonce: payload.base_nonce,
@@ -359,20 +517,98 @@ impl NetCrypto {
         self.send_status_packet(connection)
     }
 
+    /** Handle `CryptoHandshake` packet from a peer we don't have a crypto
+    connection with for its address or DHT `PublicKey`.
+
+    The cookie from the handshake was created by us so it contains long term
+    and DHT `PublicKey`s of the peer. The long term `PublicKey` is taken from
+    the cookie request and can't be trusted until the handshake payload is
+    decrypted with it. If we already have a connection with this long term
+    `PublicKey` the packet is handled by this connection and its UDP address is
+    updated if the packet is valid. Otherwise new connection with `NotConfirmed`
+    status is created if the accept policy allows it and the peer is announced
+    to new connections sink. UDP address is `None` when the packet was received
+    from TCP relay.
+
+    */
+    fn handle_crypto_handshake_new_connection(&self, packet: CryptoHandshake, addr: Option<SocketAddr>) -> IoFuture<()> {
+        let cookie = match packet.cookie.get_payload(&self.symmetric_key) {
+            Ok(cookie) => cookie,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        if cookie.is_timed_out() {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Cookie is timed out"
+            )))
+        }
+
+        if let Some(connection) = self.connection_by_key(cookie.real_pk) {
+            let mut connection = connection.write();
+            return self.handle_crypto_handshake_from(&mut connection, packet, addr)
+        }
+
+        let real_precomputed_key = precompute(&cookie.real_pk, &self.real_sk);
+        let payload = match packet.get_payload(&real_precomputed_key) {
+            Ok(payload) => payload,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        if packet.cookie.hash() != payload.cookie_hash {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Invalid SHA512 hash of cookie"
+            )))
+        }
+
+        if !self.accept_policy.read().accepts(&cookie.real_pk) {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Crypto connection is rejected by accept policy"
+            )))
+        }
+
+        let mut connection = CryptoConnection::new_not_confirmed(
+            self.dht_sk.clone(),
+            self.real_sk.clone(),
+            cookie.real_pk,
+            cookie.dht_pk,
+            payload.base_nonce,
+            payload.session_pk,
+            payload.cookie,
+            &self.symmetric_key
+        );
+        if let Some(addr) = addr {
+            self.set_udp_addr(&mut connection, addr);
+            connection.update_udp_received_time();
+        }
+
+        let send_future = self.send_status_packet(&mut connection);
+        self.connections.write().insert(cookie.real_pk, Arc::new(RwLock::new(connection)));
+
+        let announce_future = send_to(&self.new_connection_tx, (cookie.real_pk, cookie.dht_pk));
+        Box::new(announce_future.join(send_future).map(|_| ()))
+    }
+
     /// Handle `CryptoHandshake` packet received from UDP socket
     pub fn handle_udp_crypto_handshake(&self, packet: CryptoHandshake, addr: SocketAddr) -> IoFuture<()> {
         let connection = self.key_by_addr(addr).and_then(|pk| self.connection_by_key(pk));
         if let Some(connection) = connection {
             let mut connection = connection.write();
-            connection.update_udp_received_time();
+            self.handle_crypto_handshake_from(&mut connection, packet, Some(addr))
+        } else {
+            self.handle_crypto_handshake_new_connection(packet, Some(addr))
+        }
+    }
+
+    /// Handle `CryptoHandshake` packet received from TCP relay
+    pub fn handle_tcp_crypto_handshake(&self, packet: CryptoHandshake, sender_pk: PublicKey) -> IoFuture<()> {
+        if let Some(connection) = self.connection_by_dht_key(sender_pk) {
+            let mut connection = connection.write();
             self.handle_crypto_handshake(&mut connection, packet)
         } else {
-            Box::new(future::err( // TODO: create crypto connection
-                Error::new(
-                    ErrorKind::Other,
-                    format!("No crypto connection for address {}", addr)
-                )
-            ))
+            self.handle_crypto_handshake_new_connection(packet, None)
         }
     }
 
@@ -487,6 +723,8 @@ impl NetCrypto {
             Err(e) => return Box::new(future::err(e))
         };
 
+        connection.packet_counter += 1;
+
         // Find the time when the last acknowledged packet was sent
         let mut last_sent_time = NetCrypto::last_sent_time(&connection.send_array, payload.buffer_start);
 
@@ -510,7 +748,7 @@ impl NetCrypto {
             if let Some(addr) = connection.udp_addr {
                 self.keys_by_addr.write().remove(&(addr.ip(), addr.port()));
             }
-            return Box::new(future::ok(()));
+            return send_to(&self.connection_status_tx, (connection.peer_real_pk, ConnectionEvent::Killed));
         }
 
         // Update nonce if diff is big enough
@@ -518,7 +756,12 @@ impl NetCrypto {
             increment_nonce_number(&mut received_nonce, NONCE_DIFF_THRESHOLD as usize);
         }
 
-        // TODO: connection status notification
+        let status_future = if connection.is_established() {
+            Box::new(future::ok(())) as IoFuture<()>
+        } else {
+            connection.udp_connected = false;
+            send_to(&self.connection_status_tx, (connection.peer_real_pk, ConnectionEvent::Established))
+        };
 
         connection.status = ConnectionStatus::Established {
             sent_nonce,
@@ -561,7 +804,7 @@ impl NetCrypto {
             }
         }
 
-        result
+        Box::new(status_future.join(result).map(|_| ()))
     }
 
     /// Handle `CryptoData` packet received from UDP socket
@@ -581,6 +824,89 @@ impl NetCrypto {
         }
     }
 
+    /// Handle `CryptoData` packet received from TCP relay
+    pub fn handle_tcp_crypto_data(&self, packet: CryptoData, sender_pk: PublicKey) -> IoFuture<()> {
+        if let Some(connection) = self.connection_by_dht_key(sender_pk) {
+            let mut connection = connection.write();
+            self.handle_crypto_data(&mut connection, packet, /* udp */ false)
+        } else {
+            Box::new(future::err(
+                Error::new(
+                    ErrorKind::Other,
+                    "No crypto connection for TCP sender"
+                )
+            ))
+        }
+    }
+
+    /// Handle net crypto packet received from TCP relay as `Data` or
+    /// `OobReceive` packet. `sender_pk` is DHT `PublicKey` of the peer that
+    /// sent this packet.
+    pub fn handle_tcp_packet(&self, packet: DhtPacket, sender_pk: PublicKey) -> IoFuture<()> {
+        match packet {
+            DhtPacket::CookieRequest(packet) => self.handle_tcp_cookie_request(packet, sender_pk),
+            DhtPacket::CookieResponse(packet) => self.handle_tcp_cookie_response(packet, sender_pk),
+            DhtPacket::CryptoHandshake(packet) => self.handle_tcp_crypto_handshake(packet, sender_pk),
+            DhtPacket::CryptoData(packet) => self.handle_tcp_crypto_data(packet, sender_pk),
+            _ => Box::new(future::err(
+                Error::new(
+                    ErrorKind::Other,
+                    "Unexpected packet from TCP relay"
+                )
+            )),
+        }
+    }
+
+    /// Handle data received from TCP relay parsing it as net crypto packet.
+    /// `sender_pk` is DHT `PublicKey` of the peer that sent this data.
+    pub fn handle_tcp_data(&self, data: &[u8], sender_pk: PublicKey) -> IoFuture<()> {
+        match DhtPacket::from_bytes(data) {
+            IResult::Done(_, packet) => self.handle_tcp_packet(packet, sender_pk),
+            _ => Box::new(future::err(
+                Error::new(
+                    ErrorKind::Other,
+                    "Failed to parse packet from TCP relay"
+                )
+            )),
+        }
+    }
+
+    /// Transfer packets between net crypto and TCP relays. Packets from
+    /// `tcp_rx` which should be the receiving half of `tcp_tx` sink are sent
+    /// to peers through `connections`. Data from `data_rx` which should be the
+    /// receiving half of `connections` data sink is handled as net crypto
+    /// packets.
+    pub fn run_tcp(&self, connections: Connections, tcp_rx: TcpRx, data_rx: TcpDataRx) -> IoFuture<()> {
+        let send_future = tcp_rx
+            .map_err(|()| Error::new(ErrorKind::Other, "rx error"))
+            .for_each(move |(packet, peer_dht_pk)| {
+                let mut buf = [0; MAX_DHT_PACKET_SIZE];
+                let future: IoFuture<()> = match packet.to_bytes((&mut buf, 0)) {
+                    Ok((_, size)) => connections.send_data(peer_dht_pk, buf[..size].to_vec()),
+                    Err(e) => Box::new(future::err(
+                        Error::new(ErrorKind::Other, format!("Failed to serialize packet: {:?}", e))
+                    )),
+                };
+                // the packet will be resent by net crypto if it's important
+                future.or_else(|e| {
+                    debug!("Failed to send packet to TCP relay: {}", e);
+                    Ok(())
+                })
+            });
+
+        let net_crypto = self.clone();
+        let receive_future = data_rx
+            .map_err(|()| Error::new(ErrorKind::Other, "rx error"))
+            .for_each(move |(sender_pk, data)|
+                net_crypto.handle_tcp_data(&data, sender_pk).or_else(|e| {
+                    debug!("Failed to handle packet from TCP relay: {}", e);
+                    Ok(())
+                })
+            );
+
+        Box::new(send_future.join(receive_future).map(|_| ()))
+    }
+
     /// Send packet to crypto connection choosing TCP or UDP protocol
     fn send_packet(&self, packet: DhtPacket, connection: &mut CryptoConnection) -> IoFuture<()> {
         if let Some(addr) = connection.udp_addr {
@@ -596,15 +922,14 @@ impl NetCrypto {
 
             if udp_attempt_should_be_made {
                 connection.update_udp_send_attempt_time();
-                self.send_to_udp(addr, packet)
-            } else {
-                Box::new(future::ok(()))
+                // UDP may be dead so send the same packet via TCP relay as well
+                let udp_future = self.send_to_udp(addr, packet.clone());
+                let tcp_future = self.send_to_tcp(connection.peer_dht_pk, packet);
+                return Box::new(udp_future.join(tcp_future).map(|_| ()))
             }
-        } else {
-            Box::new(future::ok(()))
         }
 
-        // TODO: send via TCP relay here
+        self.send_to_tcp(connection.peer_dht_pk, packet)
     }
 
     /// Send `CookieRequest` or `CryptoHandshake` packet if needed depending on
@@ -616,11 +941,321 @@ impl NetCrypto {
         }
     }
 
+    /// Create new `CryptoConnection` to a friend and start sending
+    /// `CookieRequest` packets to it. Does nothing if a connection with this
+    /// friend already exists.
+    pub fn add_connection(&self, peer_real_pk: PublicKey, peer_dht_pk: PublicKey) {
+        let mut connections = self.connections.write();
+        if connections.contains_key(&peer_real_pk) {
+            return;
+        }
+        let connection = CryptoConnection::new(self.dht_sk.clone(), self.dht_pk, self.real_sk.clone(), self.real_pk, peer_real_pk, peer_dht_pk);
+        connections.insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+    }
+
+    /// Set UDP address of a friend that will be used to send packets to it
+    /// and to find its connection when a packet is received from UDP socket.
+    pub fn set_friend_udp_addr(&self, peer_real_pk: PublicKey, addr: SocketAddr) -> IoFuture<()> {
+        let connection = match self.connection_by_key(peer_real_pk) {
+            Some(connection) => connection,
+            None => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "No such connection"
+            )))
+        };
+        let mut connection = connection.write();
+        self.set_udp_addr(&mut connection, addr);
+        Box::new(future::ok(()))
+    }
+
+    /// Set UDP address of the connection updating the map of connections'
+    /// addresses.
+    fn set_udp_addr(&self, connection: &mut CryptoConnection, addr: SocketAddr) {
+        let mut keys_by_addr = self.keys_by_addr.write();
+        if let Some(old_addr) = connection.udp_addr {
+            keys_by_addr.remove(&(old_addr.ip(), old_addr.port()));
+        }
+        keys_by_addr.insert((addr.ip(), addr.port()), connection.peer_real_pk);
+        connection.udp_addr = Some(addr);
+    }
+
+    /// Set policy that decides whether crypto connections initiated by peers
+    /// we don't have connections with should be accepted. By default only
+    /// connections from peers that we added with `add_connection` are
+    /// accepted.
+    pub fn set_accept_policy(&self, policy: AcceptPolicy) {
+        *self.accept_policy.write() = policy;
+    }
+
+    /// Send `CryptoData` packet with the given data and packet number to the
+    /// peer. The packet is encrypted with the session key and the sent `Nonce`
+    /// of the connection is incremented afterwards.
+    fn send_data_packet(&self, connection: &mut CryptoConnection, data: Vec<u8>, packet_number: u32) -> IoFuture<()> {
+        let packet = match connection.status {
+            ConnectionStatus::NotConfirmed { ref mut sent_nonce, ref session_precomputed_key, .. }
+            | ConnectionStatus::Established { ref mut sent_nonce, ref session_precomputed_key, .. } => {
+                let payload = CryptoDataPayload {
+                    buffer_start: connection.recv_array.buffer_start,
+                    packet_number,
+                    data,
+                };
+                let packet = CryptoData::new(session_precomputed_key, *sent_nonce, payload);
+                increment_nonce(sent_nonce);
+                packet
+            },
+            _ => {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    "Can't send CryptoData in current connection state"
+                )))
+            }
+        };
+        self.send_packet(DhtPacket::CryptoData(packet), connection)
+    }
+
+    /// Check that data is not empty, fits `CryptoData` packet and that its
+    /// packet id is inside the given range.
+    fn check_data(data: &[u8], min_packet_id: u8, max_packet_id: u8) -> Result<(), Error> {
+        match data.first() {
+            None => Err(Error::new(
+                ErrorKind::Other,
+                "Data is empty"
+            )),
+            Some(&packet_id) if packet_id < min_packet_id || packet_id > max_packet_id => Err(Error::new(
+                ErrorKind::Other,
+                format!("Invalid packet id: {}", packet_id)
+            )),
+            Some(_) if data.len() > MAX_CRYPTO_DATA_SIZE => Err(Error::new(
+                ErrorKind::Other,
+                format!("Data is too big: {}", data.len())
+            )),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /** Generate request packet that contains indices of lossless packets that
+    we didn't receive yet.
+
+    Request packet consists of bytes where every byte means offset of the
+    requested packet from the previous requested packet starting from 1. Each
+    0 means adding 255 to the offset. See `handle_request_packet` for details.
+
+    */
+    fn generate_request_packet(recv_array: &PacketsArray<RecvPacket>) -> Vec<u8> {
+        let mut data = Vec::with_capacity(MAX_CRYPTO_DATA_SIZE);
+        data.push(PACKET_ID_REQUEST);
+
+        // n is an offset of the current packet from the last requested packet
+        let mut n: u32 = 1;
+        for i in recv_array.buffer_start .. recv_array.buffer_end {
+            if data.len() == MAX_CRYPTO_DATA_SIZE {
+                break
+            }
+
+            if recv_array.get(i).is_none() { // packet is missing, request it
+                data.push(n as u8);
+                n = 0;
+            } else if n == 255 {
+                data.push(0);
+                n = 0;
+            }
+
+            n += 1;
+        }
+
+        data
+    }
+
+    /// Send request packet with indices of lossless packets that we didn't
+    /// receive yet.
+    fn send_request_packet(&self, connection: &mut CryptoConnection) -> IoFuture<()> {
+        let data = NetCrypto::generate_request_packet(&connection.recv_array);
+        let packet_number = connection.send_array.buffer_end;
+        connection.last_request_packet_sent = Some(clock_now());
+        self.send_data_packet(connection, data, packet_number)
+    }
+
+    /// Resend lossless packets that were requested by the other side. The
+    /// number of resent packets is limited by `packets_left_requested`.
+    fn send_requested_packets(&self, connection: &mut CryptoConnection) -> IoFuture<()> {
+        let now = clock_now();
+        let mut futures = Vec::new();
+        for i in connection.send_array.buffer_start .. connection.send_array.buffer_end {
+            if connection.packets_left_requested == 0 {
+                break
+            }
+
+            let data = match connection.send_array.get_mut(i) {
+                Some(ref mut packet) if packet.requested => {
+                    packet.requested = false;
+                    packet.sent_time = now;
+                    packet.data.clone()
+                },
+                _ => continue,
+            };
+
+            futures.push(self.send_data_packet(connection, data, i));
+            connection.packets_left_requested -= 1;
+        }
+
+        let num_sent = futures.len() as u32;
+        connection.packets_resent += num_sent;
+        if num_sent < connection.packets_left {
+            connection.packets_left -= num_sent;
+        } else if num_sent > 0 {
+            // we resent more packets than we could send new ones
+            connection.last_congestion_event = Some(now);
+            connection.packets_left = 0;
+        }
+
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Update packets rates of established connection, send request packet
+    /// if it's time to do it and resend requested packets.
+    fn send_request_and_requested_packets(&self, connection: &mut CryptoConnection) -> IoFuture<()> {
+        connection.update_packets_rates();
+        connection.update_packets_left();
+
+        let request_future = if connection.request_packet_should_be_sent() {
+            self.send_request_packet(connection)
+        } else {
+            Box::new(future::ok(()))
+        };
+        let resend_future = self.send_requested_packets(connection);
+
+        Box::new(request_future.join(resend_future).map(|_| ()))
+    }
+
+    /// Send lossless packet to a friend. The first byte of data is a packet id
+    /// that should be in lossless range. The packet is stored in the sent
+    /// packets buffer until the friend confirms that it was received. Returns
+    /// the number of the packet that can be used to check if it was delivered.
+    /// Fails only if the packet can't be stored in the buffer.
+    pub fn send_lossless(&self, peer_real_pk: PublicKey, data: Vec<u8>) -> IoFuture<u32> {
+        if let Err(e) = NetCrypto::check_data(&data, PACKET_ID_CRYPTO_RANGE_END + 1, PACKET_ID_LOSSY_RANGE_START - 1) {
+            return Box::new(future::err(e))
+        }
+
+        let connection = match self.connection_by_key(peer_real_pk) {
+            Some(connection) => connection,
+            None => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "No such connection"
+            )))
+        };
+        let mut connection = connection.write();
+
+        if !connection.is_established() && !connection.is_not_confirmed() {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Connection is not established"
+            )))
+        }
+
+        if connection.packets_left == 0 {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Send rate limit is reached"
+            )))
+        }
+
+        let packet_number = connection.send_array.buffer_end;
+        if let Err(e) = connection.send_array.push_back(SentPacket::new(data.clone())) {
+            return Box::new(future::err(e))
+        }
+
+        connection.packets_left -= 1;
+        connection.packets_left_requested = connection.packets_left_requested.saturating_sub(1);
+        connection.packets_sent += 1;
+
+        // the packet is queued at this point and will be resent if sending fails
+        Box::new(self.send_data_packet(&mut connection, data, packet_number).then(move |res| {
+            if let Err(e) = res {
+                debug!("Failed to send lossless packet: {}", e);
+            }
+            Ok(packet_number)
+        }))
+    }
+
+    /// Check if lossless packet with the given number was delivered to a
+    /// friend. Packet is considered delivered when the friend confirmed that
+    /// it was received and it was removed from the sent packets buffer.
+    pub fn is_packet_delivered(&self, peer_real_pk: PublicKey, packet_number: u32) -> bool {
+        self.connection_by_key(peer_real_pk).map(|connection| {
+            let connection = connection.read();
+            let send_array = &connection.send_array;
+            let num = send_array.buffer_end.wrapping_sub(send_array.buffer_start);
+            packet_number.wrapping_sub(send_array.buffer_start) > num
+        }).unwrap_or(false)
+    }
+
+    /// Get number of lossless packets that can be sent to a friend right now
+    /// without exceeding neither send rate nor capacity of the sent packets
+    /// buffer. Returns 0 if the connection is neither established nor in
+    /// `NotConfirmed` status, i.e. when `send_lossless` would fail.
+    pub fn lossless_send_capacity(&self, peer_real_pk: PublicKey) -> u32 {
+        self.connection_by_key(peer_real_pk).map(|connection| {
+            let connection = connection.read();
+            if !connection.is_established() && !connection.is_not_confirmed() {
+                return 0;
+            }
+            let free_slots = CRYPTO_PACKET_BUFFER_SIZE - connection.send_array.len();
+            free_slots.min(connection.packets_left)
+        }).unwrap_or(0)
+    }
+
+    /// Send lossy packet to a friend. The first byte of data is a packet id
+    /// that should be in lossy range. Lossy packets are not stored and won't
+    /// be resent.
+    pub fn send_lossy(&self, peer_real_pk: PublicKey, data: Vec<u8>) -> IoFuture<()> {
+        if let Err(e) = NetCrypto::check_data(&data, PACKET_ID_LOSSY_RANGE_START, PACKET_ID_LOSSY_RANGE_END) {
+            return Box::new(future::err(e))
+        }
+
+        let connection = match self.connection_by_key(peer_real_pk) {
+            Some(connection) => connection,
+            None => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "No such connection"
+            )))
+        };
+        let mut connection = connection.write();
+
+        let packet_number = connection.send_array.buffer_end;
+        self.send_data_packet(&mut connection, data, packet_number)
+    }
+
+    /// Kill crypto connection to a friend sending kill packet to it if the
+    /// connection is established.
+    pub fn kill_connection(&self, peer_real_pk: PublicKey) -> IoFuture<()> {
+        let connection = match self.connections.write().remove(&peer_real_pk) {
+            Some(connection) => connection,
+            None => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "No such connection"
+            )))
+        };
+        let mut connection = connection.write();
+
+        if let Some(addr) = connection.udp_addr {
+            self.keys_by_addr.write().remove(&(addr.ip(), addr.port()));
+        }
+
+        if connection.is_established() || connection.is_not_confirmed() {
+            let packet_number = connection.send_array.buffer_end;
+            self.send_data_packet(&mut connection, vec![PACKET_ID_KILL], packet_number)
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
     /// The main loop that should be run at least 20 times per second
     pub fn main_loop(&self) -> IoFuture<()> {
         let connections = self.connections.read();
         let len = connections.len();
         let mut send_futures = Vec::with_capacity(len);
+        let mut event_futures = Vec::new();
         let mut timed_out = Vec::with_capacity(len);
         // Only one cycle over all connections to prevent many lock acquirements
         for (&pk, connection) in connections.iter() {
@@ -633,6 +1268,22 @@ impl NetCrypto {
 
             let send_future = self.send_status_packet(&mut connection);
             send_futures.push(send_future);
+
+            if connection.is_established() {
+                let udp_alive = connection.is_udp_alive();
+                if udp_alive != connection.udp_connected {
+                    connection.udp_connected = udp_alive;
+                    let event = if udp_alive { ConnectionEvent::Udp } else { ConnectionEvent::Tcp };
+                    event_futures.push(send_to(&self.connection_status_tx, (pk, event)));
+                }
+            }
+
+            // the first data packet confirms the connection to the peer so
+            // request packets are sent before it's established
+            if connection.is_established() || connection.is_not_confirmed() {
+                let send_future = self.send_request_and_requested_packets(&mut connection);
+                send_futures.push(send_future);
+            }
         }
         // release read lock and acquire write lock if we have to delete some connections
         drop(connections);
@@ -644,9 +1295,33 @@ impl NetCrypto {
                 if let Some(addr) = addr {
                     keys_by_addr.remove(&(addr.ip(), addr.port()));
                 }
+                event_futures.push(send_to(&self.connection_status_tx, (pk, ConnectionEvent::TimedOut)));
             }
         }
-        Box::new(future::join_all(send_futures).map(|_| ()))
+        // failure to send a packet to one peer shouldn't affect others and
+        // shouldn't prevent status events from being sent
+        let send_future = stream::futures_unordered(send_futures).then(|res| {
+            if let Err(e) = res {
+                debug!("Failed to send net crypto packet: {}", e);
+            }
+            Ok(())
+        }).for_each(|()| Ok(()));
+        Box::new(send_future.join(future::join_all(event_futures)).map(|_| ()))
+    }
+
+    /// Run net crypto calling `main_loop` every `MAIN_LOOP_INTERVAL`
+    /// milliseconds. It sends request packets, resends lost lossless packets
+    /// and removes timed out connections.
+    pub fn run(&self) -> IoFuture<()> {
+        let net_crypto = self.clone();
+        let wakeups = Interval::new(clock_now(), Duration::from_millis(MAIN_LOOP_INTERVAL));
+        let future = wakeups
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Net crypto timer error: {:?}", e)))
+            .for_each(move |_instant| net_crypto.main_loop().or_else(|e| {
+                debug!("Failed to run net crypto main loop: {}", e);
+                Ok(())
+            }));
+        Box::new(future)
     }
 }
 
@@ -655,27 +1330,38 @@ mod tests {
     use super::*;
 
     use futures::Stream;
+    use tokio;
+    use tokio::net::TcpListener;
+    use tokio::util::FutureExt;
     use tokio_executor;
     use tokio_timer::clock::*;
 
+    use toxcore::tcp::server::{Server, ServerConfig};
     use toxcore::time::ConstNow;
 
     #[test]
     fn net_crypto_clone() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk,
-            real_pk
+            real_pk,
+            real_sk
         });
 
         let _net_crypto_c = net_crypto.clone();
@@ -684,22 +1370,29 @@ mod tests {
     #[test]
     fn handle_cookie_request() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
         let precomputed_key = precompute(&peer_dht_pk, &dht_sk);
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk,
-            real_pk
+            real_pk,
+            real_sk
         });
 
         let cookie_request_id = 12345;
@@ -723,19 +1416,26 @@ mod tests {
     #[test]
     fn handle_cookie_request_invalid() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk,
-            real_pk
+            real_pk,
+            real_sk
         });
 
         let cookie_request = CookieRequest {
@@ -750,22 +1450,29 @@ mod tests {
     #[test]
     fn handle_udp_cookie_request() {
         let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
         let precomputed_key = precompute(&peer_dht_pk, &dht_sk);
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk,
-            real_pk
+            real_pk,
+            real_sk
         });
 
         let cookie_request_id = 12345;
@@ -798,19 +1505,26 @@ mod tests {
     #[test]
     fn handle_udp_cookie_request_invalid() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk,
-            real_pk
+            real_pk,
+            real_sk
         });
 
         let cookie_request = CookieRequest {
@@ -827,24 +1541,31 @@ mod tests {
     #[test]
     fn handle_cookie_response() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
         let cookie_request_id = unpack!(connection.status, ConnectionStatus::CookieRequesting, cookie_request_id);
 
@@ -864,32 +1585,40 @@ mod tests {
         let packet = unpack!(packet.dht_packet(), DhtPacket::CryptoHandshake);
         assert_eq!(packet.cookie, cookie);
 
-        let payload = packet.get_payload(&connection.dht_precomputed_key).unwrap();
+        let payload = packet.get_payload(&connection.real_precomputed_key).unwrap();
         assert_eq!(payload.cookie_hash, cookie.hash());
     }
 
     #[test]
     fn handle_cookie_response_invalid_status() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
         let mut connection = CryptoConnection::new_not_confirmed(
             dht_sk,
+            real_sk,
             peer_real_pk,
             peer_dht_pk,
             gen_nonce(),
@@ -917,24 +1646,31 @@ mod tests {
     #[test]
     fn handle_cookie_response_invalid_request_id() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
         let cookie_request_id = unpack!(connection.status, ConnectionStatus::CookieRequesting, cookie_request_id);
 
@@ -955,26 +1691,34 @@ mod tests {
     #[test]
     fn handle_udp_cookie_response() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
         let dht_precomputed_key = connection.dht_precomputed_key.clone();
+        let real_precomputed_key = connection.real_precomputed_key.clone();
         let cookie_request_id = unpack!(connection.status, ConnectionStatus::CookieRequesting, cookie_request_id);
 
         let addr = "127.0.0.1:12345".parse().unwrap();
@@ -1002,26 +1746,33 @@ mod tests {
         let packet = unpack!(packet.dht_packet(), DhtPacket::CryptoHandshake);
         assert_eq!(packet.cookie, cookie);
 
-        let payload = packet.get_payload(&dht_precomputed_key).unwrap();
+        let payload = packet.get_payload(&real_precomputed_key).unwrap();
         assert_eq!(payload.cookie_hash, cookie.hash());
     }
 
     #[test]
     fn handle_udp_cookie_response_no_connection() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
@@ -1045,24 +1796,31 @@ mod tests {
     #[test]
     fn handle_crypto_handshake_in_cookie_requesting_status() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
         let base_nonce = gen_nonce();
         let session_pk = gen_keypair().0;
@@ -1078,7 +1836,7 @@ mod tests {
             cookie_hash: our_encrypted_cookie.hash(),
             cookie: cookie.clone()
         };
-        let crypto_handshake = CryptoHandshake::new(&connection.dht_precomputed_key, crypto_handshake_payload, our_encrypted_cookie);
+        let crypto_handshake = CryptoHandshake::new(&connection.real_precomputed_key, crypto_handshake_payload, our_encrypted_cookie);
 
         assert!(net_crypto.handle_crypto_handshake(&mut connection, crypto_handshake).wait().is_ok());
 
@@ -1092,26 +1850,33 @@ mod tests {
         let packet = unpack!(packet.dht_packet(), DhtPacket::CryptoHandshake);
         assert_eq!(packet.cookie, cookie);
 
-        let payload = packet.get_payload(&connection.dht_precomputed_key).unwrap();
+        let payload = packet.get_payload(&connection.real_precomputed_key).unwrap();
         assert_eq!(payload.cookie_hash, cookie.hash());
     }
 
     #[test]
     fn handle_crypto_handshake_in_not_confirmed_status() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
@@ -1122,6 +1887,7 @@ mod tests {
         };
         let mut connection = CryptoConnection::new_not_confirmed(
             dht_sk,
+            real_sk,
             peer_real_pk,
             peer_dht_pk,
             gen_nonce(),
@@ -1144,7 +1910,7 @@ mod tests {
             cookie_hash: our_encrypted_cookie.hash(),
             cookie: other_cookie
         };
-        let crypto_handshake = CryptoHandshake::new(&connection.dht_precomputed_key, crypto_handshake_payload, our_encrypted_cookie);
+        let crypto_handshake = CryptoHandshake::new(&connection.real_precomputed_key, crypto_handshake_payload, our_encrypted_cookie);
 
         assert!(net_crypto.handle_crypto_handshake(&mut connection, crypto_handshake).wait().is_ok());
 
@@ -1160,31 +1926,38 @@ mod tests {
         let packet = unpack!(packet.dht_packet(), DhtPacket::CryptoHandshake);
         assert_eq!(packet.cookie, cookie);
 
-        let payload = packet.get_payload(&connection.dht_precomputed_key).unwrap();
+        let payload = packet.get_payload(&connection.real_precomputed_key).unwrap();
         assert_eq!(payload.cookie_hash, cookie.hash());
     }
 
     #[test]
     fn handle_crypto_handshake_invalid_status() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
         let (peer_session_pk, _peer_session_sk) = gen_keypair();
         let (_session_pk, session_sk) = gen_keypair();
@@ -1210,7 +1983,7 @@ mod tests {
             cookie_hash: our_encrypted_cookie.hash(),
             cookie
         };
-        let crypto_handshake = CryptoHandshake::new(&connection.dht_precomputed_key, crypto_handshake_payload, our_encrypted_cookie);
+        let crypto_handshake = CryptoHandshake::new(&connection.real_precomputed_key, crypto_handshake_payload, our_encrypted_cookie);
 
         assert!(net_crypto.handle_crypto_handshake(&mut connection, crypto_handshake).wait().is_err());
     }
@@ -1218,24 +1991,31 @@ mod tests {
     #[test]
     fn handle_crypto_handshake_invalid_hash() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
         let base_nonce = gen_nonce();
         let session_pk = gen_keypair().0;
@@ -1251,7 +2031,7 @@ mod tests {
             cookie_hash: cookie.hash(),
             cookie
         };
-        let crypto_handshake = CryptoHandshake::new(&connection.dht_precomputed_key, crypto_handshake_payload, our_encrypted_cookie);
+        let crypto_handshake = CryptoHandshake::new(&connection.real_precomputed_key, crypto_handshake_payload, our_encrypted_cookie);
 
         assert!(net_crypto.handle_crypto_handshake(&mut connection, crypto_handshake).wait().is_err());
     }
@@ -1259,24 +2039,31 @@ mod tests {
     #[test]
     fn handle_crypto_handshake_timed_out_cookie() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
         let base_nonce = gen_nonce();
         let session_pk = gen_keypair().0;
@@ -1293,7 +2080,7 @@ mod tests {
             cookie_hash: our_encrypted_cookie.hash(),
             cookie
         };
-        let crypto_handshake = CryptoHandshake::new(&connection.dht_precomputed_key, crypto_handshake_payload, our_encrypted_cookie);
+        let crypto_handshake = CryptoHandshake::new(&connection.real_precomputed_key, crypto_handshake_payload, our_encrypted_cookie);
 
         assert!(net_crypto.handle_crypto_handshake(&mut connection, crypto_handshake).wait().is_err());
     }
@@ -1301,24 +2088,31 @@ mod tests {
     #[test]
     fn handle_crypto_handshake_invalid_peer_real_pk() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
         let base_nonce = gen_nonce();
         let session_pk = gen_keypair().0;
@@ -1334,7 +2128,7 @@ mod tests {
             cookie_hash: our_encrypted_cookie.hash(),
             cookie
         };
-        let crypto_handshake = CryptoHandshake::new(&connection.dht_precomputed_key, crypto_handshake_payload, our_encrypted_cookie);
+        let crypto_handshake = CryptoHandshake::new(&connection.real_precomputed_key, crypto_handshake_payload, our_encrypted_cookie);
 
         assert!(net_crypto.handle_crypto_handshake(&mut connection, crypto_handshake).wait().is_err());
     }
@@ -1342,24 +2136,31 @@ mod tests {
     #[test]
     fn handle_crypto_handshake_invalid_peer_dht_pk() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
         let (new_dht_pk, _new_dht_sk) = gen_keypair();
 
@@ -1377,7 +2178,7 @@ mod tests {
             cookie_hash: our_encrypted_cookie.hash(),
             cookie
         };
-        let crypto_handshake = CryptoHandshake::new(&connection.dht_precomputed_key, crypto_handshake_payload, our_encrypted_cookie);
+        let crypto_handshake = CryptoHandshake::new(&connection.real_precomputed_key, crypto_handshake_payload, our_encrypted_cookie);
 
         assert!(net_crypto.handle_crypto_handshake(&mut connection, crypto_handshake).wait().is_err());
 
@@ -1391,26 +2192,33 @@ mod tests {
     #[test]
     fn handle_udp_crypto_handshake() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
-        let dht_precomputed_key = connection.dht_precomputed_key.clone();
+        let real_precomputed_key = connection.real_precomputed_key.clone();
 
         let addr = "127.0.0.1:12345".parse().unwrap();
         connection.udp_addr = Some(addr);
@@ -1432,7 +2240,7 @@ mod tests {
             cookie_hash: our_encrypted_cookie.hash(),
             cookie: cookie.clone()
         };
-        let crypto_handshake = CryptoHandshake::new(&dht_precomputed_key, crypto_handshake_payload, our_encrypted_cookie);
+        let crypto_handshake = CryptoHandshake::new(&real_precomputed_key, crypto_handshake_payload, our_encrypted_cookie);
 
         assert!(net_crypto.handle_udp_crypto_handshake(crypto_handshake, addr).wait().is_ok());
 
@@ -1449,1055 +2257,3704 @@ mod tests {
         let packet = unpack!(packet.dht_packet(), DhtPacket::CryptoHandshake);
         assert_eq!(packet.cookie, cookie);
 
-        let payload = packet.get_payload(&dht_precomputed_key).unwrap();
+        let payload = packet.get_payload(&real_precomputed_key).unwrap();
         assert_eq!(payload.cookie_hash, cookie.hash());
     }
 
+    #[test]
+    fn handle_udp_crypto_handshake_new_connection() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, peer_real_sk) = gen_keypair();
+        let base_nonce = gen_nonce();
+        let session_pk = gen_keypair().0;
+        let our_cookie = Cookie::new(peer_real_pk, peer_dht_pk);
+        let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, our_cookie);
+        let cookie = EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![43; 88]
+        };
+        let crypto_handshake_payload = CryptoHandshakePayload {
+            base_nonce,
+            session_pk,
+            cookie_hash: our_encrypted_cookie.hash(),
+            cookie: cookie.clone()
+        };
+        let crypto_handshake = CryptoHandshake::new(&precompute(&real_pk, &peer_real_sk), crypto_handshake_payload, our_encrypted_cookie);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+
+        net_crypto.set_accept_policy(AcceptPolicy::AcceptAll);
+
+        assert!(net_crypto.handle_udp_crypto_handshake(crypto_handshake, addr).wait().is_ok());
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+
+        assert_eq!(connection.peer_dht_pk, peer_dht_pk);
+        assert_eq!(connection.udp_addr, Some(addr));
+        assert_eq!(net_crypto.key_by_addr(addr), Some(peer_real_pk));
+
+        let received_nonce = unpack!(connection.status, ConnectionStatus::NotConfirmed, received_nonce);
+        let peer_session_pk = unpack!(connection.status, ConnectionStatus::NotConfirmed, peer_session_pk);
+
+        assert_eq!(received_nonce, base_nonce);
+        assert_eq!(peer_session_pk, session_pk);
+
+        let (received, _new_connection_rx) = new_connection_rx.into_future().wait().unwrap();
+        assert_eq!(received.unwrap(), (peer_real_pk, peer_dht_pk));
+
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+        assert_eq!(addr_to_send, addr);
+        let packet = unpack!(packet, DhtPacket::CryptoHandshake);
+        assert_eq!(packet.cookie, cookie);
+
+        let payload = packet.get_payload(&precompute(&peer_real_pk, &real_sk)).unwrap();
+        assert_eq!(payload.cookie_hash, cookie.hash());
+    }
+
+    #[test]
+    fn handle_udp_crypto_handshake_new_connection_friends_only() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, peer_real_sk) = gen_keypair();
+        let base_nonce = gen_nonce();
+        let session_pk = gen_keypair().0;
+        let our_cookie = Cookie::new(peer_real_pk, peer_dht_pk);
+        let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, our_cookie);
+        let cookie = EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![43; 88]
+        };
+        let crypto_handshake_payload = CryptoHandshakePayload {
+            base_nonce,
+            session_pk,
+            cookie_hash: our_encrypted_cookie.hash(),
+            cookie: cookie.clone()
+        };
+        let crypto_handshake = CryptoHandshake::new(&precompute(&real_pk, &peer_real_sk), crypto_handshake_payload, our_encrypted_cookie);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+
+        let friends = Arc::new(RwLock::new(HashSet::new()));
+        friends.write().insert(peer_real_pk);
+        net_crypto.set_accept_policy(AcceptPolicy::FriendsOnly(friends));
+
+        assert!(net_crypto.handle_udp_crypto_handshake(crypto_handshake, addr).wait().is_ok());
+
+        assert!(net_crypto.connection_by_key(peer_real_pk).is_some());
+
+        let (received, _new_connection_rx) = new_connection_rx.into_future().wait().unwrap();
+        assert_eq!(received.unwrap(), (peer_real_pk, peer_dht_pk));
+    }
+
+    #[test]
+    fn handle_udp_crypto_handshake_new_connection_rejected() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, peer_real_sk) = gen_keypair();
+        let base_nonce = gen_nonce();
+        let session_pk = gen_keypair().0;
+        let our_cookie = Cookie::new(peer_real_pk, peer_dht_pk);
+        let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, our_cookie);
+        let cookie = EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![43; 88]
+        };
+        let crypto_handshake_payload = CryptoHandshakePayload {
+            base_nonce,
+            session_pk,
+            cookie_hash: our_encrypted_cookie.hash(),
+            cookie: cookie.clone()
+        };
+        let crypto_handshake = CryptoHandshake::new(&precompute(&real_pk, &peer_real_sk), crypto_handshake_payload, our_encrypted_cookie);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+
+        // by default connections from unknown peers are rejected
+        assert!(net_crypto.handle_udp_crypto_handshake(crypto_handshake, addr).wait().is_err());
+
+        assert!(net_crypto.connections.read().is_empty());
+        assert!(net_crypto.keys_by_addr.read().is_empty());
+
+        drop(net_crypto);
+        assert!(new_connection_rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn handle_udp_crypto_handshake_new_connection_rejected_by_callback() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, peer_real_sk) = gen_keypair();
+        let base_nonce = gen_nonce();
+        let session_pk = gen_keypair().0;
+        let our_cookie = Cookie::new(peer_real_pk, peer_dht_pk);
+        let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, our_cookie);
+        let cookie = EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![43; 88]
+        };
+        let crypto_handshake_payload = CryptoHandshakePayload {
+            base_nonce,
+            session_pk,
+            cookie_hash: our_encrypted_cookie.hash(),
+            cookie: cookie.clone()
+        };
+        let crypto_handshake = CryptoHandshake::new(&precompute(&real_pk, &peer_real_sk), crypto_handshake_payload, our_encrypted_cookie);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+
+        net_crypto.set_accept_policy(AcceptPolicy::Callback(Arc::new(move |pk| *pk != peer_real_pk)));
+
+        assert!(net_crypto.handle_udp_crypto_handshake(crypto_handshake, addr).wait().is_err());
+
+        assert!(net_crypto.connections.read().is_empty());
+    }
+
+    #[test]
+    fn handle_udp_crypto_handshake_new_connection_invalid_cookie() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, peer_real_sk) = gen_keypair();
+        let base_nonce = gen_nonce();
+        let session_pk = gen_keypair().0;
+        let our_cookie = Cookie::new(peer_real_pk, peer_dht_pk);
+        let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, our_cookie);
+        let cookie = EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![43; 88]
+        };
+        let crypto_handshake_payload = CryptoHandshakePayload {
+            base_nonce,
+            session_pk,
+            cookie_hash: our_encrypted_cookie.hash(),
+            cookie: cookie.clone()
+        };
+        let crypto_handshake = CryptoHandshake::new(&precompute(&real_pk, &peer_real_sk), crypto_handshake_payload, our_encrypted_cookie);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+
+        net_crypto.set_accept_policy(AcceptPolicy::AcceptAll);
+
+        let crypto_handshake = CryptoHandshake {
+            cookie: EncryptedCookie {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; 88]
+            },
+            .. crypto_handshake
+        };
+
+        assert!(net_crypto.handle_udp_crypto_handshake(crypto_handshake, addr).wait().is_err());
+
+        assert!(net_crypto.connections.read().is_empty());
+    }
+
+    #[test]
+    fn handle_udp_crypto_handshake_known_peer_new_addr() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, peer_real_sk) = gen_keypair();
+        let base_nonce = gen_nonce();
+        let session_pk = gen_keypair().0;
+        let our_cookie = Cookie::new(peer_real_pk, peer_dht_pk);
+        let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, our_cookie);
+        let cookie = EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![43; 88]
+        };
+        let crypto_handshake_payload = CryptoHandshakePayload {
+            base_nonce,
+            session_pk,
+            cookie_hash: our_encrypted_cookie.hash(),
+            cookie: cookie.clone()
+        };
+        let crypto_handshake = CryptoHandshake::new(&precompute(&real_pk, &peer_real_sk), crypto_handshake_payload, our_encrypted_cookie);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+
+        let old_addr = "127.0.0.1:12346".parse().unwrap();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+        connection.udp_addr = Some(old_addr);
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((old_addr.ip(), old_addr.port()), peer_real_pk);
+
+        assert!(net_crypto.handle_udp_crypto_handshake(crypto_handshake, addr).wait().is_ok());
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+
+        assert_eq!(connection.udp_addr, Some(addr));
+        assert_eq!(net_crypto.key_by_addr(addr), Some(peer_real_pk));
+        assert_eq!(net_crypto.key_by_addr(old_addr), None);
+        assert_eq!(unpack!(connection.status, ConnectionStatus::NotConfirmed, received_nonce), base_nonce);
+
+        // known peer is not announced as a new connection
+        drop(net_crypto);
+        assert!(new_connection_rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn handle_udp_crypto_handshake_known_peer_invalid_handshake_keeps_addr() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let our_cookie = Cookie::new(peer_real_pk, peer_dht_pk);
+        let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, our_cookie);
+        let crypto_handshake_payload = CryptoHandshakePayload {
+            base_nonce: gen_nonce(),
+            session_pk: gen_keypair().0,
+            cookie_hash: our_encrypted_cookie.hash(),
+            cookie: EncryptedCookie {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![43; 88]
+            }
+        };
+        // attacker has the cookie but doesn't know peer's real SecretKey
+        let (_attacker_pk, attacker_sk) = gen_keypair();
+        let crypto_handshake = CryptoHandshake::new(&precompute(&real_pk, &attacker_sk), crypto_handshake_payload, our_encrypted_cookie);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+
+        let old_addr = "127.0.0.1:12346".parse().unwrap();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+        connection.udp_addr = Some(old_addr);
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((old_addr.ip(), old_addr.port()), peer_real_pk);
+
+        assert!(net_crypto.handle_udp_crypto_handshake(crypto_handshake, addr).wait().is_err());
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+
+        assert_eq!(connection.udp_addr, Some(old_addr));
+        assert_eq!(connection.udp_received_time, None);
+        assert_eq!(net_crypto.key_by_addr(addr), None);
+        assert_eq!(net_crypto.key_by_addr(old_addr), Some(peer_real_pk));
+    }
+
+    #[test]
+    fn handle_udp_crypto_handshake_new_connection_forged_real_pk() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk
+        });
+
+        // attacker got a cookie claiming to be our friend
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (friend_real_pk, _friend_real_sk) = gen_keypair();
+        let (_attacker_real_pk, attacker_real_sk) = gen_keypair();
+        let our_cookie = Cookie::new(friend_real_pk, peer_dht_pk);
+        let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, our_cookie);
+        let crypto_handshake_payload = CryptoHandshakePayload {
+            base_nonce: gen_nonce(),
+            session_pk: gen_keypair().0,
+            cookie_hash: our_encrypted_cookie.hash(),
+            cookie: EncryptedCookie {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![43; 88]
+            }
+        };
+        let crypto_handshake = CryptoHandshake::new(&precompute(&real_pk, &attacker_real_sk), crypto_handshake_payload, our_encrypted_cookie);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+
+        let friends = Arc::new(RwLock::new(HashSet::new()));
+        friends.write().insert(friend_real_pk);
+        net_crypto.set_accept_policy(AcceptPolicy::FriendsOnly(friends));
+
+        assert!(net_crypto.handle_udp_crypto_handshake(crypto_handshake, addr).wait().is_err());
+
+        assert!(net_crypto.connection_by_key(friend_real_pk).is_none());
+        assert_eq!(net_crypto.key_by_addr(addr), None);
+
+        drop(net_crypto);
+        assert!(new_connection_rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn accept_policy() {
+        let (pk, _sk) = gen_keypair();
+        let (another_pk, _another_sk) = gen_keypair();
+
+        assert!(AcceptPolicy::AcceptAll.accepts(&pk));
+
+        assert!(!AcceptPolicy::default().accepts(&pk));
+
+        let friends = Arc::new(RwLock::new(HashSet::new()));
+        friends.write().insert(pk);
+        let policy = AcceptPolicy::FriendsOnly(friends);
+        assert!(policy.accepts(&pk));
+        assert!(!policy.accepts(&another_pk));
+
+        let policy = AcceptPolicy::Callback(Arc::new(move |key| *key == pk));
+        assert!(policy.accepts(&pk));
+        assert!(!policy.accepts(&another_pk));
+    }
+
     #[test]
     fn handle_crypto_data_lossy() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+
+        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_ok());
+
+        // The diff between nonces is not bigger than the threshold so received
+        // nonce shouldn't be changed
+        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
+
+        assert_eq!(connection.recv_array.buffer_start, 0);
+        assert_eq!(connection.recv_array.buffer_end, 0);
+        assert_eq!(connection.send_array.buffer_start, 0);
+        assert_eq!(connection.send_array.buffer_end, 0);
+
+        let (received, _lossy_rx) = lossy_rx.into_future().wait().unwrap();
+        let (received_peer_real_pk, received_data) = received.unwrap();
+        assert_eq!(received_peer_real_pk, peer_real_pk);
+        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]);
+    }
+
+    #[test]
+    fn handle_crypto_data_lossy_increment_nonce() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        // Make the diff between nonces is bigger than the threshold
+        let mut packet_nonce = received_nonce;
+        increment_nonce_number(&mut packet_nonce, (2 * NONCE_DIFF_THRESHOLD + 1) as usize);
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, packet_nonce, crypto_data_payload);
+
+        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_ok());
+
+        // The diff between nonces is bigger than the threshold so received
+        // nonce should be changed increased
+        let mut expected_nonce = received_nonce;
+        increment_nonce_number(&mut expected_nonce, NONCE_DIFF_THRESHOLD as usize);
+        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), expected_nonce);
+
+        assert_eq!(connection.recv_array.buffer_start, 0);
+        assert_eq!(connection.recv_array.buffer_end, 0);
+        assert_eq!(connection.send_array.buffer_start, 0);
+        assert_eq!(connection.send_array.buffer_end, 0);
+
+        let (received, _lossy_rx) = lossy_rx.into_future().wait().unwrap();
+        let (received_peer_real_pk, received_data) = received.unwrap();
+        assert_eq!(received_peer_real_pk, peer_real_pk);
+        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]);
+    }
+
+    #[test]
+    fn handle_crypto_data_lossy_update_rtt() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let now = Instant::now();
+
+        let sent_packet = SentPacket {
+            data: vec![42; 123],
+            sent_time: now,
+            requested: false,
+        };
+        assert!(connection.send_array.insert(0, sent_packet).is_ok());
+
+        connection.rtt = Duration::from_millis(500);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 1,
+            packet_number: 0,
+            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_millis(250)));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_ok());
+        });
+
+        // The diff between nonces is not bigger than the threshold so received
+        // nonce shouldn't be changed
+        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
+
+        assert_eq!(connection.recv_array.buffer_start, 0);
+        assert_eq!(connection.recv_array.buffer_end, 0);
+        assert_eq!(connection.send_array.buffer_start, 1);
+        assert_eq!(connection.send_array.buffer_end, 1);
+
+        let (received, _lossy_rx) = lossy_rx.into_future().wait().unwrap();
+        let (received_peer_real_pk, received_data) = received.unwrap();
+        assert_eq!(received_peer_real_pk, peer_real_pk);
+        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]);
+
+        assert_eq!(connection.rtt, Duration::from_millis(250));
+    }
+
+    #[test]
+    fn handle_crypto_data_lossy_invalid_buffer_start() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 7, // bigger than end index of sent packets buffer
+            packet_number: 0,
+            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+
+        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_err());
+
+        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
+
+        assert_eq!(connection.recv_array.buffer_start, 0);
+        assert_eq!(connection.recv_array.buffer_end, 0);
+        assert_eq!(connection.send_array.buffer_start, 0);
+        assert_eq!(connection.send_array.buffer_end, 0);
+    }
+
+    #[test]
+    fn handle_crypto_data_lossless() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let crypto_data_payload_1 = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![PACKET_ID_LOSSY_RANGE_START - 1, 1, 2, 3]
+        };
+        let crypto_data_1 = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload_1);
+
+        let crypto_data_payload_2 = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 1,
+            data: vec![PACKET_ID_LOSSY_RANGE_START - 1, 4, 5, 6]
+        };
+        let crypto_data_2 = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload_2);
+
+        let crypto_data_payload_3 = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 2,
+            data: vec![PACKET_ID_LOSSY_RANGE_START - 1, 7, 8, 9]
+        };
+        let crypto_data_3 = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload_3);
+
+        // Send packets in random order
+        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data_2, /* udp */ true).wait().is_ok());
+        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data_3, /* udp */ true).wait().is_ok());
+        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data_1, /* udp */ true).wait().is_ok());
+
+        // The diff between nonces is not bigger than the threshold so received
+        // nonce shouldn't be changed
+        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
+
+        assert_eq!(connection.recv_array.buffer_start, 3);
+        assert_eq!(connection.recv_array.buffer_end, 3);
+        assert_eq!(connection.send_array.buffer_start, 0);
+        assert_eq!(connection.send_array.buffer_end, 0);
+
+        // We should receive lossless packets according to their numbers
+
+        let (received, lossless_rx) = lossless_rx.into_future().wait().unwrap();
+        let (received_peer_real_pk, received_data) = received.unwrap();
+        assert_eq!(received_peer_real_pk, peer_real_pk);
+        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START - 1, 1, 2, 3]);
+
+        let (received, lossless_rx) = lossless_rx.into_future().wait().unwrap();
+        let (received_peer_real_pk, received_data) = received.unwrap();
+        assert_eq!(received_peer_real_pk, peer_real_pk);
+        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START - 1, 4, 5, 6]);
+
+        let (received, _lossless_rx) = lossless_rx.into_future().wait().unwrap();
+        let (received_peer_real_pk, received_data) = received.unwrap();
+        assert_eq!(received_peer_real_pk, peer_real_pk);
+        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START - 1, 7, 8, 9]);
+    }
+
+    #[test]
+    fn handle_crypto_data_lossless_too_big_index() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: CRYPTO_PACKET_BUFFER_SIZE,
+            data: vec![PACKET_ID_LOSSY_RANGE_START - 1, 1, 2, 3]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+
+        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_err());
+
+        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
+
+        assert_eq!(connection.recv_array.buffer_start, 0);
+        assert_eq!(connection.recv_array.buffer_end, 0);
+        assert_eq!(connection.send_array.buffer_start, 0);
+        assert_eq!(connection.send_array.buffer_end, 0);
+    }
+
+    #[test]
+    fn handle_crypto_data_established_event() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        let packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet);
+        connection.status = ConnectionStatus::NotConfirmed {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+            packet,
+        };
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+
+        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_ok());
+
+        assert!(connection.is_established());
+
+        let (received, _connection_status_rx) = connection_status_rx.into_future().wait().unwrap();
+        let (received_peer_real_pk, event) = received.unwrap();
+        assert_eq!(received_peer_real_pk, peer_real_pk);
+        assert_eq!(event, ConnectionEvent::Established);
+    }
+
+    #[test]
+    fn handle_crypto_data_kill() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![PACKET_ID_KILL]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+
+        assert!(net_crypto.handle_crypto_data(&mut connection.write(), crypto_data, /* udp */ true).wait().is_ok());
+
+        assert!(net_crypto.connections.read().is_empty());
+        assert!(net_crypto.keys_by_addr.read().is_empty());
+
+        let (received, _connection_status_rx) = connection_status_rx.into_future().wait().unwrap();
+        let (received_peer_real_pk, event) = received.unwrap();
+        assert_eq!(received_peer_real_pk, peer_real_pk);
+        assert_eq!(event, ConnectionEvent::Killed);
+    }
+
+    #[test]
+    fn handle_crypto_data_request() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let now = Instant::now();
+
+        assert!(connection.send_array.insert(0, SentPacket::new(vec![42; 123])).is_ok());
+        // this time will be used to update rtt
+        let packet_1 = SentPacket {
+            data: vec![43; 123],
+            sent_time: now + Duration::from_millis(750),
+            requested: false,
+        };
+        assert!(connection.send_array.insert(1, packet_1).is_ok());
+        // this packet will be requested but elapsed time will be less then rtt
+        // so it shouldn't be marked
+        let packet_5 = SentPacket {
+            data: vec![44; 123],
+            sent_time: now + Duration::from_millis(750),
+            requested: false,
+        };
+        assert!(connection.send_array.insert(5, packet_5).is_ok());
+        assert!(connection.send_array.insert(7, SentPacket::new(vec![45; 123])).is_ok());
+        assert!(connection.send_array.insert(1024, SentPacket::new(vec![46; 123])).is_ok());
+
+        connection.rtt = Duration::from_millis(500);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![PACKET_ID_REQUEST, 1, 5, 0, 0, 0, 254] // request 0, 5 and 1024 packets
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(1)));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_ok());
+        });
+
+        assert!(connection.send_array.get(0).unwrap().requested);
+        assert!(connection.send_array.get(1).is_none());
+        assert!(!connection.send_array.get(5).unwrap().requested);
+        assert!(connection.send_array.get(7).is_none());
+        assert!(connection.send_array.get(1024).unwrap().requested);
+
+        assert_eq!(connection.rtt, Duration::from_millis(250));
+    }
+
+    #[test]
+    fn handle_crypto_data_empty_request() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        assert!(connection.send_array.insert(0, SentPacket::new(vec![42; 123])).is_ok());
+        assert!(connection.send_array.insert(1, SentPacket::new(vec![43; 123])).is_ok());
+        assert!(connection.send_array.insert(5, SentPacket::new(vec![44; 123])).is_ok());
+        assert!(connection.send_array.insert(7, SentPacket::new(vec![45; 123])).is_ok());
+        assert!(connection.send_array.insert(1024, SentPacket::new(vec![46; 123])).is_ok());
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![PACKET_ID_REQUEST]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+
+        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_ok());
+
+        assert!(!connection.send_array.get(0).unwrap().requested);
+        assert!(!connection.send_array.get(1).unwrap().requested);
+        assert!(!connection.send_array.get(5).unwrap().requested);
+        assert!(!connection.send_array.get(7).unwrap().requested);
+        assert!(!connection.send_array.get(1024).unwrap().requested);
+    }
+
+    #[test]
+    fn handle_crypto_data_invalid_packet_id() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![255, 1, 2, 3] // only 255 is invalid id
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+
+        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_err());
+
+        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
+
+        assert_eq!(connection.recv_array.buffer_start, 0);
+        assert_eq!(connection.recv_array.buffer_end, 0);
+        assert_eq!(connection.send_array.buffer_start, 0);
+        assert_eq!(connection.send_array.buffer_end, 0);
+    }
+
+    #[test]
+    fn handle_crypto_data_empty_data() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: Vec::new()
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+
+        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_err());
+
+        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
+
+        assert_eq!(connection.recv_array.buffer_start, 0);
+        assert_eq!(connection.recv_array.buffer_end, 0);
+        assert_eq!(connection.send_array.buffer_start, 0);
+        assert_eq!(connection.send_array.buffer_end, 0);
+    }
+
+    #[test]
+    fn handle_crypto_data_invalid_status() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![0, 0, PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+
+        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_err());
+    }
+
+    #[test]
+    fn handle_udp_crypto_data_lossy() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![0, 0, PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+
+        assert!(net_crypto.handle_udp_crypto_data(crypto_data, addr).wait().is_ok());
+
+        let connections = net_crypto.connections.read();
+        let connection = connections.get(&peer_real_pk).unwrap().read().clone();
+
+        // The diff between nonces is not bigger than the threshold so received
+        // nonce shouldn't be changed
+        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
+
+        assert_eq!(connection.recv_array.buffer_start, 0);
+        assert_eq!(connection.recv_array.buffer_end, 0);
+        assert_eq!(connection.send_array.buffer_start, 0);
+        assert_eq!(connection.send_array.buffer_end, 0);
+
+        let (received, _lossy_rx) = lossy_rx.into_future().wait().unwrap();
+        let (received_peer_real_pk, received_data) = received.unwrap();
+        assert_eq!(received_peer_real_pk, peer_real_pk);
+        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]);
+    }
+
+    #[test]
+    fn handle_tcp_cookie_request() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+
+        let cookie_request_id = 12345;
+
+        let cookie_request_payload = CookieRequestPayload {
+            pk: peer_real_pk,
+            id: cookie_request_id,
+        };
+        let cookie_request = CookieRequest::new(&precomputed_key, &peer_dht_pk, cookie_request_payload);
+
+        assert!(net_crypto.handle_tcp_packet(DhtPacket::CookieRequest(cookie_request), peer_dht_pk).wait().is_ok());
+
+        let (received, _tcp_rx) = tcp_rx.into_future().wait().unwrap();
+        let (packet, key_to_send) = received.unwrap();
+        let cookie_response = unpack!(packet, DhtPacket::CookieResponse);
+
+        assert_eq!(key_to_send, peer_dht_pk);
+
+        let cookie_response_payload = cookie_response.get_payload(&precomputed_key).unwrap();
+
+        assert_eq!(cookie_response_payload.id, cookie_request_id);
+
+        let cookie = cookie_response_payload.cookie.get_payload(&net_crypto.symmetric_key).unwrap();
+        assert_eq!(cookie.dht_pk, peer_dht_pk);
+        assert_eq!(cookie.real_pk, peer_real_pk);
+    }
+
+    #[test]
+    fn handle_tcp_cookie_response() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let dht_precomputed_key = connection.dht_precomputed_key.clone();
+        let cookie_request_id = unpack!(connection.status, ConnectionStatus::CookieRequesting, cookie_request_id);
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+
+        let cookie = EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![43; 88]
+        };
+        let cookie_response_payload = CookieResponsePayload {
+            cookie: cookie.clone(),
+            id: cookie_request_id
+        };
+        let cookie_response = CookieResponse::new(&dht_precomputed_key, cookie_response_payload);
+
+        assert!(net_crypto.handle_tcp_packet(DhtPacket::CookieResponse(cookie_response), peer_dht_pk).wait().is_ok());
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        let packet = unpack!(connection.status, ConnectionStatus::HandshakeSending, packet);
+        let packet = unpack!(packet.dht_packet(), DhtPacket::CryptoHandshake);
+        assert_eq!(packet.cookie, cookie);
+
+        // CryptoHandshake should be sent via TCP relay since there is no UDP address
+        let (received, _tcp_rx) = tcp_rx.into_future().wait().unwrap();
+        let (received, key_to_send) = received.unwrap();
+        assert_eq!(key_to_send, peer_dht_pk);
+        assert_eq!(received, DhtPacket::CryptoHandshake(packet));
+    }
+
+    #[test]
+    fn handle_tcp_cookie_response_no_connection() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk
+        });
+
+        let (peer_dht_pk, peer_dht_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&dht_pk, &peer_dht_sk);
+
+        let cookie_response_payload = CookieResponsePayload {
+            cookie: EncryptedCookie {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![43; 88]
+            },
+            id: 12345
+        };
+        let cookie_response = CookieResponse::new(&dht_precomputed_key, cookie_response_payload);
+
+        assert!(net_crypto.handle_tcp_packet(DhtPacket::CookieResponse(cookie_response), peer_dht_pk).wait().is_err());
+    }
+
+    #[test]
+    fn handle_tcp_crypto_handshake_new_connection() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, peer_real_sk) = gen_keypair();
+
+        let base_nonce = gen_nonce();
+        let session_pk = gen_keypair().0;
+        let our_cookie = Cookie::new(peer_real_pk, peer_dht_pk);
+        let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, our_cookie);
+        let cookie = EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![43; 88]
+        };
+        let crypto_handshake_payload = CryptoHandshakePayload {
+            base_nonce,
+            session_pk,
+            cookie_hash: our_encrypted_cookie.hash(),
+            cookie: cookie.clone()
+        };
+        let crypto_handshake = CryptoHandshake::new(&precompute(&real_pk, &peer_real_sk), crypto_handshake_payload, our_encrypted_cookie);
+
+        net_crypto.set_accept_policy(AcceptPolicy::AcceptAll);
+
+        assert!(net_crypto.handle_tcp_packet(DhtPacket::CryptoHandshake(crypto_handshake), peer_dht_pk).wait().is_ok());
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+
+        assert_eq!(connection.peer_dht_pk, peer_dht_pk);
+        assert_eq!(connection.udp_addr, None);
+        assert!(net_crypto.keys_by_addr.read().is_empty());
+        assert_eq!(unpack!(connection.status, ConnectionStatus::NotConfirmed, received_nonce), base_nonce);
+
+        let (received, _new_connection_rx) = new_connection_rx.into_future().wait().unwrap();
+        assert_eq!(received.unwrap(), (peer_real_pk, peer_dht_pk));
+
+        let (received, _tcp_rx) = tcp_rx.into_future().wait().unwrap();
+        let (packet, key_to_send) = received.unwrap();
+        assert_eq!(key_to_send, peer_dht_pk);
+        let packet = unpack!(packet, DhtPacket::CryptoHandshake);
+        assert_eq!(packet.cookie, cookie);
+    }
+
+    #[test]
+    fn handle_tcp_crypto_data_lossy() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+
+        assert!(net_crypto.handle_tcp_packet(DhtPacket::CryptoData(crypto_data), peer_dht_pk).wait().is_ok());
+
+        let (received, _lossy_rx) = lossy_rx.into_future().wait().unwrap();
+        let (received_peer_real_pk, received_data) = received.unwrap();
+        assert_eq!(received_peer_real_pk, peer_real_pk);
+        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]);
+    }
+
+    #[test]
+    fn handle_tcp_crypto_data_no_connection() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let crypto_data = CryptoData {
+            nonce_last_bytes: 123,
+            payload: vec![42; 123]
+        };
+
+        assert!(net_crypto.handle_tcp_packet(DhtPacket::CryptoData(crypto_data), peer_dht_pk).wait().is_err());
+    }
+
+    #[test]
+    fn handle_tcp_packet_unexpected() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let packet = DhtPacket::PingRequest(PingRequest {
+            pk: peer_dht_pk,
+            nonce: gen_nonce(),
+            payload: vec![42; 123]
+        });
+
+        assert!(net_crypto.handle_tcp_packet(packet, peer_dht_pk).wait().is_err());
+    }
+
+    #[test]
+    fn handle_tcp_data() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let cookie_request_payload = CookieRequestPayload {
+            pk: peer_real_pk,
+            id: 12345,
+        };
+        let cookie_request = DhtPacket::CookieRequest(CookieRequest::new(&precomputed_key, &peer_dht_pk, cookie_request_payload));
+
+        let mut buf = [0; MAX_DHT_PACKET_SIZE];
+        let (_, size) = cookie_request.to_bytes((&mut buf, 0)).unwrap();
+        net_crypto.handle_tcp_data(&buf[..size], peer_dht_pk).wait().unwrap();
+
+        let (received, _tcp_rx) = tcp_rx.into_future().wait().unwrap();
+        let (packet, key_to_send) = received.unwrap();
+        assert_eq!(key_to_send, peer_dht_pk);
+        let cookie_response = unpack!(packet, DhtPacket::CookieResponse);
+        assert_eq!(cookie_response.get_payload(&precomputed_key).unwrap().id, 12345);
+    }
+
+    #[test]
+    fn handle_tcp_data_invalid() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk
+        });
+
+        assert!(net_crypto.handle_tcp_data(&[42; 123], gen_keypair().0).wait().is_err());
+    }
+
+    #[test]
+    fn run_tcp_exchanges_data_through_relay() {
+        let (relay_pk, relay_sk) = gen_keypair();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = ::toxcore::tcp::server::run(listener, relay_sk, Server::new(), ServerConfig::default());
+
+        let (udp_tx_1, _udp_rx_1) = mpsc::unbounded();
+        let (tcp_tx_1, tcp_rx_1) = mpsc::unbounded();
+        let (dht_pk_tx_1, _dht_pk_rx_1) = mpsc::unbounded();
+        let (lossless_tx_1, _lossless_rx_1) = mpsc::unbounded();
+        let (lossy_tx_1, _lossy_rx_1) = mpsc::unbounded();
+        let (new_connection_tx_1, _new_connection_rx_1) = mpsc::unbounded();
+        let (connection_status_tx_1, _connection_status_rx_1) = mpsc::unbounded();
+        let (dht_pk_1, dht_sk_1) = gen_keypair();
+        let (real_pk_1, real_sk_1) = gen_keypair();
+        let net_crypto_1 = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx: udp_tx_1,
+            tcp_tx: tcp_tx_1,
+            dht_pk_tx: dht_pk_tx_1,
+            lossless_tx: lossless_tx_1,
+            lossy_tx: lossy_tx_1,
+            new_connection_tx: new_connection_tx_1,
+            connection_status_tx: connection_status_tx_1,
+            dht_pk: dht_pk_1,
+            dht_sk: dht_sk_1,
+            real_pk: real_pk_1,
+            real_sk: real_sk_1
+        });
+        let (udp_tx_2, _udp_rx_2) = mpsc::unbounded();
+        let (tcp_tx_2, tcp_rx_2) = mpsc::unbounded();
+        let (dht_pk_tx_2, _dht_pk_rx_2) = mpsc::unbounded();
+        let (lossless_tx_2, lossless_rx_2) = mpsc::unbounded();
+        let (lossy_tx_2, _lossy_rx_2) = mpsc::unbounded();
+        let (new_connection_tx_2, _new_connection_rx_2) = mpsc::unbounded();
+        let (connection_status_tx_2, _connection_status_rx_2) = mpsc::unbounded();
+        let (dht_pk_2, dht_sk_2) = gen_keypair();
+        let (real_pk_2, real_sk_2) = gen_keypair();
+        let net_crypto_2 = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx: udp_tx_2,
+            tcp_tx: tcp_tx_2,
+            dht_pk_tx: dht_pk_tx_2,
+            lossless_tx: lossless_tx_2,
+            lossy_tx: lossy_tx_2,
+            new_connection_tx: new_connection_tx_2,
+            connection_status_tx: connection_status_tx_2,
+            dht_pk: dht_pk_2,
+            dht_sk: dht_sk_2,
+            real_pk: real_pk_2,
+            real_sk: real_sk_2
+        });
+        net_crypto_2.set_accept_policy(AcceptPolicy::AcceptAll);
+
+        let (tcp_data_tx_1, tcp_data_rx_1) = mpsc::unbounded();
+        let (tcp_data_tx_2, tcp_data_rx_2) = mpsc::unbounded();
+        let connections_1 = Connections::new(net_crypto_1.dht_pk, net_crypto_1.dht_sk.clone(), tcp_data_tx_1);
+        let connections_2 = Connections::new(net_crypto_2.dht_pk, net_crypto_2.dht_sk.clone(), tcp_data_tx_2);
+        connections_1.add_relay(relay_pk, addr);
+        connections_2.add_relay(relay_pk, addr);
+        connections_1.add_friend(net_crypto_2.dht_pk);
+        connections_2.add_friend(net_crypto_1.dht_pk);
+
+        let real_pk_1 = net_crypto_1.real_pk;
+        let real_pk_2 = net_crypto_2.real_pk;
+        net_crypto_1.add_connection(real_pk_2, net_crypto_2.dht_pk);
+
+        let network = server.join5(
+            connections_1.run(),
+            connections_2.run(),
+            net_crypto_1.run_tcp(connections_1.clone(), tcp_rx_1, tcp_data_rx_1),
+            net_crypto_2.run_tcp(connections_2.clone(), tcp_rx_2, tcp_data_rx_2)
+        ).map(|_| ());
+
+        let main_loops = net_crypto_1.run().join(net_crypto_2.run()).map(|_| ());
+
+        // send data as soon as the handshake is received from the peer
+        let net_crypto_1_c = net_crypto_1.clone();
+        let sender = Interval::new(clock_now(), Duration::from_millis(50))
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Timer error: {:?}", e)))
+            .skip_while(move |_instant| Ok(net_crypto_1_c.lossless_send_capacity(real_pk_2) == 0))
+            .into_future()
+            .map_err(|(e, _interval)| e)
+            .and_then(move |_| net_crypto_1.send_lossless(real_pk_2, vec![PACKET_ID_CRYPTO_RANGE_END + 1, 42]))
+            .map(|_| ());
+        let receiver = lossless_rx_2
+            .into_future()
+            .map(move |(received, _lossless_rx_2)| assert_eq!(received.unwrap(), (real_pk_1, vec![PACKET_ID_CRYPTO_RANGE_END + 1, 42])))
+            .map_err(|_| Error::new(ErrorKind::Other, "rx error"));
+
+        let test = sender.join(receiver)
+            .map(|_| ())
+            .select(network.select(main_loops).map(|_| ()).map_err(|(e, _select_next)| e))
+            .map(|_| ())
+            .map_err(|(e, _select_next)| e)
+            .timeout(Duration::from_secs(10));
+
+        tokio::runtime::Runtime::new().unwrap().block_on(test).unwrap();
+    }
+
+    #[test]
+    fn send_status_packet() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
+
+        // send status packet first time - it should be sent
+        assert!(net_crypto.send_status_packet(&mut connection).wait().is_ok());
+
+        let packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet);
+        assert_eq!(packet.num_sent, 1);
+
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (received, addr_to_send) = received.unwrap();
+
+        assert_eq!(received, packet.dht_packet());
+        assert_eq!(addr_to_send, addr);
+        
+        // send status packet again - it shouldn't be sent
+        assert!(net_crypto.send_status_packet(&mut connection).wait().is_ok());
+
+        let packet = unpack!(connection.status, ConnectionStatus::CookieRequesting, packet);
+        assert_eq!(packet.num_sent, 1);
+    }
+
+    #[test]
+    fn send_packet_udp() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
+
+        let packet = DhtPacket::CryptoData(CryptoData {
+            nonce_last_bytes: 123,
+            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH]
+        });
+
+        assert!(net_crypto.send_packet(packet.clone(), &mut connection).wait().is_ok());
+
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (received, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+        assert_eq!(received, packet);
+    }
+
+    #[test]
+    fn send_packet_udp_attempt() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+
+        let packet = DhtPacket::CryptoData(CryptoData {
+            nonce_last_bytes: 123,
+            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH - 3] // 1 byte of packet kind and 2 bytes of nonce
+        });
+
+        assert!(net_crypto.send_packet(packet.clone(), &mut connection).wait().is_ok());
+
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (received, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+        assert_eq!(received, packet);
+
+        let (received, _tcp_rx) = tcp_rx.into_future().wait().unwrap();
+        let (received, key_to_send) = received.unwrap();
+
+        assert_eq!(key_to_send, peer_dht_pk);
+        assert_eq!(received, packet);
+    }
+
+    #[test]
+    fn send_packet_no_udp_attempt() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+
+        let packet = DhtPacket::CryptoData(CryptoData {
+            nonce_last_bytes: 123,
+            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH]
+        });
+
+        assert!(net_crypto.send_packet(packet.clone(), &mut connection).wait().is_ok());
+
+        let (received, _tcp_rx) = tcp_rx.into_future().wait().unwrap();
+        let (received, key_to_send) = received.unwrap();
+
+        assert_eq!(key_to_send, peer_dht_pk);
+        assert_eq!(received, packet);
+    }
+
+    #[test]
+    fn send_packet_tcp() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let packet = DhtPacket::CryptoData(CryptoData {
+            nonce_last_bytes: 123,
+            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH]
+        });
+
+        assert!(net_crypto.send_packet(packet.clone(), &mut connection).wait().is_ok());
+
+        let (received, _tcp_rx) = tcp_rx.into_future().wait().unwrap();
+        let (received, key_to_send) = received.unwrap();
+
+        assert_eq!(key_to_send, peer_dht_pk);
+        assert_eq!(received, packet);
+    }
+
+    #[test]
+    fn generate_request_packet() {
+        let mut recv_array = PacketsArray::new();
+        assert!(recv_array.insert(0, RecvPacket::new(vec![42; 123])).is_ok());
+        assert!(recv_array.insert(2, RecvPacket::new(vec![42; 123])).is_ok());
+        assert!(recv_array.insert(4, RecvPacket::new(vec![42; 123])).is_ok());
+        assert!(recv_array.insert(1030, RecvPacket::new(vec![42; 123])).is_ok());
+
+        let data = NetCrypto::generate_request_packet(&recv_array);
+
+        // packets 1, 3, 5 and 1029 are missing
+        let mut expected = vec![PACKET_ID_REQUEST, 2, 2, 2];
+        // packets 6 .. 1029 are missing as well
+        expected.extend(vec![1; 1029 - 5]);
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn generate_request_packet_big_offset() {
+        let mut recv_array = PacketsArray::new();
+        for i in 0 .. 1024 {
+            assert!(recv_array.insert(i, RecvPacket::new(vec![42; 123])).is_ok());
+        }
+        assert!(recv_array.insert(1025, RecvPacket::new(vec![42; 123])).is_ok());
+
+        let data = NetCrypto::generate_request_packet(&recv_array);
+
+        // only packet 1024 is missing
+        assert_eq!(data, vec![PACKET_ID_REQUEST, 0, 0, 0, 0, 5]);
+    }
+
+    #[test]
+    fn generate_and_handle_request_packet() {
+        let mut recv_array = PacketsArray::new();
+        let mut send_array = PacketsArray::new();
+        let now = Instant::now();
+        for i in 0 .. 1100 {
+            if i % 7 != 0 && i != 1024 {
+                assert!(recv_array.insert(i, RecvPacket::new(vec![42; 123])).is_ok());
+            }
+            let packet = SentPacket {
+                data: vec![42; 123],
+                sent_time: now,
+                requested: false,
+            };
+            assert!(send_array.push_back(packet).is_ok());
+        }
+        assert!(recv_array.insert(1100, RecvPacket::new(vec![42; 123])).is_ok());
+
+        let data = NetCrypto::generate_request_packet(&recv_array);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(1)));
+
+        let mut last_sent_time = None;
+        with_default(&clock, &mut enter, |_| {
+            NetCrypto::handle_request_packet(&mut send_array, &data[1..], Duration::from_millis(500), &mut last_sent_time);
+        });
+
+        for i in 0 .. 1100 {
+            if i % 7 == 0 || i == 1024 {
+                assert!(send_array.get(i).unwrap().requested);
+            } else {
+                assert!(send_array.get(i).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn send_lossless_rate_limit() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
+
+        connection.packets_left = 0;
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+
+        assert!(net_crypto.send_lossless(peer_real_pk, vec![PACKET_ID_CRYPTO_RANGE_END + 1]).wait().is_err());
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        assert_eq!(connection.send_array.buffer_end, 0);
+    }
+
+    #[test]
+    fn send_lossless_updates_counters() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+
+        assert!(net_crypto.send_lossless(peer_real_pk, vec![PACKET_ID_CRYPTO_RANGE_END + 1]).wait().is_ok());
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        assert_eq!(connection.packets_left, CRYPTO_MIN_QUEUE_LENGTH - 1);
+        assert_eq!(connection.packets_left_requested, CRYPTO_MIN_QUEUE_LENGTH - 1);
+        assert_eq!(connection.packets_sent, 1);
+    }
+
+    #[test]
+    fn main_loop_sends_request_packet() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
+
+        assert!(connection.recv_array.insert(1, RecvPacket::new(vec![42; 123])).is_ok());
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+
+        assert!(net_crypto.main_loop().wait().is_ok());
+
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+        assert_eq!(addr_to_send, addr);
+        let packet = unpack!(packet, DhtPacket::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        // packet 0 is missing
+        assert_eq!(payload.data, vec![PACKET_ID_REQUEST, 1]);
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        assert!(connection.last_request_packet_sent.is_some());
+        // request packet shouldn't be sent again right away
+        assert!(!connection.request_packet_should_be_sent());
+    }
+
+    #[test]
+    fn main_loop_sends_request_packet_when_not_confirmed() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        let packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet);
+        connection.status = ConnectionStatus::NotConfirmed {
+            sent_nonce,
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+            packet,
+        };
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+
+        assert!(net_crypto.main_loop().wait().is_ok());
+
+        drop(net_crypto);
+        let packets = udp_rx.collect().wait().unwrap();
+        let packet = packets.into_iter()
+            .filter_map(|(packet, _addr)| match packet {
+                DhtPacket::CryptoData(packet) => Some(packet),
+                _ => None,
+            })
+            .next()
+            .unwrap();
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.data, vec![PACKET_ID_REQUEST]);
+    }
+
+    #[test]
+    fn main_loop_resends_requested_packets() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
-        let (lossy_tx, lossy_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
-        let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
         let (peer_session_pk, _peer_session_sk) = gen_keypair();
         let (_session_pk, session_sk) = gen_keypair();
         let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
         connection.status = ConnectionStatus::Established {
-            sent_nonce: gen_nonce(),
-            received_nonce,
+            sent_nonce,
+            received_nonce: gen_nonce(),
             peer_session_pk,
             session_precomputed_key: session_precomputed_key.clone(),
         };
 
-        let crypto_data_payload = CryptoDataPayload {
-            buffer_start: 0,
-            packet_number: 0,
-            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
+
+        let connection_time = connection.last_packets_left_set;
+        connection.last_request_packet_sent = Some(connection_time);
+
+        let now = Instant::now();
+        let packet_0 = SentPacket {
+            data: vec![PACKET_ID_CRYPTO_RANGE_END + 1, 1],
+            sent_time: now,
+            requested: false,
         };
-        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+        let packet_1 = SentPacket {
+            data: vec![PACKET_ID_CRYPTO_RANGE_END + 1, 2],
+            sent_time: now,
+            requested: true,
+        };
+        assert!(connection.send_array.push_back(packet_0).is_ok());
+        assert!(connection.send_array.push_back(packet_1).is_ok());
 
-        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_ok());
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
 
-        // The diff between nonces is not bigger than the threshold so received
-        // nonce shouldn't be changed
-        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
+        // freeze time so that packets left won't be increased
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(connection_time));
 
-        assert_eq!(connection.recv_array.buffer_start, 0);
-        assert_eq!(connection.recv_array.buffer_end, 0);
-        assert_eq!(connection.send_array.buffer_start, 0);
-        assert_eq!(connection.send_array.buffer_end, 0);
+        with_default(&clock, &mut enter, |_| {
+            assert!(net_crypto.main_loop().wait().is_ok());
+        });
 
-        let (received, _lossy_rx) = lossy_rx.into_future().wait().unwrap();
-        let (received_peer_real_pk, received_data) = received.unwrap();
-        assert_eq!(received_peer_real_pk, peer_real_pk);
-        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]);
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+        assert_eq!(addr_to_send, addr);
+        let packet = unpack!(packet, DhtPacket::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.packet_number, 1);
+        assert_eq!(payload.data, vec![PACKET_ID_CRYPTO_RANGE_END + 1, 2]);
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        assert!(!connection.send_array.get(1).unwrap().requested);
+        assert_eq!(connection.packets_resent, 1);
+        assert_eq!(connection.packets_left_requested, CRYPTO_MIN_QUEUE_LENGTH - 1);
+        assert_eq!(connection.packets_left, CRYPTO_MIN_QUEUE_LENGTH - 1);
     }
 
     #[test]
-    fn handle_crypto_data_lossy_increment_nonce() {
-        let (udp_tx, _udp_rx) = mpsc::unbounded();
+    fn main_loop_resends_requested_packets_limit() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
-        let (lossy_tx, lossy_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
-        let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
         let (peer_session_pk, _peer_session_sk) = gen_keypair();
         let (_session_pk, session_sk) = gen_keypair();
         let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
         connection.status = ConnectionStatus::Established {
-            sent_nonce: gen_nonce(),
-            received_nonce,
+            sent_nonce,
+            received_nonce: gen_nonce(),
             peer_session_pk,
             session_precomputed_key: session_precomputed_key.clone(),
         };
 
-        // Make the diff between nonces is bigger than the threshold
-        let mut packet_nonce = received_nonce;
-        increment_nonce_number(&mut packet_nonce, (2 * NONCE_DIFF_THRESHOLD + 1) as usize);
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
 
-        let crypto_data_payload = CryptoDataPayload {
-            buffer_start: 0,
-            packet_number: 0,
-            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
-        };
-        let crypto_data = CryptoData::new(&session_precomputed_key, packet_nonce, crypto_data_payload);
+        let connection_time = connection.last_packets_left_set;
+        connection.last_request_packet_sent = Some(connection_time);
+        connection.packets_left = 0;
+        connection.packets_left_requested = 1;
 
-        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_ok());
+        for _ in 0 .. 2 {
+            let packet = SentPacket {
+                data: vec![PACKET_ID_CRYPTO_RANGE_END + 1],
+                sent_time: Instant::now(),
+                requested: true,
+            };
+            assert!(connection.send_array.push_back(packet).is_ok());
+        }
 
-        // The diff between nonces is bigger than the threshold so received
-        // nonce should be changed increased
-        let mut expected_nonce = received_nonce;
-        increment_nonce_number(&mut expected_nonce, NONCE_DIFF_THRESHOLD as usize);
-        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), expected_nonce);
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
 
-        assert_eq!(connection.recv_array.buffer_start, 0);
-        assert_eq!(connection.recv_array.buffer_end, 0);
-        assert_eq!(connection.send_array.buffer_start, 0);
-        assert_eq!(connection.send_array.buffer_end, 0);
+        // freeze time so that packets left won't be increased
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(connection_time));
 
-        let (received, _lossy_rx) = lossy_rx.into_future().wait().unwrap();
-        let (received_peer_real_pk, received_data) = received.unwrap();
-        assert_eq!(received_peer_real_pk, peer_real_pk);
-        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]);
+        with_default(&clock, &mut enter, |_| {
+            assert!(net_crypto.main_loop().wait().is_ok());
+        });
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        // only one packet can be resent
+        assert!(!connection.send_array.get(0).unwrap().requested);
+        assert!(connection.send_array.get(1).unwrap().requested);
+        assert_eq!(connection.packets_left_requested, 0);
+        assert!(connection.last_congestion_event.is_some());
+
+        drop(net_crypto);
+        assert_eq!(udp_rx.collect().wait().unwrap().len(), 1);
     }
 
     #[test]
-    fn handle_crypto_data_lossy_update_rtt() {
-        let (udp_tx, _udp_rx) = mpsc::unbounded();
+    fn main_loop_sends_status_packets() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
-        let (lossy_tx, lossy_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
-        let now = Instant::now();
+        let packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet).dht_packet();
 
-        let sent_packet = SentPacket {
-            data: vec![42; 123],
-            sent_time: now,
-            requested: false,
-        };
-        assert!(connection.send_array.insert(0, sent_packet).is_ok());
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
 
-        connection.rtt = Duration::from_millis(500);
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
 
-        let received_nonce = gen_nonce();
-        let (peer_session_pk, _peer_session_sk) = gen_keypair();
-        let (_session_pk, session_sk) = gen_keypair();
-        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
-        connection.status = ConnectionStatus::Established {
-            sent_nonce: gen_nonce(),
-            received_nonce,
-            peer_session_pk,
-            session_precomputed_key: session_precomputed_key.clone(),
-        };
+        assert!(net_crypto.main_loop().wait().is_ok());
 
-        let crypto_data_payload = CryptoDataPayload {
-            buffer_start: 1,
-            packet_number: 0,
-            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
-        };
-        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (received, addr_to_send) = received.unwrap();
 
-        let mut enter = tokio_executor::enter().unwrap();
-        let clock = Clock::new_with_now(ConstNow(now + Duration::from_millis(250)));
+        assert_eq!(addr_to_send, addr);
+        assert_eq!(received, packet);
+    }
 
-        with_default(&clock, &mut enter, |_| {
-            assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_ok());
+    #[test]
+    fn run_resends_lost_packets() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
-        // The diff between nonces is not bigger than the threshold so received
-        // nonce shouldn't be changed
-        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
-        assert_eq!(connection.recv_array.buffer_start, 0);
-        assert_eq!(connection.recv_array.buffer_end, 0);
-        assert_eq!(connection.send_array.buffer_start, 1);
-        assert_eq!(connection.send_array.buffer_end, 1);
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
 
-        let (received, _lossy_rx) = lossy_rx.into_future().wait().unwrap();
-        let (received_peer_real_pk, received_data) = received.unwrap();
-        assert_eq!(received_peer_real_pk, peer_real_pk);
-        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]);
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
 
-        assert_eq!(connection.rtt, Duration::from_millis(250));
+        // the first cookie request is considered lost, it should be resent
+        // without calling main_loop manually
+        let resent = udp_rx
+            .filter(|&(ref packet, _)| match *packet {
+                DhtPacket::CookieRequest(_) => true,
+                _ => false,
+            })
+            .skip(1)
+            .into_future()
+            .map(move |(received, _udp_rx)| {
+                let (_packet, addr_to_send) = received.unwrap();
+                assert_eq!(addr_to_send, addr);
+            })
+            .map_err(|_| Error::new(ErrorKind::Other, "rx error"));
+
+        let test = resent
+            .select(net_crypto.run())
+            .map(|_| ())
+            .map_err(|(e, _select_next)| e)
+            .timeout(Duration::from_secs(10));
+
+        tokio::runtime::Runtime::new().unwrap().block_on(test).unwrap();
     }
 
     #[test]
-    fn handle_crypto_data_lossy_invalid_buffer_start() {
+    fn main_loop_sends_transport_events() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
-        let received_nonce = gen_nonce();
-        let (peer_session_pk, _peer_session_sk) = gen_keypair();
-        let (_session_pk, session_sk) = gen_keypair();
-        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
         connection.status = ConnectionStatus::Established {
             sent_nonce: gen_nonce(),
-            received_nonce,
-            peer_session_pk,
-            session_precomputed_key: session_precomputed_key.clone(),
+            received_nonce: gen_nonce(),
+            peer_session_pk: gen_keypair().0,
+            session_precomputed_key: precompute(&gen_keypair().0, &gen_keypair().1),
         };
 
-        let crypto_data_payload = CryptoDataPayload {
-            buffer_start: 7, // bigger than end index of sent packets buffer
-            packet_number: 0,
-            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
-        };
-        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
 
-        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_err());
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
 
-        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
+        assert!(net_crypto.main_loop().wait().is_ok());
 
-        assert_eq!(connection.recv_array.buffer_start, 0);
-        assert_eq!(connection.recv_array.buffer_end, 0);
-        assert_eq!(connection.send_array.buffer_start, 0);
-        assert_eq!(connection.send_array.buffer_end, 0);
+        assert!(connection.read().udp_connected);
+
+        let (received, connection_status_rx) = connection_status_rx.into_future().wait().unwrap();
+        let (received_peer_real_pk, event) = received.unwrap();
+        assert_eq!(received_peer_real_pk, peer_real_pk);
+        assert_eq!(event, ConnectionEvent::Udp);
+
+        // make UDP dead
+        connection.write().udp_received_time = Some(clock_now() - Duration::from_secs(UDP_DIRECT_TIMEOUT + 1));
+
+        assert!(net_crypto.main_loop().wait().is_ok());
+
+        assert!(!connection.read().udp_connected);
+
+        let (received, _connection_status_rx) = connection_status_rx.into_future().wait().unwrap();
+        let (received_peer_real_pk, event) = received.unwrap();
+        assert_eq!(received_peer_real_pk, peer_real_pk);
+        assert_eq!(event, ConnectionEvent::Tcp);
     }
 
     #[test]
-    fn handle_crypto_data_lossless() {
+    fn main_loop_removes_timed_out_connections() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
-        let (lossless_tx, lossless_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk: real_sk.clone()
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+
+        // make the connection timed out
+        let cookie_request_id = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, cookie_request_id);
+        let mut packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet);
+        packet.num_sent = MAX_NUM_SENDPACKET_TRIES;
+        packet.sent_time -= Duration::from_secs(CRYPTO_SEND_PACKET_INTERVAL + 1);
+        connection.status = ConnectionStatus::CookieRequesting {
+            cookie_request_id,
+            packet
+        };
+
+        assert!(connection.is_timed_out());
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+
+        assert!(net_crypto.main_loop().wait().is_ok());
+
+        assert!(net_crypto.connections.read().is_empty());
+        assert!(net_crypto.keys_by_addr.read().is_empty());
+
+        let (received, _connection_status_rx) = connection_status_rx.into_future().wait().unwrap();
+        let (received_peer_real_pk, event) = received.unwrap();
+        assert_eq!(received_peer_real_pk, peer_real_pk);
+        assert_eq!(event, ConnectionEvent::TimedOut);
+    }
+
+    #[test]
+    fn main_loop_sends_events_when_packets_are_not_sent() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
+        // sending of UDP packets fails
+        drop(udp_rx);
+
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
-
-        let received_nonce = gen_nonce();
-        let (peer_session_pk, _peer_session_sk) = gen_keypair();
-        let (_session_pk, session_sk) = gen_keypair();
-        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
-        connection.status = ConnectionStatus::Established {
-            sent_nonce: gen_nonce(),
-            received_nonce,
-            peer_session_pk,
-            session_precomputed_key: session_precomputed_key.clone(),
-        };
-
-        let crypto_data_payload_1 = CryptoDataPayload {
-            buffer_start: 0,
-            packet_number: 0,
-            data: vec![PACKET_ID_LOSSY_RANGE_START - 1, 1, 2, 3]
-        };
-        let crypto_data_1 = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload_1);
+        let mut connection = CryptoConnection::new(dht_sk.clone(), dht_pk, real_sk.clone(), real_pk, peer_real_pk, peer_dht_pk);
+        connection.udp_addr = Some("127.0.0.1:12345".parse().unwrap());
+        connection.update_udp_received_time();
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
 
-        let crypto_data_payload_2 = CryptoDataPayload {
-            buffer_start: 0,
-            packet_number: 1,
-            data: vec![PACKET_ID_LOSSY_RANGE_START - 1, 4, 5, 6]
-        };
-        let crypto_data_2 = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload_2);
+        let (timed_out_dht_pk, _timed_out_dht_sk) = gen_keypair();
+        let (timed_out_real_pk, _timed_out_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, timed_out_real_pk, timed_out_dht_pk);
 
-        let crypto_data_payload_3 = CryptoDataPayload {
-            buffer_start: 0,
-            packet_number: 2,
-            data: vec![PACKET_ID_LOSSY_RANGE_START - 1, 7, 8, 9]
+        // make the connection timed out
+        let cookie_request_id = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, cookie_request_id);
+        let mut packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet);
+        packet.num_sent = MAX_NUM_SENDPACKET_TRIES;
+        packet.sent_time -= Duration::from_secs(CRYPTO_SEND_PACKET_INTERVAL + 1);
+        connection.status = ConnectionStatus::CookieRequesting {
+            cookie_request_id,
+            packet
         };
-        let crypto_data_3 = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload_3);
-
-        // Send packets in random order
-        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data_2, /* udp */ true).wait().is_ok());
-        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data_3, /* udp */ true).wait().is_ok());
-        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data_1, /* udp */ true).wait().is_ok());
-
-        // The diff between nonces is not bigger than the threshold so received
-        // nonce shouldn't be changed
-        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
-
-        assert_eq!(connection.recv_array.buffer_start, 3);
-        assert_eq!(connection.recv_array.buffer_end, 3);
-        assert_eq!(connection.send_array.buffer_start, 0);
-        assert_eq!(connection.send_array.buffer_end, 0);
-
-        // We should receive lossless packets according to their numbers
+        net_crypto.connections.write().insert(timed_out_real_pk, Arc::new(RwLock::new(connection)));
 
-        let (received, lossless_rx) = lossless_rx.into_future().wait().unwrap();
-        let (received_peer_real_pk, received_data) = received.unwrap();
-        assert_eq!(received_peer_real_pk, peer_real_pk);
-        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START - 1, 1, 2, 3]);
+        assert!(net_crypto.main_loop().wait().is_ok());
 
-        let (received, lossless_rx) = lossless_rx.into_future().wait().unwrap();
-        let (received_peer_real_pk, received_data) = received.unwrap();
-        assert_eq!(received_peer_real_pk, peer_real_pk);
-        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START - 1, 4, 5, 6]);
+        assert!(!net_crypto.connections.read().contains_key(&timed_out_real_pk));
 
-        let (received, _lossless_rx) = lossless_rx.into_future().wait().unwrap();
-        let (received_peer_real_pk, received_data) = received.unwrap();
-        assert_eq!(received_peer_real_pk, peer_real_pk);
-        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START - 1, 7, 8, 9]);
+        let (received, _connection_status_rx) = connection_status_rx.into_future().wait().unwrap();
+        let (received_peer_real_pk, event) = received.unwrap();
+        assert_eq!(received_peer_real_pk, timed_out_real_pk);
+        assert_eq!(event, ConnectionEvent::TimedOut);
     }
 
     #[test]
-    fn handle_crypto_data_lossless_too_big_index() {
+    fn add_connection() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
-
-        let received_nonce = gen_nonce();
-        let (peer_session_pk, _peer_session_sk) = gen_keypair();
-        let (_session_pk, session_sk) = gen_keypair();
-        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
-        connection.status = ConnectionStatus::Established {
-            sent_nonce: gen_nonce(),
-            received_nonce,
-            peer_session_pk,
-            session_precomputed_key: session_precomputed_key.clone(),
-        };
-
-        let crypto_data_payload = CryptoDataPayload {
-            buffer_start: 0,
-            packet_number: CRYPTO_PACKET_BUFFER_SIZE,
-            data: vec![PACKET_ID_LOSSY_RANGE_START - 1, 1, 2, 3]
-        };
-        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
 
-        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_err());
-
-        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
-
-        assert_eq!(connection.recv_array.buffer_start, 0);
-        assert_eq!(connection.recv_array.buffer_end, 0);
-        assert_eq!(connection.send_array.buffer_start, 0);
-        assert_eq!(connection.send_array.buffer_end, 0);
+        let connections = net_crypto.connections.read();
+        let connection = connections.get(&peer_real_pk).unwrap().read().clone();
+        assert_eq!(connection.peer_real_pk, peer_real_pk);
+        assert_eq!(connection.peer_dht_pk, peer_dht_pk);
+        assert!(unpack!(connection.status, ConnectionStatus::CookieRequesting, packet).should_be_sent());
     }
 
     #[test]
-    fn handle_crypto_data_kill() {
+    fn add_connection_already_exists() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
-
-        let addr = "127.0.0.1:12345".parse().unwrap();
-        connection.udp_addr = Some(addr);
-
-        let received_nonce = gen_nonce();
-        let (peer_session_pk, _peer_session_sk) = gen_keypair();
-        let (_session_pk, session_sk) = gen_keypair();
-        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
-        connection.status = ConnectionStatus::Established {
-            sent_nonce: gen_nonce(),
-            received_nonce,
-            peer_session_pk,
-            session_precomputed_key: session_precomputed_key.clone(),
-        };
-
-        let connection = Arc::new(RwLock::new(connection));
-        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
-
-        let crypto_data_payload = CryptoDataPayload {
-            buffer_start: 0,
-            packet_number: 0,
-            data: vec![PACKET_ID_KILL]
-        };
-        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
 
-        assert!(net_crypto.handle_crypto_data(&mut connection.write(), crypto_data, /* udp */ true).wait().is_ok());
+        let (another_peer_dht_pk, _another_peer_dht_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, another_peer_dht_pk);
 
-        assert!(net_crypto.connections.read().is_empty());
-        assert!(net_crypto.keys_by_addr.read().is_empty());
+        let connections = net_crypto.connections.read();
+        let connection = connections.get(&peer_real_pk).unwrap().read().clone();
+        assert_eq!(connection.peer_dht_pk, peer_dht_pk);
     }
 
     #[test]
-    fn handle_crypto_data_request() {
+    fn set_friend_udp_addr() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
-
-        let now = Instant::now();
-
-        assert!(connection.send_array.insert(0, SentPacket::new(vec![42; 123])).is_ok());
-        // this time will be used to update rtt
-        let packet_1 = SentPacket {
-            data: vec![43; 123],
-            sent_time: now + Duration::from_millis(750),
-            requested: false,
-        };
-        assert!(connection.send_array.insert(1, packet_1).is_ok());
-        // this packet will be requested but elapsed time will be less then rtt
-        // so it shouldn't be marked
-        let packet_5 = SentPacket {
-            data: vec![44; 123],
-            sent_time: now + Duration::from_millis(750),
-            requested: false,
-        };
-        assert!(connection.send_array.insert(5, packet_5).is_ok());
-        assert!(connection.send_array.insert(7, SentPacket::new(vec![45; 123])).is_ok());
-        assert!(connection.send_array.insert(1024, SentPacket::new(vec![46; 123])).is_ok());
-
-        connection.rtt = Duration::from_millis(500);
-
-        let received_nonce = gen_nonce();
-        let (peer_session_pk, _peer_session_sk) = gen_keypair();
-        let (_session_pk, session_sk) = gen_keypair();
-        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
-        connection.status = ConnectionStatus::Established {
-            sent_nonce: gen_nonce(),
-            received_nonce,
-            peer_session_pk,
-            session_precomputed_key: session_precomputed_key.clone(),
-        };
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
 
-        let crypto_data_payload = CryptoDataPayload {
-            buffer_start: 0,
-            packet_number: 0,
-            data: vec![PACKET_ID_REQUEST, 1, 5, 0, 0, 0, 254] // request 0, 5 and 1024 packets
-        };
-        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+        let old_addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        assert!(net_crypto.set_friend_udp_addr(peer_real_pk, old_addr).wait().is_ok());
+        let addr: SocketAddr = "127.0.0.1:12346".parse().unwrap();
+        assert!(net_crypto.set_friend_udp_addr(peer_real_pk, addr).wait().is_ok());
 
-        let mut enter = tokio_executor::enter().unwrap();
-        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(1)));
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        assert_eq!(connection.udp_addr, Some(addr));
+        assert_eq!(net_crypto.key_by_addr(addr), Some(peer_real_pk));
+        assert_eq!(net_crypto.key_by_addr(old_addr), None);
+    }
 
-        with_default(&clock, &mut enter, |_| {
-            assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_ok());
+    #[test]
+    fn set_friend_udp_addr_no_connection() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            tcp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk
         });
 
-        assert!(connection.send_array.get(0).unwrap().requested);
-        assert!(connection.send_array.get(1).is_none());
-        assert!(!connection.send_array.get(5).unwrap().requested);
-        assert!(connection.send_array.get(7).is_none());
-        assert!(connection.send_array.get(1024).unwrap().requested);
-
-        assert_eq!(connection.rtt, Duration::from_millis(250));
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        assert!(net_crypto.set_friend_udp_addr(peer_real_pk, addr).wait().is_err());
     }
 
     #[test]
-    fn handle_crypto_data_empty_request() {
-        let (udp_tx, _udp_rx) = mpsc::unbounded();
+    fn send_lossless() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
-        assert!(connection.send_array.insert(0, SentPacket::new(vec![42; 123])).is_ok());
-        assert!(connection.send_array.insert(1, SentPacket::new(vec![43; 123])).is_ok());
-        assert!(connection.send_array.insert(5, SentPacket::new(vec![44; 123])).is_ok());
-        assert!(connection.send_array.insert(7, SentPacket::new(vec![45; 123])).is_ok());
-        assert!(connection.send_array.insert(1024, SentPacket::new(vec![46; 123])).is_ok());
-
-        let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
         let (peer_session_pk, _peer_session_sk) = gen_keypair();
         let (_session_pk, session_sk) = gen_keypair();
         let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
         connection.status = ConnectionStatus::Established {
-            sent_nonce: gen_nonce(),
-            received_nonce,
+            sent_nonce,
+            received_nonce: gen_nonce(),
             peer_session_pk,
             session_precomputed_key: session_precomputed_key.clone(),
         };
 
-        let crypto_data_payload = CryptoDataPayload {
-            buffer_start: 0,
-            packet_number: 0,
-            data: vec![PACKET_ID_REQUEST]
-        };
-        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
 
-        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_ok());
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
 
-        assert!(!connection.send_array.get(0).unwrap().requested);
-        assert!(!connection.send_array.get(1).unwrap().requested);
-        assert!(!connection.send_array.get(5).unwrap().requested);
-        assert!(!connection.send_array.get(7).unwrap().requested);
-        assert!(!connection.send_array.get(1024).unwrap().requested);
+        let data = vec![PACKET_ID_CRYPTO_RANGE_END + 1, 1, 2, 3];
+        assert_eq!(net_crypto.send_lossless(peer_real_pk, data.clone()).wait().unwrap(), 0);
+        assert_eq!(net_crypto.send_lossless(peer_real_pk, data.clone()).wait().unwrap(), 1);
+
+        let (received, udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+        assert_eq!(addr_to_send, addr);
+        let packet = unpack!(packet, DhtPacket::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.buffer_start, 0);
+        assert_eq!(payload.packet_number, 0);
+        assert_eq!(payload.data, data);
+
+        // the second packet should be encrypted with incremented nonce
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (packet, _addr_to_send) = received.unwrap();
+        let packet = unpack!(packet, DhtPacket::CryptoData);
+        let mut next_nonce = sent_nonce;
+        increment_nonce(&mut next_nonce);
+        let payload = packet.get_payload(&session_precomputed_key, &next_nonce).unwrap();
+        assert_eq!(payload.packet_number, 1);
+        assert_eq!(payload.data, data);
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        assert_eq!(connection.send_array.buffer_start, 0);
+        assert_eq!(connection.send_array.buffer_end, 2);
+        assert_eq!(connection.send_array.get(0).unwrap().data, data);
+        let mut expected_nonce = next_nonce;
+        increment_nonce(&mut expected_nonce);
+        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, sent_nonce), expected_nonce);
     }
 
     #[test]
-    fn handle_crypto_data_invalid_packet_id() {
+    fn is_packet_delivered() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
-
-        let received_nonce = gen_nonce();
-        let (peer_session_pk, _peer_session_sk) = gen_keypair();
-        let (_session_pk, session_sk) = gen_keypair();
-        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
-        connection.status = ConnectionStatus::Established {
-            sent_nonce: gen_nonce(),
-            received_nonce,
-            peer_session_pk,
-            session_precomputed_key: session_precomputed_key.clone(),
-        };
-
-        let crypto_data_payload = CryptoDataPayload {
-            buffer_start: 0,
-            packet_number: 0,
-            data: vec![255, 1, 2, 3] // only 255 is invalid id
-        };
-        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
-        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_err());
+        for _ in 0 .. 4 {
+            connection.send_array.push_back(SentPacket::new(vec![PACKET_ID_CRYPTO_RANGE_END + 1])).unwrap();
+        }
+        connection.send_array.set_buffer_start(2).unwrap();
 
-        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
 
-        assert_eq!(connection.recv_array.buffer_start, 0);
-        assert_eq!(connection.recv_array.buffer_end, 0);
-        assert_eq!(connection.send_array.buffer_start, 0);
-        assert_eq!(connection.send_array.buffer_end, 0);
+        assert!(net_crypto.is_packet_delivered(peer_real_pk, 0));
+        assert!(net_crypto.is_packet_delivered(peer_real_pk, 1));
+        assert!(!net_crypto.is_packet_delivered(peer_real_pk, 2));
+        assert!(!net_crypto.is_packet_delivered(peer_real_pk, 3));
+        // unknown connection
+        assert!(!net_crypto.is_packet_delivered(gen_keypair().0, 0));
     }
 
     #[test]
-    fn handle_crypto_data_empty_data() {
+    fn lossless_send_capacity() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
 
-        let received_nonce = gen_nonce();
-        let (peer_session_pk, _peer_session_sk) = gen_keypair();
-        let (_session_pk, session_sk) = gen_keypair();
-        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
-        connection.status = ConnectionStatus::Established {
-            sent_nonce: gen_nonce(),
-            received_nonce,
-            peer_session_pk,
-            session_precomputed_key: session_precomputed_key.clone(),
-        };
+        // connection is not established
+        assert_eq!(net_crypto.lossless_send_capacity(peer_real_pk), 0);
 
-        let crypto_data_payload = CryptoDataPayload {
-            buffer_start: 0,
-            packet_number: 0,
-            data: Vec::new()
+        // connection is not confirmed yet but send_lossless accepts data
+        connection.write().status = ConnectionStatus::NotConfirmed {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            peer_session_pk: gen_keypair().0,
+            session_precomputed_key: precompute(&gen_keypair().0, &gen_keypair().1),
+            packet: StatusPacket::new_crypto_handshake(CryptoHandshake {
+                cookie: EncryptedCookie {
+                    nonce: secretbox::gen_nonce(),
+                    payload: vec![42; 88],
+                },
+                nonce: gen_nonce(),
+                payload: vec![42; 248],
+            }),
         };
-        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
+        connection.write().packets_left = 3;
+        assert_eq!(net_crypto.lossless_send_capacity(peer_real_pk), 3);
 
-        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_err());
+        {
+            let mut connection = connection.write();
+            connection.status = ConnectionStatus::Established {
+                sent_nonce: gen_nonce(),
+                received_nonce: gen_nonce(),
+                peer_session_pk: gen_keypair().0,
+                session_precomputed_key: precompute(&gen_keypair().0, &gen_keypair().1),
+            };
+            connection.packets_left = CRYPTO_PACKET_BUFFER_SIZE;
+            for _ in 0 .. 4 {
+                connection.send_array.push_back(SentPacket::new(vec![PACKET_ID_CRYPTO_RANGE_END + 1])).unwrap();
+            }
+        }
+        assert_eq!(net_crypto.lossless_send_capacity(peer_real_pk), CRYPTO_PACKET_BUFFER_SIZE - 4);
 
-        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
+        // send rate limits capacity
+        connection.write().packets_left = 2;
+        assert_eq!(net_crypto.lossless_send_capacity(peer_real_pk), 2);
 
-        assert_eq!(connection.recv_array.buffer_start, 0);
-        assert_eq!(connection.recv_array.buffer_end, 0);
-        assert_eq!(connection.send_array.buffer_start, 0);
-        assert_eq!(connection.send_array.buffer_end, 0);
+        // unknown connection
+        assert_eq!(net_crypto.lossless_send_capacity(gen_keypair().0), 0);
     }
 
     #[test]
-    fn handle_crypto_data_invalid_status() {
-        let (udp_tx, _udp_rx) = mpsc::unbounded();
+    fn send_lossless_transport_failure() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
+        // sending of UDP packets fails
+        drop(udp_rx);
+
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
-        let received_nonce = gen_nonce();
-        let (peer_session_pk, _peer_session_sk) = gen_keypair();
-        let (_session_pk, session_sk) = gen_keypair();
-        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
-        let crypto_data_payload = CryptoDataPayload {
-            buffer_start: 0,
-            packet_number: 0,
-            data: vec![0, 0, PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            peer_session_pk: gen_keypair().0,
+            session_precomputed_key: precompute(&gen_keypair().0, &gen_keypair().1),
         };
-        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
 
-        assert!(net_crypto.handle_crypto_data(&mut connection, crypto_data, /* udp */ true).wait().is_err());
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+
+        // the packet is queued and will be resent
+        let data = vec![PACKET_ID_CRYPTO_RANGE_END + 1, 1, 2, 3];
+        assert_eq!(net_crypto.send_lossless(peer_real_pk, data.clone()).wait().unwrap(), 0);
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        assert_eq!(connection.send_array.get(0).unwrap().data, data);
     }
 
     #[test]
-    fn handle_udp_crypto_data_lossy() {
+    fn send_lossless_invalid_data() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
-        let (lossy_tx, lossy_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
-        let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
         let (peer_session_pk, _peer_session_sk) = gen_keypair();
         let (_session_pk, session_sk) = gen_keypair();
         let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
         connection.status = ConnectionStatus::Established {
-            sent_nonce: gen_nonce(),
-            received_nonce,
+            sent_nonce,
+            received_nonce: gen_nonce(),
             peer_session_pk,
             session_precomputed_key: session_precomputed_key.clone(),
         };
 
         let addr = "127.0.0.1:12345".parse().unwrap();
         connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
 
         net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
         net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
 
-        let crypto_data_payload = CryptoDataPayload {
-            buffer_start: 0,
-            packet_number: 0,
-            data: vec![0, 0, PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
-        };
-        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, crypto_data_payload);
-
-        assert!(net_crypto.handle_udp_crypto_data(crypto_data, addr).wait().is_ok());
-
-        let connections = net_crypto.connections.read();
-        let connection = connections.get(&peer_real_pk).unwrap().read().clone();
-
-        // The diff between nonces is not bigger than the threshold so received
-        // nonce shouldn't be changed
-        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
+        assert!(net_crypto.send_lossless(peer_real_pk, Vec::new()).wait().is_err());
+        assert!(net_crypto.send_lossless(peer_real_pk, vec![PACKET_ID_KILL]).wait().is_err());
+        assert!(net_crypto.send_lossless(peer_real_pk, vec![PACKET_ID_LOSSY_RANGE_START]).wait().is_err());
+        assert!(net_crypto.send_lossless(peer_real_pk, vec![PACKET_ID_CRYPTO_RANGE_END + 1; MAX_CRYPTO_DATA_SIZE + 1]).wait().is_err());
 
-        assert_eq!(connection.recv_array.buffer_start, 0);
-        assert_eq!(connection.recv_array.buffer_end, 0);
-        assert_eq!(connection.send_array.buffer_start, 0);
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
         assert_eq!(connection.send_array.buffer_end, 0);
-
-        let (received, _lossy_rx) = lossy_rx.into_future().wait().unwrap();
-        let (received_peer_real_pk, received_data) = received.unwrap();
-        assert_eq!(received_peer_real_pk, peer_real_pk);
-        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]);
     }
 
     #[test]
-    fn send_status_packet() {
-        let (udp_tx, udp_rx) = mpsc::unbounded();
+    fn send_lossless_not_established() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
 
-        let addr = "127.0.0.1:12345".parse().unwrap();
-        connection.udp_addr = Some(addr);
-        connection.update_udp_received_time();
-
-        // send status packet first time - it should be sent
-        assert!(net_crypto.send_status_packet(&mut connection).wait().is_ok());
-
-        let packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet);
-        assert_eq!(packet.num_sent, 1);
-
-        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
-        let (received, addr_to_send) = received.unwrap();
-
-        assert_eq!(received, packet.dht_packet());
-        assert_eq!(addr_to_send, addr);
-        
-        // send status packet again - it shouldn't be sent
-        assert!(net_crypto.send_status_packet(&mut connection).wait().is_ok());
+        assert!(net_crypto.send_lossless(peer_real_pk, vec![PACKET_ID_CRYPTO_RANGE_END + 1]).wait().is_err());
 
-        let packet = unpack!(connection.status, ConnectionStatus::CookieRequesting, packet);
-        assert_eq!(packet.num_sent, 1);
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        assert_eq!(connection.send_array.buffer_end, 0);
     }
 
     #[test]
-    fn send_packet_udp() {
-        let (udp_tx, udp_rx) = mpsc::unbounded();
+    fn send_lossless_no_connection() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk
         });
 
-        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
-
-        let addr = "127.0.0.1:12345".parse().unwrap();
-        connection.udp_addr = Some(addr);
-        connection.update_udp_received_time();
-
-        let packet = DhtPacket::CryptoData(CryptoData {
-            nonce_last_bytes: 123,
-            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH]
-        });
-
-        assert!(net_crypto.send_packet(packet.clone(), &mut connection).wait().is_ok());
-
-        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
-        let (received, addr_to_send) = received.unwrap();
-
-        assert_eq!(addr_to_send, addr);
-        assert_eq!(received, packet);
+        assert!(net_crypto.send_lossless(peer_real_pk, vec![PACKET_ID_CRYPTO_RANGE_END + 1]).wait().is_err());
     }
 
     #[test]
-    fn send_packet_udp_attempt() {
+    fn send_lossy() {
         let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
 
         let addr = "127.0.0.1:12345".parse().unwrap();
         connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
 
-        let packet = DhtPacket::CryptoData(CryptoData {
-            nonce_last_bytes: 123,
-            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH - 3] // 1 byte of packet kind and 2 bytes of nonce
-        });
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
 
-        assert!(net_crypto.send_packet(packet.clone(), &mut connection).wait().is_ok());
+        let data = vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3];
+        assert!(net_crypto.send_lossy(peer_real_pk, data.clone()).wait().is_ok());
 
         let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
-        let (received, addr_to_send) = received.unwrap();
-
+        let (packet, addr_to_send) = received.unwrap();
         assert_eq!(addr_to_send, addr);
-        assert_eq!(received, packet);
-
-        // TODO: check that TCP received the packet
+        let packet = unpack!(packet, DhtPacket::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.buffer_start, 0);
+        assert_eq!(payload.packet_number, 0);
+        assert_eq!(payload.data, data);
+
+        // lossy packets are not stored
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        assert_eq!(connection.send_array.buffer_end, 0);
     }
 
     #[test]
-    fn send_packet_no_udp_attempt() {
+    fn send_lossy_invalid_packet_id() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
 
         let addr = "127.0.0.1:12345".parse().unwrap();
         connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
 
-        let packet = DhtPacket::CryptoData(CryptoData {
-            nonce_last_bytes: 123,
-            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH]
-        });
-
-        assert!(net_crypto.send_packet(packet.clone(), &mut connection).wait().is_ok());
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
 
-        // TODO: check that TCP received the packet
+        assert!(net_crypto.send_lossy(peer_real_pk, vec![PACKET_ID_CRYPTO_RANGE_END + 1]).wait().is_err());
+        assert!(net_crypto.send_lossy(peer_real_pk, vec![PACKET_ID_LOSSY_RANGE_END + 1]).wait().is_err());
     }
 
     #[test]
-    fn send_packet_tcp() {
-        let (udp_tx, _udp_rx) = mpsc::unbounded();
+    fn kill_connection() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk: real_sk.clone()
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_sk, real_pk, peer_real_pk, peer_dht_pk);
 
-        let packet = DhtPacket::CryptoData(CryptoData {
-            nonce_last_bytes: 123,
-            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH]
-        });
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
 
-        assert!(net_crypto.send_packet(packet.clone(), &mut connection).wait().is_ok());
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+
+        assert!(net_crypto.kill_connection(peer_real_pk).wait().is_ok());
+
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+        assert_eq!(addr_to_send, addr);
+        let packet = unpack!(packet, DhtPacket::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.data, vec![PACKET_ID_KILL]);
 
-        // TODO: check that TCP received the packet
+        assert!(net_crypto.connections.read().is_empty());
+        assert!(net_crypto.keys_by_addr.read().is_empty());
     }
 
     #[test]
-    fn main_loop_sends_status_packets() {
+    fn kill_connection_not_established() {
         let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk
         });
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
-
-        let packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet).dht_packet();
-
-        let addr = "127.0.0.1:12345".parse().unwrap();
-        connection.udp_addr = Some(addr);
-        connection.update_udp_received_time();
-
-        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
-
-        assert!(net_crypto.main_loop().wait().is_ok());
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
 
-        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
-        let (received, addr_to_send) = received.unwrap();
+        assert!(net_crypto.kill_connection(peer_real_pk).wait().is_ok());
 
-        assert_eq!(addr_to_send, addr);
-        assert_eq!(received, packet);
+        // nothing should be sent
+        drop(net_crypto);
+        assert!(udp_rx.collect().wait().unwrap().is_empty());
     }
 
     #[test]
-    fn main_loop_removes_timed_out_connections() {
+    fn kill_connection_no_connection() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (tcp_tx, _tcp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (new_connection_tx, _new_connection_rx) = mpsc::unbounded();
+        let (connection_status_tx, _connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, _real_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
         let net_crypto = NetCrypto::new(NetCryptoNewArgs {
             udp_tx,
+            tcp_tx,
             dht_pk_tx,
             lossless_tx,
             lossy_tx,
+            new_connection_tx,
+            connection_status_tx,
             dht_pk,
             dht_sk: dht_sk.clone(),
-            real_pk
+            real_pk,
+            real_sk
         });
 
-        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let mut connection = CryptoConnection::new(dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
-
-        let addr = "127.0.0.1:12345".parse().unwrap();
-        connection.udp_addr = Some(addr);
-
-        // make the connection timed out
-        let cookie_request_id = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, cookie_request_id);
-        let mut packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet);
-        packet.num_sent = MAX_NUM_SENDPACKET_TRIES;
-        packet.sent_time -= Duration::from_secs(CRYPTO_SEND_PACKET_INTERVAL + 1);
-        connection.status = ConnectionStatus::CookieRequesting {
-            cookie_request_id,
-            packet
-        };
-
-        assert!(connection.is_timed_out());
-
-        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
-
-        assert!(net_crypto.main_loop().wait().is_ok());
-
-        assert!(net_crypto.connections.read().is_empty());
-        assert!(net_crypto.keys_by_addr.read().is_empty());
+        assert!(net_crypto.kill_connection(peer_real_pk).wait().is_err());
     }
 }
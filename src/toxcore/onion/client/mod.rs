@@ -0,0 +1,1376 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Onion client allows to announce our long term `PublicKey` to onion nodes
+and to find friends by their long term `PublicKey`.
+
+Onion client builds onion paths from DHT close nodes and sends
+`OnionAnnounceRequest` packets through them. When there are not enough DHT
+close nodes (e.g. UDP is blocked) onion paths start with a TCP relay we are
+connected to and the rest of the path is built from onion nodes we learned
+about. Announce requests with our long
+term `PublicKey` are sent to onion nodes closest to this key so that our
+friends can find us. Search requests with friend's long term `PublicKey` are
+sent to onion nodes closest to friend's key. When friend is found we send him
+our DHT `PublicKey` via `OnionDataRequest` packet. When friend sends us his DHT
+`PublicKey` either via onion or via `DhtRequest` packet we send it to the sink
+so that DHT and net crypto modules can connect to him. Friend requests are
+sent and received via `OnionDataRequest` packets as well.
+
+*/
+
+mod onion_path;
+
+pub use self::onion_path::*;
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::{Future, Stream, future, stream};
+use futures::sync::mpsc;
+use parking_lot::RwLock;
+use tokio::timer::Interval;
+
+use toxcore::crypto_core::*;
+use toxcore::dht::packet::*;
+use toxcore::dht::packed_node::*;
+use toxcore::dht::kbucket::*;
+use toxcore::io_tokio::*;
+use toxcore::onion::packet::*;
+use toxcore::onion::onion_announce::initial_ping_id;
+use toxcore::tcp::client::Connections;
+use toxcore::time::*;
+
+/// Number of onion paths that onion client maintains.
+pub const NUMBER_ONION_PATHS: usize = 6;
+
+/// Path is considered expired after this number of seconds and is replaced
+/// with a new one.
+pub const ONION_PATH_MAX_LIFETIME: u64 = 1200;
+
+/// Maximum number of onion nodes we announce ourselves to.
+pub const MAX_ONION_ANNOUNCE_NODES: usize = 12;
+
+/// Maximum number of onion nodes we send search requests for a friend to.
+pub const MAX_ONION_FRIEND_NODES: usize = 8;
+
+/// Maximum number of random DHT nodes used to start announcing or searching
+/// when we don't know any onion nodes yet.
+pub const MAX_ONION_BOOTSTRAP_NODES: usize = 4;
+
+/// Interval in seconds of sending announce requests to onion nodes that
+/// haven't announced us yet.
+pub const ANNOUNCE_INTERVAL_NOT_ANNOUNCED: u64 = 3;
+
+/// Interval in seconds of sending announce requests to onion nodes that
+/// announced us.
+pub const ANNOUNCE_INTERVAL_ANNOUNCED: u64 = 15;
+
+/// Interval in seconds of sending search requests for a friend.
+pub const ANNOUNCE_FRIEND_INTERVAL: u64 = 15;
+
+/// Onion node is removed after this number of requests without response.
+pub const ONION_NODE_MAX_PINGS: u32 = 3;
+
+/// Timeout in seconds for announce requests. If response was not received
+/// during this time it will be ignored.
+pub const ANNOUNCE_REQUEST_TIMEOUT: u64 = 10;
+
+/// Interval in seconds of sending our DHT `PublicKey` to a friend via onion.
+pub const ONION_DHTPK_SEND_INTERVAL: u64 = 30;
+
+/// Interval in seconds for running the main loop.
+/// Maximum number of nodes that are kept to build onion paths when there are
+/// not enough DHT close nodes.
+pub const MAX_PATH_NODES: usize = 32;
+
+const MAIN_LOOP_INTERVAL: u64 = 1;
+
+/// Shorthand for the transmit half of the message channel for sending DHT
+/// packets.
+type UdpTx = mpsc::UnboundedSender<(DhtPacket, SocketAddr)>;
+
+/// Shorthand for the transmit half of the message channel for sending DHT
+/// `PublicKey` when it gets known. The first key is a long term key, the second
+/// key is a DHT key.
+type DhtPkTx = mpsc::UnboundedSender<(PublicKey, PublicKey)>;
+
+/// Shorthand for the transmit half of the message channel for sending received
+/// friend requests. The key is a long term `PublicKey` of the sender.
+type FriendRequestTx = mpsc::UnboundedSender<(PublicKey, FriendRequest)>;
+
+/// Shorthand for the receiving half of the message channel for onion responses
+/// received from TCP relays.
+type OnionResponseRx = mpsc::UnboundedReceiver<InnerOnionResponse>;
+
+/// Onion node close to our or friend's long term `PublicKey`.
+#[derive(Clone, Debug)]
+struct OnionNode {
+    /// DHT `PublicKey` of the node
+    pk: PublicKey,
+    /// Address of the node
+    saddr: SocketAddr,
+    /// Id of the path used to send requests to this node. Onion ping id is
+    /// bound to the address of the last node of the path so the same path
+    /// should be used for all requests.
+    path_id: u64,
+    /// Onion ping id received from this node
+    ping_id: Option<sha256::Digest>,
+    /// `PublicKey` that should be used to send data packets to a friend
+    /// announced on this node
+    data_pk: Option<PublicKey>,
+    /// Whether we are announced on this node or friend is found on this node
+    is_stored: bool,
+    /// Number of requests sent without response
+    unsuccessful_pings: u32,
+    /// Time when the last request was sent to this node
+    last_ping_time: Option<Instant>,
+}
+
+impl OnionNode {
+    /// Check if enough time passed since the last request to send a new one.
+    fn should_be_pinged(&self, interval: Duration) -> bool {
+        self.last_ping_time.map_or(true, |time| clock_elapsed(time) >= interval)
+    }
+}
+
+/// Check if node with `pk` can be added to the list of onion nodes sorted by
+/// distance to `base_pk`.
+fn can_add_node(nodes: &[OnionNode], base_pk: &PublicKey, pk: &PublicKey, capacity: usize) -> bool {
+    if nodes.iter().any(|node| node.pk == *pk) {
+        return false;
+    }
+    nodes.len() < capacity || nodes.last().map_or(true, |last|
+        base_pk.distance(pk, &last.pk) == Ordering::Less
+    )
+}
+
+/// Add or update node in the list of onion nodes keeping it sorted by distance
+/// to `base_pk` and not longer than `capacity`.
+fn insert_node(nodes: &mut Vec<OnionNode>, base_pk: &PublicKey, node: OnionNode, capacity: usize) {
+    if let Some(existing) = nodes.iter_mut().find(|existing| existing.pk == node.pk) {
+        *existing = node;
+        return;
+    }
+    nodes.push(node);
+    nodes.sort_by(|node_1, node_2| base_pk.distance(&node_1.pk, &node_2.pk));
+    nodes.truncate(capacity);
+}
+
+/// Friend we are searching for.
+#[derive(Clone, Debug)]
+struct OnionFriend {
+    /// Long term `PublicKey` of the friend
+    real_pk: PublicKey,
+    /// Temporary `PublicKey` used for search requests
+    temporary_pk: PublicKey,
+    /// Temporary `SecretKey` used for search requests
+    temporary_sk: SecretKey,
+    /// DHT `PublicKey` of the friend if it's known
+    dht_pk: Option<PublicKey>,
+    /// The last `no_reply` number received from the friend
+    last_no_reply: u64,
+    /// Onion nodes close to friend's long term `PublicKey`
+    close_nodes: Vec<OnionNode>,
+    /// Time when we sent our DHT `PublicKey` to the friend via onion
+    last_dht_pk_onion_sent: Option<Instant>,
+}
+
+impl OnionFriend {
+    /// Create new `OnionFriend`.
+    fn new(real_pk: PublicKey) -> OnionFriend {
+        let (temporary_pk, temporary_sk) = gen_keypair();
+        OnionFriend {
+            real_pk,
+            temporary_pk,
+            temporary_sk,
+            dht_pk: None,
+            last_no_reply: 0,
+            close_nodes: Vec::new(),
+            last_dht_pk_onion_sent: None,
+        }
+    }
+}
+
+/// Info about sent announce request used to handle response.
+#[derive(Clone, Debug)]
+struct AnnounceRequest {
+    /// DHT `PublicKey` of the node request was sent to
+    pk: PublicKey,
+    /// Address of the node request was sent to
+    saddr: SocketAddr,
+    /// Id of the path request was sent through
+    path_id: u64,
+    /// Long term `PublicKey` of the friend if it's a search request
+    friend_pk: Option<PublicKey>,
+    /// Time when the request was sent
+    time: Instant,
+}
+
+/// Mutable state of onion client.
+#[derive(Clone, Default)]
+struct OnionClientState {
+    /// Onion paths used to send requests
+    paths: Vec<OnionPath>,
+    /// Onion nodes close to our long term `PublicKey`
+    announce_nodes: Vec<OnionNode>,
+    /// Friends we are searching for by their long term `PublicKey`
+    friends: HashMap<PublicKey, OnionFriend>,
+    /// Sent announce requests by their `sendback_data`
+    announce_requests: HashMap<u64, AnnounceRequest>,
+    /// Onion nodes used to build onion paths through TCP relays
+    path_nodes: Vec<PackedNode>,
+}
+
+/// Add node to the list of nodes used to build onion paths. If the list is
+/// full the oldest node is removed.
+fn insert_path_node(path_nodes: &mut Vec<PackedNode>, node: PackedNode) {
+    if path_nodes.iter().any(|path_node| path_node.pk == node.pk) {
+        return;
+    }
+    if path_nodes.len() >= MAX_PATH_NODES {
+        path_nodes.remove(0);
+    }
+    path_nodes.push(node);
+}
+
+/// Onion client that announces our long term `PublicKey` and searches for
+/// friends.
+#[derive(Clone)]
+pub struct OnionClient {
+    /// Sink to send packet to UDP socket
+    udp_tx: UdpTx,
+    /// Sink to send DHT `PublicKey` when it gets known. The first key is a long
+    /// term key, the second key is a DHT key.
+    dht_pk_tx: DhtPkTx,
+    /// Sink to send received friend requests
+    friend_request_tx: Option<FriendRequestTx>,
+    /// TCP connections used to send onion requests when there are not enough
+    /// DHT close nodes
+    tcp_connections: Option<Connections>,
+    /// Our DHT `PublicKey`
+    dht_pk: PublicKey,
+    /// Our real `PublicKey`
+    real_pk: PublicKey,
+    /// Our real `SecretKey`
+    real_sk: SecretKey,
+    /// `PublicKey` that friends should use to send data packets to us
+    data_pk: PublicKey,
+    /// `SecretKey` used to decrypt data packets sent to us
+    data_sk: SecretKey,
+    /// DHT close nodes used to build onion paths
+    close_nodes: Arc<RwLock<Kbucket>>,
+    /// Mutable state of onion client
+    state: Arc<RwLock<OnionClientState>>,
+}
+
+impl OnionClient {
+    /// Create new `OnionClient` object.
+    pub fn new(
+        udp_tx: UdpTx,
+        dht_pk_tx: DhtPkTx,
+        dht_pk: PublicKey,
+        real_pk: PublicKey,
+        real_sk: SecretKey,
+        close_nodes: Arc<RwLock<Kbucket>>
+    ) -> OnionClient {
+        let (data_pk, data_sk) = gen_keypair();
+        OnionClient {
+            udp_tx,
+            dht_pk_tx,
+            friend_request_tx: None,
+            tcp_connections: None,
+            dht_pk,
+            real_pk,
+            real_sk,
+            data_pk,
+            data_sk,
+            close_nodes,
+            state: Arc::new(RwLock::new(OnionClientState::default())),
+        }
+    }
+
+    /// Set sink to send received friend requests. Friend requests are ignored
+    /// if it's not set.
+    pub fn set_friend_request_sink(&mut self, friend_request_tx: FriendRequestTx) {
+        self.friend_request_tx = Some(friend_request_tx);
+    }
+
+    /// Set TCP connections used to send onion requests through relays when
+    /// there are not enough DHT close nodes. Responses from relays should be
+    /// passed to `run_tcp`.
+    pub fn set_tcp_connections(&mut self, tcp_connections: Connections) {
+        self.tcp_connections = Some(tcp_connections);
+    }
+
+    /// Add node that can be used to build onion paths through TCP relays,
+    /// e.g. a bootstrap node.
+    pub fn add_path_node(&self, node: PackedNode) {
+        insert_path_node(&mut self.state.write().path_nodes, node);
+    }
+
+    /// Add friend to search for him by his long term `PublicKey`.
+    pub fn add_friend(&self, real_pk: PublicKey) {
+        self.state.write().friends.entry(real_pk).or_insert_with(|| OnionFriend::new(real_pk));
+    }
+
+    /// Stop searching for friend.
+    pub fn remove_friend(&self, real_pk: &PublicKey) {
+        self.state.write().friends.remove(real_pk);
+    }
+
+    /// Check if at least one onion node announced us.
+    pub fn is_announced(&self) -> bool {
+        self.state.read().announce_nodes.iter().any(|node| node.is_stored)
+    }
+
+    /// Get DHT `PublicKey` of friend if it's known.
+    pub fn friend_dht_pk(&self, real_pk: &PublicKey) -> Option<PublicKey> {
+        self.state.read().friends.get(real_pk).and_then(|friend| friend.dht_pk)
+    }
+
+    /// Get random DHT close nodes.
+    fn random_close_nodes(&self, count: usize) -> Vec<PackedNode> {
+        let mut nodes = self.close_nodes.read().iter().collect::<Vec<_>>();
+        let mut result = Vec::new();
+        while result.len() < count && !nodes.is_empty() {
+            let index = random_u32() as usize % nodes.len();
+            result.push(nodes.swap_remove(index));
+        }
+        result
+    }
+
+    /// Get random nodes from DHT close nodes and nodes kept to build onion
+    /// paths.
+    fn random_path_nodes(&self, path_nodes: &[PackedNode], count: usize) -> Vec<PackedNode> {
+        let mut nodes = self.close_nodes.read().iter().collect::<Vec<_>>();
+        for node in path_nodes {
+            if !nodes.iter().any(|close_node| close_node.pk == node.pk) {
+                nodes.push(*node);
+            }
+        }
+        let mut result = Vec::new();
+        while result.len() < count && !nodes.is_empty() {
+            let index = random_u32() as usize % nodes.len();
+            result.push(nodes.swap_remove(index));
+        }
+        result
+    }
+
+    /// Get onion path by its id. If there is no such path (e.g. it's expired)
+    /// then random path is returned. New paths are built from DHT close nodes
+    /// if there are less than `NUMBER_ONION_PATHS` of them. If there are not
+    /// enough DHT close nodes then new paths start with a random TCP relay.
+    fn get_path(&self, state: &mut OnionClientState, path_id: Option<u64>) -> Option<OnionPath> {
+        if let Some(path) = path_id.and_then(|id| state.paths.iter().find(|path| path.id == id)) {
+            return Some(path.clone());
+        }
+
+        if state.paths.len() < NUMBER_ONION_PATHS {
+            let nodes = self.random_close_nodes(ONION_PATH_LENGTH);
+            if nodes.len() == ONION_PATH_LENGTH {
+                let path = OnionPath::new([nodes[0], nodes[1], nodes[2]]);
+                state.paths.push(path.clone());
+                return Some(path);
+            }
+
+            let relay = self.tcp_connections.as_ref().and_then(|connections| connections.random_relay());
+            if let Some(relay) = relay {
+                let nodes = self.random_path_nodes(&state.path_nodes, ONION_PATH_LENGTH - 1);
+                if nodes.len() == ONION_PATH_LENGTH - 1 {
+                    let path = OnionPath::new_tcp(relay, [nodes[0], nodes[1]]);
+                    state.paths.push(path.clone());
+                    return Some(path);
+                }
+            }
+        }
+
+        if state.paths.is_empty() {
+            None
+        } else {
+            let index = random_u32() as usize % state.paths.len();
+            Some(state.paths[index].clone())
+        }
+    }
+
+    /// Send inner onion request to the node through onion path. Returns id of
+    /// the used path.
+    fn send_onion_request(&self, state: &mut OnionClientState, path_id: Option<u64>, saddr: SocketAddr, inner: InnerOnionRequest)
+        -> Result<(u64, IoFuture<()>), Error> {
+        let path = match self.get_path(state, path_id) {
+            Some(path) => path,
+            None => return Err(Error::new(ErrorKind::Other, "Not enough nodes to build onion path")),
+        };
+        let first_node = path.nodes()[0];
+        if path.is_tcp {
+            let connections = match self.tcp_connections {
+                Some(ref connections) => connections,
+                None => return Err(Error::new(ErrorKind::Other, "TCP connections are not set")),
+            };
+            let packet = path.create_tcp_onion_request(IpPort::from_udp_saddr(saddr), inner);
+            Ok((path.id, connections.send_onion_request(&first_node.pk, packet)))
+        } else {
+            let packet = path.create_udp_onion_request(IpPort::from_udp_saddr(saddr), inner);
+            Ok((path.id, send_to(&self.udp_tx, (DhtPacket::OnionRequest0(packet), first_node.saddr))))
+        }
+    }
+
+    /// Send `OnionAnnounceRequest` packet to the node. If `friend_pk` is `None`
+    /// then it's a request to announce ourselves, otherwise it's a request to
+    /// search for the friend.
+    fn send_announce_request(&self, state: &mut OnionClientState, node: &PackedNode, path_id: Option<u64>,
+                             ping_id: Option<sha256::Digest>, friend_pk: Option<PublicKey>) -> IoFuture<()> {
+        let sendback_data = random_u64();
+        let payload = OnionAnnounceRequestPayload {
+            ping_id: ping_id.unwrap_or_else(initial_ping_id),
+            search_pk: friend_pk.unwrap_or(self.real_pk),
+            data_pk: if friend_pk.is_some() { PublicKey([0; PUBLICKEYBYTES]) } else { self.data_pk },
+            sendback_data,
+        };
+        let inner = if let Some(friend_pk) = friend_pk {
+            let friend = match state.friends.get(&friend_pk) {
+                Some(friend) => friend,
+                None => return Box::new(future::err(
+                    Error::new(ErrorKind::Other, "No such friend")
+                )),
+            };
+            InnerOnionAnnounceRequest::new(&precompute(&node.pk, &friend.temporary_sk), &friend.temporary_pk, payload)
+        } else {
+            InnerOnionAnnounceRequest::new(&precompute(&node.pk, &self.real_sk), &self.real_pk, payload)
+        };
+
+        match self.send_onion_request(state, path_id, node.saddr, InnerOnionRequest::InnerOnionAnnounceRequest(inner)) {
+            Ok((path_id, future)) => {
+                state.announce_requests.insert(sendback_data, AnnounceRequest {
+                    pk: node.pk,
+                    saddr: node.saddr,
+                    path_id,
+                    friend_pk,
+                    time: clock_now(),
+                });
+                future
+            },
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// Send our DHT `PublicKey` to the friend through onion node that knows
+    /// friend's data `PublicKey`.
+    fn send_dht_pk_announce(&self, state: &mut OnionClientState, friend_pk: &PublicKey, node: &OnionNode, data_pk: &PublicKey) -> IoFuture<()> {
+        let dht_pk_announce = DhtPkAnnouncePayload {
+            no_reply: unix_time(SystemTime::now()),
+            dht_pk: self.dht_pk,
+            nodes: self.close_nodes.read().get_closest(&self.dht_pk),
+        };
+        let inner_payload = OnionDataResponseInnerPayload::DhtPkAnnounce(dht_pk_announce);
+        self.send_data(state, friend_pk, node, data_pk, inner_payload)
+    }
+
+    /// Send data to the friend through onion node that knows friend's data
+    /// `PublicKey`.
+    fn send_data(&self, state: &mut OnionClientState, friend_pk: &PublicKey, node: &OnionNode, data_pk: &PublicKey,
+                 inner_payload: OnionDataResponseInnerPayload) -> IoFuture<()> {
+        let nonce = gen_nonce();
+        let (temporary_pk, temporary_sk) = gen_keypair();
+        let payload = OnionDataResponsePayload::new(
+            &precompute(friend_pk, &self.real_sk),
+            &self.real_pk,
+            &nonce,
+            inner_payload
+        );
+        let inner = InnerOnionDataRequest::new(&precompute(data_pk, &temporary_sk), friend_pk, &temporary_pk, &nonce, payload);
+
+        match self.send_onion_request(state, Some(node.path_id), node.saddr, InnerOnionRequest::InnerOnionDataRequest(inner)) {
+            Ok((_, future)) => future,
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// Send friend request to the friend through all onion nodes he is
+    /// announced on. The friend should be added to onion client so that
+    /// these nodes can be found.
+    pub fn send_friend_request(&self, friend_pk: PublicKey, friend_request: FriendRequest) -> IoFuture<()> {
+        let mut state = self.state.write();
+
+        let found_nodes = match state.friends.get(&friend_pk) {
+            Some(friend) => friend.close_nodes.iter()
+                .filter_map(|node| node.data_pk.map(|data_pk| (node.clone(), data_pk)))
+                .collect::<Vec<_>>(),
+            None => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "No such friend")
+            )),
+        };
+
+        if found_nodes.is_empty() {
+            return Box::new(future::err(
+                Error::new(ErrorKind::Other, "Friend is not found yet")
+            ));
+        }
+
+        let futures = found_nodes.into_iter()
+            .map(|(node, data_pk)| {
+                let inner_payload = OnionDataResponseInnerPayload::FriendRequest(friend_request.clone());
+                self.send_data(&mut state, &friend_pk, &node, &data_pk, inner_payload)
+            })
+            .collect::<Vec<_>>();
+
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /** Handle `OnionAnnounceResponse` packet.
+
+    Response is matched with sent request by `sendback_data`. Onion node that
+    sent the response is added to the list of nodes close to our or friend's
+    long term `PublicKey` and nodes from the response are asked in the same way
+    if they are closer than known nodes.
+    */
+    pub fn handle_announce_response(&self, packet: OnionAnnounceResponse) -> IoFuture<()> {
+        let mut state = self.state.write();
+
+        let request = match state.announce_requests.remove(&packet.sendback_data) {
+            Some(request) => request,
+            None => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "OnionAnnounceResponse doesn't match any sent request")
+            )),
+        };
+
+        let secret_key = if let Some(friend_pk) = request.friend_pk {
+            match state.friends.get(&friend_pk) {
+                Some(friend) => friend.temporary_sk.clone(),
+                None => return Box::new(future::err(
+                    Error::new(ErrorKind::Other, "OnionAnnounceResponse is received for unknown friend")
+                )),
+            }
+        } else {
+            self.real_sk.clone()
+        };
+
+        let payload = match packet.get_payload(&precompute(&request.pk, &secret_key)) {
+            Ok(payload) => payload,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        let mut node = OnionNode {
+            pk: request.pk,
+            saddr: request.saddr,
+            path_id: request.path_id,
+            ping_id: None,
+            data_pk: None,
+            is_stored: false,
+            unsuccessful_pings: 0,
+            last_ping_time: Some(request.time),
+        };
+        match payload.announce_status {
+            AnnounceStatus::Failed => node.ping_id = Some(payload.ping_id_or_pk),
+            AnnounceStatus::Found => {
+                node.data_pk = Some(digest_as_pk(payload.ping_id_or_pk));
+                node.is_stored = true;
+            },
+            AnnounceStatus::Announced => {
+                node.ping_id = Some(payload.ping_id_or_pk);
+                node.is_stored = true;
+            },
+        }
+
+        let (base_pk, capacity) = if let Some(friend_pk) = request.friend_pk {
+            (friend_pk, MAX_ONION_FRIEND_NODES)
+        } else {
+            (self.real_pk, MAX_ONION_ANNOUNCE_NODES)
+        };
+
+        let new_nodes = {
+            let nodes = if let Some(friend_pk) = request.friend_pk {
+                // friend existence is checked above
+                &mut state.friends.get_mut(&friend_pk).unwrap().close_nodes
+            } else {
+                &mut state.announce_nodes
+            };
+            insert_node(nodes, &base_pk, node, capacity);
+            payload.nodes.iter()
+                .filter(|node| can_add_node(nodes, &base_pk, &node.pk, capacity))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        for node in payload.nodes {
+            insert_path_node(&mut state.path_nodes, node);
+        }
+
+        let requests = new_nodes.iter()
+            .map(|node| self.send_announce_request(&mut state, node, None, None, request.friend_pk))
+            .collect::<Vec<_>>();
+
+        let requests_stream = stream::futures_unordered(requests).then(|_| Ok(()));
+        Box::new(requests_stream.for_each(|()| Ok(())))
+    }
+
+    /// Handle `OnionDataResponse` packet. It's sent by our friends through
+    /// onion nodes we announced ourselves to.
+    pub fn handle_data_response(&self, packet: OnionDataResponse) -> IoFuture<()> {
+        let payload = match packet.get_payload(&precompute(&packet.temporary_pk, &self.data_sk)) {
+            Ok(payload) => payload,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let inner_payload = match payload.get_payload(&packet.nonce, &precompute(&payload.real_pk, &self.real_sk)) {
+            Ok(payload) => payload,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        match inner_payload {
+            OnionDataResponseInnerPayload::DhtPkAnnounce(dht_pk_announce) =>
+                self.handle_dht_pk_announce_payload(&payload.real_pk, dht_pk_announce),
+            OnionDataResponseInnerPayload::FriendRequest(friend_request) =>
+                match self.friend_request_tx {
+                    Some(ref friend_request_tx) => send_to(friend_request_tx, (payload.real_pk, friend_request)),
+                    None => Box::new(future::ok(())),
+                },
+        }
+    }
+
+    /// Handle onion response received from TCP relay.
+    pub fn handle_tcp_onion_response(&self, response: InnerOnionResponse) -> IoFuture<()> {
+        match response {
+            InnerOnionResponse::OnionAnnounceResponse(packet) => self.handle_announce_response(packet),
+            InnerOnionResponse::OnionDataResponse(packet) => self.handle_data_response(packet),
+        }
+    }
+
+    /// Handle `DhtPkAnnounce` packet received via `DhtRequest` packet.
+    pub fn handle_dht_pk_announce(&self, packet: DhtPkAnnounce) -> IoFuture<()> {
+        let payload = match packet.get_payload(&precompute(&packet.pk, &self.real_sk)) {
+            Ok(payload) => payload,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        self.handle_dht_pk_announce_payload(&packet.pk, payload)
+    }
+
+    /// Handle `DhtPkAnnouncePayload` sent by friend. If it's not a replayed
+    /// packet then friend's DHT `PublicKey` is sent to the sink.
+    fn handle_dht_pk_announce_payload(&self, real_pk: &PublicKey, payload: DhtPkAnnouncePayload) -> IoFuture<()> {
+        let mut state = self.state.write();
+
+        let friend = match state.friends.get_mut(real_pk) {
+            Some(friend) => friend,
+            None => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "DhtPkAnnounce is received from unknown friend")
+            )),
+        };
+
+        if payload.no_reply <= friend.last_no_reply {
+            return Box::new(future::err(
+                Error::new(ErrorKind::Other, "DhtPkAnnounce no_reply is not bigger than the last one")
+            ))
+        }
+
+        friend.last_no_reply = payload.no_reply;
+        friend.dht_pk = Some(payload.dht_pk);
+
+        // TODO: nodes from the payload can be used to speed up friend search in DHT
+
+        send_to(&self.dht_pk_tx, (*real_pk, payload.dht_pk))
+    }
+
+    /// Remove expired paths and timed out requests.
+    fn remove_timed_out(&self, state: &mut OnionClientState) {
+        let path_lifetime = Duration::from_secs(ONION_PATH_MAX_LIFETIME);
+        state.paths.retain(|path| clock_elapsed(path.creation_time) < path_lifetime);
+
+        let request_timeout = Duration::from_secs(ANNOUNCE_REQUEST_TIMEOUT);
+        state.announce_requests.retain(|_, request| clock_elapsed(request.time) < request_timeout);
+
+        state.announce_nodes.retain(|node| node.unsuccessful_pings < ONION_NODE_MAX_PINGS);
+        for friend in state.friends.values_mut() {
+            friend.close_nodes.retain(|node| node.unsuccessful_pings < ONION_NODE_MAX_PINGS);
+        }
+    }
+
+    /// Send announce requests to nodes close to our long term `PublicKey`. If
+    /// there are no such nodes then random DHT or path nodes are used.
+    fn announce_loop(&self, state: &mut OnionClientState) -> Vec<IoFuture<()>> {
+        if state.announce_nodes.is_empty() {
+            return self.random_path_nodes(&state.path_nodes, MAX_ONION_BOOTSTRAP_NODES).iter()
+                .map(|node| self.send_announce_request(state, node, None, None, None))
+                .collect();
+        }
+
+        let to_ping = state.announce_nodes.iter_mut()
+            .filter(|node| {
+                let interval = if node.is_stored {
+                    ANNOUNCE_INTERVAL_ANNOUNCED
+                } else {
+                    ANNOUNCE_INTERVAL_NOT_ANNOUNCED
+                };
+                node.should_be_pinged(Duration::from_secs(interval))
+            })
+            .map(|node| {
+                node.unsuccessful_pings += 1;
+                node.last_ping_time = Some(clock_now());
+                (PackedNode::new(true, node.saddr, &node.pk), node.path_id, node.ping_id)
+            })
+            .collect::<Vec<_>>();
+
+        to_ping.into_iter()
+            .map(|(node, path_id, ping_id)| self.send_announce_request(state, &node, Some(path_id), ping_id, None))
+            .collect()
+    }
+
+    /// Send search requests for friends and send our DHT `PublicKey` to found
+    /// friends.
+    fn friends_loop(&self, state: &mut OnionClientState) -> Vec<IoFuture<()>> {
+        let search_interval = Duration::from_secs(ANNOUNCE_FRIEND_INTERVAL);
+        let dht_pk_interval = Duration::from_secs(ONION_DHTPK_SEND_INTERVAL);
+
+        let mut to_search = Vec::new();
+        let mut to_announce = Vec::new();
+        for friend in state.friends.values_mut() {
+            if friend.close_nodes.is_empty() {
+                to_search.extend(self.random_path_nodes(&state.path_nodes, MAX_ONION_BOOTSTRAP_NODES).into_iter()
+                    .map(|node| (friend.real_pk, node, None)));
+            } else {
+                for node in friend.close_nodes.iter_mut().filter(|node| node.should_be_pinged(search_interval)) {
+                    node.unsuccessful_pings += 1;
+                    node.last_ping_time = Some(clock_now());
+                    to_search.push((friend.real_pk, PackedNode::new(true, node.saddr, &node.pk), Some(node.path_id)));
+                }
+            }
+
+            if friend.last_dht_pk_onion_sent.map_or(true, |time| clock_elapsed(time) >= dht_pk_interval) {
+                let found_nodes = friend.close_nodes.iter()
+                    .filter_map(|node| node.data_pk.map(|data_pk| (node.clone(), data_pk)))
+                    .collect::<Vec<_>>();
+                if !found_nodes.is_empty() {
+                    friend.last_dht_pk_onion_sent = Some(clock_now());
+                    to_announce.extend(found_nodes.into_iter().map(|(node, data_pk)| (friend.real_pk, node, data_pk)));
+                }
+            }
+        }
+
+        let search_requests = to_search.into_iter()
+            .map(|(friend_pk, node, path_id)| self.send_announce_request(state, &node, path_id, None, Some(friend_pk)))
+            .collect::<Vec<_>>();
+        let dht_pk_announces = to_announce.into_iter()
+            .map(|(friend_pk, node, data_pk)| self.send_dht_pk_announce(state, &friend_pk, &node, &data_pk))
+            .collect::<Vec<_>>();
+
+        search_requests.into_iter().chain(dht_pk_announces).collect()
+    }
+
+    /// Main loop of onion client, call this function every second.
+    pub fn main_loop(&self) -> IoFuture<()> {
+        let mut state = self.state.write();
+
+        self.remove_timed_out(&mut state);
+
+        let mut requests = self.announce_loop(&mut state);
+        requests.extend(self.friends_loop(&mut state));
+
+        let requests_stream = stream::futures_unordered(requests).then(|_| Ok(()));
+        Box::new(requests_stream.for_each(|()| Ok(())))
+    }
+
+    /// Run onion client calling `main_loop` periodically.
+    pub fn run(&self) -> IoFuture<()> {
+        let onion_client = self.clone();
+        let wakeups = Interval::new(clock_now(), Duration::from_secs(MAIN_LOOP_INTERVAL));
+        let future = wakeups
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Onion client timer error: {:?}", e)))
+            .for_each(move |_instant| onion_client.main_loop());
+        Box::new(future)
+    }
+
+    /// Handle onion responses from `onion_response_rx` which should be the
+    /// receiving half of `Connections` onion response sink.
+    pub fn run_tcp(&self, onion_response_rx: OnionResponseRx) -> IoFuture<()> {
+        let onion_client = self.clone();
+        let future = onion_response_rx
+            .map_err(|()| Error::new(ErrorKind::Other, "rx error"))
+            .for_each(move |response|
+                onion_client.handle_tcp_onion_response(response).or_else(|e| {
+                    debug!("Failed to handle onion response from TCP relay: {}", e);
+                    Ok(())
+                })
+            );
+        Box::new(future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio;
+    use tokio::net::TcpListener;
+    use tokio::util::FutureExt;
+
+    use toxcore::tcp::packet::OnionRequest;
+    use toxcore::tcp::server::{Server, ServerConfig};
+    use toxcore::toxid::NoSpam;
+
+    const ONION_RETURN_1_PAYLOAD_SIZE: usize = ONION_RETURN_1_SIZE - secretbox::NONCEBYTES;
+    const ONION_RETURN_2_PAYLOAD_SIZE: usize = ONION_RETURN_2_SIZE - secretbox::NONCEBYTES;
+
+    /// DHT node with its secret key to decrypt onion requests.
+    struct TestNode {
+        node: PackedNode,
+        sk: SecretKey,
+    }
+
+    type UdpRx = mpsc::UnboundedReceiver<(DhtPacket, SocketAddr)>;
+    type DhtPkRx = mpsc::UnboundedReceiver<(PublicKey, PublicKey)>;
+
+    fn create_client(nodes_count: u16) -> (OnionClient, UdpRx, DhtPkRx, Vec<TestNode>) {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, dht_pk_rx) = mpsc::unbounded();
+        let (dht_pk, _dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let mut kbucket = Kbucket::new(&dht_pk);
+        let nodes = (0 .. nodes_count).map(|i| {
+            let (pk, sk) = gen_keypair();
+            let node = PackedNode::new(true, SocketAddr::new("127.0.0.1".parse().unwrap(), 33445 + i), &pk);
+            assert!(kbucket.try_add(&node));
+            TestNode { node, sk }
+        }).collect();
+        let close_nodes = Arc::new(RwLock::new(kbucket));
+        let onion_client = OnionClient::new(udp_tx, dht_pk_tx, dht_pk, real_pk, real_sk, close_nodes);
+        (onion_client, udp_rx, dht_pk_rx, nodes)
+    }
+
+    fn find_sk(nodes: &[TestNode], saddr: SocketAddr) -> SecretKey {
+        nodes.iter().find(|node| node.node.saddr == saddr).unwrap().sk.clone()
+    }
+
+    /// Decrypt `OnionRequest0` sent to `saddr` the same way as it would be done
+    /// by nodes of onion path.
+    fn unpack_onion_request(nodes: &[TestNode], request_0: OnionRequest0, saddr: SocketAddr) -> OnionRequest2Payload {
+        let payload_0 = request_0.get_payload(&precompute(&request_0.temporary_pk, &find_sk(nodes, saddr))).unwrap();
+        unpack_tcp_onion_request(nodes, OnionRequest {
+            nonce: request_0.nonce,
+            ip_port: payload_0.ip_port,
+            temporary_pk: payload_0.temporary_pk,
+            payload: payload_0.inner,
+        })
+    }
+
+    /// Decrypt TCP `OnionRequest` the same way as it would be done by the
+    /// second and the third nodes of onion path.
+    fn unpack_tcp_onion_request(nodes: &[TestNode], request: OnionRequest) -> OnionRequest2Payload {
+        let request_1 = OnionRequest1 {
+            nonce: request.nonce,
+            temporary_pk: request.temporary_pk,
+            payload: request.payload,
+            onion_return: OnionReturn {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE]
+            }
+        };
+        let payload_1 = request_1.get_payload(&precompute(&request_1.temporary_pk, &find_sk(nodes, request.ip_port.to_saddr()))).unwrap();
+        let request_2 = OnionRequest2 {
+            nonce: request_1.nonce,
+            temporary_pk: payload_1.temporary_pk,
+            payload: payload_1.inner,
+            onion_return: OnionReturn {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
+            }
+        };
+        request_2.get_payload(&precompute(&request_2.temporary_pk, &find_sk(nodes, payload_1.ip_port.to_saddr()))).unwrap()
+    }
+
+    /// Receive the next onion request and return announce request from it with
+    /// the address of destination node.
+    fn next_announce_request(nodes: &[TestNode], udp_rx: UdpRx) -> (InnerOnionAnnounceRequest, SocketAddr, UdpRx) {
+        let (received, udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (packet, addr) = received.unwrap();
+        let request_0 = unpack!(packet, DhtPacket::OnionRequest0);
+        let payload = unpack_onion_request(nodes, request_0, addr);
+        let request = unpack!(payload.inner, InnerOnionRequest::InnerOnionAnnounceRequest);
+        (request, payload.ip_port.to_saddr(), udp_rx)
+    }
+
+    #[test]
+    fn add_remove_friend() {
+        let (onion_client, _udp_rx, _dht_pk_rx, _nodes) = create_client(3);
+        let (friend_pk, _friend_sk) = gen_keypair();
+
+        onion_client.add_friend(friend_pk);
+        assert!(onion_client.state.read().friends.contains_key(&friend_pk));
+
+        onion_client.remove_friend(&friend_pk);
+        assert!(!onion_client.state.read().friends.contains_key(&friend_pk));
+    }
+
+    #[test]
+    fn main_loop_without_nodes() {
+        let (onion_client, udp_rx, _dht_pk_rx, _nodes) = create_client(0);
+
+        onion_client.main_loop().wait().unwrap();
+
+        assert!(onion_client.state.read().paths.is_empty());
+        assert!(onion_client.state.read().announce_requests.is_empty());
+
+        drop(onion_client);
+        assert!(udp_rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn run_sends_announce_requests() {
+        let (onion_client, udp_rx, _dht_pk_rx, _nodes) = create_client(3);
+
+        let received = udp_rx
+            .into_future()
+            .map(|(received, _udp_rx)| {
+                let (packet, _saddr) = received.unwrap();
+                unpack!(packet, DhtPacket::OnionRequest0);
+            })
+            .map_err(|_| Error::new(ErrorKind::Other, "rx error"));
+
+        let test = received
+            .select(onion_client.run())
+            .map(|_| ())
+            .map_err(|(e, _select_next)| e)
+            .timeout(Duration::from_secs(10));
+
+        tokio::runtime::Runtime::new().unwrap().block_on(test).unwrap();
+    }
+
+    #[test]
+    fn announce() {
+        let (onion_client, udp_rx, _dht_pk_rx, nodes) = create_client(3);
+
+        onion_client.main_loop().wait().unwrap();
+
+        let (request, saddr, _udp_rx) = next_announce_request(&nodes, udp_rx);
+        assert_eq!(request.pk, onion_client.real_pk);
+        let node_sk = find_sk(&nodes, saddr);
+        let node_pk = nodes.iter().find(|node| node.node.saddr == saddr).unwrap().node.pk;
+        let shared_secret = precompute(&request.pk, &node_sk);
+        let payload = request.get_payload(&shared_secret).unwrap();
+        assert_eq!(payload.ping_id, initial_ping_id());
+        assert_eq!(payload.search_pk, onion_client.real_pk);
+        assert_eq!(payload.data_pk, onion_client.data_pk);
+
+        assert!(!onion_client.is_announced());
+
+        let ping_id = sha256::hash(&[1, 2, 3]);
+        let response = OnionAnnounceResponse::new(&shared_secret, payload.sendback_data, OnionAnnounceResponsePayload {
+            announce_status: AnnounceStatus::Announced,
+            ping_id_or_pk: ping_id,
+            nodes: Vec::new()
+        });
+        onion_client.handle_announce_response(response).wait().unwrap();
+
+        assert!(onion_client.is_announced());
+        let state = onion_client.state.read();
+        let node = state.announce_nodes.iter().find(|node| node.pk == node_pk).unwrap();
+        assert_eq!(node.ping_id, Some(ping_id));
+        assert_eq!(node.saddr, saddr);
+    }
+
+    #[test]
+    fn handle_announce_response_unknown_sendback_data() {
+        let (onion_client, _udp_rx, _dht_pk_rx, _nodes) = create_client(3);
+
+        let response = OnionAnnounceResponse::new(&precompute(&gen_keypair().0, &gen_keypair().1), 42, OnionAnnounceResponsePayload {
+            announce_status: AnnounceStatus::Announced,
+            ping_id_or_pk: initial_ping_id(),
+            nodes: Vec::new()
+        });
+        assert!(onion_client.handle_announce_response(response).wait().is_err());
+    }
+
+    #[test]
+    fn handle_announce_response_sends_requests_to_new_nodes() {
+        let (onion_client, udp_rx, _dht_pk_rx, nodes) = create_client(3);
+
+        onion_client.main_loop().wait().unwrap();
+
+        let (request, saddr, _udp_rx) = next_announce_request(&nodes, udp_rx);
+        let shared_secret = precompute(&request.pk, &find_sk(&nodes, saddr));
+        let payload = request.get_payload(&shared_secret).unwrap();
+
+        let new_node = PackedNode::new(true, "127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+        let response = OnionAnnounceResponse::new(&shared_secret, payload.sendback_data, OnionAnnounceResponsePayload {
+            announce_status: AnnounceStatus::Failed,
+            ping_id_or_pk: sha256::hash(&[1, 2, 3]),
+            nodes: vec![new_node]
+        });
+
+        // drop requests that were sent to other random DHT nodes
+        onion_client.state.write().announce_requests.retain(|&sendback_data, _| sendback_data == payload.sendback_data);
+
+        onion_client.handle_announce_response(response).wait().unwrap();
+
+        let state = onion_client.state.read();
+        assert_eq!(state.announce_requests.len(), 1);
+        let request = state.announce_requests.values().next().unwrap();
+        assert_eq!(request.pk, new_node.pk);
+        assert_eq!(request.saddr, new_node.saddr);
+        assert_eq!(request.friend_pk, None);
+    }
+
+    #[test]
+    fn search_friend_and_send_dht_pk() {
+        let (onion_client, udp_rx, _dht_pk_rx, nodes) = create_client(3);
+        let (friend_pk, friend_sk) = gen_keypair();
+        let (friend_data_pk, friend_data_sk) = gen_keypair();
+
+        onion_client.add_friend(friend_pk);
+        // we have only friend search requests
+        onion_client.state.write().announce_nodes.push(OnionNode {
+            pk: gen_keypair().0,
+            saddr: "127.0.0.1:12345".parse().unwrap(),
+            path_id: 0,
+            ping_id: None,
+            data_pk: None,
+            is_stored: true,
+            unsuccessful_pings: 0,
+            last_ping_time: Some(clock_now()),
+        });
+
+        onion_client.main_loop().wait().unwrap();
+
+        let (request, saddr, mut udp_rx) = next_announce_request(&nodes, udp_rx);
+        let shared_secret = precompute(&request.pk, &find_sk(&nodes, saddr));
+        let payload = request.get_payload(&shared_secret).unwrap();
+        assert_eq!(payload.search_pk, friend_pk);
+        assert_eq!(payload.ping_id, initial_ping_id());
+
+        let response = OnionAnnounceResponse::new(&shared_secret, payload.sendback_data, OnionAnnounceResponsePayload {
+            announce_status: AnnounceStatus::Found,
+            ping_id_or_pk: pk_as_digest(friend_data_pk),
+            nodes: Vec::new()
+        });
+        onion_client.handle_announce_response(response).wait().unwrap();
+
+        // skip other search requests
+        for _ in 1 .. 3 {
+            udp_rx = udp_rx.into_future().wait().unwrap().1;
+        }
+
+        onion_client.main_loop().wait().unwrap();
+
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (packet, addr) = received.unwrap();
+        let request_0 = unpack!(packet, DhtPacket::OnionRequest0);
+        let payload = unpack_onion_request(&nodes, request_0, addr);
+        assert_eq!(payload.ip_port.to_saddr(), saddr);
+        let request = unpack!(payload.inner, InnerOnionRequest::InnerOnionDataRequest);
+        assert_eq!(request.destination_pk, friend_pk);
+
+        // friend decrypts data request as OnionDataResponse
+        let response = OnionDataResponse {
+            nonce: request.nonce,
+            temporary_pk: request.temporary_pk,
+            payload: request.payload,
+        };
+        let payload = response.get_payload(&precompute(&response.temporary_pk, &friend_data_sk)).unwrap();
+        assert_eq!(payload.real_pk, onion_client.real_pk);
+        let inner_payload = payload.get_payload(&response.nonce, &precompute(&payload.real_pk, &friend_sk)).unwrap();
+        let dht_pk_announce = unpack!(inner_payload, OnionDataResponseInnerPayload::DhtPkAnnounce);
+        assert_eq!(dht_pk_announce.dht_pk, onion_client.dht_pk);
+    }
+
+    #[test]
+    fn handle_data_response() {
+        let (onion_client, _udp_rx, dht_pk_rx, _nodes) = create_client(3);
+        let (friend_pk, friend_sk) = gen_keypair();
+        let (friend_dht_pk, _friend_dht_sk) = gen_keypair();
+        let (temporary_pk, temporary_sk) = gen_keypair();
+
+        onion_client.add_friend(friend_pk);
+
+        let nonce = gen_nonce();
+        let inner_payload = OnionDataResponseInnerPayload::DhtPkAnnounce(DhtPkAnnouncePayload {
+            no_reply: 42,
+            dht_pk: friend_dht_pk,
+            nodes: Vec::new()
+        });
+        let payload = OnionDataResponsePayload::new(&precompute(&onion_client.real_pk, &friend_sk), &friend_pk, &nonce, inner_payload);
+        let packet = OnionDataResponse::new(&precompute(&onion_client.data_pk, &temporary_sk), &temporary_pk, &nonce, payload);
+
+        onion_client.handle_data_response(packet.clone()).wait().unwrap();
+
+        assert_eq!(onion_client.friend_dht_pk(&friend_pk), Some(friend_dht_pk));
+        let (received, _dht_pk_rx) = dht_pk_rx.into_future().wait().unwrap();
+        assert_eq!(received.unwrap(), (friend_pk, friend_dht_pk));
+
+        // the same packet is rejected
+        assert!(onion_client.handle_data_response(packet).wait().is_err());
+    }
+
+    #[test]
+    fn handle_data_response_friend_request() {
+        let (mut onion_client, _udp_rx, _dht_pk_rx, _nodes) = create_client(3);
+        let (friend_request_tx, friend_request_rx) = mpsc::unbounded();
+        onion_client.set_friend_request_sink(friend_request_tx);
+        let (friend_pk, friend_sk) = gen_keypair();
+        let (temporary_pk, temporary_sk) = gen_keypair();
+
+        let nonce = gen_nonce();
+        let friend_request = FriendRequest {
+            nospam: NoSpam([42; 4]),
+            message: "Hello".to_string()
+        };
+        let inner_payload = OnionDataResponseInnerPayload::FriendRequest(friend_request.clone());
+        let payload = OnionDataResponsePayload::new(&precompute(&onion_client.real_pk, &friend_sk), &friend_pk, &nonce, inner_payload);
+        let packet = OnionDataResponse::new(&precompute(&onion_client.data_pk, &temporary_sk), &temporary_pk, &nonce, payload);
+
+        // friend requests are received from unknown friends
+        onion_client.handle_data_response(packet).wait().unwrap();
+
+        let (received, _friend_request_rx) = friend_request_rx.into_future().wait().unwrap();
+        assert_eq!(received.unwrap(), (friend_pk, friend_request));
+    }
+
+    #[test]
+    fn send_friend_request() {
+        let (onion_client, udp_rx, _dht_pk_rx, nodes) = create_client(3);
+        let (friend_pk, friend_sk) = gen_keypair();
+        let (friend_data_pk, friend_data_sk) = gen_keypair();
+
+        onion_client.add_friend(friend_pk);
+        let node_saddr = "127.0.0.1:12345".parse().unwrap();
+        onion_client.state.write().friends.get_mut(&friend_pk).unwrap().close_nodes.push(OnionNode {
+            pk: gen_keypair().0,
+            saddr: node_saddr,
+            path_id: 0,
+            ping_id: None,
+            data_pk: Some(friend_data_pk),
+            is_stored: true,
+            unsuccessful_pings: 0,
+            last_ping_time: Some(clock_now()),
+        });
+
+        // the longest friend request should fit into onion packet
+        let friend_request = FriendRequest {
+            nospam: NoSpam([42; 4]),
+            message: "x".repeat(MAX_FRIEND_REQUEST_MESSAGE_LENGTH)
+        };
+        onion_client.send_friend_request(friend_pk, friend_request.clone()).wait().unwrap();
+
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (packet, addr) = received.unwrap();
+        let request_0 = unpack!(packet, DhtPacket::OnionRequest0);
+        let payload = unpack_onion_request(&nodes, request_0, addr);
+        assert_eq!(payload.ip_port.to_saddr(), node_saddr);
+        let request = unpack!(payload.inner, InnerOnionRequest::InnerOnionDataRequest);
+        assert_eq!(request.destination_pk, friend_pk);
+
+        // friend decrypts data request as OnionDataResponse
+        let response = OnionDataResponse {
+            nonce: request.nonce,
+            temporary_pk: request.temporary_pk,
+            payload: request.payload,
+        };
+        let payload = response.get_payload(&precompute(&response.temporary_pk, &friend_data_sk)).unwrap();
+        let inner_payload = payload.get_payload(&response.nonce, &precompute(&payload.real_pk, &friend_sk)).unwrap();
+        assert_eq!(unpack!(inner_payload, OnionDataResponseInnerPayload::FriendRequest), friend_request);
+    }
+
+    #[test]
+    fn send_friend_request_not_found() {
+        let (onion_client, _udp_rx, _dht_pk_rx, _nodes) = create_client(3);
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let friend_request = FriendRequest {
+            nospam: NoSpam([42; 4]),
+            message: "Hello".to_string()
+        };
+
+        // unknown friend
+        assert!(onion_client.send_friend_request(friend_pk, friend_request.clone()).wait().is_err());
+
+        // friend is added but his onion nodes are not found yet
+        onion_client.add_friend(friend_pk);
+        assert!(onion_client.send_friend_request(friend_pk, friend_request).wait().is_err());
+    }
+
+    #[test]
+    fn handle_dht_pk_announce() {
+        let (onion_client, _udp_rx, dht_pk_rx, _nodes) = create_client(3);
+        let (friend_pk, friend_sk) = gen_keypair();
+        let (friend_dht_pk, _friend_dht_sk) = gen_keypair();
+
+        onion_client.add_friend(friend_pk);
+
+        let packet = DhtPkAnnounce::new(&precompute(&onion_client.real_pk, &friend_sk), &friend_pk, DhtPkAnnouncePayload {
+            no_reply: 42,
+            dht_pk: friend_dht_pk,
+            nodes: Vec::new()
+        });
+
+        onion_client.handle_dht_pk_announce(packet).wait().unwrap();
+
+        assert_eq!(onion_client.friend_dht_pk(&friend_pk), Some(friend_dht_pk));
+        let (received, _dht_pk_rx) = dht_pk_rx.into_future().wait().unwrap();
+        assert_eq!(received.unwrap(), (friend_pk, friend_dht_pk));
+    }
+
+    #[test]
+    fn handle_dht_pk_announce_unknown_friend() {
+        let (onion_client, _udp_rx, _dht_pk_rx, _nodes) = create_client(3);
+        let (friend_pk, friend_sk) = gen_keypair();
+
+        let packet = DhtPkAnnounce::new(&precompute(&onion_client.real_pk, &friend_sk), &friend_pk, DhtPkAnnouncePayload {
+            no_reply: 42,
+            dht_pk: gen_keypair().0,
+            nodes: Vec::new()
+        });
+
+        assert!(onion_client.handle_dht_pk_announce(packet).wait().is_err());
+    }
+
+    #[test]
+    fn remove_timed_out_nodes() {
+        let (onion_client, _udp_rx, _dht_pk_rx, _nodes) = create_client(3);
+
+        onion_client.state.write().announce_nodes.push(OnionNode {
+            pk: gen_keypair().0,
+            saddr: "127.0.0.1:12345".parse().unwrap(),
+            path_id: 0,
+            ping_id: None,
+            data_pk: None,
+            is_stored: false,
+            unsuccessful_pings: ONION_NODE_MAX_PINGS,
+            last_ping_time: None,
+        });
+
+        onion_client.main_loop().wait().unwrap();
+
+        // the node is removed and requests are sent to random DHT nodes
+        let state = onion_client.state.read();
+        assert!(state.announce_nodes.is_empty());
+        assert_eq!(state.announce_requests.len(), 3);
+    }
+
+    #[test]
+    fn add_path_node() {
+        let (onion_client, _udp_rx, _dht_pk_rx, _nodes) = create_client(0);
+
+        let nodes = (0 .. MAX_PATH_NODES as u16 + 1).map(|i|
+            PackedNode::new(true, SocketAddr::new("127.0.0.1".parse().unwrap(), 12345 + i), &gen_keypair().0)
+        ).collect::<Vec<_>>();
+        for node in &nodes {
+            onion_client.add_path_node(*node);
+        }
+        onion_client.add_path_node(nodes[1]);
+
+        // the oldest node is removed and duplicates are ignored
+        assert_eq!(onion_client.state.read().path_nodes, nodes[1 ..].to_vec());
+    }
+
+    #[test]
+    fn main_loop_without_nodes_and_relays() {
+        let (mut onion_client, _udp_rx, _dht_pk_rx, _nodes) = create_client(0);
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (tcp_data_tx, _tcp_data_rx) = mpsc::unbounded();
+        onion_client.set_tcp_connections(Connections::new(dht_pk, dht_sk, tcp_data_tx));
+        let (node_pk, _node_sk) = gen_keypair();
+        onion_client.add_path_node(PackedNode::new(true, "127.0.0.1:12345".parse().unwrap(), &node_pk));
+
+        onion_client.main_loop().wait().unwrap();
+
+        // there are path nodes but no connected relays to build onion path
+        assert!(onion_client.state.read().paths.is_empty());
+    }
+
+    #[test]
+    fn run_tcp_announce() {
+        let (relay_pk, relay_sk) = gen_keypair();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (onion_tx, onion_rx) = mpsc::unbounded();
+        let server = Server::new_with_onion(onion_tx);
+        let server_c = server.clone();
+        let server_future = ::toxcore::tcp::server::run(listener, relay_sk, server, ServerConfig::default());
+
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (tcp_data_tx, _tcp_data_rx) = mpsc::unbounded();
+        let (onion_response_tx, onion_response_rx) = mpsc::unbounded();
+        let mut connections = Connections::new(dht_pk, dht_sk, tcp_data_tx);
+        connections.set_onion_response_sink(onion_response_tx);
+        connections.add_relay(relay_pk, addr);
+
+        // there are no DHT close nodes so onion paths go through the relay
+        let (mut onion_client, _udp_rx, _dht_pk_rx, _nodes) = create_client(0);
+        onion_client.set_tcp_connections(connections.clone());
+        let nodes = (0 .. 2).map(|i| {
+            let (pk, sk) = gen_keypair();
+            let node = PackedNode::new(true, SocketAddr::new("127.0.0.1".parse().unwrap(), 12345 + i), &pk);
+            onion_client.add_path_node(node);
+            TestNode { node, sk }
+        }).collect::<Vec<_>>();
+
+        let network = server_future
+            .join3(connections.run(), onion_client.run_tcp(onion_response_rx))
+            .map(|_| ());
+
+        // announce ourselves when the relay is connected
+        let onion_client_c = onion_client.clone();
+        let sender = Interval::new(clock_now(), Duration::from_millis(100))
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Timer error: {:?}", e)))
+            .skip_while(move |_instant| Ok(connections.random_relay().is_none()))
+            .into_future()
+            .map_err(|(e, _interval)| e)
+            .and_then(move |_| onion_client_c.main_loop());
+
+        // respond to the announce request through the relay
+        let onion_client_c = onion_client.clone();
+        let responder = onion_rx
+            .into_future()
+            .map_err(|_| Error::new(ErrorKind::Other, "rx error"))
+            .and_then(move |(received, _onion_rx)| {
+                let (request, client_addr) = received.unwrap();
+                let payload = unpack_tcp_onion_request(&nodes, request);
+                let request = unpack!(payload.inner, InnerOnionRequest::InnerOnionAnnounceRequest);
+                assert_eq!(request.pk, onion_client_c.real_pk);
+                let node_sk = find_sk(&nodes, payload.ip_port.to_saddr());
+                let shared_secret = precompute(&request.pk, &node_sk);
+                let payload = request.get_payload(&shared_secret).unwrap();
+                let response = OnionAnnounceResponse::new(&shared_secret, payload.sendback_data, OnionAnnounceResponsePayload {
+                    announce_status: AnnounceStatus::Announced,
+                    ping_id_or_pk: sha256::hash(&[1, 2, 3]),
+                    nodes: Vec::new()
+                });
+                let response = InnerOnionResponse::OnionAnnounceResponse(response);
+                server_c.handle_udp_onion_response(client_addr.ip(), client_addr.port(), response)
+            });
+
+        // the response is handled by onion client
+        let announced = Interval::new(clock_now(), Duration::from_millis(100))
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Timer error: {:?}", e)))
+            .skip_while(move |_instant| Ok(!onion_client.is_announced()))
+            .into_future()
+            .map_err(|(e, _interval)| e);
+
+        let test = sender.join3(responder, announced)
+            .map(|_| ())
+            .select(network)
+            .map(|_| ())
+            .map_err(|(e, _select_next)| e)
+            .timeout(Duration::from_secs(10));
+
+        tokio::runtime::Runtime::new().unwrap().block_on(test).unwrap();
+    }
+}
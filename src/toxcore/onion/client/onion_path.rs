@@ -0,0 +1,285 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Onion path that consists of 3 nodes and is used to send onion requests.
+*/
+
+use std::time::Instant;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::packed_node::*;
+use toxcore::onion::packet::*;
+use toxcore::tcp::packet::OnionRequest;
+use toxcore::time::*;
+
+/// Number of nodes in onion path.
+pub const ONION_PATH_LENGTH: usize = 3;
+
+/// Encrypt serialized payload using the given `Nonce` and `PrecomputedKey`.
+fn seal_payload<T: ToBytes>(payload: &T, nonce: &Nonce, shared_secret: &PrecomputedKey) -> Vec<u8> {
+    let mut buf = [0; ONION_MAX_PACKET_SIZE];
+    let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+    seal_precomputed(&buf[..size], nonce, shared_secret)
+}
+
+/// Node of onion path with temporary key that is used to encrypt data for this
+/// node.
+#[derive(Clone)]
+struct OnionPathNode {
+    /// DHT node
+    node: PackedNode,
+    /// Temporary `PublicKey` that is sent to the node
+    temporary_pk: PublicKey,
+    /// Precomputed key for temporary `SecretKey` and `PublicKey` of the node
+    precomputed_key: PrecomputedKey,
+}
+
+impl OnionPathNode {
+    /// Create new `OnionPathNode` generating new temporary key pair for it.
+    fn new(node: PackedNode) -> OnionPathNode {
+        let (temporary_pk, temporary_sk) = gen_keypair();
+        let precomputed_key = precompute(&node.pk, &temporary_sk);
+        OnionPathNode {
+            node,
+            temporary_pk,
+            precomputed_key,
+        }
+    }
+}
+
+/** Path of 3 DHT nodes that is used to send onion requests.
+
+Onion request is encrypted three times: for the first, second and third nodes
+with temporary keys generated for each node when the path was created. All
+layers use the same `Nonce` since intermediate nodes pass it through unchanged.
+
+The first node of the path can be a TCP relay we are connected to. In this case
+onion request is sent to the relay as TCP `OnionRequest` packet which is
+encrypted only for the second and the third nodes.
+*/
+#[derive(Clone)]
+pub struct OnionPath {
+    /// Random id of the path used to match sent requests with it
+    pub id: u64,
+    /// Nodes of the path
+    nodes: [OnionPathNode; ONION_PATH_LENGTH],
+    /// Whether the first node of the path is a TCP relay
+    pub is_tcp: bool,
+    /// Time when the path was created
+    pub creation_time: Instant,
+}
+
+impl OnionPath {
+    /// Create new `OnionPath` from 3 DHT nodes.
+    pub fn new(nodes: [PackedNode; ONION_PATH_LENGTH]) -> OnionPath {
+        OnionPath {
+            id: random_u64(),
+            nodes: [
+                OnionPathNode::new(nodes[0]),
+                OnionPathNode::new(nodes[1]),
+                OnionPathNode::new(nodes[2]),
+            ],
+            is_tcp: false,
+            creation_time: clock_now(),
+        }
+    }
+
+    /// Create new `OnionPath` from TCP relay and 2 DHT nodes.
+    pub fn new_tcp(relay: PackedNode, nodes: [PackedNode; ONION_PATH_LENGTH - 1]) -> OnionPath {
+        OnionPath {
+            is_tcp: true,
+            .. OnionPath::new([relay, nodes[0], nodes[1]])
+        }
+    }
+
+    /// Nodes of the path. The first node is a TCP relay for TCP paths.
+    pub fn nodes(&self) -> [PackedNode; ONION_PATH_LENGTH] {
+        [self.nodes[0].node, self.nodes[1].node, self.nodes[2].node]
+    }
+
+    /// Create `OnionRequest0` packet that should be sent to the first node of
+    /// the path. The third node will send `inner` request to `destination`.
+    pub fn create_udp_onion_request(&self, destination: IpPort, inner: InnerOnionRequest) -> OnionRequest0 {
+        let nonce = gen_nonce();
+
+        let payload = OnionRequest2Payload {
+            ip_port: destination,
+            inner,
+        };
+        let payload = OnionRequest1Payload {
+            ip_port: IpPort::from_udp_saddr(self.nodes[2].node.saddr),
+            temporary_pk: self.nodes[2].temporary_pk,
+            inner: seal_payload(&payload, &nonce, &self.nodes[2].precomputed_key),
+        };
+        let payload = OnionRequest0Payload {
+            ip_port: IpPort::from_udp_saddr(self.nodes[1].node.saddr),
+            temporary_pk: self.nodes[1].temporary_pk,
+            inner: seal_payload(&payload, &nonce, &self.nodes[1].precomputed_key),
+        };
+
+        OnionRequest0 {
+            nonce,
+            temporary_pk: self.nodes[0].temporary_pk,
+            payload: seal_payload(&payload, &nonce, &self.nodes[0].precomputed_key),
+        }
+    }
+
+    /// Create `OnionRequest` packet that should be sent to the TCP relay which
+    /// is the first node of the path. The relay sends the request to the
+    /// second node as `OnionRequest1` so it's encrypted only for the second
+    /// and the third nodes. The third node will send `inner` request to
+    /// `destination`.
+    pub fn create_tcp_onion_request(&self, destination: IpPort, inner: InnerOnionRequest) -> OnionRequest {
+        let nonce = gen_nonce();
+
+        let payload = OnionRequest2Payload {
+            ip_port: destination,
+            inner,
+        };
+        let payload = OnionRequest1Payload {
+            ip_port: IpPort::from_udp_saddr(self.nodes[2].node.saddr),
+            temporary_pk: self.nodes[2].temporary_pk,
+            inner: seal_payload(&payload, &nonce, &self.nodes[2].precomputed_key),
+        };
+
+        OnionRequest {
+            nonce,
+            ip_port: IpPort::from_udp_saddr(self.nodes[1].node.saddr),
+            temporary_pk: self.nodes[1].temporary_pk,
+            payload: seal_payload(&payload, &nonce, &self.nodes[1].precomputed_key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONION_RETURN_1_PAYLOAD_SIZE: usize = ONION_RETURN_1_SIZE - secretbox::NONCEBYTES;
+    const ONION_RETURN_2_PAYLOAD_SIZE: usize = ONION_RETURN_2_SIZE - secretbox::NONCEBYTES;
+
+    #[test]
+    fn onion_path_nodes() {
+        let node_1 = PackedNode::new(false, "127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+        let node_2 = PackedNode::new(false, "127.0.0.1:12346".parse().unwrap(), &gen_keypair().0);
+        let node_3 = PackedNode::new(false, "127.0.0.1:12347".parse().unwrap(), &gen_keypair().0);
+        let path = OnionPath::new([node_1, node_2, node_3]);
+        assert_eq!(path.nodes(), [node_1, node_2, node_3]);
+    }
+
+    #[test]
+    fn create_udp_onion_request() {
+        let (node_1_pk, node_1_sk) = gen_keypair();
+        let (node_2_pk, node_2_sk) = gen_keypair();
+        let (node_3_pk, node_3_sk) = gen_keypair();
+        let node_1 = PackedNode::new(false, "127.0.0.1:12345".parse().unwrap(), &node_1_pk);
+        let node_2 = PackedNode::new(false, "127.0.0.1:12346".parse().unwrap(), &node_2_pk);
+        let node_3 = PackedNode::new(false, "127.0.0.1:12347".parse().unwrap(), &node_3_pk);
+        let path = OnionPath::new([node_1, node_2, node_3]);
+
+        let destination = IpPort::from_udp_saddr("127.0.0.1:12348".parse().unwrap());
+        let inner = InnerOnionRequest::InnerOnionAnnounceRequest(InnerOnionAnnounceRequest {
+            nonce: gen_nonce(),
+            pk: gen_keypair().0,
+            payload: vec![42; 123]
+        });
+        let request_0 = path.create_udp_onion_request(destination.clone(), inner.clone());
+
+        // the first node decrypts OnionRequest0
+        let payload_0 = request_0.get_payload(&precompute(&request_0.temporary_pk, &node_1_sk)).unwrap();
+        assert_eq!(payload_0.ip_port, IpPort::from_udp_saddr(node_2.saddr));
+
+        // the second node decrypts OnionRequest1
+        let request_1 = OnionRequest1 {
+            nonce: request_0.nonce,
+            temporary_pk: payload_0.temporary_pk,
+            payload: payload_0.inner,
+            onion_return: OnionReturn {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE]
+            }
+        };
+        let payload_1 = request_1.get_payload(&precompute(&request_1.temporary_pk, &node_2_sk)).unwrap();
+        assert_eq!(payload_1.ip_port, IpPort::from_udp_saddr(node_3.saddr));
+
+        // the third node decrypts OnionRequest2
+        let request_2 = OnionRequest2 {
+            nonce: request_1.nonce,
+            temporary_pk: payload_1.temporary_pk,
+            payload: payload_1.inner,
+            onion_return: OnionReturn {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
+            }
+        };
+        let payload_2 = request_2.get_payload(&precompute(&request_2.temporary_pk, &node_3_sk)).unwrap();
+        assert_eq!(payload_2.ip_port, destination);
+        assert_eq!(payload_2.inner, inner);
+    }
+
+    #[test]
+    fn create_tcp_onion_request() {
+        let (node_1_pk, _node_1_sk) = gen_keypair();
+        let (node_2_pk, node_2_sk) = gen_keypair();
+        let (node_3_pk, node_3_sk) = gen_keypair();
+        let node_1 = PackedNode::new(false, "127.0.0.1:12345".parse().unwrap(), &node_1_pk);
+        let node_2 = PackedNode::new(false, "127.0.0.1:12346".parse().unwrap(), &node_2_pk);
+        let node_3 = PackedNode::new(false, "127.0.0.1:12347".parse().unwrap(), &node_3_pk);
+        let path = OnionPath::new_tcp(node_1, [node_2, node_3]);
+        assert!(path.is_tcp);
+
+        let destination = IpPort::from_udp_saddr("127.0.0.1:12348".parse().unwrap());
+        let inner = InnerOnionRequest::InnerOnionAnnounceRequest(InnerOnionAnnounceRequest {
+            nonce: gen_nonce(),
+            pk: gen_keypair().0,
+            payload: vec![42; 123]
+        });
+        let request = path.create_tcp_onion_request(destination.clone(), inner.clone());
+        assert_eq!(request.ip_port, IpPort::from_udp_saddr(node_2.saddr));
+
+        // the relay sends OnionRequest1 to the second node
+        let request_1 = OnionRequest1 {
+            nonce: request.nonce,
+            temporary_pk: request.temporary_pk,
+            payload: request.payload,
+            onion_return: OnionReturn {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE]
+            }
+        };
+        let payload_1 = request_1.get_payload(&precompute(&request_1.temporary_pk, &node_2_sk)).unwrap();
+        assert_eq!(payload_1.ip_port, IpPort::from_udp_saddr(node_3.saddr));
+
+        // the third node decrypts OnionRequest2
+        let request_2 = OnionRequest2 {
+            nonce: request_1.nonce,
+            temporary_pk: payload_1.temporary_pk,
+            payload: payload_1.inner,
+            onion_return: OnionReturn {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
+            }
+        };
+        let payload_2 = request_2.get_payload(&precompute(&request_2.temporary_pk, &node_3_sk)).unwrap();
+        assert_eq!(payload_2.ip_port, destination);
+        assert_eq!(payload_2.inner, inner);
+    }
+}
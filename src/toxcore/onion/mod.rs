@@ -61,5 +61,6 @@ onion return addresses.
 
 */
 
+pub mod client;
 pub mod onion_announce;
 pub mod packet;
@@ -0,0 +1,99 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! FriendRequest struct
+*/
+
+use nom::rest;
+
+use std::str;
+
+use toxcore::binary_io::*;
+use toxcore::toxid::NoSpam;
+
+/// Maximum length of a friend request message in bytes.
+pub const MAX_FRIEND_REQUEST_MESSAGE_LENGTH: usize = 1016;
+
+/** Friend request that is sent to a not yet added friend inside onion data
+packets. Receiver should accept it only if `NoSpam` matches his own one.
+
+Serialized form:
+
+Length        | Content
+------------- | ------
+`1`           | `0x20`
+`4`           | `NoSpam`
+`[1, 1016]`   | Message in UTF-8
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FriendRequest {
+    /// `NoSpam` of the receiver taken from his `ToxId`
+    pub nospam: NoSpam,
+    /// Message that is shown to the receiver
+    pub message: String,
+}
+
+impl FromBytes for FriendRequest {
+    named!(from_bytes<FriendRequest>, do_parse!(
+        tag!(&[0x20][..]) >>
+        nospam: call!(NoSpam::from_bytes) >>
+        message: map_res!(
+            verify!(rest, |message: &[u8]| !message.is_empty() && message.len() <= MAX_FRIEND_REQUEST_MESSAGE_LENGTH),
+            str::from_utf8
+        ) >>
+        (FriendRequest { nospam, message: message.to_string() })
+    ));
+}
+
+impl ToBytes for FriendRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_cond!(self.message.is_empty() || self.message.len() > MAX_FRIEND_REQUEST_MESSAGE_LENGTH, |buf| gen_error(buf, 0)) >>
+            gen_be_u8!(0x20) >>
+            gen_slice!(self.nospam.0) >>
+            gen_slice!(self.message.as_bytes())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        friend_request_encode_decode,
+        FriendRequest {
+            nospam: NoSpam([42; 4]),
+            message: "Hello, let's be friends".to_string()
+        }
+    );
+
+    #[test]
+    fn friend_request_empty_message() {
+        let packet = FriendRequest {
+            nospam: NoSpam([42; 4]),
+            message: String::new()
+        };
+        let mut buf = [0; 5];
+        assert!(packet.to_bytes((&mut buf, 0)).is_err());
+        assert!(FriendRequest::from_bytes(&[0x20, 42, 42, 42, 42]).is_err());
+    }
+}
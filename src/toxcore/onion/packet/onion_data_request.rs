@@ -87,6 +87,22 @@ impl ToBytes for InnerOnionDataRequest {
     }
 }
 
+impl InnerOnionDataRequest {
+    /// Create new `InnerOnionDataRequest` object.
+    pub fn new(shared_secret: &PrecomputedKey, destination_pk: &PublicKey, temporary_pk: &PublicKey, nonce: &Nonce, payload: OnionDataResponsePayload) -> InnerOnionDataRequest {
+        let mut buf = [0; ONION_MAX_PACKET_SIZE];
+        let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+        let payload = seal_precomputed(&buf[..size], nonce, shared_secret);
+
+        InnerOnionDataRequest {
+            destination_pk: *destination_pk,
+            nonce: *nonce,
+            temporary_pk: *temporary_pk,
+            payload
+        }
+    }
+}
+
 /** Same as `InnerOnionDataRequest` but with `OnionReturn` addresses. It's sent
 from the third node from onion chain to the destination node.
 
@@ -25,8 +25,10 @@ use super::*;
 
 use toxcore::binary_io::*;
 use toxcore::crypto_core::*;
+use toxcore::dht::packet::DhtPkAnnouncePayload;
 
 use nom::rest;
+use std::io::{Error, ErrorKind};
 
 /** When onion node receives `OnionDataRequest` packet it converts it to
 `OnionDataResponse` and sends to destination node if it announced itself
@@ -79,10 +81,165 @@ impl ToBytes for OnionDataResponse {
     }
 }
 
+impl OnionDataResponse {
+    /// Create new `OnionDataResponse` object.
+    pub fn new(shared_secret: &PrecomputedKey, temporary_pk: &PublicKey, nonce: &Nonce, payload: OnionDataResponsePayload) -> OnionDataResponse {
+        let mut buf = [0; ONION_MAX_PACKET_SIZE];
+        let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+        let payload = seal_precomputed(&buf[..size], nonce, shared_secret);
+
+        OnionDataResponse { nonce: *nonce, temporary_pk: *temporary_pk, payload }
+    }
+
+    /** Decrypt payload and try to parse it as `OnionDataResponsePayload`.
+
+    Returns `Error` in case of failure:
+
+    - fails to decrypt
+    - fails to parse as `OnionDataResponsePayload`
+    */
+    pub fn get_payload(&self, shared_secret: &PrecomputedKey) -> Result<OnionDataResponsePayload, Error> {
+        let decrypted = open_precomputed(&self.payload, &self.nonce, shared_secret)
+            .map_err(|()| {
+                debug!("Decrypting OnionDataResponse failed!");
+                Error::new(ErrorKind::Other, "OnionDataResponse decrypt error.")
+            })?;
+        match OnionDataResponsePayload::from_bytes(&decrypted) {
+            IResult::Incomplete(e) => {
+                debug!(target: "Onion", "OnionDataResponsePayload deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("OnionDataResponsePayload deserialize error: {:?}", e)))
+            },
+            IResult::Error(e) => {
+                debug!(target: "Onion", "OnionDataResponsePayload deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("OnionDataResponsePayload deserialize error: {:?}", e)))
+            },
+            IResult::Done(_, inner) => {
+                Ok(inner)
+            }
+        }
+    }
+}
+
+/** Unencrypted payload of `OnionDataResponse` packet.
+
+It contains long term `PublicKey` of sender and the data encrypted with long
+term keys of sender and receiver using the same `Nonce` as the
+`OnionDataResponse` packet.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`32`     | Long term `PublicKey` of sender
+variable | Payload
+
+where payload is encrypted [`OnionDataResponseInnerPayload`](./enum.OnionDataResponseInnerPayload.html)
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionDataResponsePayload {
+    /// Long term `PublicKey` of sender
+    pub real_pk: PublicKey,
+    /// Encrypted payload
+    pub payload: Vec<u8>
+}
+
+impl FromBytes for OnionDataResponsePayload {
+    named!(from_bytes<OnionDataResponsePayload>, do_parse!(
+        real_pk: call!(PublicKey::from_bytes) >>
+        payload: rest >>
+        (OnionDataResponsePayload {
+            real_pk,
+            payload: payload.to_vec()
+        })
+    ));
+}
+
+impl ToBytes for OnionDataResponsePayload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(self.real_pk.as_ref()) >>
+            gen_slice!(self.payload)
+        )
+    }
+}
+
+impl OnionDataResponsePayload {
+    /// Create new `OnionDataResponsePayload` object.
+    pub fn new(shared_secret: &PrecomputedKey, real_pk: &PublicKey, nonce: &Nonce, payload: OnionDataResponseInnerPayload) -> OnionDataResponsePayload {
+        let mut buf = [0; ONION_MAX_PACKET_SIZE];
+        let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+        let payload = seal_precomputed(&buf[..size], nonce, shared_secret);
+
+        OnionDataResponsePayload { real_pk: *real_pk, payload }
+    }
+
+    /** Decrypt payload and try to parse it as `OnionDataResponseInnerPayload`.
+
+    Returns `Error` in case of failure:
+
+    - fails to decrypt
+    - fails to parse as `OnionDataResponseInnerPayload`
+    */
+    pub fn get_payload(&self, nonce: &Nonce, shared_secret: &PrecomputedKey) -> Result<OnionDataResponseInnerPayload, Error> {
+        let decrypted = open_precomputed(&self.payload, nonce, shared_secret)
+            .map_err(|()| {
+                debug!("Decrypting OnionDataResponsePayload failed!");
+                Error::new(ErrorKind::Other, "OnionDataResponsePayload decrypt error.")
+            })?;
+        match OnionDataResponseInnerPayload::from_bytes(&decrypted) {
+            IResult::Incomplete(e) => {
+                debug!(target: "Onion", "OnionDataResponseInnerPayload deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("OnionDataResponseInnerPayload deserialize error: {:?}", e)))
+            },
+            IResult::Error(e) => {
+                debug!(target: "Onion", "OnionDataResponseInnerPayload deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("OnionDataResponseInnerPayload deserialize error: {:?}", e)))
+            },
+            IResult::Done(_, inner) => {
+                Ok(inner)
+            }
+        }
+    }
+}
+
+/** Data that is sent to a friend through onion paths after it's decrypted with
+long term keys.
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum OnionDataResponseInnerPayload {
+    /// [`DhtPkAnnouncePayload`](../dht/struct.DhtPkAnnouncePayload.html) structure.
+    DhtPkAnnounce(DhtPkAnnouncePayload),
+    /// [`FriendRequest`](./struct.FriendRequest.html) structure.
+    FriendRequest(FriendRequest)
+}
+
+impl FromBytes for OnionDataResponseInnerPayload {
+    named!(from_bytes<OnionDataResponseInnerPayload>, alt!(
+        map!(DhtPkAnnouncePayload::from_bytes, OnionDataResponseInnerPayload::DhtPkAnnounce) |
+        map!(FriendRequest::from_bytes, OnionDataResponseInnerPayload::FriendRequest)
+    ));
+}
+
+impl ToBytes for OnionDataResponseInnerPayload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        match *self {
+            OnionDataResponseInnerPayload::DhtPkAnnounce(ref p) => p.to_bytes(buf),
+            OnionDataResponseInnerPayload::FriendRequest(ref p) => p.to_bytes(buf)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use toxcore::toxid::NoSpam;
+
     encode_decode_test!(
         onion_data_response_encode_decode,
         OnionDataResponse {
@@ -91,4 +248,78 @@ mod tests {
             payload: vec![42; 123]
         }
     );
+
+    encode_decode_test!(
+        onion_data_response_payload_encode_decode,
+        OnionDataResponsePayload {
+            real_pk: gen_keypair().0,
+            payload: vec![42; 123]
+        }
+    );
+
+    encode_decode_test!(
+        onion_data_response_inner_payload_encode_decode,
+        OnionDataResponseInnerPayload::DhtPkAnnounce(DhtPkAnnouncePayload {
+            no_reply: 42,
+            dht_pk: gen_keypair().0,
+            nodes: vec![
+                PackedNode::new(false, "127.0.0.1:12345".parse().unwrap(), &gen_keypair().0)
+            ]
+        })
+    );
+
+    encode_decode_test!(
+        onion_data_response_inner_payload_friend_request_encode_decode,
+        OnionDataResponseInnerPayload::FriendRequest(FriendRequest {
+            nospam: NoSpam([42; 4]),
+            message: "Hello".to_string()
+        })
+    );
+
+    #[test]
+    fn onion_data_response_payload_encrypt_decrypt() {
+        let (alice_real_pk, alice_real_sk) = gen_keypair();
+        let (bob_real_pk, bob_real_sk) = gen_keypair();
+        let (temporary_pk, temporary_sk) = gen_keypair();
+        let (data_pk, data_sk) = gen_keypair();
+        let nonce = gen_nonce();
+        let inner_payload = OnionDataResponseInnerPayload::DhtPkAnnounce(DhtPkAnnouncePayload {
+            no_reply: 42,
+            dht_pk: gen_keypair().0,
+            nodes: Vec::new()
+        });
+        let payload = OnionDataResponsePayload::new(&precompute(&bob_real_pk, &alice_real_sk), &alice_real_pk, &nonce, inner_payload.clone());
+        // encode payload with shared secret
+        let packet = OnionDataResponse::new(&precompute(&data_pk, &temporary_sk), &temporary_pk, &nonce, payload.clone());
+        // decode payload with bob's data secret key
+        let decoded_payload = packet.get_payload(&precompute(&packet.temporary_pk, &data_sk)).unwrap();
+        // payloads should be equal
+        assert_eq!(decoded_payload, payload);
+        // decode inner payload with bob's real secret key
+        let decoded_inner_payload = decoded_payload.get_payload(&packet.nonce, &precompute(&decoded_payload.real_pk, &bob_real_sk)).unwrap();
+        // inner payloads should be equal
+        assert_eq!(decoded_inner_payload, inner_payload);
+    }
+
+    #[test]
+    fn onion_data_response_payload_encrypt_decrypt_invalid_key() {
+        let (alice_real_pk, alice_real_sk) = gen_keypair();
+        let (bob_real_pk, _bob_real_sk) = gen_keypair();
+        let (temporary_pk, temporary_sk) = gen_keypair();
+        let (data_pk, _data_sk) = gen_keypair();
+        let (_eve_pk, eve_sk) = gen_keypair();
+        let nonce = gen_nonce();
+        let inner_payload = OnionDataResponseInnerPayload::DhtPkAnnounce(DhtPkAnnouncePayload {
+            no_reply: 42,
+            dht_pk: gen_keypair().0,
+            nodes: Vec::new()
+        });
+        let payload = OnionDataResponsePayload::new(&precompute(&bob_real_pk, &alice_real_sk), &alice_real_pk, &nonce, inner_payload);
+        // encode payload with shared secret
+        let packet = OnionDataResponse::new(&precompute(&data_pk, &temporary_sk), &temporary_pk, &nonce, payload.clone());
+        // try to decode payload with eve's secret key
+        assert!(packet.get_payload(&precompute(&packet.temporary_pk, &eve_sk)).is_err());
+        // try to decode inner payload with eve's secret key
+        assert!(payload.get_payload(&packet.nonce, &precompute(&payload.real_pk, &eve_sk)).is_err());
+    }
 }
@@ -22,13 +22,19 @@
 */
 
 
+use toxcore::crypto_core::*;
 use toxcore::tcp::packet::*;
+use toxcore::tcp::{TCP_PING_FREQUENCY, TCP_PING_TIMEOUT};
 use toxcore::io_tokio::*;
+use toxcore::time::*;
 
 use futures::sync::mpsc;
 use futures::future;
+use parking_lot::RwLock;
 
 use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /** A packet received from server.
     May be handled by user in callbacks
@@ -43,6 +49,8 @@ pub enum IncomingPacket {
     DisconnectNotification(DisconnectNotification),
     /// oob from other client with sender pk
     OobReceive(OobReceive),
+    /// onion response that should be handled by onion client
+    OnionResponse(OnionResponse),
     /// data from connected client
     Data(Data)
 }
@@ -58,10 +66,24 @@ pub enum OutgoingPacket {
     DisconnectNotification(DisconnectNotification),
     /// send oob by pk
     OobSend(OobSend),
+    /// ask server to send onion request to the next onion node
+    OnionRequest(OnionRequest),
     /// send data to connected client by id
     Data(Data)
 }
 
+/** State of pings sent to the server to check that it's still alive
+*/
+#[derive(Debug, Clone, Copy)]
+struct PingState {
+    /// Id and sending time of the `PingRequest` that is not answered yet
+    sent_ping: Option<(u64, Instant)>,
+    /// Time when the last `PingRequest` was sent
+    last_ping_time: Instant,
+    /// Round trip time measured by the last answered `PingRequest`
+    rtt: Option<Duration>,
+}
+
 /** Connection between server and client
 */
 #[derive(Debug, Clone)]
@@ -70,6 +92,8 @@ pub struct Connection {
     server_tx: mpsc::UnboundedSender<Packet>,
     /// The channel side to send packets to client to handle them in callbacks
     callback_tx: mpsc::UnboundedSender<IncomingPacket>,
+    /// State of pings sent to server
+    ping: Arc<RwLock<PingState>>,
 }
 
 impl Connection {
@@ -78,7 +102,80 @@ impl Connection {
     */
     pub fn new(server_tx: mpsc::UnboundedSender<Packet>,
             callback_tx: mpsc::UnboundedSender<IncomingPacket>) -> Connection {
-        Connection { server_tx, callback_tx }
+        let ping = PingState {
+            sent_ping: None,
+            last_ping_time: clock_now(),
+            rtt: None,
+        };
+        Connection { server_tx, callback_tx, ping: Arc::new(RwLock::new(ping)) }
+    }
+    /** Round trip time to server measured by the last answered `PingRequest`
+        It's `None` until the first `PongResponse` is received
+    */
+    pub fn rtt(&self) -> Option<Duration> {
+        self.ping.read().rtt
+    }
+    /** Check if server didn't answer the last `PingRequest` in time
+    */
+    pub fn is_ping_timed_out(&self) -> bool {
+        match self.ping.read().sent_ping {
+            Some((_, time)) => clock_elapsed(time) >= Duration::from_secs(TCP_PING_TIMEOUT),
+            None => false,
+        }
+    }
+    /** Send `PingRequest` with a random id to server
+    */
+    pub fn send_ping_request(&self) -> IoFuture<()> {
+        // ping_id 0 is reserved
+        let ping_id = loop {
+            let ping_id = random_u64();
+            if ping_id != 0 {
+                break ping_id
+            }
+        };
+        {
+            let mut ping = self.ping.write();
+            let now = clock_now();
+            ping.sent_ping = Some((ping_id, now));
+            ping.last_ping_time = now;
+        }
+        self.send_to_server(Packet::PingRequest(PingRequest { ping_id }))
+    }
+    /** Send `PingRequest` to server if it's time to do it and fail if server
+        didn't answer the previous one in time. Should be called periodically
+        so that dead connections are detected
+    */
+    pub(super) fn check_ping(&self) -> IoFuture<()> {
+        if self.is_ping_timed_out() {
+            return Box::new( future::err(
+                Error::new(ErrorKind::TimedOut,
+                    "Server didn't answer PingRequest in time"
+            )))
+        }
+        let ping_needed = {
+            let ping = self.ping.read();
+            ping.sent_ping.is_none() && clock_elapsed(ping.last_ping_time) >= Duration::from_secs(TCP_PING_FREQUENCY)
+        };
+        if ping_needed {
+            self.send_ping_request()
+        } else {
+            Box::new( future::ok(()) )
+        }
+    }
+    /// Check `PongResponse` from server and update round trip time
+    fn handle_pong_response(&self, packet: PongResponse) -> IoFuture<()> {
+        let mut ping = self.ping.write();
+        match ping.sent_ping {
+            Some((ping_id, time)) if ping_id == packet.ping_id => {
+                ping.sent_ping = None;
+                ping.rtt = Some(clock_elapsed(time));
+                Box::new( future::ok(()) )
+            },
+            _ => Box::new( future::err(
+                Error::new(ErrorKind::Other,
+                    "PongResponse with unexpected ping_id"
+            ))),
+        }
     }
     pub(super) fn handle_from_server(&self, packet: Packet) -> IoFuture<()> {
         match packet {
@@ -102,9 +199,8 @@ impl Connection {
                     PongResponse { ping_id: packet.ping_id }
                 ))
             },
-            Packet::PongResponse(_packet) => {
-                // TODO check ping_id
-                Box::new( future::ok(()) )
+            Packet::PongResponse(packet) => {
+                self.handle_pong_response(packet)
             },
             Packet::OobSend(_packet) => {
                 Box::new( future::err(
@@ -115,10 +211,18 @@ impl Connection {
             Packet::OobReceive(packet) => {
                 self.send_to_client( IncomingPacket::OobReceive(packet) )
             },
+            Packet::OnionRequest(_packet) => {
+                Box::new( future::err(
+                    Error::new(ErrorKind::Other,
+                        "Server must not send OnionRequest to client"
+                )))
+            },
+            Packet::OnionResponse(packet) => {
+                self.send_to_client( IncomingPacket::OnionResponse(packet) )
+            },
             Packet::Data(packet) => {
                 self.send_to_client( IncomingPacket::Data(packet) )
             },
-            _ => unimplemented!() // TODO onion
         }
     }
     /** Handle packet from client
@@ -135,6 +239,9 @@ impl Connection {
             OutgoingPacket::OobSend(packet) => {
                 self.send_to_server( Packet::OobSend(packet) )
             },
+            OutgoingPacket::OnionRequest(packet) => {
+                self.send_to_server( Packet::OnionRequest(packet) )
+            },
             OutgoingPacket::Data(packet) => {
                 self.send_to_server( Packet::Data(packet) )
             },
@@ -153,9 +260,14 @@ impl Connection {
 #[cfg(test)]
 mod tests {
     use toxcore::crypto_core::*;
+    use toxcore::onion::packet::{InnerOnionResponse, IpPort, OnionAnnounceResponse, ProtocolType};
     use toxcore::tcp::client::connection::*;
     use futures::prelude::*;
     use futures::sync::mpsc;
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    use toxcore::time::ConstNow;
 
     fn create_connection_channels()
         -> (Connection, mpsc::UnboundedReceiver<Packet>, mpsc::UnboundedReceiver<IncomingPacket>) {
@@ -219,6 +331,26 @@ mod tests {
         ));
     }
     #[test]
+    fn client_onion_request() {
+        let (connection, server_rx, _callback_rx) = create_connection_channels();
+
+        let request = OnionRequest {
+            nonce: gen_nonce(),
+            ip_port: IpPort {
+                protocol: ProtocolType::UDP,
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345,
+            },
+            temporary_pk: gen_keypair().0,
+            payload: vec![13; 170]
+        };
+        let outgoing_packet = OutgoingPacket::OnionRequest(request.clone());
+        connection.handle_from_client(outgoing_packet).wait().unwrap();
+
+        let (incoming_packet, _tail) = server_rx.into_future().wait().unwrap();
+        assert_eq!(incoming_packet.unwrap(), Packet::OnionRequest(request));
+    }
+    #[test]
     fn client_data() {
         let (connection, server_rx, _callback_rx) = create_connection_channels();
 
@@ -307,6 +439,81 @@ mod tests {
         ));
     }
     #[test]
+    fn server_pong_response() {
+        let (connection, server_rx, _callback_rx) = create_connection_channels();
+
+        let now = clock_now();
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+
+        with_default(&clock, &mut enter, |_| {
+            connection.send_ping_request().wait().unwrap();
+        });
+        let (packet, _server_rx) = server_rx.into_future().wait().unwrap();
+        let ping_id = unpack!(packet.unwrap(), Packet::PingRequest).ping_id;
+        assert!(connection.rtt().is_none());
+
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_millis(250)));
+
+        with_default(&clock, &mut enter, |_| {
+            let packet = Packet::PongResponse(PongResponse { ping_id });
+            connection.handle_from_server(packet).wait().unwrap();
+        });
+
+        assert_eq!(connection.rtt(), Some(Duration::from_millis(250)));
+        assert!(connection.ping.read().sent_ping.is_none());
+    }
+    #[test]
+    fn server_pong_response_unexpected() {
+        let (connection, _server_rx, _callback_rx) = create_connection_channels();
+
+        // no PingRequest was sent
+        let packet = Packet::PongResponse(PongResponse { ping_id: 42 });
+        assert!(connection.handle_from_server(packet).wait().is_err());
+
+        connection.send_ping_request().wait().unwrap();
+        let ping_id = connection.ping.read().sent_ping.unwrap().0;
+        let packet = Packet::PongResponse(PongResponse { ping_id: ping_id.wrapping_add(1) });
+        assert!(connection.handle_from_server(packet).wait().is_err());
+    }
+    #[test]
+    fn check_ping_sends_ping_request() {
+        let (connection, server_rx, _callback_rx) = create_connection_channels();
+
+        // it's too early to send PingRequest
+        connection.check_ping().wait().unwrap();
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(clock_now() + Duration::from_secs(TCP_PING_FREQUENCY)));
+
+        with_default(&clock, &mut enter, |_| {
+            connection.check_ping().wait().unwrap();
+            // PingRequest is sent only once until it's answered
+            connection.check_ping().wait().unwrap();
+        });
+        drop(connection);
+
+        let packets = server_rx.collect().wait().unwrap();
+        assert_eq!(packets.len(), 1);
+        unpack!(packets[0].clone(), Packet::PingRequest);
+    }
+    #[test]
+    fn check_ping_timed_out() {
+        let (connection, _server_rx, _callback_rx) = create_connection_channels();
+
+        connection.send_ping_request().wait().unwrap();
+        assert!(!connection.is_ping_timed_out());
+        assert!(connection.check_ping().wait().is_ok());
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(clock_now() + Duration::from_secs(TCP_PING_TIMEOUT)));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(connection.is_ping_timed_out());
+            assert!(connection.check_ping().wait().is_err());
+        });
+    }
+    #[test]
     fn server_oob_send() {
         let (connection, _server_rx, _callback_rx) = create_connection_channels();
 
@@ -340,6 +547,38 @@ mod tests {
         ));
     }
     #[test]
+    fn server_onion_request() {
+        let (connection, _server_rx, _callback_rx) = create_connection_channels();
+
+        let packet = Packet::OnionRequest(OnionRequest {
+            nonce: gen_nonce(),
+            ip_port: IpPort {
+                protocol: ProtocolType::UDP,
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345,
+            },
+            temporary_pk: gen_keypair().0,
+            payload: vec![13; 170]
+        });
+        let handle_res = connection.handle_from_server(packet).wait();
+        assert!(handle_res.is_err());
+    }
+    #[test]
+    fn server_onion_response() {
+        let (connection, _server_rx, callback_rx) = create_connection_channels();
+
+        let response = OnionResponse {
+            payload: InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
+                sendback_data: 12345,
+                nonce: gen_nonce(),
+                payload: vec![42; 123]
+            })
+        };
+        connection.handle_from_server(Packet::OnionResponse(response.clone())).wait().unwrap();
+        let (incoming_packet, _tail) = callback_rx.into_future().wait().unwrap();
+        assert_eq!(incoming_packet.unwrap(), IncomingPacket::OnionResponse(response));
+    }
+    #[test]
     fn server_data() {
         let (connection, _server_rx, callback_rx) = create_connection_channels();
 
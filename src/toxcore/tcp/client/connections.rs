@@ -0,0 +1,965 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Manager of connections to several TCP relays. It connects to added relays,
+reconnects to them with backoff when they drop and routes packets to friends
+through the best connected relays.
+*/
+
+use toxcore::crypto_core::*;
+use toxcore::dht::packed_node::PackedNode;
+use toxcore::io_tokio::*;
+use toxcore::onion::packet::InnerOnionResponse;
+use toxcore::tcp::codec::Codec;
+use toxcore::tcp::client::{ClientProcessor, Connection, Relay};
+use toxcore::tcp::connector::{self, ProxyType};
+use toxcore::tcp::handshake::make_client_handshake;
+use toxcore::tcp::packet::OnionRequest;
+use toxcore::time::*;
+
+use futures::{Future, Sink, Stream, future, stream};
+use futures::sync::{mpsc, oneshot};
+use parking_lot::RwLock;
+use tokio::timer::Interval;
+use tokio::util::FutureExt;
+use tokio_codec::Framed;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Number of connected relays through which we route packets to a friend.
+pub const RECOMMENDED_FRIEND_TCP_CONNECTIONS: usize = 3;
+
+/// Timeout in seconds for establishing connection with a relay including the
+/// handshake.
+pub const TCP_CONNECTION_TIMEOUT: u64 = 10;
+
+/// Delay in seconds before the first reconnection attempt to a dropped relay.
+/// It's doubled after each failed attempt.
+pub const RECONNECT_BASE_DELAY: u64 = 1;
+
+/// Maximum delay in seconds between reconnection attempts to a relay.
+pub const RECONNECT_MAX_DELAY: u64 = 64;
+
+/// Interval in seconds for running the main loop.
+const MAIN_LOOP_INTERVAL: u64 = 1;
+
+/// Shorthand for the transmit half of the message channel for sending data
+/// received from friends through relays. The key is a `PublicKey` of the
+/// friend that sent the data.
+type DataTx = mpsc::UnboundedSender<(PublicKey, Vec<u8>)>;
+
+/// Shorthand for the transmit half of the message channel for sending onion
+/// responses received from relays.
+type OnionResponseTx = mpsc::UnboundedSender<InnerOnionResponse>;
+
+/// Status of the connection to a relay.
+enum RelayStatus {
+    /// We are not connected to the relay and will try to connect at the
+    /// given time
+    Sleeping(Instant),
+    /// Connection to the relay is being established. Dropping `stop_tx`
+    /// stops the attempt.
+    Connecting {
+        /// Id of the connection attempt
+        session_id: u64,
+        /// Sender that stops the connection when dropped
+        stop_tx: oneshot::Sender<()>,
+    },
+    /// We are connected to the relay. Dropping `stop_tx` closes the
+    /// connection.
+    Connected {
+        /// Id of the connection attempt
+        session_id: u64,
+        /// Sender that closes the connection when dropped
+        _stop_tx: oneshot::Sender<()>,
+        /// Routes to friends through the relay
+        relay: Relay,
+        /// Connection that is used to get round trip time to the relay
+        connection: Connection,
+    },
+}
+
+/// Relay we should be connected to.
+struct RelayConnection {
+    /// Address of the relay
+    addr: SocketAddr,
+    /// Status of the connection
+    status: RelayStatus,
+    /// Number of failed connection attempts in a row
+    failures: u32,
+}
+
+impl RelayConnection {
+    /// Get `Relay` if we are connected to it.
+    fn relay(&self) -> Option<&Relay> {
+        match self.status {
+            RelayStatus::Connected { ref relay, .. } => Some(relay),
+            _ => None,
+        }
+    }
+}
+
+/// Delay before the next connection attempt after the given number of
+/// failed attempts in a row.
+fn reconnect_delay(failures: u32) -> Duration {
+    let delay = RECONNECT_BASE_DELAY.checked_shl(failures.saturating_sub(1))
+        .unwrap_or(RECONNECT_MAX_DELAY);
+    Duration::from_secs(cmp::min(delay, RECONNECT_MAX_DELAY))
+}
+
+/** Connections to several TCP relays.
+
+Relays are connected using our DHT key pair. For each friend
+`RECOMMENDED_FRIEND_TCP_CONNECTIONS` connected relays with the lowest round trip
+time and the least number of routed friends are chosen and `RouteRequest`s
+are sent to them. Data to a friend is sent through a relay the friend is
+connected to. Once the friend is connected through some relays the rest of
+relays chosen for him are forgotten. When a relay drops routes through it are
+forgotten and other relays are chosen for its friends. Connected relays can also be used as the
+first nodes of onion paths.
+*/
+#[derive(Clone)]
+pub struct Connections {
+    /// Our DHT `PublicKey`
+    dht_pk: PublicKey,
+    /// Our DHT `SecretKey`
+    dht_sk: SecretKey,
+    /// Sink to send data received from friends
+    data_tx: DataTx,
+    /// Sink to send onion responses received from relays
+    onion_response_tx: Option<OnionResponseTx>,
+    /// Relays by their `PublicKey`
+    relays: Arc<RwLock<HashMap<PublicKey, RelayConnection>>>,
+    /// `PublicKey`s of relays that are used for routing packets to friends by
+    /// friend's `PublicKey`
+    friends: Arc<RwLock<HashMap<PublicKey, Vec<PublicKey>>>>,
+    /// Proxy through which connections to relays are opened
+    proxy: Arc<RwLock<Option<ProxyType>>>,
+}
+
+impl Connections {
+    /// Create new `Connections`.
+    pub fn new(dht_pk: PublicKey, dht_sk: SecretKey, data_tx: DataTx) -> Connections {
+        Connections {
+            dht_pk,
+            dht_sk,
+            data_tx,
+            onion_response_tx: None,
+            relays: Arc::new(RwLock::new(HashMap::new())),
+            friends: Arc::new(RwLock::new(HashMap::new())),
+            proxy: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Set sink to send onion responses received from relays. Should be
+    /// called before relays are connected.
+    pub fn set_onion_response_sink(&mut self, onion_response_tx: OnionResponseTx) {
+        self.onion_response_tx = Some(onion_response_tx);
+    }
+
+    /// Set proxy through which new connections to relays should be opened.
+    /// `None` means that relays should be connected directly.
+    pub fn set_proxy(&self, proxy: Option<ProxyType>) {
+        *self.proxy.write() = proxy;
+    }
+
+    /// Add relay we should connect to. The connection is established in the
+    /// main loop.
+    pub fn add_relay(&self, relay_pk: PublicKey, addr: SocketAddr) {
+        self.relays.write().entry(relay_pk).or_insert_with(|| RelayConnection {
+            addr,
+            status: RelayStatus::Sleeping(clock_now()),
+            failures: 0,
+        });
+    }
+
+    /// Remove relay closing the connection to it.
+    pub fn remove_relay(&self, relay_pk: &PublicKey) {
+        if self.relays.write().remove(relay_pk).is_some() {
+            Connections::forget_relay(&mut self.friends.write(), relay_pk);
+        }
+    }
+
+    /// Check if we are connected to the relay.
+    pub fn is_relay_connected(&self, relay_pk: &PublicKey) -> bool {
+        self.relays.read().get(relay_pk).and_then(|relay| relay.relay()).is_some()
+    }
+
+    /// Add friend to route packets to him through relays. Routes are
+    /// requested in the main loop.
+    pub fn add_friend(&self, friend_pk: PublicKey) {
+        self.friends.write().entry(friend_pk).or_insert_with(Vec::new);
+    }
+
+    /// Add relay the friend is connected to. Routes to the friend are
+    /// requested through it once we are connected to the relay.
+    pub fn add_friend_relay(&self, friend_pk: PublicKey, relay_pk: PublicKey, addr: SocketAddr) {
+        self.add_relay(relay_pk, addr);
+        if let Some(relay_pks) = self.friends.write().get_mut(&friend_pk) {
+            if !relay_pks.contains(&relay_pk) {
+                relay_pks.push(relay_pk);
+            }
+        }
+    }
+
+    /// Remove friend and routes to him.
+    pub fn remove_friend(&self, friend_pk: &PublicKey) -> IoFuture<()> {
+        let relay_pks = match self.friends.write().remove(friend_pk) {
+            Some(relay_pks) => relay_pks,
+            None => return Box::new(future::err(
+                Error::new(ErrorKind::Other, "No such friend")
+            )),
+        };
+        let relays = self.relays.read();
+        let futures = relay_pks.iter()
+            .filter_map(|relay_pk| relays.get(relay_pk).and_then(|relay| relay.relay()))
+            .map(|relay| relay.remove_route(*friend_pk))
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Check if the friend is connected to us through at least one relay.
+    pub fn is_friend_online(&self, friend_pk: &PublicKey) -> bool {
+        self.online_relay(friend_pk).is_some()
+    }
+
+    /// Send data to the friend through a relay he is connected to. If there
+    /// is no such relay data is sent as OOB packet through a connected relay
+    /// chosen for the friend.
+    pub fn send_data(&self, friend_pk: PublicKey, data: Vec<u8>) -> IoFuture<()> {
+        match self.online_relay(&friend_pk).or_else(|| self.friend_relay(&friend_pk)) {
+            Some(relay) => relay.send_data(friend_pk, data),
+            None => Box::new(future::err(
+                Error::new(ErrorKind::Other, "Friend has no connected relays")
+            )),
+        }
+    }
+
+    /// Get a random connected relay that can be used as the first node of an
+    /// onion path.
+    pub fn random_relay(&self) -> Option<PackedNode> {
+        let relays = self.relays.read();
+        let connected = relays.iter()
+            .filter(|&(_, relay_connection)| relay_connection.relay().is_some())
+            .map(|(relay_pk, relay_connection)| PackedNode::new(false, relay_connection.addr, relay_pk))
+            .collect::<Vec<_>>();
+        if connected.is_empty() {
+            None
+        } else {
+            Some(connected[random_u32() as usize % connected.len()])
+        }
+    }
+
+    /// Ask the connected relay to send onion request to the next node of an
+    /// onion path.
+    pub fn send_onion_request(&self, relay_pk: &PublicKey, request: OnionRequest) -> IoFuture<()> {
+        match self.relays.read().get(relay_pk).and_then(|relay| relay.relay()) {
+            Some(relay) => relay.send_onion_request(request),
+            None => Box::new(future::err(
+                Error::new(ErrorKind::Other, "Relay is not connected")
+            )),
+        }
+    }
+
+    /// Find a relay through which the friend is connected to us.
+    fn online_relay(&self, friend_pk: &PublicKey) -> Option<Relay> {
+        let friends = self.friends.read();
+        let relays = self.relays.read();
+        friends.get(friend_pk)?.iter()
+            .filter_map(|relay_pk| relays.get(relay_pk).and_then(|relay| relay.relay()))
+            .find(|relay| relay.is_online(friend_pk))
+            .cloned()
+    }
+
+    /// Find a connected relay chosen for the friend.
+    fn friend_relay(&self, friend_pk: &PublicKey) -> Option<Relay> {
+        let friends = self.friends.read();
+        let relays = self.relays.read();
+        friends.get(friend_pk)?.iter()
+            .filter_map(|relay_pk| relays.get(relay_pk).and_then(|relay| relay.relay()))
+            .next()
+            .cloned()
+    }
+
+    /// Remove the relay from the lists of relays used for friends.
+    fn forget_relay(friends: &mut HashMap<PublicKey, Vec<PublicKey>>, relay_pk: &PublicKey) {
+        for relay_pks in friends.values_mut() {
+            relay_pks.retain(|pk| pk != relay_pk);
+        }
+    }
+
+    /// Mark the relay as connected if the connection attempt is still
+    /// actual. Returns `false` if the connection should be closed.
+    fn set_connected(&self, relay_pk: &PublicKey, id: u64, relay: Relay, connection: Connection) -> bool {
+        let mut relays = self.relays.write();
+        let relay_connection = match relays.get_mut(relay_pk) {
+            Some(relay_connection) => relay_connection,
+            None => return false,
+        };
+        match mem::replace(&mut relay_connection.status, RelayStatus::Sleeping(clock_now())) {
+            RelayStatus::Connecting { session_id, stop_tx } if session_id == id => {
+                relay_connection.status = RelayStatus::Connected {
+                    session_id,
+                    _stop_tx: stop_tx,
+                    relay,
+                    connection,
+                };
+                relay_connection.failures = 0;
+                true
+            },
+            status => {
+                relay_connection.status = status;
+                false
+            },
+        }
+    }
+
+    /// Mark the relay as disconnected if the connection attempt is still
+    /// actual and schedule the next attempt.
+    fn set_disconnected(&self, relay_pk: &PublicKey, id: u64) {
+        // relays lock should be released before taking friends lock
+        let was_connected = {
+            let mut relays = self.relays.write();
+            let relay_connection = match relays.get_mut(relay_pk) {
+                Some(relay_connection) => relay_connection,
+                None => return,
+            };
+            let was_connected = match relay_connection.status {
+                RelayStatus::Connecting { session_id, .. } if session_id == id => false,
+                RelayStatus::Connected { session_id, .. } if session_id == id => true,
+                _ => return,
+            };
+            relay_connection.failures += 1;
+            relay_connection.status = RelayStatus::Sleeping(clock_now() + reconnect_delay(relay_connection.failures));
+            was_connected
+        };
+        if was_connected {
+            Connections::forget_relay(&mut self.friends.write(), relay_pk);
+        }
+    }
+
+    /// Connect to the relay and transfer packets between it and `Relay` until
+    /// the connection is closed or `stop_rx` is resolved.
+    fn connect_relay(&self, relay_pk: PublicKey, addr: SocketAddr, session_id: u64, stop_rx: oneshot::Receiver<()>) -> IoFuture<()> {
+        let dht_pk = self.dht_pk;
+        let dht_sk = self.dht_sk.clone();
+        let handshake = connector::connect(addr, self.proxy.read().as_ref())
+            .and_then(move |stream| make_client_handshake(stream, dht_pk, dht_sk, relay_pk))
+            .timeout(Duration::from_secs(TCP_CONNECTION_TIMEOUT))
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Failed to connect to relay: {:?}", e)));
+
+        let connections = self.clone();
+        let session = handshake.and_then(move |(stream, channel)| -> IoFuture<()> {
+            debug!("Connected to relay {:?}", relay_pk);
+            let ClientProcessor {
+                from_client_tx,
+                to_client_rx,
+                from_server_tx,
+                to_server_rx,
+                connection,
+                processor
+            } = ClientProcessor::new();
+            let mut relay = Relay::new(from_client_tx, connections.data_tx.clone());
+            if let Some(ref onion_response_tx) = connections.onion_response_tx {
+                relay.set_onion_response_sink(onion_response_tx.clone());
+            }
+            if !connections.set_connected(&relay_pk, session_id, relay.clone(), connection) {
+                return Box::new(future::ok(()));
+            }
+
+            let (to_server, from_server) = Framed::new(stream, Codec::new(channel)).split();
+
+            let writer = to_server_rx
+                .map_err(|()| Error::from(ErrorKind::UnexpectedEof))
+                .forward(to_server)
+                .map(|_| ());
+
+            let reader = from_server
+                .forward(from_server_tx
+                    .sink_map_err(|e| Error::new(ErrorKind::Other,
+                        format!("Could not forward message from relay {:?}", e)))
+                )
+                .map(|_| ());
+
+            let handler = to_client_rx
+                .map_err(|()| Error::from(ErrorKind::UnexpectedEof))
+                .for_each(move |packet| relay.handle_packet(packet).or_else(|e| {
+                    debug!("Failed to handle packet from relay: {}", e);
+                    Ok(())
+                }));
+
+            Box::new(processor
+                .select(writer).map(|_| ()).map_err(|(e, _select_next)| e)
+                .select(reader).map(|_| ()).map_err(|(e, _select_next)| e)
+                .select(handler).map(|_| ()).map_err(|(e, _select_next)| e))
+        });
+
+        // dropping of stop_tx means that the relay is removed
+        let stop = stop_rx.then(|_| Ok(()));
+
+        let connections = self.clone();
+        Box::new(session
+            .select(stop)
+            .map(|_| ())
+            .map_err(|(e, _select_next)| e)
+            .then(move |res| {
+                debug!("Connection to relay {:?} is closed: {:?}", relay_pk, res);
+                connections.set_disconnected(&relay_pk, session_id);
+                res
+            }))
+    }
+
+    /// Start connecting to relays which reconnection time has come. Returns
+    /// futures that serve these connections.
+    fn connect_relays(&self) -> Vec<IoFuture<()>> {
+        let mut relays = self.relays.write();
+        let now = clock_now();
+        relays.iter_mut()
+            .filter(|&(_, ref relay_connection)| match relay_connection.status {
+                RelayStatus::Sleeping(time) => time <= now,
+                _ => false,
+            })
+            .map(|(&relay_pk, relay_connection)| {
+                let session_id = random_u64();
+                let (stop_tx, stop_rx) = oneshot::channel();
+                relay_connection.status = RelayStatus::Connecting { session_id, stop_tx };
+                self.connect_relay(relay_pk, relay_connection.addr, session_id, stop_rx)
+            })
+            .collect()
+    }
+
+    /// Choose connected relays for friends that have less than
+    /// `RECOMMENDED_FRIEND_TCP_CONNECTIONS` of them and request routes to
+    /// friends through chosen relays. Routes refused by relays are requested
+    /// again. Once a friend is connected to us through some relays the other
+    /// relays chosen for him are forgotten.
+    pub fn main_loop(&self) -> IoFuture<()> {
+        let mut friends = self.friends.write();
+        let relays = self.relays.read();
+
+        let mut futures = Vec::new();
+        for (friend_pk, relay_pks) in friends.iter_mut() {
+            let online_relay_pks = relay_pks.iter()
+                .filter(|relay_pk| match relays.get(*relay_pk).and_then(|relay| relay.relay()) {
+                    Some(relay) => relay.is_online(friend_pk),
+                    None => false,
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            if online_relay_pks.is_empty() {
+                continue;
+            }
+            for relay_pk in relay_pks.iter().filter(|relay_pk| !online_relay_pks.contains(relay_pk)) {
+                if let Some(relay) = relays.get(relay_pk).and_then(|relay| relay.relay()) {
+                    futures.push(relay.remove_route(*friend_pk));
+                }
+            }
+            *relay_pks = online_relay_pks;
+        }
+
+        // number of friends routed through each relay
+        let mut load = HashMap::new();
+        for relay_pk in friends.values().flat_map(|relay_pks| relay_pks.iter()) {
+            *load.entry(*relay_pk).or_insert(0) += 1;
+        }
+
+        for (friend_pk, relay_pks) in friends.iter_mut() {
+            // does nothing if the route is already requested
+            for relay_pk in relay_pks.iter() {
+                if let Some(relay) = relays.get(relay_pk).and_then(|relay| relay.relay()) {
+                    futures.push(relay.add_route(*friend_pk));
+                }
+            }
+
+            let is_online = relay_pks.iter()
+                .filter_map(|relay_pk| relays.get(relay_pk).and_then(|relay| relay.relay()))
+                .any(|relay| relay.is_online(friend_pk));
+            if is_online || relay_pks.len() >= RECOMMENDED_FRIEND_TCP_CONNECTIONS {
+                continue;
+            }
+
+            let mut candidates = relays.iter()
+                .filter(|&(relay_pk, _)| !relay_pks.contains(relay_pk))
+                .filter_map(|(relay_pk, relay_connection)| match relay_connection.status {
+                    RelayStatus::Connected { ref relay, ref connection, .. } =>
+                        Some((*relay_pk, relay, connection.rtt())),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            // relays with known lower RTT and lower load go first
+            candidates.sort_by_key(|&(relay_pk, _, rtt)|
+                (rtt.is_none(), rtt, load.get(&relay_pk).cloned().unwrap_or(0))
+            );
+
+            let count = RECOMMENDED_FRIEND_TCP_CONNECTIONS - relay_pks.len();
+            for (relay_pk, relay, _) in candidates.into_iter().take(count) {
+                relay_pks.push(relay_pk);
+                *load.entry(relay_pk).or_insert(0) += 1;
+                futures.push(relay.add_route(*friend_pk));
+            }
+        }
+
+        let futures_stream = stream::futures_unordered(futures).then(|_| Ok(()));
+        Box::new(futures_stream.for_each(|()| Ok(())))
+    }
+
+    /// Run connections to relays calling `main_loop` periodically.
+    pub fn run(&self) -> IoFuture<()> {
+        let connections = self.clone();
+        let future = Interval::new(clock_now(), Duration::from_secs(MAIN_LOOP_INTERVAL))
+            .map_err(|e| Error::new(ErrorKind::Other, format!("TCP connections timer error: {:?}", e)))
+            .map(move |_instant| {
+                let mut futures = connections.connect_relays();
+                futures.push(connections.main_loop());
+                stream::iter_ok(futures)
+            })
+            .flatten()
+            .map(|future| future.or_else(|e| {
+                debug!("TCP connections future ended with error: {}", e);
+                Ok(())
+            }))
+            // connections to relays should be served simultaneously
+            .buffer_unordered(usize::max_value())
+            .for_each(|()| Ok(()));
+        Box::new(future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio;
+    use tokio::net::TcpListener;
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    use toxcore::onion::packet::IpPort;
+    use toxcore::tcp::client::{IncomingPacket, OutgoingPacket};
+    use toxcore::tcp::packet::*;
+    use toxcore::tcp::server::{Server, ServerConfig};
+
+    type DataRx = mpsc::UnboundedReceiver<(PublicKey, Vec<u8>)>;
+    type FromClientRx = mpsc::UnboundedReceiver<OutgoingPacket>;
+
+    fn create_connections() -> (Connections, DataRx) {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (data_tx, data_rx) = mpsc::unbounded();
+        (Connections::new(dht_pk, dht_sk, data_tx), data_rx)
+    }
+
+    fn add_connected_relay(connections: &Connections) -> (PublicKey, Relay, FromClientRx) {
+        let relay_pk = gen_keypair().0;
+        let (from_client_tx, from_client_rx) = mpsc::unbounded();
+        let relay = Relay::new(from_client_tx, connections.data_tx.clone());
+        let (server_tx, _server_rx) = mpsc::unbounded();
+        let (callback_tx, _callback_rx) = mpsc::unbounded();
+        let connection = Connection::new(server_tx, callback_tx);
+        let (stop_tx, _stop_rx) = oneshot::channel();
+        connections.relays.write().insert(relay_pk, RelayConnection {
+            addr: "127.0.0.1:12345".parse().unwrap(),
+            status: RelayStatus::Connected {
+                session_id: 42,
+                _stop_tx: stop_tx,
+                relay: relay.clone(),
+                connection,
+            },
+            failures: 0,
+        });
+        (relay_pk, relay, from_client_rx)
+    }
+
+    fn set_friend_online(relay: &Relay, friend_pk: PublicKey) {
+        relay.handle_packet(IncomingPacket::RouteResponse(RouteResponse { connection_id: 42, pk: friend_pk })).wait().unwrap();
+        relay.handle_packet(IncomingPacket::ConnectNotification(ConnectNotification { connection_id: 42 })).wait().unwrap();
+    }
+
+    #[test]
+    fn reconnect_delay_grows() {
+        assert_eq!(reconnect_delay(1), Duration::from_secs(RECONNECT_BASE_DELAY));
+        assert_eq!(reconnect_delay(2), Duration::from_secs(RECONNECT_BASE_DELAY * 2));
+        assert_eq!(reconnect_delay(3), Duration::from_secs(RECONNECT_BASE_DELAY * 4));
+        assert_eq!(reconnect_delay(10), Duration::from_secs(RECONNECT_MAX_DELAY));
+        assert_eq!(reconnect_delay(100), Duration::from_secs(RECONNECT_MAX_DELAY));
+    }
+
+    #[test]
+    fn connect_relays() {
+        let (connections, _data_rx) = create_connections();
+        let relay_pk = gen_keypair().0;
+        connections.add_relay(relay_pk, "127.0.0.1:12345".parse().unwrap());
+
+        assert_eq!(connections.connect_relays().len(), 1);
+        // the relay is being connected already
+        assert!(connections.connect_relays().is_empty());
+        assert!(!connections.is_relay_connected(&relay_pk));
+    }
+
+    #[test]
+    fn connect_relay_through_proxy() {
+        let (connections, _data_rx) = create_connections();
+        let relay_pk = gen_keypair().0;
+        let relay_addr: SocketAddr = "1.2.3.4:33445".parse().unwrap();
+        connections.add_relay(relay_pk, relay_addr);
+
+        // proxy stand-in that refuses to connect to the relay
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        connections.set_proxy(Some(ProxyType::Socks5 { addr: proxy_addr, auth: None }));
+
+        let proxy = listener.incoming()
+            .into_future()
+            .map_err(|(e, _incoming)| e)
+            .and_then(|(stream, _incoming)| tokio::io::read_exact(stream.unwrap(), [0; 3]))
+            .and_then(|(stream, _greeting)| tokio::io::write_all(stream, [5, 0]))
+            .and_then(|(stream, _)| tokio::io::read_exact(stream, [0; 10]))
+            .and_then(move |(stream, request)| {
+                assert_eq!(&request[4..8], &[1, 2, 3, 4]);
+                tokio::io::write_all(stream, [5, 5, 0, 1, 0, 0, 0, 0, 0, 0])
+            })
+            .map(|_| ());
+
+        let mut futures = connections.connect_relays();
+        assert_eq!(futures.len(), 1);
+        let future = futures.pop().unwrap().join(proxy).map(|_| ());
+        assert!(tokio::runtime::Runtime::new().unwrap().block_on(future).is_err());
+
+        let relays = connections.relays.read();
+        let relay_connection = &relays[&relay_pk];
+        assert_eq!(relay_connection.failures, 1);
+        assert!(!connections.is_relay_connected(&relay_pk));
+    }
+
+    #[test]
+    fn set_disconnected_schedules_reconnect() {
+        let (connections, _data_rx) = create_connections();
+        let relay_pk = gen_keypair().0;
+        connections.add_relay(relay_pk, "127.0.0.1:12345".parse().unwrap());
+
+        let now = clock_now();
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+
+        with_default(&clock, &mut enter, |_| {
+            let _sessions = connections.connect_relays();
+            let session_id = match connections.relays.read()[&relay_pk].status {
+                RelayStatus::Connecting { session_id, .. } => session_id,
+                _ => panic!("Relay should be connecting"),
+            };
+
+            // outdated connection attempt shouldn't affect the relay
+            connections.set_disconnected(&relay_pk, session_id.wrapping_add(1));
+            assert_eq!(connections.relays.read()[&relay_pk].failures, 0);
+
+            connections.set_disconnected(&relay_pk, session_id);
+            connections.set_disconnected(&relay_pk, session_id);
+        });
+
+        let relays = connections.relays.read();
+        let relay_connection = &relays[&relay_pk];
+        assert_eq!(relay_connection.failures, 1);
+        match relay_connection.status {
+            RelayStatus::Sleeping(next_attempt) => assert_eq!(next_attempt, now + Duration::from_secs(RECONNECT_BASE_DELAY)),
+            _ => panic!("Relay should be sleeping"),
+        }
+    }
+
+    #[test]
+    fn set_disconnected_forgets_relay() {
+        let (connections, _data_rx) = create_connections();
+        let (relay_pk, _relay, _from_client_rx) = add_connected_relay(&connections);
+        let friend_pk = gen_keypair().0;
+        connections.add_friend(friend_pk);
+        connections.main_loop().wait().unwrap();
+        assert_eq!(connections.friends.read()[&friend_pk], vec![relay_pk]);
+
+        connections.set_disconnected(&relay_pk, 42);
+        assert!(!connections.is_relay_connected(&relay_pk));
+        assert!(connections.friends.read()[&friend_pk].is_empty());
+    }
+
+    #[test]
+    fn main_loop_chooses_relays() {
+        let (connections, _data_rx) = create_connections();
+        let relays = (0 .. RECOMMENDED_FRIEND_TCP_CONNECTIONS + 1)
+            .map(|_| add_connected_relay(&connections))
+            .collect::<Vec<_>>();
+        let friend_pk = gen_keypair().0;
+        connections.add_friend(friend_pk);
+
+        connections.main_loop().wait().unwrap();
+        // the second call shouldn't request routes again
+        connections.main_loop().wait().unwrap();
+
+        let chosen = connections.friends.read()[&friend_pk].clone();
+        assert_eq!(chosen.len(), RECOMMENDED_FRIEND_TCP_CONNECTIONS);
+
+        drop(connections);
+        for (relay_pk, relay, from_client_rx) in relays {
+            drop(relay);
+            let packets = from_client_rx.collect().wait().unwrap();
+            if chosen.contains(&relay_pk) {
+                assert_eq!(packets, vec![OutgoingPacket::RouteRequest(RouteRequest { pk: friend_pk })]);
+            } else {
+                assert!(packets.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn main_loop_repeats_refused_route_request() {
+        let (connections, _data_rx) = create_connections();
+        let (_relay_pk, relay, from_client_rx) = add_connected_relay(&connections);
+        let friend_pk = gen_keypair().0;
+        connections.add_friend(friend_pk);
+
+        connections.main_loop().wait().unwrap();
+        relay.handle_packet(IncomingPacket::RouteResponse(RouteResponse { connection_id: 0, pk: friend_pk })).wait().unwrap();
+        connections.main_loop().wait().unwrap();
+
+        drop(connections);
+        drop(relay);
+        let packets = from_client_rx.collect().wait().unwrap();
+        assert_eq!(packets, vec![
+            OutgoingPacket::RouteRequest(RouteRequest { pk: friend_pk }),
+            OutgoingPacket::RouteRequest(RouteRequest { pk: friend_pk }),
+        ]);
+    }
+
+    #[test]
+    fn main_loop_forgets_relays_friend_is_not_connected_through() {
+        let (connections, _data_rx) = create_connections();
+        let relays = (0 .. RECOMMENDED_FRIEND_TCP_CONNECTIONS + 1)
+            .map(|_| add_connected_relay(&connections))
+            .collect::<Vec<_>>();
+        let friend_pk = gen_keypair().0;
+        connections.add_friend(friend_pk);
+        connections.main_loop().wait().unwrap();
+
+        let chosen = connections.friends.read()[&friend_pk].clone();
+        let online_relay_pk = chosen[0];
+        for &(relay_pk, ref relay, _) in &relays {
+            if chosen.contains(&relay_pk) {
+                relay.handle_packet(IncomingPacket::RouteResponse(RouteResponse { connection_id: 42, pk: friend_pk })).wait().unwrap();
+            }
+            if relay_pk == online_relay_pk {
+                relay.handle_packet(IncomingPacket::ConnectNotification(ConnectNotification { connection_id: 42 })).wait().unwrap();
+            }
+        }
+
+        connections.main_loop().wait().unwrap();
+        // the friend is online so other relays shouldn't be chosen
+        connections.main_loop().wait().unwrap();
+        assert_eq!(connections.friends.read()[&friend_pk], vec![online_relay_pk]);
+        assert!(connections.is_friend_online(&friend_pk));
+
+        drop(connections);
+        for (relay_pk, relay, from_client_rx) in relays {
+            drop(relay);
+            let packets = from_client_rx.collect().wait().unwrap();
+            if relay_pk == online_relay_pk || !chosen.contains(&relay_pk) {
+                assert!(!packets.contains(&OutgoingPacket::DisconnectNotification(DisconnectNotification { connection_id: 42 })));
+            } else {
+                assert_eq!(packets, vec![
+                    OutgoingPacket::RouteRequest(RouteRequest { pk: friend_pk }),
+                    OutgoingPacket::DisconnectNotification(DisconnectNotification { connection_id: 42 }),
+                ]);
+            }
+        }
+    }
+
+    #[test]
+    fn main_loop_prefers_relays_with_lower_load() {
+        let (connections, _data_rx) = create_connections();
+        let relays = (0 .. 4)
+            .map(|_| add_connected_relay(&connections).0)
+            .collect::<Vec<_>>();
+        let friend_pk_1 = gen_keypair().0;
+        let friend_pk_2 = gen_keypair().0;
+        connections.friends.write().insert(friend_pk_1, vec![relays[0], relays[1], relays[2]]);
+        connections.friends.write().insert(friend_pk_2, vec![relays[0], relays[1], relays[3]]);
+        let friend_pk_3 = gen_keypair().0;
+        connections.add_friend(friend_pk_3);
+
+        connections.main_loop().wait().unwrap();
+
+        // the third and the fourth relays are used only for one friend
+        let mut chosen = connections.friends.read()[&friend_pk_3][.. 2].to_vec();
+        chosen.sort();
+        let mut expected = vec![relays[2], relays[3]];
+        expected.sort();
+        assert_eq!(chosen, expected);
+    }
+
+    #[test]
+    fn send_data() {
+        let (connections, _data_rx) = create_connections();
+        let (_relay_pk, relay, from_client_rx) = add_connected_relay(&connections);
+        let friend_pk = gen_keypair().0;
+        connections.add_friend(friend_pk);
+
+        // the friend doesn't have connected relays yet
+        assert!(connections.send_data(friend_pk, vec![42; 123]).wait().is_err());
+
+        connections.main_loop().wait().unwrap();
+        assert!(!connections.is_friend_online(&friend_pk));
+        connections.send_data(friend_pk, vec![42; 123]).wait().unwrap();
+
+        set_friend_online(&relay, friend_pk);
+        assert!(connections.is_friend_online(&friend_pk));
+        connections.send_data(friend_pk, vec![43; 123]).wait().unwrap();
+
+        drop(connections);
+        drop(relay);
+        let packets = from_client_rx.collect().wait().unwrap();
+        assert_eq!(packets, vec![
+            OutgoingPacket::RouteRequest(RouteRequest { pk: friend_pk }),
+            OutgoingPacket::OobSend(OobSend { destination_pk: friend_pk, data: vec![42; 123] }),
+            OutgoingPacket::Data(Data { connection_id: 42, data: vec![43; 123] }),
+        ]);
+    }
+
+    #[test]
+    fn add_friend_relay() {
+        let (connections, _data_rx) = create_connections();
+        let friend_pk = gen_keypair().0;
+        let relay_pk = gen_keypair().0;
+        connections.add_friend(friend_pk);
+
+        connections.add_friend_relay(friend_pk, relay_pk, "127.0.0.1:12345".parse().unwrap());
+        connections.add_friend_relay(friend_pk, relay_pk, "127.0.0.1:12345".parse().unwrap());
+
+        assert!(connections.relays.read().contains_key(&relay_pk));
+        assert_eq!(connections.friends.read()[&friend_pk], vec![relay_pk]);
+    }
+
+    #[test]
+    fn random_relay() {
+        let (connections, _data_rx) = create_connections();
+        connections.add_relay(gen_keypair().0, "127.0.0.1:12345".parse().unwrap());
+        // the relay is not connected yet
+        assert!(connections.random_relay().is_none());
+
+        let (relay_pk, _relay, _from_client_rx) = add_connected_relay(&connections);
+        assert_eq!(connections.random_relay(), Some(PackedNode::new(false, "127.0.0.1:12345".parse().unwrap(), &relay_pk)));
+    }
+
+    #[test]
+    fn send_onion_request() {
+        let (connections, _data_rx) = create_connections();
+        let (relay_pk, relay, from_client_rx) = add_connected_relay(&connections);
+
+        let request = OnionRequest {
+            nonce: gen_nonce(),
+            ip_port: IpPort::from_udp_saddr("5.6.7.8:12345".parse().unwrap()),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 170]
+        };
+        connections.send_onion_request(&relay_pk, request.clone()).wait().unwrap();
+        assert!(connections.send_onion_request(&gen_keypair().0, request.clone()).wait().is_err());
+
+        drop(connections);
+        drop(relay);
+        let packets = from_client_rx.collect().wait().unwrap();
+        assert_eq!(packets, vec![OutgoingPacket::OnionRequest(request)]);
+    }
+
+    #[test]
+    fn remove_friend() {
+        let (connections, _data_rx) = create_connections();
+        let (_relay_pk, relay, from_client_rx) = add_connected_relay(&connections);
+        let friend_pk = gen_keypair().0;
+        connections.add_friend(friend_pk);
+        connections.main_loop().wait().unwrap();
+        set_friend_online(&relay, friend_pk);
+
+        connections.remove_friend(&friend_pk).wait().unwrap();
+        assert!(!connections.friends.read().contains_key(&friend_pk));
+        assert!(!relay.is_online(&friend_pk));
+        assert!(connections.remove_friend(&friend_pk).wait().is_err());
+
+        drop(connections);
+        drop(relay);
+        let packets = from_client_rx.collect().wait().unwrap();
+        assert_eq!(packets.last().unwrap().clone(), OutgoingPacket::DisconnectNotification(DisconnectNotification { connection_id: 42 }));
+    }
+
+    #[test]
+    fn remove_relay() {
+        let (connections, _data_rx) = create_connections();
+        let (relay_pk, _relay, _from_client_rx) = add_connected_relay(&connections);
+        let friend_pk = gen_keypair().0;
+        connections.add_friend(friend_pk);
+        connections.main_loop().wait().unwrap();
+
+        connections.remove_relay(&relay_pk);
+        assert!(!connections.is_relay_connected(&relay_pk));
+        assert!(connections.friends.read()[&friend_pk].is_empty());
+    }
+
+    #[test]
+    fn run_data_exchange() {
+        let (relay_pk, relay_sk) = gen_keypair();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = ::toxcore::tcp::server::run(listener, relay_sk, Server::new(), ServerConfig::default());
+
+        let (connections_1, _data_rx_1) = create_connections();
+        let (connections_2, data_rx_2) = create_connections();
+        connections_1.add_relay(relay_pk, addr);
+        connections_2.add_relay(relay_pk, addr);
+        connections_1.add_friend(connections_2.dht_pk);
+        connections_2.add_friend(connections_1.dht_pk);
+
+        let pk_1 = connections_1.dht_pk;
+        let pk_2 = connections_2.dht_pk;
+        let network = server
+            .join3(connections_1.run(), connections_2.run())
+            .map(|_| ());
+
+        // send data when the friend is connected through the relay
+        let connections_c = connections_1.clone();
+        let sender = Interval::new(clock_now(), Duration::from_millis(100))
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Timer error: {:?}", e)))
+            .skip_while(move |_instant| Ok(!connections_c.is_friend_online(&pk_2)))
+            .into_future()
+            .map_err(|(e, _interval)| e)
+            .and_then(move |_| connections_1.send_data(pk_2, vec![42; 123]));
+        let receiver = data_rx_2
+            .into_future()
+            .map(move |(received, _data_rx_2)| assert_eq!(received.unwrap(), (pk_1, vec![42; 123])))
+            .map_err(|_| Error::new(ErrorKind::Other, "rx error"));
+
+        let test = sender.join(receiver)
+            .map(|_| ())
+            .select(network)
+            .map(|_| ())
+            .map_err(|(e, _select_next)| e)
+            .timeout(Duration::from_secs(10));
+
+        tokio::runtime::Runtime::new().unwrap().block_on(test).unwrap();
+    }
+}
@@ -22,9 +22,13 @@
 */
 
 mod connection;
+mod connections;
 mod processor;
+mod relay;
 
 pub use self::connection::Connection;
 pub use self::connection::IncomingPacket;
 pub use self::connection::OutgoingPacket;
+pub use self::connections::*;
 pub use self::processor::ClientProcessor;
+pub use self::relay::Relay;
@@ -24,11 +24,17 @@
 use toxcore::tcp::client::connection::*;
 use toxcore::tcp::packet::*;
 use toxcore::io_tokio::IoFuture;
+use toxcore::time::*;
 
 use futures::prelude::*;
 use futures::sync::mpsc;
+use tokio::timer::Interval;
 
 use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+/// Interval in seconds for checking whether it's time to ping server.
+const PING_CHECK_INTERVAL: u64 = 1;
 
 /** `ClientProcessor` helps you to manage logic for client connection,
     handle packets, send them back, handle ping/pong gracefully
@@ -44,6 +50,9 @@ pub struct ClientProcessor {
     /// Server is notified with each `Packet`
     pub to_server_rx: mpsc::UnboundedReceiver<Packet>,
 
+    /// Connection that can be used to get round trip time to server
+    pub connection: Connection,
+
     /// Run this future to process connection
     pub processor: IoFuture<()>
 }
@@ -84,14 +93,27 @@ impl ClientProcessor {
                 res
             });
 
+        // the connection is dropped when server doesn't answer pings
+        let connection_c = connection.clone();
+        let ping_loop = Interval::new(clock_now(), Duration::from_secs(PING_CHECK_INTERVAL))
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Ping timer error: {:?}", e)))
+            .for_each(move |_instant| connection_c.check_ping())
+            .then(|res| {
+                debug!("ping_loop ended with {:?}", res);
+                res
+            });
+
         let processor = process_messages_from_server
             .select(process_messages_from_client)
             .map(|_| ())
+            .map_err(|(err, _select_next)| err)
+            .select(ping_loop)
+            .map(|_| ())
             .map_err(|(err, _select_next)| err);
 
         let processor = Box::new(processor);
 
-        ClientProcessor { to_client_rx, from_client_tx, from_server_tx, to_server_rx, processor }
+        ClientProcessor { to_client_rx, from_client_tx, from_server_tx, to_server_rx, connection, processor }
     }
 }
 
@@ -110,6 +132,7 @@ mod tests {
             to_client_rx,
             from_server_tx,
             to_server_rx,
+            connection: _connection,
             processor
         } = ClientProcessor::new();
         let client_processor = processor.map_err(|_| ());
@@ -132,6 +155,7 @@ mod tests {
             to_client_rx,
             from_server_tx,
             to_server_rx,
+            connection: _connection,
             processor
         } = ClientProcessor::new();
         let client_processor = processor.map_err(|_| ());
@@ -0,0 +1,426 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Roman Proskuryakov <humbug@deeptown.org>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Routing of data packets to friends through TCP Relay
+*/
+
+use toxcore::crypto_core::*;
+use toxcore::onion::packet::InnerOnionResponse;
+use toxcore::tcp::client::connection::*;
+use toxcore::tcp::packet::*;
+use toxcore::io_tokio::*;
+
+use futures::sync::mpsc;
+use futures::future;
+use parking_lot::RwLock;
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+/// Shorthand for the transmit half of the message channel for sending data
+/// received from friends through the relay. The key is a `PublicKey` of the
+/// friend that sent the data.
+type DataTx = mpsc::UnboundedSender<(PublicKey, Vec<u8>)>;
+
+/// Shorthand for the transmit half of the message channel for sending onion
+/// responses received from the relay.
+type OnionResponseTx = mpsc::UnboundedSender<InnerOnionResponse>;
+
+/// Status of a route to a friend through the relay.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum RouteStatus {
+    /// `RouteRequest` was sent but `RouteResponse` wasn't received yet
+    Requested,
+    /// The relay assigned `connection_id` to the friend but the friend is not
+    /// connected to us yet
+    Registered(u8),
+    /// The friend is connected to us through the relay
+    Online(u8),
+}
+
+/** Routes to friends through a single TCP Relay.
+
+Data to a friend is sent as `Data` packet when the friend is connected to us
+through the relay and as `OobSend` packet otherwise. Data received from friends
+as `Data` or `OobReceive` packets is sent to the data sink with `PublicKey` of
+the sender. The relay can also be used as the first node of onion paths: onion
+responses received from it are sent to the onion response sink.
+*/
+#[derive(Clone)]
+pub struct Relay {
+    /// The channel side to send packets to server
+    from_client_tx: mpsc::UnboundedSender<OutgoingPacket>,
+    /// Sink to send data received from friends
+    data_tx: DataTx,
+    /// Sink to send onion responses received from the relay
+    onion_response_tx: Option<OnionResponseTx>,
+    /// Routes to friends by their `PublicKey`
+    routes: Arc<RwLock<HashMap<PublicKey, RouteStatus>>>,
+}
+
+impl Relay {
+    /** Create a new `Relay`
+        `from_client_tx` is the channel side of `ClientProcessor` to send
+        packets to server
+    */
+    pub fn new(from_client_tx: mpsc::UnboundedSender<OutgoingPacket>, data_tx: DataTx) -> Relay {
+        Relay {
+            from_client_tx,
+            data_tx,
+            onion_response_tx: None,
+            routes: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Set sink to send onion responses received from the relay.
+    pub fn set_onion_response_sink(&mut self, onion_response_tx: OnionResponseTx) {
+        self.onion_response_tx = Some(onion_response_tx);
+    }
+
+    /// Ask the relay to route packets to the friend with the given `PublicKey`.
+    /// Does nothing if the route is already requested. If the relay refuses
+    /// to route packets the route is forgotten so that calling this function
+    /// again will repeat the request.
+    pub fn add_route(&self, pk: PublicKey) -> IoFuture<()> {
+        let mut routes = self.routes.write();
+        if routes.contains_key(&pk) {
+            return Box::new(future::ok(()))
+        }
+        routes.insert(pk, RouteStatus::Requested);
+        send_to(&self.from_client_tx, OutgoingPacket::RouteRequest(RouteRequest { pk }))
+    }
+
+    /// Remove route to the friend with the given `PublicKey`
+    pub fn remove_route(&self, pk: PublicKey) -> IoFuture<()> {
+        match self.routes.write().remove(&pk) {
+            Some(RouteStatus::Registered(connection_id)) | Some(RouteStatus::Online(connection_id)) =>
+                send_to(&self.from_client_tx, OutgoingPacket::DisconnectNotification(
+                    DisconnectNotification { connection_id }
+                )),
+            _ => Box::new(future::ok(())),
+        }
+    }
+
+    /// Check if the friend with the given `PublicKey` is connected to us
+    /// through the relay
+    pub fn is_online(&self, pk: &PublicKey) -> bool {
+        match self.routes.read().get(pk) {
+            Some(&RouteStatus::Online(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Send data to the friend with the given `PublicKey`
+    pub fn send_data(&self, pk: PublicKey, data: Vec<u8>) -> IoFuture<()> {
+        let packet = match self.routes.read().get(&pk) {
+            Some(&RouteStatus::Online(connection_id)) => OutgoingPacket::Data(Data { connection_id, data }),
+            _ => OutgoingPacket::OobSend(OobSend { destination_pk: pk, data }),
+        };
+        send_to(&self.from_client_tx, packet)
+    }
+
+    /// Ask the relay to send onion request to the next node of an onion path
+    pub fn send_onion_request(&self, request: OnionRequest) -> IoFuture<()> {
+        send_to(&self.from_client_tx, OutgoingPacket::OnionRequest(request))
+    }
+
+    /// Find `PublicKey` of the friend with the given `connection_id`
+    fn pk_by_connection_id(routes: &HashMap<PublicKey, RouteStatus>, connection_id: u8) -> Option<PublicKey> {
+        routes.iter().find(|&(_, status)| match *status {
+            RouteStatus::Registered(id) | RouteStatus::Online(id) => id == connection_id,
+            RouteStatus::Requested => false,
+        }).map(|(&pk, _)| pk)
+    }
+
+    /// Handle packet received from server
+    pub fn handle_packet(&self, packet: IncomingPacket) -> IoFuture<()> {
+        match packet {
+            IncomingPacket::RouteResponse(packet) => {
+                let mut routes = self.routes.write();
+                if !routes.contains_key(&packet.pk) {
+                    return Box::new(future::err(
+                        Error::new(ErrorKind::Other,
+                            "RouteResponse for unknown PublicKey"
+                    )))
+                }
+                // connection_id 0 means that the relay refused to route packets
+                if packet.connection_id == 0 {
+                    routes.remove(&packet.pk);
+                } else {
+                    routes.insert(packet.pk, RouteStatus::Registered(packet.connection_id));
+                }
+                Box::new(future::ok(()))
+            },
+            IncomingPacket::ConnectNotification(packet) => {
+                let mut routes = self.routes.write();
+                match Relay::pk_by_connection_id(&routes, packet.connection_id) {
+                    Some(pk) => {
+                        routes.insert(pk, RouteStatus::Online(packet.connection_id));
+                        Box::new(future::ok(()))
+                    },
+                    None => Box::new(future::err(
+                        Error::new(ErrorKind::Other,
+                            "ConnectNotification for unknown connection_id"
+                    ))),
+                }
+            },
+            IncomingPacket::DisconnectNotification(packet) => {
+                let mut routes = self.routes.write();
+                match Relay::pk_by_connection_id(&routes, packet.connection_id) {
+                    Some(pk) => {
+                        routes.insert(pk, RouteStatus::Registered(packet.connection_id));
+                        Box::new(future::ok(()))
+                    },
+                    None => Box::new(future::err(
+                        Error::new(ErrorKind::Other,
+                            "DisconnectNotification for unknown connection_id"
+                    ))),
+                }
+            },
+            IncomingPacket::OobReceive(packet) => {
+                send_to(&self.data_tx, (packet.sender_pk, packet.data))
+            },
+            IncomingPacket::Data(packet) => {
+                let pk = Relay::pk_by_connection_id(&self.routes.read(), packet.connection_id);
+                match pk {
+                    Some(pk) => send_to(&self.data_tx, (pk, packet.data)),
+                    None => Box::new(future::err(
+                        Error::new(ErrorKind::Other,
+                            "Data for unknown connection_id"
+                    ))),
+                }
+            },
+            IncomingPacket::OnionResponse(packet) => {
+                match self.onion_response_tx {
+                    Some(ref onion_response_tx) => send_to(onion_response_tx, packet.payload),
+                    None => Box::new(future::ok(())),
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use toxcore::tcp::client::relay::*;
+    use toxcore::onion::packet::{IpPort, OnionAnnounceResponse, ProtocolType};
+    use futures::prelude::*;
+
+    type FromClientRx = mpsc::UnboundedReceiver<OutgoingPacket>;
+    type DataRx = mpsc::UnboundedReceiver<(PublicKey, Vec<u8>)>;
+
+    fn create_relay() -> (Relay, FromClientRx, DataRx) {
+        let (from_client_tx, from_client_rx) = mpsc::unbounded();
+        let (data_tx, data_rx) = mpsc::unbounded();
+        let relay = Relay::new(from_client_tx, data_tx);
+        (relay, from_client_rx, data_rx)
+    }
+
+    #[test]
+    fn add_route() {
+        let (relay, from_client_rx, _data_rx) = create_relay();
+        let (pk, _sk) = gen_keypair();
+
+        relay.add_route(pk).wait().unwrap();
+        // the second call shouldn't send RouteRequest again
+        relay.add_route(pk).wait().unwrap();
+        drop(relay);
+
+        let packets = from_client_rx.collect().wait().unwrap();
+        assert_eq!(packets, vec![OutgoingPacket::RouteRequest(RouteRequest { pk })]);
+    }
+
+    #[test]
+    fn remove_route() {
+        let (relay, from_client_rx, _data_rx) = create_relay();
+        let (pk, _sk) = gen_keypair();
+
+        relay.add_route(pk).wait().unwrap();
+        relay.handle_packet(IncomingPacket::RouteResponse(RouteResponse { connection_id: 42, pk })).wait().unwrap();
+        relay.remove_route(pk).wait().unwrap();
+        drop(relay);
+
+        let packets = from_client_rx.collect().wait().unwrap();
+        assert_eq!(packets, vec![
+            OutgoingPacket::RouteRequest(RouteRequest { pk }),
+            OutgoingPacket::DisconnectNotification(DisconnectNotification { connection_id: 42 }),
+        ]);
+    }
+
+    #[test]
+    fn send_data_oob() {
+        let (relay, from_client_rx, _data_rx) = create_relay();
+        let (pk, _sk) = gen_keypair();
+
+        relay.add_route(pk).wait().unwrap();
+        relay.handle_packet(IncomingPacket::RouteResponse(RouteResponse { connection_id: 42, pk })).wait().unwrap();
+        assert!(!relay.is_online(&pk));
+        relay.send_data(pk, vec![42; 123]).wait().unwrap();
+        drop(relay);
+
+        let packets = from_client_rx.collect().wait().unwrap();
+        assert_eq!(packets[1], OutgoingPacket::OobSend(OobSend { destination_pk: pk, data: vec![42; 123] }));
+    }
+
+    #[test]
+    fn send_data_online() {
+        let (relay, from_client_rx, _data_rx) = create_relay();
+        let (pk, _sk) = gen_keypair();
+
+        relay.add_route(pk).wait().unwrap();
+        relay.handle_packet(IncomingPacket::RouteResponse(RouteResponse { connection_id: 42, pk })).wait().unwrap();
+        relay.handle_packet(IncomingPacket::ConnectNotification(ConnectNotification { connection_id: 42 })).wait().unwrap();
+        assert!(relay.is_online(&pk));
+        relay.send_data(pk, vec![42; 123]).wait().unwrap();
+        drop(relay);
+
+        let packets = from_client_rx.collect().wait().unwrap();
+        assert_eq!(packets[1], OutgoingPacket::Data(Data { connection_id: 42, data: vec![42; 123] }));
+    }
+
+    #[test]
+    fn handle_disconnect_notification() {
+        let (relay, _from_client_rx, _data_rx) = create_relay();
+        let (pk, _sk) = gen_keypair();
+
+        relay.add_route(pk).wait().unwrap();
+        relay.handle_packet(IncomingPacket::RouteResponse(RouteResponse { connection_id: 42, pk })).wait().unwrap();
+        relay.handle_packet(IncomingPacket::ConnectNotification(ConnectNotification { connection_id: 42 })).wait().unwrap();
+        relay.handle_packet(IncomingPacket::DisconnectNotification(DisconnectNotification { connection_id: 42 })).wait().unwrap();
+        assert!(!relay.is_online(&pk));
+    }
+
+    #[test]
+    fn handle_route_response_unknown_pk() {
+        let (relay, _from_client_rx, _data_rx) = create_relay();
+        let (pk, _sk) = gen_keypair();
+
+        let packet = IncomingPacket::RouteResponse(RouteResponse { connection_id: 42, pk });
+        assert!(relay.handle_packet(packet).wait().is_err());
+    }
+
+    #[test]
+    fn handle_route_response_refused() {
+        let (relay, from_client_rx, _data_rx) = create_relay();
+        let (pk, _sk) = gen_keypair();
+
+        relay.add_route(pk).wait().unwrap();
+        let packet = IncomingPacket::RouteResponse(RouteResponse { connection_id: 0, pk });
+        relay.handle_packet(packet).wait().unwrap();
+        assert!(relay.routes.read().is_empty());
+
+        // the route can be requested again
+        relay.add_route(pk).wait().unwrap();
+        drop(relay);
+
+        let packets = from_client_rx.collect().wait().unwrap();
+        assert_eq!(packets, vec![
+            OutgoingPacket::RouteRequest(RouteRequest { pk }),
+            OutgoingPacket::RouteRequest(RouteRequest { pk }),
+        ]);
+    }
+
+    #[test]
+    fn handle_oob_receive() {
+        let (relay, _from_client_rx, data_rx) = create_relay();
+        let (pk, _sk) = gen_keypair();
+
+        let packet = IncomingPacket::OobReceive(OobReceive { sender_pk: pk, data: vec![42; 123] });
+        relay.handle_packet(packet).wait().unwrap();
+
+        let (received, _data_rx) = data_rx.into_future().wait().unwrap();
+        assert_eq!(received.unwrap(), (pk, vec![42; 123]));
+    }
+
+    #[test]
+    fn handle_data() {
+        let (relay, _from_client_rx, data_rx) = create_relay();
+        let (pk, _sk) = gen_keypair();
+
+        relay.add_route(pk).wait().unwrap();
+        relay.handle_packet(IncomingPacket::RouteResponse(RouteResponse { connection_id: 42, pk })).wait().unwrap();
+        relay.handle_packet(IncomingPacket::ConnectNotification(ConnectNotification { connection_id: 42 })).wait().unwrap();
+        relay.handle_packet(IncomingPacket::Data(Data { connection_id: 42, data: vec![42; 123] })).wait().unwrap();
+
+        let (received, _data_rx) = data_rx.into_future().wait().unwrap();
+        assert_eq!(received.unwrap(), (pk, vec![42; 123]));
+    }
+
+    #[test]
+    fn handle_data_unknown_connection_id() {
+        let (relay, _from_client_rx, _data_rx) = create_relay();
+
+        let packet = IncomingPacket::Data(Data { connection_id: 42, data: vec![42; 123] });
+        assert!(relay.handle_packet(packet).wait().is_err());
+    }
+
+    #[test]
+    fn send_onion_request() {
+        let (relay, from_client_rx, _data_rx) = create_relay();
+
+        let request = OnionRequest {
+            nonce: gen_nonce(),
+            ip_port: IpPort {
+                protocol: ProtocolType::UDP,
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345,
+            },
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 170]
+        };
+        relay.send_onion_request(request.clone()).wait().unwrap();
+        drop(relay);
+
+        let packets = from_client_rx.collect().wait().unwrap();
+        assert_eq!(packets, vec![OutgoingPacket::OnionRequest(request)]);
+    }
+
+    #[test]
+    fn handle_onion_response() {
+        let (mut relay, _from_client_rx, _data_rx) = create_relay();
+        let (onion_response_tx, onion_response_rx) = mpsc::unbounded();
+        relay.set_onion_response_sink(onion_response_tx);
+
+        let payload = InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
+            sendback_data: 12345,
+            nonce: gen_nonce(),
+            payload: vec![42; 123]
+        });
+        let packet = IncomingPacket::OnionResponse(OnionResponse { payload: payload.clone() });
+        relay.handle_packet(packet).wait().unwrap();
+
+        let (received, _onion_response_rx) = onion_response_rx.into_future().wait().unwrap();
+        assert_eq!(received.unwrap(), payload);
+    }
+
+    #[test]
+    fn handle_onion_response_without_sink() {
+        let (relay, _from_client_rx, _data_rx) = create_relay();
+
+        let payload = InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
+            sendback_data: 12345,
+            nonce: gen_nonce(),
+            payload: vec![42; 123]
+        });
+        let packet = IncomingPacket::OnionResponse(OnionResponse { payload });
+        assert!(relay.handle_packet(packet).wait().is_ok());
+    }
+}
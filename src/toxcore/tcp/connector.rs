@@ -0,0 +1,409 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Connector that opens TCP connections to relays either directly or through
+SOCKS5 and HTTP CONNECT proxies. The stream it returns is connected to the
+relay so the Tox handshake can be made over it as usual.
+*/
+
+use toxcore::io_tokio::IoFuture;
+
+use futures::{Future, future};
+use tokio::io::{read_exact, write_all};
+use tokio::net::TcpStream;
+
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+
+/// SOCKS protocol version.
+const SOCKS5_VERSION: u8 = 0x05;
+/// Version of SOCKS5 username/password authentication.
+const SOCKS5_AUTH_VERSION: u8 = 0x01;
+/// SOCKS5 method that doesn't require authentication.
+const SOCKS5_METHOD_NO_AUTH: u8 = 0x00;
+/// SOCKS5 username/password authentication method.
+const SOCKS5_METHOD_USERNAME_PASSWORD: u8 = 0x02;
+/// SOCKS5 reply to a greeting when none of offered methods is acceptable.
+const SOCKS5_METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+/// SOCKS5 CONNECT command.
+const SOCKS5_CMD_CONNECT: u8 = 0x01;
+/// SOCKS5 address type for IPv4 address.
+const SOCKS5_ATYP_IPV4: u8 = 0x01;
+/// SOCKS5 address type for domain name.
+const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+/// SOCKS5 address type for IPv6 address.
+const SOCKS5_ATYP_IPV6: u8 = 0x04;
+/// SOCKS5 reply code that means success.
+const SOCKS5_REPLY_SUCCEEDED: u8 = 0x00;
+
+/// Maximum length of HTTP response headers we accept from proxy.
+const MAX_HTTP_RESPONSE_SIZE: usize = 4096;
+
+/// Proxy through which connections to relays are opened.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProxyType {
+    /// SOCKS5 proxy with optional username/password authentication
+    Socks5 {
+        /// Address of the proxy
+        addr: SocketAddr,
+        /// Username and password if the proxy requires authentication
+        auth: Option<(String, String)>,
+    },
+    /// HTTP proxy that supports `CONNECT` method
+    Http {
+        /// Address of the proxy
+        addr: SocketAddr,
+    },
+}
+
+/// Open TCP connection to `addr` directly or through the proxy if it's
+/// specified.
+pub fn connect(addr: SocketAddr, proxy: Option<&ProxyType>) -> IoFuture<TcpStream> {
+    match proxy {
+        None => Box::new(TcpStream::connect(&addr)),
+        Some(&ProxyType::Socks5 { addr: proxy_addr, ref auth }) => {
+            let auth = auth.clone();
+            Box::new(TcpStream::connect(&proxy_addr)
+                .and_then(move |stream| socks5_connect(stream, addr, auth)))
+        },
+        Some(&ProxyType::Http { addr: proxy_addr }) => {
+            Box::new(TcpStream::connect(&proxy_addr)
+                .and_then(move |stream| http_connect(stream, addr)))
+        },
+    }
+}
+
+/// Make SOCKS5 handshake over the stream connected to proxy asking it to
+/// connect to `addr`.
+fn socks5_connect(stream: TcpStream, addr: SocketAddr, auth: Option<(String, String)>) -> IoFuture<TcpStream> {
+    let greeting = if auth.is_some() {
+        vec![SOCKS5_VERSION, 2, SOCKS5_METHOD_NO_AUTH, SOCKS5_METHOD_USERNAME_PASSWORD]
+    } else {
+        vec![SOCKS5_VERSION, 1, SOCKS5_METHOD_NO_AUTH]
+    };
+
+    let future = write_all(stream, greeting)
+        .and_then(|(stream, _greeting)| read_exact(stream, [0; 2]))
+        .and_then(move |(stream, reply)| -> IoFuture<TcpStream> {
+            if reply[0] != SOCKS5_VERSION {
+                return Box::new(future::err(
+                    Error::new(ErrorKind::InvalidData, "Invalid SOCKS5 version")
+                ));
+            }
+            match (reply[1], auth) {
+                (SOCKS5_METHOD_NO_AUTH, _) => Box::new(future::ok(stream)),
+                (SOCKS5_METHOD_USERNAME_PASSWORD, Some((username, password))) =>
+                    socks5_authenticate(stream, &username, &password),
+                (SOCKS5_METHOD_NOT_ACCEPTABLE, _) => Box::new(future::err(
+                    Error::new(ErrorKind::PermissionDenied, "SOCKS5 proxy doesn't accept offered methods")
+                )),
+                _ => Box::new(future::err(
+                    Error::new(ErrorKind::InvalidData, "SOCKS5 proxy chose unknown method")
+                )),
+            }
+        })
+        .and_then(move |stream| write_all(stream, socks5_connect_request(addr)))
+        .and_then(|(stream, _request)| read_exact(stream, [0; 4]))
+        .and_then(|(stream, reply)| -> IoFuture<TcpStream> {
+            if reply[0] != SOCKS5_VERSION {
+                return Box::new(future::err(
+                    Error::new(ErrorKind::InvalidData, "Invalid SOCKS5 version")
+                ));
+            }
+            if reply[1] != SOCKS5_REPLY_SUCCEEDED {
+                return Box::new(future::err(
+                    Error::new(ErrorKind::ConnectionRefused, format!("SOCKS5 proxy failed to connect: {}", reply[1]))
+                ));
+            }
+            // skip bound address which we don't need
+            match reply[3] {
+                SOCKS5_ATYP_IPV4 => Box::new(read_exact(stream, vec![0; 4 + 2]).map(|(stream, _)| stream)),
+                SOCKS5_ATYP_IPV6 => Box::new(read_exact(stream, vec![0; 16 + 2]).map(|(stream, _)| stream)),
+                SOCKS5_ATYP_DOMAIN => Box::new(read_exact(stream, [0; 1]).and_then(|(stream, len)|
+                    read_exact(stream, vec![0; len[0] as usize + 2]).map(|(stream, _)| stream)
+                )),
+                _ => Box::new(future::err(
+                    Error::new(ErrorKind::InvalidData, "Invalid SOCKS5 address type")
+                )),
+            }
+        });
+    Box::new(future)
+}
+
+/// Authenticate with username and password on SOCKS5 proxy.
+fn socks5_authenticate(stream: TcpStream, username: &str, password: &str) -> IoFuture<TcpStream> {
+    if username.len() > 255 || password.len() > 255 {
+        return Box::new(future::err(
+            Error::new(ErrorKind::InvalidInput, "SOCKS5 username and password should be not longer than 255 bytes")
+        ));
+    }
+
+    let mut request = Vec::with_capacity(3 + username.len() + password.len());
+    request.push(SOCKS5_AUTH_VERSION);
+    request.push(username.len() as u8);
+    request.extend_from_slice(username.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+
+    Box::new(write_all(stream, request)
+        .and_then(|(stream, _request)| read_exact(stream, [0; 2]))
+        .and_then(|(stream, reply)| if reply[0] != SOCKS5_AUTH_VERSION {
+            Err(Error::new(ErrorKind::InvalidData, "Invalid SOCKS5 authentication version"))
+        } else if reply[1] == 0 {
+            Ok(stream)
+        } else {
+            Err(Error::new(ErrorKind::PermissionDenied, "SOCKS5 authentication failed"))
+        }))
+}
+
+/// Serialize SOCKS5 request to connect to `addr`.
+fn socks5_connect_request(addr: SocketAddr) -> Vec<u8> {
+    let mut request = vec![SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            request.push(SOCKS5_ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        },
+        IpAddr::V6(ip) => {
+            request.push(SOCKS5_ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        },
+    }
+    request.push((addr.port() >> 8) as u8);
+    request.push(addr.port() as u8);
+    request
+}
+
+/// Ask HTTP proxy to connect to `addr` with `CONNECT` method over the stream
+/// connected to the proxy.
+fn http_connect(stream: TcpStream, addr: SocketAddr) -> IoFuture<TcpStream> {
+    let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", addr);
+    let future = write_all(stream, request.into_bytes())
+        .and_then(|(stream, _request)| read_http_response(stream))
+        .and_then(|(stream, response)| {
+            let status = response.lines().next()
+                .and_then(|line| line.split_whitespace().nth(1))
+                .map(|code| code.to_owned());
+            match status {
+                Some(ref code) if code.starts_with('2') => Ok(stream),
+                Some(code) => Err(Error::new(ErrorKind::ConnectionRefused,
+                    format!("HTTP proxy failed to connect: {}", code))),
+                None => Err(Error::new(ErrorKind::InvalidData, "Invalid HTTP proxy response")),
+            }
+        });
+    Box::new(future)
+}
+
+/// Read HTTP response headers. Data is read byte by byte so that nothing
+/// after the headers is consumed.
+fn read_http_response(stream: TcpStream) -> IoFuture<(TcpStream, String)> {
+    let future = future::loop_fn((stream, Vec::new()), |(stream, mut response)|
+        read_exact(stream, [0; 1]).and_then(move |(stream, byte)| {
+            response.push(byte[0]);
+            if response.ends_with(b"\r\n\r\n") {
+                Ok(future::Loop::Break((stream, response)))
+            } else if response.len() >= MAX_HTTP_RESPONSE_SIZE {
+                Err(Error::new(ErrorKind::InvalidData, "HTTP proxy response is too long"))
+            } else {
+                Ok(future::Loop::Continue((stream, response)))
+            }
+        })
+    ).and_then(|(stream, response)| String::from_utf8(response)
+        .map(|response| (stream, response))
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    );
+    Box::new(future)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::TcpListener;
+    use tokio::runtime::Runtime;
+    use futures::Stream;
+
+    /// Run the proxy stand-in that accepts one connection and handles it
+    /// with `handler`. Returns address of the proxy and the future that
+    /// should be run.
+    fn proxy<F, R>(handler: F) -> (SocketAddr, IoFuture<()>)
+        where F: FnOnce(TcpStream) -> R + Send + 'static,
+              R: Future<Item=(), Error=Error> + Send + 'static
+    {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let future = listener.incoming()
+            .into_future()
+            .map_err(|(e, _incoming)| e)
+            .and_then(|(stream, _incoming)| handler(stream.unwrap()));
+        (addr, Box::new(future))
+    }
+
+    /// Connect through the proxy and read greeting sent by the target.
+    fn connect_and_read(target: SocketAddr, proxy: ProxyType, proxy_future: IoFuture<()>) -> Result<Vec<u8>, Error> {
+        let client = connect(target, Some(&proxy))
+            .and_then(|stream| read_exact(stream, vec![0; 5]))
+            .map(|(_stream, data)| data);
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(client.join(proxy_future).map(|(data, ())| data))
+    }
+
+    #[test]
+    fn socks5_connect_request_ipv4() {
+        let request = socks5_connect_request("1.2.3.4:33445".parse().unwrap());
+        assert_eq!(request, vec![5, 1, 0, 1, 1, 2, 3, 4, 0x82, 0xa5]);
+    }
+
+    #[test]
+    fn socks5_connect_request_ipv6() {
+        let request = socks5_connect_request("[::1]:33445".parse().unwrap());
+        let mut expected = vec![5, 1, 0, 4];
+        expected.extend_from_slice(&[0; 15]);
+        expected.extend_from_slice(&[1, 0x82, 0xa5]);
+        assert_eq!(request, expected);
+    }
+
+    #[test]
+    fn socks5_no_auth() {
+        let target: SocketAddr = "1.2.3.4:33445".parse().unwrap();
+        let (addr, proxy_future) = proxy(move |stream|
+            read_exact(stream, [0; 3])
+                .and_then(|(stream, greeting)| {
+                    assert_eq!(greeting, [5, 1, 0]);
+                    write_all(stream, [5, 0])
+                })
+                .and_then(|(stream, _)| read_exact(stream, [0; 10]))
+                .and_then(move |(stream, request)| {
+                    assert_eq!(request.to_vec(), socks5_connect_request(target));
+                    write_all(stream, [5, 0, 0, 1, 127, 0, 0, 1, 0, 42])
+                })
+                .and_then(|(stream, _)| write_all(stream, *b"hello"))
+                .map(|_| ())
+        );
+
+        let proxy = ProxyType::Socks5 { addr, auth: None };
+        assert_eq!(connect_and_read(target, proxy, proxy_future).unwrap(), b"hello".to_vec());
+    }
+
+    #[test]
+    fn socks5_username_password() {
+        let target: SocketAddr = "1.2.3.4:33445".parse().unwrap();
+        let (addr, proxy_future) = proxy(move |stream|
+            read_exact(stream, [0; 4])
+                .and_then(|(stream, greeting)| {
+                    assert_eq!(greeting, [5, 2, 0, 2]);
+                    write_all(stream, [5, 2])
+                })
+                .and_then(|(stream, _)| read_exact(stream, [0; 11]))
+                .and_then(|(stream, auth)| {
+                    assert_eq!(&auth, b"\x01\x04user\x04pass");
+                    write_all(stream, [1, 0])
+                })
+                .and_then(|(stream, _)| read_exact(stream, [0; 10]))
+                .and_then(|(stream, _request)| {
+                    // bound address is a domain name
+                    write_all(stream, *b"\x05\x00\x00\x03\x05proxy\x00\x2a")
+                })
+                .and_then(|(stream, _)| write_all(stream, *b"hello"))
+                .map(|_| ())
+        );
+
+        let proxy = ProxyType::Socks5 { addr, auth: Some(("user".to_owned(), "pass".to_owned())) };
+        assert_eq!(connect_and_read(target, proxy, proxy_future).unwrap(), b"hello".to_vec());
+    }
+
+    #[test]
+    fn socks5_authentication_failed() {
+        let target: SocketAddr = "1.2.3.4:33445".parse().unwrap();
+        let (addr, proxy_future) = proxy(move |stream|
+            read_exact(stream, [0; 4])
+                .and_then(|(stream, _greeting)| write_all(stream, [5, 2]))
+                .and_then(|(stream, _)| read_exact(stream, [0; 11]))
+                .and_then(|(stream, _auth)| write_all(stream, [1, 1]))
+                .map(|_| ())
+        );
+
+        let proxy = ProxyType::Socks5 { addr, auth: Some(("user".to_owned(), "pass".to_owned())) };
+        let error = connect_and_read(target, proxy, proxy_future).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn socks5_invalid_authentication_version() {
+        let target: SocketAddr = "1.2.3.4:33445".parse().unwrap();
+        let (addr, proxy_future) = proxy(move |stream|
+            read_exact(stream, [0; 4])
+                .and_then(|(stream, _greeting)| write_all(stream, [5, 2]))
+                .and_then(|(stream, _)| read_exact(stream, [0; 11]))
+                .and_then(|(stream, _auth)| write_all(stream, [5, 0]))
+                .map(|_| ())
+        );
+
+        let proxy = ProxyType::Socks5 { addr, auth: Some(("user".to_owned(), "pass".to_owned())) };
+        let error = connect_and_read(target, proxy, proxy_future).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn socks5_connection_refused() {
+        let target: SocketAddr = "1.2.3.4:33445".parse().unwrap();
+        let (addr, proxy_future) = proxy(move |stream|
+            read_exact(stream, [0; 3])
+                .and_then(|(stream, _greeting)| write_all(stream, [5, 0]))
+                .and_then(|(stream, _)| read_exact(stream, [0; 10]))
+                .and_then(|(stream, _request)| write_all(stream, [5, 5, 0, 1, 0, 0, 0, 0, 0, 0]))
+                .map(|_| ())
+        );
+
+        let proxy = ProxyType::Socks5 { addr, auth: None };
+        let error = connect_and_read(target, proxy, proxy_future).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn http_connect_ok() {
+        let target: SocketAddr = "1.2.3.4:33445".parse().unwrap();
+        let (addr, proxy_future) = proxy(move |stream|
+            read_http_response(stream)
+                .and_then(|(stream, request)| {
+                    assert_eq!(request, "CONNECT 1.2.3.4:33445 HTTP/1.1\r\nHost: 1.2.3.4:33445\r\n\r\n");
+                    write_all(stream, b"HTTP/1.1 200 Connection established\r\n\r\nhello".to_vec())
+                })
+                .map(|_| ())
+        );
+
+        let proxy = ProxyType::Http { addr };
+        assert_eq!(connect_and_read(target, proxy, proxy_future).unwrap(), b"hello".to_vec());
+    }
+
+    #[test]
+    fn http_connect_forbidden() {
+        let target: SocketAddr = "1.2.3.4:33445".parse().unwrap();
+        let (addr, proxy_future) = proxy(move |stream|
+            read_http_response(stream)
+                .and_then(|(stream, _request)| write_all(stream, b"HTTP/1.1 403 Forbidden\r\n\r\n".to_vec()))
+                .map(|_| ())
+        );
+
+        let proxy = ProxyType::Http { addr };
+        let error = connect_and_read(target, proxy, proxy_future).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+    }
+}
@@ -26,9 +26,18 @@ pub mod handshake;
 pub mod secure;
 pub mod packet;
 pub mod codec;
+pub mod connector;
 pub mod server;
 pub mod client;
 
+/// Interval in seconds between `PingRequest`s sent by both client and server
+/// to check that the other side is still alive.
+pub const TCP_PING_FREQUENCY: u64 = 30;
+
+/// Timeout in seconds for `PongResponse`. When it's exceeded the other side
+/// is considered dead and the connection is closed.
+pub const TCP_PING_TIMEOUT: u64 = 10;
+
 #[cfg(test)]
 mod tests {
     use toxcore::crypto_core::*;
@@ -50,6 +59,7 @@ mod tests {
             to_client_rx,
             from_server_tx,
             to_server_rx,
+            connection: _connection,
             processor
         } = ClientProcessor::new();
         let client_processor = processor;
@@ -0,0 +1,82 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Allowlist of clients for private TCP relays.
+*/
+
+use toxcore::crypto_core::*;
+
+use std::collections::HashSet;
+
+/** Clients allowed to use a private TCP relay. Clients are identified by their
+long-term `PublicKey` learned during the handshake.
+*/
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Allowlist {
+    /// `PublicKey`s of allowed clients
+    pub keys: HashSet<PublicKey>,
+    /// Whether clients can link only with other allowed clients by
+    /// `RouteRequest`
+    pub restrict_routes: bool,
+}
+
+impl Allowlist {
+    /// Create new `Allowlist` with the given keys that doesn't restrict
+    /// routes.
+    pub fn new(keys: HashSet<PublicKey>) -> Allowlist {
+        Allowlist {
+            keys,
+            restrict_routes: false,
+        }
+    }
+
+    /// Check if the client is allowed to connect to the relay.
+    pub fn is_allowed(&self, pk: &PublicKey) -> bool {
+        self.keys.contains(pk)
+    }
+
+    /// Check if the client is allowed to link with the given `PublicKey`.
+    pub fn is_route_allowed(&self, pk: &PublicKey) -> bool {
+        !self.restrict_routes || self.is_allowed(pk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_allowed() {
+        let pk = gen_keypair().0;
+        let allowlist = Allowlist::new(vec![pk].into_iter().collect());
+        assert!(allowlist.is_allowed(&pk));
+        assert!(!allowlist.is_allowed(&gen_keypair().0));
+    }
+
+    #[test]
+    fn is_route_allowed() {
+        let pk = gen_keypair().0;
+        let mut allowlist = Allowlist::new(vec![pk].into_iter().collect());
+        assert!(allowlist.is_route_allowed(&gen_keypair().0));
+        allowlist.restrict_routes = true;
+        assert!(allowlist.is_route_allowed(&pk));
+        assert!(!allowlist.is_route_allowed(&gen_keypair().0));
+    }
+}
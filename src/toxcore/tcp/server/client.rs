@@ -23,14 +23,123 @@
 
 use toxcore::crypto_core::*;
 use toxcore::tcp::packet::*;
+use toxcore::tcp::server::limits::{ClientLimiter, Limits};
+use toxcore::tcp::{TCP_PING_FREQUENCY, TCP_PING_TIMEOUT};
 use toxcore::io_tokio::*;
 use toxcore::onion::packet::InnerOnionResponse;
+use toxcore::time::*;
 
 use std::net::IpAddr;
 use std::slice::Iter;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use futures::Future;
+use futures::{Async, Future, Poll, Stream, future};
 use futures::sync::mpsc;
+use parking_lot::Mutex;
+
+/// Default maximum number of packets waiting to be sent to a client.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+/// What to do when the queue of packets waiting to be sent to a client is
+/// full.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// Drop `Data` and `OobReceive` packets to the client since they can be
+    /// resent by the other side. The client is disconnected if any other
+    /// packet doesn't fit into the queue.
+    DropData,
+    /// Disconnect the client.
+    Disconnect,
+}
+
+/// Limits of the queue of packets waiting to be sent to a client.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct QueueConfig {
+    /// Maximum number of packets in the queue.
+    pub capacity: usize,
+    /// What to do when the queue is full.
+    pub overflow_policy: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            capacity: DEFAULT_QUEUE_CAPACITY,
+            overflow_policy: OverflowPolicy::DropData,
+        }
+    }
+}
+
+/// Statistics of the queue of packets waiting to be sent to a client.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct QueueStats {
+    /// Number of packets in the queue.
+    pub len: usize,
+    /// Number of packets dropped because the queue was full.
+    pub dropped: usize,
+}
+
+/// State of the client's queue shared between `Client` and `ClientRx`.
+#[derive(Default)]
+struct QueueState {
+    /// Number of packets in the queue.
+    len: AtomicUsize,
+    /// Number of packets dropped because the queue was full.
+    dropped: AtomicUsize,
+    /// Whether the client should be disconnected because of queue overflow.
+    overflowed: AtomicBool,
+}
+
+/// Queue of the client with its limits.
+struct Queue {
+    config: QueueConfig,
+    state: Arc<QueueState>,
+}
+
+impl Queue {
+    /// Reserve a slot for the packet in the queue. Returns `false` if the
+    /// queue is full. The check and the increment are done atomically so that
+    /// concurrent senders can't exceed the capacity.
+    fn try_reserve(&self) -> bool {
+        let mut len = self.state.len.load(Ordering::SeqCst);
+        loop {
+            if len >= self.config.capacity {
+                return false;
+            }
+            match self.state.len.compare_exchange(len, len + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return true,
+                Err(actual) => len = actual,
+            }
+        }
+    }
+}
+
+/** Receiving end of the client's bounded queue created by
+`Client::new_with_queue`. It counts packets taken from the queue and ends with
+error when the client should be disconnected because of queue overflow.
+*/
+pub struct ClientRx {
+    rx: mpsc::UnboundedReceiver<Packet>,
+    state: Arc<QueueState>,
+}
+
+impl Stream for ClientRx {
+    type Item = Packet;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Packet>, ()> {
+        if self.state.overflowed.load(Ordering::SeqCst) {
+            return Err(());
+        }
+        let packet = self.rx.poll()?;
+        if let Async::Ready(Some(_)) = packet {
+            self.state.len.fetch_sub(1, Ordering::SeqCst);
+        }
+        Ok(packet)
+    }
+}
 
 /** Structure that represents how Server keeps connected clients. A write-only socket with
 human interface. A client cannot send a message directly to another client, whereas server can.
@@ -55,8 +164,19 @@ pub struct Client {
     inside this module.
     */
     links: [Option<PublicKey>; 240],
-    /// Used to check whether PongResponse is correct
-    ping_id: u64
+    /// Used to check whether PongResponse is correct. 0 means that there is
+    /// no unanswered PingRequest
+    ping_id: u64,
+    /// Time when the last PingRequest was sent to the client
+    last_pinged: Instant,
+    /// Time when the last correct PongResponse was received from the client
+    last_pong_resp: Instant,
+    /// Limits of the outgoing queue. `None` means that the queue is unbounded
+    queue: Option<Queue>,
+    /// Rate limiter of packets from the client. `None` means that the client
+    /// is not limited. It's behind the mutex so that packets can be checked
+    /// without exclusive access to the client
+    limiter: Option<Mutex<ClientLimiter>>,
 }
 
 impl Client {
@@ -69,10 +189,28 @@ impl Client {
             port,
             tx,
             links: [None; 240],
-            ping_id: 0
+            ping_id: 0,
+            last_pinged: clock_now(),
+            last_pong_resp: clock_now(),
+            queue: None,
+            limiter: None,
         }
     }
 
+    /** Create new Client with bounded outgoing queue. Packets sent to the
+    client should be read from the returned `ClientRx`.
+    */
+    pub fn new_with_queue(pk: &PublicKey, ip_addr: IpAddr, port: u16, config: QueueConfig) -> (Client, ClientRx) {
+        let (tx, rx) = mpsc::unbounded();
+        let state = Arc::new(QueueState::default());
+        let mut client = Client::new(tx, pk, ip_addr, port);
+        client.queue = Some(Queue {
+            config,
+            state: state.clone(),
+        });
+        (client, ClientRx { rx, state })
+    }
+
     /** PK of the `Client`
     */
     pub fn pk(&self) -> PublicKey {
@@ -97,6 +235,32 @@ impl Client {
         self.ping_id
     }
 
+    /** Time when the last correct PongResponse was received from the client
+    */
+    pub fn last_pong_resp(&self) -> Instant {
+        self.last_pong_resp
+    }
+
+    /** Mark the sent PingRequest as answered. Should be called when the
+    client sends PongResponse with correct ping_id.
+    */
+    pub fn set_pong_received(&mut self) {
+        self.ping_id = 0;
+        self.last_pong_resp = clock_now();
+    }
+
+    /** Check if the client didn't answer the last PingRequest in time
+    */
+    pub fn is_pong_timedout(&self) -> bool {
+        self.ping_id != 0 && clock_elapsed(self.last_pinged) >= Duration::from_secs(TCP_PING_TIMEOUT)
+    }
+
+    /** Check if it's time to send the next PingRequest to the client
+    */
+    pub fn is_ping_interval_passed(&self) -> bool {
+        self.ping_id == 0 && clock_elapsed(self.last_pinged) >= Duration::from_secs(TCP_PING_FREQUENCY)
+    }
+
     /** Return index of of the link by PK
 
     Some(index + 16) if link exists
@@ -147,9 +311,60 @@ impl Client {
         self.links.iter()
     }
 
-    /** This is actually the sender method
+    /** Limit rate of packets from the client. Counters of the previous
+    limits are reset
+    */
+    pub fn set_limits(&mut self, limits: &Limits) {
+        self.limiter = Some(Mutex::new(ClientLimiter::new(limits)));
+    }
+
+    /** Check whether the client is allowed to send the packet now according
+    to its rate limits
+    */
+    pub fn check_limits(&self, packet: &Packet) -> bool {
+        match self.limiter {
+            Some(ref limiter) => limiter.lock().check(packet),
+            None => true,
+        }
+    }
+
+    /** Number of packets from the client that exceeded rate limits
+    */
+    pub fn violations(&self) -> usize {
+        self.limiter.as_ref().map_or(0, |limiter| limiter.lock().violations())
+    }
+
+    /** Statistics of the outgoing queue. Always empty for unbounded queue
+    */
+    pub fn queue_stats(&self) -> QueueStats {
+        self.queue.as_ref().map_or_else(QueueStats::default, |queue| QueueStats {
+            len: queue.state.len.load(Ordering::SeqCst),
+            dropped: queue.state.dropped.load(Ordering::SeqCst),
+        })
+    }
+
+    /** This is actually the sender method. When the bounded queue is full
+    the packet is dropped and the client may be marked for disconnection
+    according to the overflow policy
     */
     fn send_impl(&self, packet: Packet) -> IoFuture<()> {
+        if let Some(ref queue) = self.queue {
+            if !queue.try_reserve() {
+                queue.state.dropped.fetch_add(1, Ordering::SeqCst);
+                // only packets with friends' data can be dropped
+                let droppable = match packet {
+                    Packet::Data(_) | Packet::OobReceive(_) => true,
+                    _ => false,
+                };
+                if queue.config.overflow_policy == OverflowPolicy::Disconnect || !droppable {
+                    debug!("Queue of client {:?} is full, disconnecting", self.pk);
+                    queue.state.overflowed.store(true, Ordering::SeqCst);
+                    // wake up the receiving end so it sees the overflow
+                    self.tx.unbounded_send(packet).ok();
+                }
+                return Box::new(future::ok(()))
+            }
+        }
         send_to(&self.tx, packet)
     }
     /** Send a packet. This method does not ignore IO error
@@ -185,6 +400,23 @@ impl Client {
             Packet::DisconnectNotification(DisconnectNotification { connection_id })
         )
     }
+    /** Construct PingRequest with a random ping_id, remember it to check
+    PongResponse and send the request to Client
+    */
+    pub fn send_ping_request(&mut self) -> IoFuture<()> {
+        // ping_id 0 is reserved
+        let ping_id = loop {
+            let ping_id = random_u64();
+            if ping_id != 0 {
+                break ping_id
+            }
+        };
+        self.ping_id = ping_id;
+        self.last_pinged = clock_now();
+        self.send(
+            Packet::PingRequest(PingRequest { ping_id })
+        )
+    }
     /** Construct PongResponse and send it to Client
     */
     pub fn send_pong_response(&self, ping_id: u64) -> IoFuture<()> {
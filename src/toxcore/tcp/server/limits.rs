@@ -0,0 +1,250 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Limits that protect TCP relay from clients that flood it with packets or
+connections.
+*/
+
+use toxcore::tcp::packet::Packet;
+use toxcore::time::*;
+
+use std::time::Instant;
+
+/// Default rate limit of `OobSend` packets.
+pub const DEFAULT_OOB_SEND_LIMIT: RateLimit = RateLimit { rate: 10, burst: 20 };
+/// Default rate limit of `RouteRequest` packets. Burst is big enough to fill
+/// all 240 links right after connection.
+pub const DEFAULT_ROUTE_REQUEST_LIMIT: RateLimit = RateLimit { rate: 10, burst: 256 };
+/// Default rate limit of `OnionRequest` packets.
+pub const DEFAULT_ONION_REQUEST_LIMIT: RateLimit = RateLimit { rate: 20, burst: 50 };
+/// Default rate limit of `Data` packets.
+pub const DEFAULT_DATA_LIMIT: RateLimit = RateLimit { rate: 1000, burst: 1000 };
+/// Default maximum number of simultaneously connected clients from one IP
+/// address.
+pub const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 32;
+/// Default number of rate limit violations after which the client is
+/// disconnected.
+pub const DEFAULT_MAX_VIOLATIONS: usize = 100;
+
+/// Rate of packets of one kind a client is allowed to send.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RateLimit {
+    /// Number of packets per second.
+    pub rate: u32,
+    /// Maximum number of packets that can be sent at once.
+    pub burst: u32,
+}
+
+/// Limits applied by TCP relay to its clients.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Limits {
+    /// Rate limit of `OobSend` packets.
+    pub oob_send: RateLimit,
+    /// Rate limit of `RouteRequest` packets.
+    pub route_request: RateLimit,
+    /// Rate limit of `OnionRequest` packets.
+    pub onion_request: RateLimit,
+    /// Rate limit of `Data` packets.
+    pub data: RateLimit,
+    /// Maximum number of simultaneously connected clients from one IP
+    /// address.
+    pub max_connections_per_ip: usize,
+    /// Number of rate limit violations after which the client is
+    /// disconnected. `None` means that clients are never disconnected and
+    /// packets exceeding limits are just dropped.
+    pub max_violations: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            oob_send: DEFAULT_OOB_SEND_LIMIT,
+            route_request: DEFAULT_ROUTE_REQUEST_LIMIT,
+            onion_request: DEFAULT_ONION_REQUEST_LIMIT,
+            data: DEFAULT_DATA_LIMIT,
+            max_connections_per_ip: DEFAULT_MAX_CONNECTIONS_PER_IP,
+            max_violations: Some(DEFAULT_MAX_VIOLATIONS),
+        }
+    }
+}
+
+/** Token bucket that allows `burst` packets at once and then `rate` packets
+per second on average.
+*/
+#[derive(Clone, Debug)]
+struct TokenBucket {
+    /// Limit of the bucket
+    limit: RateLimit,
+    /// Number of tokens currently in the bucket
+    tokens: f64,
+    /// Time when the bucket was refilled last time
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create new full `TokenBucket`.
+    fn new(limit: RateLimit) -> TokenBucket {
+        TokenBucket {
+            limit,
+            tokens: f64::from(limit.burst),
+            last_refill: clock_now(),
+        }
+    }
+
+    /// Take one token from the bucket. Returns `false` if the bucket is empty.
+    fn try_take(&mut self) -> bool {
+        let now = clock_now();
+        let elapsed = now - self.last_refill;
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0;
+        self.tokens = (self.tokens + elapsed * f64::from(self.limit.rate)).min(f64::from(self.limit.burst));
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/** Rate limiter of a single client. It has a token bucket for each kind of
+rate limited packets and counts packets that exceeded limits.
+*/
+#[derive(Clone, Debug)]
+pub struct ClientLimiter {
+    oob_send: TokenBucket,
+    route_request: TokenBucket,
+    onion_request: TokenBucket,
+    data: TokenBucket,
+    /// Number of packets that exceeded limits
+    violations: usize,
+}
+
+impl ClientLimiter {
+    /// Create new `ClientLimiter` with full buckets.
+    pub fn new(limits: &Limits) -> ClientLimiter {
+        ClientLimiter {
+            oob_send: TokenBucket::new(limits.oob_send),
+            route_request: TokenBucket::new(limits.route_request),
+            onion_request: TokenBucket::new(limits.onion_request),
+            data: TokenBucket::new(limits.data),
+            violations: 0,
+        }
+    }
+
+    /// Check whether the client is allowed to send the packet now. Packets
+    /// that are not rate limited are always allowed. Denied packets are
+    /// counted as violations.
+    pub fn check(&mut self, packet: &Packet) -> bool {
+        let allowed = match *packet {
+            Packet::OobSend(_) => self.oob_send.try_take(),
+            Packet::RouteRequest(_) => self.route_request.try_take(),
+            Packet::OnionRequest(_) => self.onion_request.try_take(),
+            Packet::Data(_) => self.data.try_take(),
+            _ => true,
+        };
+        if !allowed {
+            self.violations += 1;
+        }
+        allowed
+    }
+
+    /// Number of packets that exceeded limits.
+    pub fn violations(&self) -> usize {
+        self.violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use toxcore::crypto_core::*;
+    use toxcore::tcp::packet::*;
+
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn limits_default() {
+        let limits = Limits::default();
+        assert_eq!(limits.oob_send, DEFAULT_OOB_SEND_LIMIT);
+        assert_eq!(limits.route_request, DEFAULT_ROUTE_REQUEST_LIMIT);
+        assert_eq!(limits.onion_request, DEFAULT_ONION_REQUEST_LIMIT);
+        assert_eq!(limits.data, DEFAULT_DATA_LIMIT);
+        assert_eq!(limits.max_connections_per_ip, DEFAULT_MAX_CONNECTIONS_PER_IP);
+        assert_eq!(limits.max_violations, Some(DEFAULT_MAX_VIOLATIONS));
+    }
+
+    #[test]
+    fn token_bucket_refill() {
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+        let mut bucket = with_default(&clock, &mut enter, |_| {
+            let mut bucket = TokenBucket::new(RateLimit { rate: 2, burst: 3 });
+            for _ in 0 .. 3 {
+                assert!(bucket.try_take());
+            }
+            assert!(!bucket.try_take());
+            bucket
+        });
+
+        // one token is added in half a second
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_millis(500)));
+        with_default(&clock, &mut enter, |_| {
+            assert!(bucket.try_take());
+            assert!(!bucket.try_take());
+        });
+
+        // bucket is not filled over burst
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(100)));
+        with_default(&clock, &mut enter, |_| {
+            for _ in 0 .. 3 {
+                assert!(bucket.try_take());
+            }
+            assert!(!bucket.try_take());
+        });
+    }
+
+    #[test]
+    fn client_limiter_check() {
+        let limits = Limits {
+            data: RateLimit { rate: 1, burst: 1 },
+            .. Limits::default()
+        };
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+        with_default(&clock, &mut enter, |_| {
+            let mut limiter = ClientLimiter::new(&limits);
+            let data = Packet::Data(Data { connection_id: 16, data: vec![42; 42] });
+            assert!(limiter.check(&data));
+            assert!(!limiter.check(&data));
+            assert!(!limiter.check(&data));
+            // other packets are not affected
+            assert!(limiter.check(&Packet::PingRequest(PingRequest { ping_id: 42 })));
+            assert!(limiter.check(&Packet::RouteRequest(RouteRequest { pk: gen_keypair().0 })));
+            assert_eq!(limiter.violations(), 2);
+        });
+    }
+}
@@ -0,0 +1,277 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! The implementation of TCP relay listener that accepts connections from
+clients and serves them with the `Server`.
+*/
+
+use toxcore::crypto_core::*;
+use toxcore::io_tokio::IoFuture;
+use toxcore::tcp::codec::Codec;
+use toxcore::tcp::handshake::make_server_handshake;
+use toxcore::tcp::server::{Server, ServerProcessor, QueueConfig};
+use toxcore::time::*;
+
+use futures::{Future, Sink, Stream, future};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::Interval;
+use tokio::util::FutureExt;
+use tokio_codec::Framed;
+
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+/// Interval in seconds for checking whether clients should be pinged.
+const PING_INTERVAL: u64 = 1;
+
+/// Default timeout in seconds for handshake with a client.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: u64 = 10;
+
+/// Default timeout in seconds for sending a packet to a client.
+pub const DEFAULT_WRITE_TIMEOUT: u64 = 30;
+
+/// Default maximum number of simultaneously served connections.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 4096;
+
+/// Settings of the TCP relay listener.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ServerConfig {
+    /// Timeout for handshake with a client. Clients that don't finish the
+    /// handshake in time are disconnected.
+    pub handshake_timeout: Duration,
+    /// Timeout for sending a packet to a client. Clients that don't read
+    /// packets in time are disconnected.
+    pub write_timeout: Duration,
+    /// Maximum number of simultaneously served connections. New connections
+    /// are not accepted until some of the current ones are closed.
+    pub max_connections: usize,
+    /// Limits of the queue of packets waiting to be sent to each client.
+    pub queue: QueueConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            handshake_timeout: Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT),
+            write_timeout: Duration::from_secs(DEFAULT_WRITE_TIMEOUT),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            queue: QueueConfig::default(),
+        }
+    }
+}
+
+/** Run TCP relay on the given listener. For each accepted connection it makes
+the handshake, registers the client in the `Server` and transfers packets
+between the client and the `Server` until the connection is closed. Clients
+are periodically pinged so that dead connections are detected.
+
+All connections are served inside the returned future so dropping it closes
+them without removing clients from the `Server`. The future is resolved with
+error if the listener fails.
+*/
+pub fn run(listener: TcpListener, server_sk: SecretKey, server: Server, config: ServerConfig) -> IoFuture<()> {
+    let server_c = server.clone();
+    let connections = listener.incoming()
+        .map(move |stream|
+            process_connection(stream, server_sk.clone(), server_c.clone(), config)
+                .then(|res| -> Result<(), Error> {
+                    if let Err(e) = res {
+                        debug!("Connection ended with error: {:?}", e);
+                    }
+                    Ok(())
+                })
+        )
+        .buffer_unordered(config.max_connections)
+        .for_each(|()| Ok(()));
+
+    let pings = Interval::new(clock_now(), Duration::from_secs(PING_INTERVAL))
+        .map_err(|e| Error::new(ErrorKind::Other, format!("Ping timer error: {:?}", e)))
+        .for_each(move |_instant| server.send_pings());
+
+    Box::new(connections.join(pings).map(|_| ()))
+}
+
+/// Make handshake with the client and transfer packets between it and the
+/// `Server` until the connection is closed. Then the client is removed from
+/// the `Server`.
+fn process_connection(stream: TcpStream, server_sk: SecretKey, server: Server, config: ServerConfig) -> IoFuture<()> {
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    debug!("A new client connected from {}", addr);
+
+    let handshake = make_server_handshake(stream, server_sk)
+        .timeout(config.handshake_timeout)
+        .map_err(|e| Error::new(ErrorKind::Other, format!("Handshake error: {:?}", e)));
+
+    Box::new(handshake.and_then(move |(stream, channel, client_pk)| {
+        debug!("Handshake for client {:?} completed", client_pk);
+
+        let secure_socket = Framed::new(stream, Codec::new(channel));
+        let (to_client, from_client) = secure_socket.split();
+        let server_c = server.clone();
+        let ServerProcessor { from_client_tx, to_client_rx, processor } =
+            ServerProcessor::create_with_queue(server, client_pk, addr.ip(), addr.port(), config.queue);
+
+        // writer = for each Packet from to_client_rx send it to client
+        let write_timeout = config.write_timeout;
+        let writer = to_client_rx
+            .map_err(|()| Error::from(ErrorKind::UnexpectedEof))
+            .fold(to_client, move |to_client, packet| {
+                debug!("Send {:?} => {:?}", client_pk, packet);
+                to_client.send(packet)
+                    .timeout(write_timeout)
+                    .map_err(|e| Error::new(ErrorKind::Other, format!("Writer error: {:?}", e)))
+            })
+            // drop to_client when to_client_rx stream is exhausted
+            .map(|_to_client| ());
+
+        // reader = for each Packet from client send it to server processor
+        let reader = from_client
+            .forward(from_client_tx
+                .sink_map_err(|e| Error::new(ErrorKind::Other,
+                    format!("Could not forward message from client to server {:?}", e)))
+            )
+            .map(|(_from_client, _from_client_tx)| ());
+
+        processor
+            .select(reader)
+            .map(|_| ())
+            .map_err(|(err, _select_next)| err)
+            .select(writer)
+            .map(|_| ())
+            .map_err(|(err, _select_next)| err)
+            .then(move |res| {
+                // processor doesn't shut down the client when the reader or
+                // the writer ends first, otherwise the client is removed already
+                server_c.shutdown_client(&client_pk).then(|_| res)
+            })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio;
+
+    use toxcore::tcp::handshake::make_client_handshake;
+    use toxcore::tcp::packet::*;
+
+    #[test]
+    fn server_config_default() {
+        let config = ServerConfig::default();
+        assert_eq!(config.handshake_timeout, Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT));
+        assert_eq!(config.write_timeout, Duration::from_secs(DEFAULT_WRITE_TIMEOUT));
+        assert_eq!(config.max_connections, DEFAULT_MAX_CONNECTIONS);
+        assert_eq!(config.queue, QueueConfig::default());
+    }
+
+    #[test]
+    fn run_ping_pong() {
+        let (client_pk, client_sk) = gen_keypair();
+        let (server_pk, server_sk) = gen_keypair();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = run(listener, server_sk, Server::new(), ServerConfig::default());
+
+        let client = TcpStream::connect(&addr)
+            .and_then(move |stream| make_client_handshake(stream, client_pk, client_sk, server_pk))
+            .and_then(|(stream, channel)| {
+                let secure_socket = Framed::new(stream, Codec::new(channel));
+                secure_socket.send(Packet::PingRequest(PingRequest { ping_id: 42 }))
+            })
+            .and_then(|secure_socket| secure_socket.into_future().map_err(|(e, _secure_socket)| e))
+            .map(|(packet, _secure_socket)| {
+                assert_eq!(packet.unwrap(), Packet::PongResponse(PongResponse { ping_id: 42 }));
+            });
+
+        let test = client.select(server)
+            .map(|_| ())
+            .map_err(|(e, _select_next)| panic!("Error: {:?}", e));
+
+        tokio::run(test);
+    }
+
+    #[test]
+    fn run_shutdown_disconnected_client() {
+        let (client_pk, client_sk) = gen_keypair();
+        let (server_pk, server_sk) = gen_keypair();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new();
+        let server_c = server.clone();
+        let relay = run(listener, server_sk, server.clone(), ServerConfig::default());
+
+        let client = TcpStream::connect(&addr)
+            .and_then(move |stream| make_client_handshake(stream, client_pk, client_sk, server_pk))
+            .and_then(|(stream, channel)| {
+                let secure_socket = Framed::new(stream, Codec::new(channel));
+                secure_socket.send(Packet::PingRequest(PingRequest { ping_id: 42 }))
+            })
+            // the client is registered when it gets PongResponse
+            .and_then(|secure_socket| secure_socket.into_future().map_err(|(e, _secure_socket)| e))
+            .and_then(move |(_packet, secure_socket)| {
+                assert!(server.queue_stats(&client_pk).is_some());
+                drop(secure_socket);
+                Interval::new(clock_now(), Duration::from_millis(10))
+                    .map_err(|e| Error::new(ErrorKind::Other, format!("Timer error: {:?}", e)))
+                    .skip_while(move |_instant| Ok(server_c.queue_stats(&client_pk).is_some()))
+                    .into_future()
+                    .map(|_| ())
+                    .map_err(|(e, _interval)| e)
+            });
+
+        let test = client.select(relay)
+            .map(|_| ())
+            .map_err(|(e, _select_next)| e)
+            .timeout(Duration::from_secs(10));
+
+        tokio::runtime::Runtime::new().unwrap().block_on(test).unwrap();
+    }
+
+    #[test]
+    fn run_handshake_timeout() {
+        let (_server_pk, server_sk) = gen_keypair();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            handshake_timeout: Duration::from_millis(100),
+            .. ServerConfig::default()
+        };
+        let server = run(listener, server_sk, Server::new(), config);
+
+        // the client doesn't send handshake so the server closes the
+        // connection
+        let client = TcpStream::connect(&addr)
+            .and_then(|stream| tokio::io::read_to_end(stream, Vec::new()))
+            .map(|(_stream, data)| assert!(data.is_empty()));
+
+        let test = client.select(server)
+            .map(|_| ())
+            .map_err(|(e, _select_next)| panic!("Error: {:?}", e));
+
+        tokio::run(test);
+    }
+}
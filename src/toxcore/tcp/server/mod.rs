@@ -24,7 +24,16 @@
 mod client;
 mod server;
 mod processor;
+mod listener;
+mod limits;
+mod allowlist;
+mod onion;
 
-pub use self::client::Client;
+pub use self::client::{Client, ClientRx, OverflowPolicy, QueueConfig, QueueStats, DEFAULT_QUEUE_CAPACITY};
 pub use self::server::Server;
 pub use self::processor::ServerProcessor;
+pub use self::allowlist::Allowlist;
+pub use self::limits::{Limits, RateLimit, DEFAULT_OOB_SEND_LIMIT, DEFAULT_ROUTE_REQUEST_LIMIT, DEFAULT_ONION_REQUEST_LIMIT,
+    DEFAULT_DATA_LIMIT, DEFAULT_MAX_CONNECTIONS_PER_IP, DEFAULT_MAX_VIOLATIONS};
+pub use self::listener::{run, ServerConfig, DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_WRITE_TIMEOUT, DEFAULT_MAX_CONNECTIONS};
+pub use self::onion::{new_onion_bridge, run_onion_bridge, TcpOnionRequestRx, TcpOnionResponseRx};
@@ -0,0 +1,163 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Bridge between the TCP relay and the DHT server for onion packets.
+
+TCP relay can't send onion requests of its clients by itself: they should be
+sent as `OnionRequest1` packets through the DHT server's UDP socket. Responses
+to these requests come to the DHT server as `OnionResponse1` packets and should
+be sent back to the client through the TCP relay.
+*/
+
+use toxcore::dht::server::Server as DhtServer;
+use toxcore::io_tokio::IoFuture;
+use toxcore::onion::packet::InnerOnionResponse;
+use toxcore::tcp::packet::OnionRequest;
+use toxcore::tcp::server::Server;
+
+use futures::{Future, Stream};
+use futures::sync::mpsc;
+
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+
+/// Shorthand for the receive half of the channel with onion requests from
+/// TCP relay clients.
+pub type TcpOnionRequestRx = mpsc::UnboundedReceiver<(OnionRequest, SocketAddr)>;
+
+/// Shorthand for the receive half of the channel with onion responses for TCP
+/// relay clients.
+pub type TcpOnionResponseRx = mpsc::UnboundedReceiver<(InnerOnionResponse, SocketAddr)>;
+
+/** Create channels for onion packets and connect TCP relay with DHT server.
+
+TCP relay `Server` is created with the sink for onion requests and the sink for
+onion responses is set to the DHT server. Returned receivers should be passed
+to `run_onion_bridge`.
+*/
+pub fn new_onion_bridge(dht_server: &mut DhtServer) -> (Server, TcpOnionRequestRx, TcpOnionResponseRx) {
+    let (request_tx, request_rx) = mpsc::unbounded();
+    let (response_tx, response_rx) = mpsc::unbounded();
+    dht_server.set_tcp_onion_sink(response_tx);
+    (Server::new_with_onion(request_tx), request_rx, response_rx)
+}
+
+/// Route onion requests of TCP relay clients through the DHT server and send
+/// onion responses back to them through the TCP relay. Failures of single
+/// packets are logged and don't stop the bridge.
+pub fn run_onion_bridge(
+    server: Server,
+    dht_server: DhtServer,
+    request_rx: TcpOnionRequestRx,
+    response_rx: TcpOnionResponseRx
+) -> IoFuture<()> {
+    let requests = request_rx
+        .map_err(|()| Error::new(ErrorKind::Other, "TCP onion requests rx error"))
+        .for_each(move |(request, addr)|
+            dht_server.handle_tcp_onion_request(request, addr).or_else(|e| {
+                debug!("Failed to send TCP onion request: {}", e);
+                Ok(())
+            })
+        );
+
+    let responses = response_rx
+        .map_err(|()| Error::new(ErrorKind::Other, "TCP onion responses rx error"))
+        .for_each(move |(response, addr)|
+            server.handle_udp_onion_response(addr.ip(), addr.port(), response).or_else(|e| {
+                debug!("Failed to send TCP onion response: {}", e);
+                Ok(())
+            })
+        );
+
+    Box::new(requests.join(responses).map(|_| ()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use failure::Fail;
+    use tokio;
+
+    use toxcore::crypto_core::*;
+    use toxcore::dht::packet::DhtPacket;
+    use toxcore::onion::packet::{IpPort, OnionAnnounceResponse, OnionResponse1};
+    use toxcore::tcp::packet::{OnionResponse, Packet};
+    use toxcore::tcp::server::Client;
+
+    #[test]
+    fn onion_request_and_response() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let mut dht_server = DhtServer::new(udp_tx, dht_pk, dht_sk);
+        let (server, request_rx, response_rx) = new_onion_bridge(&mut dht_server);
+
+        let client_pk = gen_keypair().0;
+        let client_addr: SocketAddr = "1.2.3.4:12345".parse().unwrap();
+        let (client_tx, client_rx) = mpsc::unbounded();
+        server.insert(Client::new(client_tx, &client_pk, client_addr.ip(), client_addr.port()));
+
+        let node_addr: SocketAddr = "5.6.7.8:33445".parse().unwrap();
+        let request = OnionRequest {
+            nonce: gen_nonce(),
+            ip_port: IpPort::from_udp_saddr(node_addr),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123],
+        };
+        let response = InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
+            sendback_data: 12345,
+            nonce: gen_nonce(),
+            payload: vec![42; 123],
+        });
+
+        let bridge = run_onion_bridge(server.clone(), dht_server.clone(), request_rx, response_rx);
+
+        // the client sends onion request to the relay
+        let request_c = request.clone();
+        let response_c = response.clone();
+        let test = server.handle_packet(&client_pk, Packet::OnionRequest(request.clone()))
+            // the DHT server should send it as OnionRequest1 to the next node
+            .and_then(|()| udp_rx.into_future().map_err(|_| Error::from(ErrorKind::UnexpectedEof)))
+            .and_then(move |(received, _udp_rx)| {
+                let (packet, addr) = received.unwrap();
+                assert_eq!(addr, node_addr);
+                let request_1 = unpack!(packet, DhtPacket::OnionRequest1);
+                assert_eq!(request_1.nonce, request_c.nonce);
+                assert_eq!(request_1.temporary_pk, request_c.temporary_pk);
+                assert_eq!(request_1.payload, request_c.payload);
+
+                // the next node answers with OnionResponse1 with the same
+                // onion return
+                let response_1 = OnionResponse1 {
+                    onion_return: request_1.onion_return,
+                    payload: response_c,
+                };
+                dht_server.handle_packet(DhtPacket::OnionResponse1(response_1), node_addr)
+                    .map_err(|e| Error::new(ErrorKind::Other, e.compat()))
+            })
+            // the relay should send the response to the client
+            .and_then(|()| client_rx.into_future().map_err(|_| Error::from(ErrorKind::UnexpectedEof)))
+            .map(move |(received, _client_rx)| {
+                assert_eq!(received.unwrap(), Packet::OnionResponse(OnionResponse { payload: response }));
+            });
+
+        tokio::run(bridge.select(test).map(|_| ()).map_err(|(e, _)| panic!("error: {:?}", e)));
+    }
+}
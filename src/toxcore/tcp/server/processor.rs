@@ -22,11 +22,12 @@
 */
 
 use toxcore::tcp::packet::*;
-use toxcore::tcp::server::{Server, Client};
+use toxcore::tcp::server::{Server, Client, ClientRx, QueueConfig};
 use toxcore::crypto_core::PublicKey;
 use toxcore::io_tokio::IoFuture;
 
 use futures::prelude::*;
+use futures::future;
 use futures::sync::mpsc;
 
 use std::net::IpAddr;
@@ -39,7 +40,7 @@ pub struct ServerProcessor {
     /// Send all `Packet`'s received from client to server
     pub from_client_tx: mpsc::UnboundedSender<Packet>,
     /// Client is notified with each packets of type `Packet`
-    pub to_client_rx: mpsc::UnboundedReceiver<Packet>,
+    pub to_client_rx: ClientRx,
 
     /// Run this future to process connection
     pub processor: IoFuture<()>
@@ -47,13 +48,26 @@ pub struct ServerProcessor {
 
 
 impl ServerProcessor {
-    /** Create `ServerProcessor` for the given server and connection
+    /** Create `ServerProcessor` for the given server and connection with
+    default limits of the client's queue
     */
     pub fn create(server: Server, client_pk: PublicKey, addr: IpAddr, port: u16) -> ServerProcessor {
+        ServerProcessor::create_with_queue(server, client_pk, addr, port, QueueConfig::default())
+    }
+
+    /** Create `ServerProcessor` for the given server and connection with the
+    given limits of the client's queue
+    */
+    pub fn create_with_queue(server: Server, client_pk: PublicKey, addr: IpAddr, port: u16, queue_config: QueueConfig) -> ServerProcessor {
         let (from_client_tx, from_client_rx) = mpsc::unbounded();
-        let (to_client_tx, to_client_rx) = mpsc::unbounded();
+        let (client, to_client_rx) = Client::new_with_queue(&client_pk, addr, port, queue_config);
 
-        server.insert(Client::new(to_client_tx, &client_pk, addr, port));
+        if !server.insert(client) {
+            let processor = Box::new(future::err(
+                Error::new(ErrorKind::Other, "Server refused to accept the client")
+            ));
+            return ServerProcessor { from_client_tx, to_client_rx, processor }
+        }
 
         let server_c = server.clone();
         // processor = for each Packet from client process it
@@ -113,6 +127,26 @@ mod tests {
         tokio::run(server_processor);
     }
     #[test]
+    fn server_processor_too_many_connections() {
+        let (client_pk, _sk) = gen_keypair();
+        let server = Server::new();
+        server.set_limits(Some(Limits { max_connections_per_ip: 0, .. Limits::default() }));
+
+        let ServerProcessor {
+            from_client_tx: _from_client_tx,
+            to_client_rx: _to_client_rx,
+            processor
+        } = ServerProcessor::create(
+            server.clone(),
+            client_pk,
+            "0.0.0.0".parse().unwrap(),
+            0
+        );
+
+        assert!(processor.wait().is_err());
+        assert_eq!(server.violations(&client_pk), None);
+    }
+    #[test]
     fn server_processor_handle_packet() {
         use toxcore::tcp::packet::*;
 
@@ -23,7 +23,9 @@
 
 use toxcore::crypto_core::*;
 use toxcore::onion::packet::InnerOnionResponse;
-use toxcore::tcp::server::client::Client;
+use toxcore::tcp::server::client::{Client, QueueStats};
+use toxcore::tcp::server::limits::Limits;
+use toxcore::tcp::server::allowlist::Allowlist;
 use toxcore::tcp::packet::*;
 use toxcore::io_tokio::IoFuture;
 
@@ -52,6 +54,10 @@ pub struct Server {
     state: Arc<RwLock<ServerState>>,
     // None if the server is not responsible to handle OnionRequests
     onion_sink: Option<mpsc::UnboundedSender<(OnionRequest, SocketAddr)>>,
+    // None if clients are not limited
+    limits: Arc<RwLock<Option<Limits>>>,
+    // None if the server accepts all clients
+    allowlist: Arc<RwLock<Option<Allowlist>>>,
 }
 
 #[derive(Default)]
@@ -73,21 +79,113 @@ impl Server {
         Server {
             state: Default::default(),
             onion_sink: Some(onion_sink),
+            limits: Default::default(),
+            allowlist: Default::default(),
         }
     }
-    /** Insert the client into connected_clients. Do nothing else.
+    /** Set limits applied to clients or disable them with `None`. Affects
+    only clients inserted after this call.
     */
-    pub fn insert(&self, client: Client) {
+    pub fn set_limits(&self, limits: Option<Limits>) {
+        *self.limits.write() = limits;
+    }
+    /** Make the relay private accepting only clients from the allowlist or
+    make it public again with `None`. Can be called at runtime: connected
+    clients that are not allowed anymore are shut down.
+    */
+    pub fn set_allowlist(&self, allowlist: Option<Allowlist>) -> IoFuture<()> {
+        // store the allowlist first so that clients inserted during the scan
+        // are checked against it
+        *self.allowlist.write() = allowlist.clone();
+        let not_allowed = if let Some(ref allowlist) = allowlist {
+            self.state.read().connected_clients.keys()
+                .filter(|pk| !allowlist.is_allowed(pk))
+                .cloned()
+                .collect::<Vec<_>>()
+        } else {
+            Vec::new()
+        };
+
+        let shutdowns = not_allowed.iter()
+            .map(|pk| {
+                debug!("Client {:?} is not allowed anymore", pk);
+                self.shutdown_client(pk)
+            })
+            .collect::<Vec<_>>();
+        // ignore errors of particular clients
+        Box::new( stream::futures_unordered(shutdowns)
+            .then(|_| Ok(()))
+            .for_each(Ok)
+        )
+    }
+    /** Insert the client into connected_clients and apply limits to it.
+    Returns `false` and doesn't insert the client if it's not in the
+    allowlist or there are too many clients connected from the same IP
+    address.
+    */
+    pub fn insert(&self, mut client: Client) -> bool {
+        if let Some(ref allowlist) = *self.allowlist.read() {
+            if !allowlist.is_allowed(&client.pk()) {
+                debug!("Client {:?} is not in the allowlist", client.pk());
+                return false;
+            }
+        }
+        let limits = *self.limits.read();
         let mut state = self.state.write();
+        if let Some(ref limits) = limits {
+            let connections_from_ip = state.connected_clients.values()
+                .filter(|c| c.ip_addr() == client.ip_addr() && c.pk() != client.pk())
+                .count();
+            if connections_from_ip >= limits.max_connections_per_ip {
+                debug!("Too many connections from {}", client.ip_addr());
+                return false;
+            }
+            client.set_limits(limits);
+        }
         state.keys_by_addr
             .insert((client.ip_addr(), client.port()), client.pk());
         state.connected_clients
             .insert(client.pk(), client);
+        true
+    }
+    /** Statistics of the outgoing queue of the client with the given PK
+    */
+    pub fn queue_stats(&self, pk: &PublicKey) -> Option<QueueStats> {
+        self.state.read().connected_clients.get(pk).map(|client| client.queue_stats())
+    }
+    /** Number of packets from the client with the given PK that exceeded
+    rate limits
+    */
+    pub fn violations(&self, pk: &PublicKey) -> Option<usize> {
+        self.state.read().connected_clients.get(pk).map(|client| client.violations())
     }
     /**The main processing function. Call in on each incoming packet from connected and
     handshaked client.
     */
     pub fn handle_packet(&self, pk: &PublicKey, packet: Packet) -> IoFuture<()> {
+        let limits = *self.limits.read();
+        // the state is not locked at all when limits are disabled
+        if let Some(limits) = limits {
+            let state = self.state.read();
+            if let Some(client) = state.connected_clients.get(pk) {
+                if !client.check_limits(&packet) {
+                    debug!("Client {:?} exceeded rate limit with {:?}", pk, packet);
+                    let disconnect = match limits.max_violations {
+                        Some(max_violations) => client.violations() >= max_violations,
+                        None => false,
+                    };
+                    return if disconnect {
+                        Box::new( future::err(
+                            Error::new(ErrorKind::Other,
+                                "Too many rate limit violations"
+                        )))
+                    } else {
+                        // drop the packet
+                        Box::new( future::ok(()) )
+                    }
+                }
+            }
+        }
         match packet {
             Packet::RouteRequest(packet) => self.handle_route_request(pk, packet),
             Packet::RouteResponse(packet) => self.handle_route_response(pk, packet),
@@ -151,6 +249,39 @@ impl Server {
         Box::new( stream::futures_unordered(notifications).for_each(Ok) )
     }
 
+    /** Send PingRequest to clients that were not pinged for a while and
+    shutdown clients that didn't answer the previous PingRequest in time.
+    Should be called periodically.
+    */
+    pub fn send_pings(&self) -> IoFuture<()> {
+        let (timed_out, pings) = {
+            let mut state = self.state.write();
+            let timed_out = state.connected_clients.values()
+                .filter(|client| client.is_pong_timedout())
+                .map(|client| client.pk())
+                .collect::<Vec<_>>();
+            let pings = state.connected_clients.values_mut()
+                .filter(|client| client.is_ping_interval_passed())
+                .map(|client| client.send_ping_request())
+                .collect::<Vec<_>>();
+            (timed_out, pings)
+        };
+
+        let shutdowns = timed_out.iter()
+            .map(|pk| {
+                debug!("Client {:?} didn't answer PingRequest in time", pk);
+                self.shutdown_client(pk)
+            })
+            .collect::<Vec<_>>();
+
+        // ignore errors of particular clients
+        let futures = pings.into_iter().chain(shutdowns);
+        Box::new( stream::futures_unordered(futures)
+            .then(|_| Ok(()))
+            .for_each(Ok)
+        )
+    }
+
     // Here start the impl of `handle_***` methods
 
     fn handle_route_request(&self, pk: &PublicKey, packet: RouteRequest) -> IoFuture<()> {
@@ -162,6 +293,15 @@ impl Server {
                     // send RouteResponse(0) if client requests its own pk
                     return client_a.send_route_response(pk, 0)
                 }
+                let route_allowed = match *self.allowlist.read() {
+                    Some(ref allowlist) => allowlist.is_route_allowed(&packet.pk),
+                    None => true,
+                };
+                if !route_allowed {
+                    // send RouteResponse(0) if private relay doesn't allow to
+                    // link with pk
+                    return client_a.send_route_response(&packet.pk, 0)
+                }
                 if let Some(b_id_in_client_a) = client_a.get_connection_id(&packet.pk) {
                     // send RouteResponse if client was already linked to pk
                     return client_a.send_route_response(&packet.pk, b_id_in_client_a)
@@ -282,9 +422,10 @@ impl Server {
                     "PongResponse.ping_id == 0"
             )))
         }
-        let state = self.state.read();
-        if let Some(client_a) = state.connected_clients.get(pk) {
+        let mut state = self.state.write();
+        if let Some(client_a) = state.connected_clients.get_mut(pk) {
             if packet.ping_id == client_a.ping_id() {
+                client_a.set_pong_received();
                 Box::new( future::ok(()) )
             } else {
                 Box::new( future::err(
@@ -397,11 +538,17 @@ mod tests {
     use ::toxcore::crypto_core::*;
     use ::toxcore::onion::packet::*;
     use ::toxcore::tcp::packet::*;
-    use ::toxcore::tcp::server::{Client, Server};
+    use ::toxcore::tcp::server::{Allowlist, Client, ClientRx, Limits, OverflowPolicy, QueueConfig, QueueStats, RateLimit, Server};
+    use ::toxcore::tcp::{TCP_PING_FREQUENCY, TCP_PING_TIMEOUT};
+    use ::toxcore::time::*;
     use futures::sync::mpsc;
     use futures::{Stream, Future};
     use quickcheck::{Arbitrary, StdGen};
     use std::net::{IpAddr, Ipv4Addr};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tokio_executor;
+    use tokio_timer::clock::*;
 
     #[test]
     fn server_is_clonable() {
@@ -424,6 +571,309 @@ mod tests {
         (client, rx)
     }
 
+    /// Create client with bounded queue and link it with another client.
+    /// The queue of the new client contains RouteResponse and
+    /// ConnectNotification after linking.
+    fn create_linked_clients(server: &Server, config: QueueConfig) -> (PublicKey, PublicKey, ClientRx) {
+        let (client_1, _rx_1) = create_random_client();
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1);
+
+        let client_pk_2 = gen_keypair().0;
+        let (client_2, rx_2) = Client::new_with_queue(&client_pk_2, "1.2.3.4".parse().unwrap(), 12345, config);
+        server.insert(client_2);
+
+        server.handle_packet(&client_pk_1, Packet::RouteRequest(RouteRequest { pk: client_pk_2 })).wait().unwrap();
+        server.handle_packet(&client_pk_2, Packet::RouteRequest(RouteRequest { pk: client_pk_1 })).wait().unwrap();
+
+        (client_pk_1, client_pk_2, rx_2)
+    }
+
+    #[test]
+    fn queue_overflow_drop_data() {
+        let server = Server::new();
+        let config = QueueConfig { capacity: 4, overflow_policy: OverflowPolicy::DropData };
+        let (client_pk_1, client_pk_2, rx_2) = create_linked_clients(&server, config);
+
+        assert_eq!(server.queue_stats(&client_pk_2), Some(QueueStats { len: 2, dropped: 0 }));
+
+        for i in 0 .. 3 {
+            let data = Packet::Data(Data { connection_id: 16, data: vec![i; 42] });
+            assert!(server.handle_packet(&client_pk_1, data).wait().is_ok());
+        }
+
+        // the last Data packet is dropped
+        assert_eq!(server.queue_stats(&client_pk_2), Some(QueueStats { len: 4, dropped: 1 }));
+
+        let packets = rx_2.take(4).collect().wait().unwrap();
+        assert_eq!(packets[2], Packet::Data(Data { connection_id: 16, data: vec![0; 42] }));
+        assert_eq!(packets[3], Packet::Data(Data { connection_id: 16, data: vec![1; 42] }));
+        assert_eq!(server.queue_stats(&client_pk_2), Some(QueueStats { len: 0, dropped: 1 }));
+    }
+
+    #[test]
+    fn queue_overflow_concurrent_senders() {
+        let server = Server::new();
+        let config = QueueConfig { capacity: 4, overflow_policy: OverflowPolicy::DropData };
+        let (client_pk_1, client_pk_2, _rx_2) = create_linked_clients(&server, config);
+
+        let threads = (0 .. 8).map(|_| {
+            let server = server.clone();
+            thread::spawn(move || {
+                for i in 0 .. 100 {
+                    let data = Packet::Data(Data { connection_id: 16, data: vec![i; 42] });
+                    server.handle_packet(&client_pk_1, data).wait().unwrap();
+                }
+            })
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // the queue is never filled over its capacity
+        let stats = server.queue_stats(&client_pk_2).unwrap();
+        assert!(stats.len <= config.capacity);
+        assert_eq!(stats.len + stats.dropped, 2 + 8 * 100);
+    }
+
+    #[test]
+    fn queue_overflow_drop_data_disconnect_on_control_packet() {
+        let server = Server::new();
+        let config = QueueConfig { capacity: 2, overflow_policy: OverflowPolicy::DropData };
+        let (_client_pk_1, client_pk_2, rx_2) = create_linked_clients(&server, config);
+
+        // PongResponse doesn't fit into the queue
+        let ping = Packet::PingRequest(PingRequest { ping_id: 42 });
+        assert!(server.handle_packet(&client_pk_2, ping).wait().is_ok());
+
+        assert_eq!(server.queue_stats(&client_pk_2), Some(QueueStats { len: 2, dropped: 1 }));
+        assert!(rx_2.into_future().wait().is_err());
+    }
+
+    #[test]
+    fn queue_overflow_disconnect() {
+        let server = Server::new();
+        let config = QueueConfig { capacity: 2, overflow_policy: OverflowPolicy::Disconnect };
+        let (client_pk_1, client_pk_2, rx_2) = create_linked_clients(&server, config);
+
+        // the sender is not punished for the slow receiver
+        let data = Packet::Data(Data { connection_id: 16, data: vec![42; 42] });
+        assert!(server.handle_packet(&client_pk_1, data).wait().is_ok());
+
+        assert_eq!(server.queue_stats(&client_pk_2), Some(QueueStats { len: 2, dropped: 1 }));
+        assert!(rx_2.into_future().wait().is_err());
+    }
+
+    #[test]
+    fn insert_too_many_connections_from_ip() {
+        let server = Server::new();
+        server.set_limits(Some(Limits { max_connections_per_ip: 2, .. Limits::default() }));
+
+        let ip_addr = "1.2.3.4".parse().unwrap();
+        let (tx, _rx) = mpsc::unbounded();
+        let client_pk_1 = gen_keypair().0;
+        assert!(server.insert(Client::new(tx.clone(), &client_pk_1, ip_addr, 12345)));
+        assert!(server.insert(Client::new(tx.clone(), &gen_keypair().0, ip_addr, 12346)));
+        assert!(!server.insert(Client::new(tx.clone(), &gen_keypair().0, ip_addr, 12347)));
+        // reconnection of the same client is allowed
+        assert!(server.insert(Client::new(tx.clone(), &client_pk_1, ip_addr, 12348)));
+        // other IP addresses are not affected
+        assert!(server.insert(Client::new(tx, &gen_keypair().0, "1.2.3.5".parse().unwrap(), 12345)));
+    }
+
+    #[test]
+    fn set_limits_affects_clones() {
+        let server = Server::new();
+        let server_c = server.clone();
+
+        let ip_addr = "1.2.3.4".parse().unwrap();
+        let (tx, _rx) = mpsc::unbounded();
+        // clients are not limited by default
+        assert!(server.insert(Client::new(tx.clone(), &gen_keypair().0, ip_addr, 12345)));
+        assert!(server.insert(Client::new(tx.clone(), &gen_keypair().0, ip_addr, 12346)));
+
+        server_c.set_limits(Some(Limits { max_connections_per_ip: 2, .. Limits::default() }));
+        assert!(!server.insert(Client::new(tx, &gen_keypair().0, ip_addr, 12347)));
+    }
+
+    #[test]
+    fn handle_oob_send_rate_limited() {
+        let server = Server::new();
+        server.set_limits(Some(Limits {
+            oob_send: RateLimit { rate: 1, burst: 1 },
+            max_violations: None,
+            .. Limits::default()
+        }));
+
+        let (client_1, _rx_1) = create_random_client();
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1);
+
+        let (client_2, rx_2) = create_random_client();
+        let client_pk_2 = client_2.pk();
+        server.insert(client_2);
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+        with_default(&clock, &mut enter, |_| {
+            for i in 0 .. 3 {
+                server.handle_packet(&client_pk_1, Packet::OobSend(
+                    OobSend { destination_pk: client_pk_2, data: vec![i; 42] }
+                )).wait().unwrap();
+            }
+        });
+
+        // only the first packet is delivered
+        assert_eq!(server.violations(&client_pk_1), Some(2));
+        drop(server);
+        let packets = rx_2.collect().wait().unwrap();
+        assert_eq!(packets, vec![Packet::OobReceive(
+            OobReceive { sender_pk: client_pk_1, data: vec![0; 42] }
+        )]);
+    }
+
+    #[test]
+    fn handle_oob_send_limits_disabled() {
+        let server = Server::new();
+        server.set_limits(Some(Limits {
+            oob_send: RateLimit { rate: 1, burst: 1 },
+            max_violations: None,
+            .. Limits::default()
+        }));
+
+        let (client_1, _rx_1) = create_random_client();
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1);
+
+        let (client_2, rx_2) = create_random_client();
+        let client_pk_2 = client_2.pk();
+        server.insert(client_2);
+
+        // clients inserted with limits are not limited after they are disabled
+        server.set_limits(None);
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+        with_default(&clock, &mut enter, |_| {
+            for i in 0 .. 3 {
+                server.handle_packet(&client_pk_1, Packet::OobSend(
+                    OobSend { destination_pk: client_pk_2, data: vec![i; 42] }
+                )).wait().unwrap();
+            }
+        });
+
+        assert_eq!(server.violations(&client_pk_1), Some(0));
+        drop(server);
+        let packets = rx_2.collect().wait().unwrap();
+        assert_eq!(packets.len(), 3);
+    }
+
+    #[test]
+    fn handle_packet_too_many_violations() {
+        let server = Server::new();
+        server.set_limits(Some(Limits {
+            data: RateLimit { rate: 1, burst: 1 },
+            max_violations: Some(2),
+            .. Limits::default()
+        }));
+
+        let (client_1, _rx_1) = create_random_client();
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1);
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+        with_default(&clock, &mut enter, |_| {
+            let data = Packet::Data(Data { connection_id: 42, data: vec![42; 42] });
+            // the first packet passes the limit but it's not linked
+            assert!(server.handle_packet(&client_pk_1, data.clone()).wait().is_err());
+            assert!(server.handle_packet(&client_pk_1, data.clone()).wait().is_ok());
+            assert!(server.handle_packet(&client_pk_1, data).wait().is_err());
+        });
+        assert_eq!(server.violations(&client_pk_1), Some(2));
+    }
+
+    #[test]
+    fn insert_not_allowlisted() {
+        let (client_1, _rx_1) = create_random_client();
+        let client_pk_1 = client_1.pk();
+        let (client_2, _rx_2) = create_random_client();
+        let client_pk_2 = client_2.pk();
+
+        let server = Server::new();
+        let allowlist = Allowlist::new(vec![client_pk_1].into_iter().collect());
+        server.set_allowlist(Some(allowlist)).wait().unwrap();
+
+        assert!(server.insert(client_1));
+        assert!(!server.insert(client_2));
+        assert_eq!(server.queue_stats(&client_pk_1), Some(QueueStats::default()));
+        assert_eq!(server.queue_stats(&client_pk_2), None);
+    }
+
+    #[test]
+    fn set_allowlist_shuts_down_not_allowed_clients() {
+        let server = Server::new();
+
+        let (client_1, _rx_1) = create_random_client();
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1);
+
+        let (client_2, rx_2) = create_random_client();
+        let client_pk_2 = client_2.pk();
+        server.insert(client_2);
+
+        let allowlist = Allowlist::new(vec![client_pk_1].into_iter().collect());
+        server.set_allowlist(Some(allowlist)).wait().unwrap();
+
+        assert!(server.queue_stats(&client_pk_1).is_some());
+        assert!(server.queue_stats(&client_pk_2).is_none());
+        // the channel of the removed client is closed
+        assert!(rx_2.collect().wait().unwrap().is_empty());
+
+        // the relay is public again
+        server.set_allowlist(None).wait().unwrap();
+        let (client_3, _rx_3) = create_random_client();
+        assert!(server.insert(client_3));
+    }
+
+    #[test]
+    fn handle_route_request_restricted() {
+        let (client_1, rx_1) = create_random_client();
+        let client_pk_1 = client_1.pk();
+        let (client_2, _rx_2) = create_random_client();
+        let client_pk_2 = client_2.pk();
+        let stranger_pk = gen_keypair().0;
+
+        let server = Server::new();
+        let mut allowlist = Allowlist::new(vec![client_pk_1, client_pk_2].into_iter().collect());
+        allowlist.restrict_routes = true;
+        server.set_allowlist(Some(allowlist)).wait().unwrap();
+        server.insert(client_1);
+        server.insert(client_2);
+
+        server.handle_packet(&client_pk_1, Packet::RouteRequest(
+            RouteRequest { pk: stranger_pk }
+        )).wait().unwrap();
+        server.handle_packet(&client_pk_1, Packet::RouteRequest(
+            RouteRequest { pk: client_pk_2 }
+        )).wait().unwrap();
+
+        let packets = rx_1.take(2).collect().wait().unwrap();
+        assert_eq!(packets, vec![
+            Packet::RouteResponse(RouteResponse { pk: stranger_pk, connection_id: 0 }),
+            Packet::RouteResponse(RouteResponse { pk: client_pk_2, connection_id: 16 }),
+        ]);
+    }
+
+    #[test]
+    fn queue_stats_unknown_client() {
+        let server = Server::new();
+        assert_eq!(server.queue_stats(&gen_keypair().0), None);
+    }
+
     #[test]
     fn normal_communication_scenario() {
         let server = Server::new();
@@ -701,6 +1151,94 @@ mod tests {
         ));
     }
     #[test]
+    fn send_pings() {
+        let server = Server::new();
+
+        let (client_1, rx_1) = create_random_client();
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1);
+
+        // it's too early to ping the client
+        server.send_pings().wait().unwrap();
+
+        let now = clock_now() + Duration::from_secs(TCP_PING_FREQUENCY);
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+
+        with_default(&clock, &mut enter, |_| {
+            server.send_pings().wait().unwrap();
+            // the client is pinged only once until it answers
+            server.send_pings().wait().unwrap();
+        });
+
+        let (packet, _rx_1) = rx_1.into_future().wait().unwrap();
+        let ping_id = unpack!(packet.unwrap(), Packet::PingRequest).ping_id;
+        assert_ne!(ping_id, 0);
+
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(1)));
+
+        with_default(&clock, &mut enter, |_| {
+            server.handle_packet(&client_pk_1, Packet::PongResponse(
+                PongResponse { ping_id }
+            )).wait().unwrap();
+        });
+
+        let state = server.state.read();
+        let client_1 = &state.connected_clients[&client_pk_1];
+        assert_eq!(client_1.ping_id(), 0);
+        assert_eq!(client_1.last_pong_resp(), now + Duration::from_secs(1));
+    }
+    #[test]
+    fn send_pings_pong_timeout() {
+        let server = Server::new();
+
+        let (client_1, _rx_1) = create_random_client();
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1);
+
+        let (client_2, rx_2) = create_random_client();
+        let client_pk_2 = client_2.pk();
+        server.insert(client_2);
+
+        // link clients with each other
+        server.handle_packet(&client_pk_1, Packet::RouteRequest(
+            RouteRequest { pk: client_pk_2 }
+        )).wait().unwrap();
+        server.handle_packet(&client_pk_2, Packet::RouteRequest(
+            RouteRequest { pk: client_pk_1 }
+        )).wait().unwrap();
+
+        let now = clock_now() + Duration::from_secs(TCP_PING_FREQUENCY);
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+
+        with_default(&clock, &mut enter, |_| {
+            server.send_pings().wait().unwrap();
+        });
+
+        // only client_2 answers PingRequest
+        let ping_id = server.state.read().connected_clients[&client_pk_2].ping_id();
+        server.handle_packet(&client_pk_2, Packet::PongResponse(
+            PongResponse { ping_id }
+        )).wait().unwrap();
+
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(TCP_PING_TIMEOUT)));
+
+        with_default(&clock, &mut enter, |_| {
+            server.send_pings().wait().unwrap();
+        });
+
+        assert!(!server.state.read().connected_clients.contains_key(&client_pk_1));
+        assert!(server.state.read().connected_clients.contains_key(&client_pk_2));
+
+        // client_2 should be notified that client_1 is disconnected
+        drop(server);
+        let packets = rx_2.collect().wait().unwrap();
+        assert_eq!(packets.last().unwrap().clone(), Packet::DisconnectNotification(
+            DisconnectNotification { connection_id: 16 }
+        ));
+    }
+    #[test]
     fn handle_oob_send() {
         let server = Server::new();
 
@@ -215,6 +215,26 @@ impl ToxId {
         ToxId { pk, nospam, checksum }
     }
 
+    /** Get `NoSpam` of `ToxId`.
+
+    E.g.
+
+    ```
+    use self::tox::toxcore::crypto_core::gen_keypair;
+    use self::tox::toxcore::toxid::{NoSpam, ToxId};
+
+    let (pk, _) = gen_keypair();
+    let mut toxid = ToxId::new(pk);
+    let nospam = NoSpam::new();
+    toxid.new_nospam(Some(nospam));
+
+    assert_eq!(toxid.nospam(), nospam);
+    ```
+    */
+    pub fn nospam(&self) -> NoSpam {
+        self.nospam
+    }
+
     /** Change `NoSpam`. If provided, change to provided value. If not provided
     (`None`), generate random `NoSpam`.
 